@@ -0,0 +1,63 @@
+use crate::*;
+use dev::*;
+use std::{fs::{File, OpenOptions}, io::{Read as _, Seek as _, SeekFrom, Write as _}, slice};
+
+const BLOCK_SIZE: usize = 512;
+
+// a volume image in a host file, only opened for writing when it is to be repaired
+pub struct ImageFile {
+    file: File,
+    path: String,
+}
+
+impl ImageFile {
+    pub fn open(path: &str, writable: bool) -> Result<ImageFile, Error> {
+        let file = OpenOptions::new().read(true).write(writable).open(path).map_err(|_| Error::EntryNotFound)?;
+        Ok(ImageFile {
+            file,
+            path: String::from(path),
+        })
+    }
+
+    fn seek_block(&mut self, block: u64) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(block * BLOCK_SIZE as u64)).map_err(|_| Error::IOFailure)?;
+        Ok(())
+    }
+}
+
+impl BlockRead for ImageFile {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn read_block(&mut self, block: u64, buffer: *mut u8) -> Result<(), Error> {
+        self.read_blocks(block, 1, buffer)
+    }
+
+    fn read_blocks(&mut self, start_block: u64, count: u64, buffer: *mut u8) -> Result<(), Error> {
+        self.seek_block(start_block)?;
+        let buf = unsafe { slice::from_raw_parts_mut(buffer, count as usize * BLOCK_SIZE) };
+        self.file.read_exact(buf).map_err(|_| Error::ReadFailure)
+    }
+}
+
+impl BlockWrite for ImageFile {
+    fn write_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.write_blocks(block, &mut buffer[..BLOCK_SIZE])
+    }
+
+    fn write_blocks(&mut self, start_block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.seek_block(start_block)?;
+        self.file.write_all(buffer).map_err(|_| Error::WriteFailure)
+    }
+}
+
+impl Resource for ImageFile {
+    fn unwrap(&mut self) -> ResourceType {
+        ResourceType::Other
+    }
+
+    fn resource_path(&self) -> Vec<String> {
+        split_resource_path(self.path.clone())
+    }
+}
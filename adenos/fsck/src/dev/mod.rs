@@ -0,0 +1,37 @@
+use crate::*;
+use namespace::*;
+
+#[path = "../../../kernel/src/dev/filesystem/mod.rs"]
+pub mod filesystem;
+pub mod image;
+
+pub trait Write {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error>;
+}
+
+pub trait Read {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+}
+
+pub trait Seek {
+    fn seek(&mut self, position: u64) -> Result<(), Error>;
+    fn offset(&self) -> u64;
+    fn size(&self) -> u64;
+}
+
+pub trait RandomReadWrite: Seek + Read + Write {}
+impl<T: Seek + Read + Write> RandomReadWrite for T {}
+
+pub trait BlockRead {
+    fn block_size(&self) -> usize;
+    fn read_block(&mut self, block: u64, buffer: *mut u8) -> Result<(), Error>;
+    fn read_blocks(&mut self, start_block: u64, count: u64, buffer: *mut u8) -> Result<(), Error>;
+}
+
+pub trait BlockWrite: BlockRead {
+    fn write_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error>;
+    fn write_blocks(&mut self, start_block: u64, buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+pub trait BlockReadWrite: BlockRead + BlockWrite + Resource {}
+impl<T: BlockRead + BlockWrite + Resource> BlockReadWrite for T {}
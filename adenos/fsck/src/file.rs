@@ -0,0 +1,30 @@
+use crate::*;
+use dev::{filesystem::FileSystem, RandomReadWrite};
+use bitflags::bitflags;
+
+bitflags! {
+    pub struct FilePermissions: u16 {
+        const READ = 0b0000000000000001;
+        const WRITE = 0b0000000000000010;
+        const EXECUTE = 0b0000000000000100;
+    }
+}
+
+// files are never opened by the checker, this is only what the driver hands out
+pub struct File {
+    _fs: &'static dyn FileSystem,
+    _fs_file: Box<dyn RandomReadWrite>,
+    _path: String,
+    _permissions: FilePermissions,
+}
+
+impl File {
+    pub fn new(path: String, fs: &'static dyn FileSystem, fs_file: Box<dyn RandomReadWrite>, permissions: FilePermissions) -> File {
+        File {
+            _fs: fs,
+            _fs_file: fs_file,
+            _path: path,
+            _permissions: permissions,
+        }
+    }
+}
@@ -0,0 +1,76 @@
+#![feature(iter_advance_by)]
+
+// the kernel's FAT driver is built as it is, on top of host versions of what it uses from the kernel
+pub mod dev;
+pub mod file;
+pub mod time;
+pub mod namespace;
+
+extern crate alloc;
+
+pub use infinity::error::*;
+
+use std::{env, process};
+use dev::{image::ImageFile, filesystem::fat::FATFileSystem};
+use infinity::os::FileSystemCheck;
+
+// exit codes as other fsck tools have them
+const EXIT_CLEAN: i32 = 0;
+const EXIT_REPAIRED: i32 = 1;
+const EXIT_ERRORS_LEFT: i32 = 4;
+const EXIT_FAILED: i32 = 8;
+
+fn usage() -> ! {
+    eprintln!("usage: fsck [-r | --repair] <image>");
+    process::exit(EXIT_FAILED);
+}
+
+// also tells whether the volume was marked as not unmounted cleanly
+fn check(path: &str, repair: bool) -> Result<(FileSystemCheck, bool), Error> {
+    let image = ImageFile::open(path, repair)?;
+    let fat_fs = FATFileSystem::from_drive(Box::leak(Box::new(image)))?.ok_or(Error::InvalidData)?;
+    let dirty = !fat_fs.mounted_clean();
+    if dirty {
+        println!("{} was not unmounted cleanly", path);
+    }
+    let report = fat_fs.check_volume(repair)?;
+    if repair {
+        // consistent now, so there is nothing left for the next mount to check
+        fat_fs.set_clean(true)?;
+    }
+    Ok((report, dirty))
+}
+
+fn main() {
+    let mut repair = false;
+    let mut image = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "-r" | "--repair" => repair = true,
+            _ if image.is_none() && !arg.starts_with('-') => image = Some(arg),
+            _ => usage(),
+        }
+    }
+    let image = image.unwrap_or_else(|| usage());
+    let (report, dirty) = match check(&image, repair) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}: check failed: {:?}", image, err);
+            process::exit(EXIT_FAILED);
+        },
+    };
+    println!("lost clusters: {} in {} chains", report.lost_clusters, report.lost_chains);
+    println!("cross-linked files: {}", report.cross_linked_files);
+    println!("broken chains: {}", report.broken_chains);
+    println!("size mismatches: {}", report.size_mismatches);
+    println!("bad long names: {}", report.bad_long_names);
+    // without repairing the dirty flag stays set, so such a volume still counts as having errors left
+    process::exit(if report.errors() == 0 && !dirty {
+        EXIT_CLEAN
+    } else if repair {
+        println!("{} repaired", image);
+        EXIT_REPAIRED
+    } else {
+        EXIT_ERRORS_LEFT
+    });
+}
@@ -0,0 +1,32 @@
+use crate::*;
+use dev::{filesystem::FileSystem, BlockReadWrite};
+
+pub enum ResourceType<'a> {
+    FileSystem(&'a mut dyn FileSystem),
+    Other,
+}
+
+pub trait Resource {
+    fn is_open(&self) -> bool {
+        true
+    }
+
+    fn set_open_state(&mut self, _open: bool) {
+
+    }
+
+    fn unwrap(&mut self) -> ResourceType;
+    fn resource_path(&self) -> Vec<String>;
+    fn resource_path_string(&self) -> String {
+        self.resource_path().iter().map(|node| String::from("/") + node.as_str()).collect()
+    }
+}
+
+pub fn split_resource_path(path: String) -> Vec<String> {
+    path.split("/").filter(|s| !s.is_empty()).map(|s| String::from(s)).collect()
+}
+
+// there is no namespace to look drives up in, they are handed to the file system directly
+pub fn get_block_device(_path: String) -> Option<&'static mut dyn BlockReadWrite> {
+    None
+}
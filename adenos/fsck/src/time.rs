@@ -0,0 +1,10 @@
+use infinity::os::DateTime;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_time_microseconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_micros() as u64)
+}
+
+pub fn now() -> DateTime {
+    DateTime::from_unix_time(unix_time_microseconds() / 1_000_000)
+}
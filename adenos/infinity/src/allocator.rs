@@ -1,3 +1,135 @@
-use slab_allocator_rs::LockedHeap;
-#[global_allocator]
-pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
\ No newline at end of file
+use core::{alloc::{GlobalAlloc, Layout}, ptr::{self, NonNull}, sync::atomic::{AtomicUsize, Ordering}};
+#[cfg(not(feature = "kernel_mode"))]
+use crate::os::{self, MemoryProtection};
+
+// upper bounds of the size classes counted in the statistics, anything bigger goes into the last one
+pub const SIZE_CLASSES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+// grow in steps of at least this much, so that small allocations do not each have to map memory
+const HEAP_GROWTH: usize = 0x10000;
+
+const NO_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// maps size more bytes at the given top of the heap, or anywhere when the heap is still empty, and returns where they start
+pub type GrowHandler = fn(Option<*mut u8>, usize) -> Option<*mut u8>;
+
+#[cfg(feature = "kernel_mode")]
+static mut GROW_HANDLER: Option<GrowHandler> = None;
+#[cfg(not(feature = "kernel_mode"))]
+static mut GROW_HANDLER: Option<GrowHandler> = Some(map_more_memory);
+
+// a program running on the host keeps the standard library's
+#[cfg_attr(not(feature = "host"), global_allocator)]
+pub static ALLOCATOR: GrowingHeap = GrowingHeap::empty();
+
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStatistics {
+    pub heap_size: usize,
+    pub in_use: usize,
+    pub peak: usize,
+    pub failed: usize,
+    // live allocations per entry of SIZE_CLASSES, plus the ones above it
+    pub allocations: [usize; SIZE_CLASSES.len() + 1],
+}
+
+pub struct GrowingHeap {
+    heap: linked_list_allocator::LockedHeap,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    failed: AtomicUsize,
+    allocations: [AtomicUsize; SIZE_CLASSES.len() + 1],
+}
+
+impl GrowingHeap {
+    pub const fn empty() -> GrowingHeap {
+        GrowingHeap {
+            heap: linked_list_allocator::LockedHeap::empty(),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            allocations: [NO_ALLOCATIONS; SIZE_CLASSES.len() + 1],
+        }
+    }
+
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.heap.lock().init(start as *mut u8, size);
+    }
+
+    pub fn set_grow_handler(&self, handler: GrowHandler) {
+        unsafe { GROW_HANDLER = Some(handler); }
+    }
+
+    pub fn statistics(&self) -> HeapStatistics {
+        let mut allocations = [0; SIZE_CLASSES.len() + 1];
+        for (count, class) in allocations.iter_mut().zip(self.allocations.iter()) {
+            *count = class.load(Ordering::Relaxed);
+        }
+        HeapStatistics {
+            heap_size: self.heap.lock().size(),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            allocations,
+        }
+    }
+
+    fn size_class(size: usize) -> usize {
+        SIZE_CLASSES.iter().position(|&class| size <= class).unwrap_or(SIZE_CLASSES.len())
+    }
+
+    unsafe fn grow(heap: &mut linked_list_allocator::Heap, layout: Layout) -> bool {
+        let handler = match GROW_HANDLER {
+            Some(handler) => handler,
+            None => return false,
+        };
+        let grow = ((layout.size() + layout.align() + HEAP_GROWTH - 1) / HEAP_GROWTH) * HEAP_GROWTH;
+        if heap.size() == 0 {
+            match handler(None, grow) {
+                Some(bottom) => heap.init(bottom, grow),
+                None => return false,
+            }
+        } else {
+            match handler(Some(heap.top()), grow) {
+                Some(_) => heap.extend(grow),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for GrowingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        let ptr = match heap.allocate_first_fit(layout) {
+            Ok(ptr) => Some(ptr),
+            Err(_) if Self::grow(&mut heap, layout) => heap.allocate_first_fit(layout).ok(),
+            Err(_) => None,
+        };
+        drop(heap);
+        match ptr {
+            Some(ptr) => {
+                let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+                self.peak.fetch_max(in_use, Ordering::Relaxed);
+                self.allocations[Self::size_class(layout.size())].fetch_add(1, Ordering::Relaxed);
+                ptr.as_ptr()
+            },
+            None => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                ptr::null_mut()
+            },
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.allocations[Self::size_class(layout.size())].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// the heap starts at HEAP_BASE and grows up from there
+#[cfg(not(feature = "kernel_mode"))]
+fn map_more_memory(top: Option<*mut u8>, size: usize) -> Option<*mut u8> {
+    let address = top.unwrap_or(os::HEAP_BASE as *mut u8);
+    os::map_memory(Some(address), size, MemoryProtection::READ | MemoryProtection::WRITE).ok()
+}
@@ -25,7 +25,12 @@ pub enum Error {
     AlreadyOpen = -17,
     InvalidExecutable = -18,
     NoData = -19,
-
+    InvalidAddress = -20,
+    TimedOut = -21,
+    AlreadyExists = -22,
+    NotDirectory = -23,
+    IsDirectory = -24,
+    DirectoryNotEmpty = -25,
 }
 
 impl Error {
@@ -1,11 +1,150 @@
 use crate::*;
-use os::*;
-use alloc::collections::BTreeMap;
+use alloc::{vec, vec::Vec, format, collections::BTreeMap};
+use core::{mem::size_of, ptr};
+
+const LPC_CONNECT_QUEUE: &str = "lpc_connect";
+const LPC_FRAME_BUFFER_SIZE: usize = 0x1000;
+// how long to wait before ringing a server whose connect queue was full again
+const LPC_NOTIFY_RETRY: u32 = 10;
+
+pub type LPCFunction = fn(u32, &[u8]) -> Result<Vec<u8>, Error>;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LPCFrameType {
+    Connect = 0,
+    Accept = 1,
+    Call = 2,
+    Notify = 3,
+    Response = 4,
+    Disconnect = 5,
+}
+
+impl LPCFrameType {
+    fn from_u8(val: u8) -> Option<LPCFrameType> {
+        match val {
+            0 => Some(LPCFrameType::Connect),
+            1 => Some(LPCFrameType::Accept),
+            2 => Some(LPCFrameType::Call),
+            3 => Some(LPCFrameType::Notify),
+            4 => Some(LPCFrameType::Response),
+            5 => Some(LPCFrameType::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+struct LPCHeader {
+    frame_type: u8,
+    client: u32,
+    function_id: u32,
+    call_id: u32,
+    status: i64,
+}
+
+#[derive(Debug)]
+pub struct LPCFrame {
+    pub frame_type: LPCFrameType,
+    pub client: u32,
+    pub function_id: u32,
+    pub call_id: u32,
+    pub status: i64,
+    pub payload: Vec<u8>,
+}
+
+impl LPCFrame {
+    pub fn new(frame_type: LPCFrameType, client: u32, function_id: u32, call_id: u32, status: i64, payload: &[u8]) -> LPCFrame {
+        LPCFrame {
+            frame_type,
+            client,
+            function_id,
+            call_id,
+            status,
+            payload: payload.to_vec(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let head = LPCHeader {
+            frame_type: self.frame_type as u8,
+            client: self.client,
+            function_id: self.function_id,
+            call_id: self.call_id,
+            status: self.status,
+        };
+        let mut bytes = vec![0; size_of::<LPCHeader>()];
+        unsafe { ptr::write_unaligned(bytes.as_mut_ptr() as *mut LPCHeader, head); }
+        bytes.extend_from_slice(self.payload.as_slice());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<LPCFrame, Error> {
+        if bytes.len() < size_of::<LPCHeader>() {
+            return Err(Error::InvalidData);
+        }
+        let head = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const LPCHeader) };
+        Ok(LPCFrame {
+            frame_type: LPCFrameType::from_u8(head.frame_type).ok_or(Error::InvalidData)?,
+            client: head.client,
+            function_id: head.function_id,
+            call_id: head.call_id,
+            status: head.status,
+            payload: bytes[size_of::<LPCHeader>()..].to_vec(),
+        })
+    }
+
+    pub fn result(self) -> Result<Vec<u8>, Error> {
+        Error::from_code_to_nothing(self.status)?;
+        Ok(self.payload)
+    }
+}
+
+fn send_frame(handle: u32, frame: &LPCFrame) -> Result<(), Error> {
+    os::send_message(handle, frame.to_bytes().as_slice())
+}
+
+fn receive_frame(handle: u32, timeout: Option<u32>) -> Result<LPCFrame, Error> {
+    let mut buf = vec![0; LPC_FRAME_BUFFER_SIZE];
+    let len = match os::receive_message(handle, buf.as_mut_slice(), timeout) {
+        Err(Error::BufferTooSmall) => {
+            // the message is still queued, grow the buffer and take it
+            buf = vec![0; os::available_message_size(handle)?];
+            os::receive_message(handle, buf.as_mut_slice(), Some(0))?
+        },
+        res => res?,
+    };
+    LPCFrame::from_bytes(&buf[..len])
+}
+
+// the frame and the process that sent it, which unlike the frame's client field cannot be made up
+fn receive_frame_from(handle: u32, timeout: Option<u32>) -> Result<(LPCFrame, u32), Error> {
+    let from = os::wait_message(handle, timeout)?;
+    Ok((receive_frame(handle, Some(0))?, from))
+}
+
+fn connect_queue_path(server: u32) -> alloc::string::String {
+    format!("/Processes/{}/MessageChannels/{}", server, LPC_CONNECT_QUEUE)
+}
+
+fn call_queue_name(client: u32) -> alloc::string::String {
+    format!("lpc_{}", client)
+}
+
+fn reply_queue_name(server: u32) -> alloc::string::String {
+    format!("lpc_reply_{}", server)
+}
+
+struct LPCConnection {
+    call_channel: u32,
+    reply_channel: u32,
+}
 
 pub struct LPCServer {
     connection_handle: Option<u32>,
-    client_channels: BTreeMap<u32, u32>,
-    functions: BTreeMap<u32, fn(*const u8) -> i64>,
+    client_channels: BTreeMap<u32, LPCConnection>,
+    functions: BTreeMap<u32, LPCFunction>,
 }
 
 impl LPCServer {
@@ -18,17 +157,223 @@ impl LPCServer {
     }
 
     pub fn init(&mut self) -> Result<(), Error> {
-        let _ = self.connection_handle.insert(os::create_message_queue("lpc_connect", 0)?);
+        let _ = self.connection_handle.insert(os::create_message_queue(LPC_CONNECT_QUEUE, 0)?);
         Ok(())
     }
 
-    pub fn register_function(&mut self, id: u32, function: fn(*const u8) -> i64) {
+    pub fn register_function(&mut self, id: u32, function: LPCFunction) {
         self.functions.insert(id, function);
     }
 
-    pub fn run() {
+    pub fn run(&mut self) -> Result<(), Error> {
+        let connection_handle = self.connection_handle.ok_or(Error::InitFailure)?;
         loop {
-            
+            let (frame, client) = match receive_frame_from(connection_handle, None) {
+                Ok(received) => received,
+                Err(Error::InvalidData) => continue,
+                Err(err) => return Err(err),
+            };
+            match frame.frame_type {
+                LPCFrameType::Connect => self.accept(client),
+                LPCFrameType::Notify => self.service(client),
+                _ => (),
+            }
         }
     }
-}
\ No newline at end of file
+
+    fn accept(&mut self, client: u32) {
+        if self.client_channels.contains_key(&client) {
+            return;
+        }
+        let reply_channel = match os::acquire_handle(format!("/Processes/{}/MessageChannels/{}", client, reply_queue_name(os::get_process_id())).as_str()) {
+            Ok(hndl) => hndl,
+            Err(_) => return, // nobody to tell about it
+        };
+        match os::create_message_queue(call_queue_name(client).as_str(), client) {
+            Ok(call_channel) => {
+                self.client_channels.insert(client, LPCConnection {
+                    call_channel,
+                    reply_channel,
+                });
+                let _ = send_frame(reply_channel, &LPCFrame::new(LPCFrameType::Accept, client, 0, 0, 0, &[]));
+            },
+            Err(err) => {
+                let _ = send_frame(reply_channel, &LPCFrame::new(LPCFrameType::Accept, client, 0, 0, err.code(), &[]));
+                let _ = os::release_handle(reply_channel);
+            },
+        }
+    }
+
+    fn service(&mut self, client: u32) {
+        let (call_channel, reply_channel) = match self.client_channels.get(&client) {
+            Some(conn) => (conn.call_channel, conn.reply_channel),
+            None => return,
+        };
+        while let Ok(count) = os::available_messages(call_channel) {
+            if count == 0 {
+                break;
+            }
+            let frame = match receive_frame(call_channel, Some(0)) {
+                Ok(frame) => frame,
+                Err(Error::InvalidData) => continue,
+                Err(_) => break,
+            };
+            match frame.frame_type {
+                LPCFrameType::Call => {
+                    let (status, payload) = match self.functions.get(&frame.function_id) {
+                        Some(function) => match function(client, frame.payload.as_slice()) {
+                            Ok(payload) => (0, payload),
+                            Err(err) => (err.code(), Vec::new()),
+                        },
+                        None => (Error::EntryNotFound.code(), Vec::new()),
+                    };
+                    let _ = send_frame(reply_channel, &LPCFrame::new(LPCFrameType::Response, client, frame.function_id, frame.call_id, status, payload.as_slice()));
+                },
+                LPCFrameType::Disconnect => {
+                    self.client_channels.remove(&client);
+                    let _ = os::release_handle(call_channel);
+                    let _ = os::release_handle(reply_channel);
+                    return;
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+pub struct LPCClient {
+    server: u32,
+    connection_handle: u32,
+    call_channel: u32,
+    reply_channel: u32,
+    next_call_id: u32,
+    pending: BTreeMap<u32, Result<Vec<u8>, Error>>,
+    // frames were queued that the server has not been told about yet
+    notify_pending: bool,
+}
+
+impl LPCClient {
+    pub fn connect(server: u32) -> Result<LPCClient, Error> {
+        let pid = os::get_process_id();
+        let reply_channel = os::create_message_queue(reply_queue_name(server).as_str(), server)?;
+        let connection_handle = match os::acquire_handle(connect_queue_path(server).as_str()) {
+            Ok(hndl) => hndl,
+            Err(err) => {
+                let _ = os::release_handle(reply_channel);
+                return Err(err);
+            }
+        };
+        let accepted = send_frame(connection_handle, &LPCFrame::new(LPCFrameType::Connect, pid, 0, 0, 0, &[]))
+            .and_then(|_| loop {
+                let frame = receive_frame(reply_channel, None)?;
+                if frame.frame_type == LPCFrameType::Accept {
+                    break Error::from_code_to_nothing(frame.status);
+                }
+            })
+            .and_then(|_| os::acquire_handle(format!("/Processes/{}/MessageChannels/{}", server, call_queue_name(pid)).as_str()));
+        match accepted {
+            Ok(call_channel) => Ok(LPCClient {
+                server,
+                connection_handle,
+                call_channel,
+                reply_channel,
+                next_call_id: 0,
+                pending: BTreeMap::new(),
+                notify_pending: false,
+            }),
+            Err(err) => {
+                let _ = os::release_handle(connection_handle);
+                let _ = os::release_handle(reply_channel);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn server(&self) -> u32 {
+        self.server
+    }
+
+    pub fn call(&mut self, function_id: u32, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let call_id = self.call_async(function_id, payload)?;
+        self.wait_reply(call_id)
+    }
+
+    pub fn call_async(&mut self, function_id: u32, payload: &[u8]) -> Result<u32, Error> {
+        let call_id = self.next_call_id;
+        self.next_call_id = self.next_call_id.wrapping_add(1);
+        send_frame(self.call_channel, &LPCFrame::new(LPCFrameType::Call, os::get_process_id(), function_id, call_id, 0, payload))?;
+        // the call is queued either way, a notify that does not fit is sent again while waiting for the reply
+        self.notify_pending = true;
+        self.flush_notify()?;
+        Ok(call_id)
+    }
+
+    pub fn wait_reply(&mut self, call_id: u32) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(result) = self.pending.remove(&call_id) {
+                return result;
+            }
+            self.flush_notify()?;
+            let timeout = if self.notify_pending { Some(LPC_NOTIFY_RETRY) } else { None };
+            match self.receive_reply(timeout) {
+                Ok(()) | Err(Error::TimedOut) => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn poll_reply(&mut self, call_id: u32) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(result) = self.pending.remove(&call_id) {
+                return result.map(|payload| Some(payload));
+            }
+            self.flush_notify()?;
+            match self.receive_reply(Some(0)) {
+                Ok(()) => (),
+                Err(Error::TimedOut) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn disconnect(mut self) -> Result<(), Error> {
+        send_frame(self.call_channel, &LPCFrame::new(LPCFrameType::Disconnect, os::get_process_id(), 0, 0, 0, &[]))?;
+        // nothing else would ring the server for it later
+        self.notify_pending = true;
+        loop {
+            self.flush_notify()?;
+            if !self.notify_pending {
+                break;
+            }
+            os::sleep(LPC_NOTIFY_RETRY);
+        }
+        os::release_handle(self.call_channel)?;
+        os::release_handle(self.connection_handle)?;
+        os::release_handle(self.reply_channel)
+    }
+
+    fn notify(&self) -> Result<(), Error> {
+        // the server only blocks on its connection queue, so ring it after queueing work
+        send_frame(self.connection_handle, &LPCFrame::new(LPCFrameType::Notify, os::get_process_id(), 0, 0, 0, &[]))
+    }
+
+    fn flush_notify(&mut self) -> Result<(), Error> {
+        if self.notify_pending {
+            match self.notify() {
+                Ok(()) => self.notify_pending = false,
+                // the server has a backlog of notifications to get through first
+                Err(Error::OutOfSpace) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_reply(&mut self, timeout: Option<u32>) -> Result<(), Error> {
+        let frame = receive_frame(self.reply_channel, timeout)?;
+        if frame.frame_type == LPCFrameType::Response {
+            self.pending.insert(frame.call_id, frame.result());
+        }
+        Ok(())
+    }
+}
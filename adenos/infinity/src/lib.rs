@@ -6,6 +6,7 @@ pub mod arch;
 
 pub mod os;
 pub mod ipc;
+pub mod thread;
 pub mod error;
 pub mod allocator;
 
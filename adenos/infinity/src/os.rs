@@ -1,5 +1,7 @@
 use crate::*;
 use arch;
+use alloc::vec::Vec;
+use bitflags::bitflags;
 
 pub const SYSTEM_CALL_READ: usize = 0;
 pub const SYSTEM_CALL_WRITE: usize = 1;
@@ -12,6 +14,43 @@ pub const SYSTEM_CALL_ACQUIRE_HANDLE: usize = 7;
 pub const SYSTEM_CALL_RELEASE_HANDLE: usize = 8;
 pub const SYSTEM_CALL_AVAILABLE_MESSAGES: usize = 9;
 pub const SYSTEM_CALL_AVAILABLE_MESSAGE_SIZE: usize = 10;
+pub const SYSTEM_CALL_SEND_MESSAGE: usize = 11;
+pub const SYSTEM_CALL_RECEIVE_MESSAGE: usize = 12;
+pub const SYSTEM_CALL_CREATE_SHARED_MEMORY: usize = 13;
+pub const SYSTEM_CALL_GRANT_SHARED_MEMORY: usize = 14;
+pub const SYSTEM_CALL_MAP_SHARED_MEMORY: usize = 15;
+pub const SYSTEM_CALL_UNMAP_SHARED_MEMORY: usize = 16;
+pub const SYSTEM_CALL_DUPLICATE_HANDLE: usize = 17;
+pub const SYSTEM_CALL_SPAWN: usize = 18;
+pub const SYSTEM_CALL_WAIT: usize = 19;
+pub const SYSTEM_CALL_CREATE_THREAD: usize = 20;
+pub const SYSTEM_CALL_JOIN_THREAD: usize = 21;
+pub const SYSTEM_CALL_EXIT_THREAD: usize = 22;
+pub const SYSTEM_CALL_SLEEP: usize = 23;
+pub const SYSTEM_CALL_GET_PRIORITY: usize = 24;
+pub const SYSTEM_CALL_SET_PRIORITY: usize = 25;
+pub const SYSTEM_CALL_MAP_MEMORY: usize = 26;
+pub const SYSTEM_CALL_UNMAP_MEMORY: usize = 27;
+pub const SYSTEM_CALL_FORK: usize = 28;
+pub const SYSTEM_CALL_MAP_FILE: usize = 29;
+pub const SYSTEM_CALL_SYNC_MEMORY: usize = 30;
+pub const SYSTEM_CALL_SET_FS_BASE: usize = 31;
+pub const SYSTEM_CALL_MOUNT: usize = 32;
+pub const SYSTEM_CALL_UNMOUNT: usize = 33;
+pub const SYSTEM_CALL_READ_DIRECTORY: usize = 34;
+pub const SYSTEM_CALL_STAT: usize = 35;
+pub const SYSTEM_CALL_REMOVE: usize = 36;
+pub const SYSTEM_CALL_RENAME: usize = 37;
+pub const SYSTEM_CALL_CREATE_DIRECTORY: usize = 38;
+pub const SYSTEM_CALL_REMOVE_DIRECTORY: usize = 39;
+pub const SYSTEM_CALL_CHECK_FILE_SYSTEM: usize = 40;
+pub const SYSTEM_CALL_GET_TIME: usize = 41;
+pub const SYSTEM_CALL_GET_MONOTONIC_TIME: usize = 42;
+pub const SYSTEM_CALL_WAIT_MESSAGE: usize = 43;
+
+pub const TIMEOUT_INFINITE: u32 = u32::MAX;
+// map_memory picks addresses from the top of the anonymous memory range, so the heap can grow up from here
+pub const HEAP_BASE: usize = 0x5000_0000;
 
 #[repr(usize)]
 pub enum IOHandle {
@@ -20,10 +59,174 @@ pub enum IOHandle {
     Log = 2,
 }
 
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SharedMemoryAccess {
+    ReadOnly = 0,
+    ReadWrite = 1,
+}
+
+bitflags! {
+    pub struct MemoryProtection: usize {
+        const READ = 1;
+        const WRITE = 2;
+        const EXECUTE = 4;
+    }
+}
+
+bitflags! {
+    pub struct MappingFlags: usize {
+        // writes go to the file and are seen by every other shared mapping of it
+        const SHARED = 1;
+    }
+}
+
+bitflags! {
+    pub struct FileAttributes: u32 {
+        const READ_ONLY = 0x01;
+        const HIDDEN = 0x02;
+        const SYSTEM = 0x04;
+        const DIRECTORY = 0x10;
+        const ARCHIVE = 0x20;
+    }
+}
+
+// calendar time as file systems keep it, without a time zone
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// days since 1970-01-01 to and from the proleptic Gregorian calendar, counted in 400-year eras
+impl DateTime {
+    pub fn from_unix_time(seconds: u64) -> DateTime {
+        let days = (seconds / 86400) as i64 + 719468;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        // months start in March, so that the leap day comes last
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let time = seconds % 86400;
+        DateTime {
+            year: (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    pub fn unix_time(&self) -> u64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146097 + day_of_era - 719468).max(0) as u64;
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FileStatus {
+    pub size: u64,
+    pub attributes: FileAttributes,
+    pub created: DateTime,
+    pub modified: DateTime,
+    pub accessed: DateTime,
+}
+
+pub const MAX_FILE_NAME: usize = 255;
+
+// what read_directory fills in, the name is not NULL-terminated
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirectoryEntry {
+    pub status: FileStatus,
+    pub name_length: u32,
+    pub name: [u8; MAX_FILE_NAME],
+}
+
+impl DirectoryEntry {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length as usize]).unwrap_or("")
+    }
+}
+
+// what a file system check found, and whether it fixed it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FileSystemCheck {
+    // allocated, but no file or directory leads to them
+    pub lost_clusters: u32,
+    pub lost_chains: u32,
+    // files and directories sharing clusters with one found before them
+    pub cross_linked_files: u32,
+    // chains leading to free or nonexistent clusters
+    pub broken_chains: u32,
+    // file sizes that do not match the length of the chain
+    pub size_mismatches: u32,
+    // long file names with the wrong checksum or sequence, or without a short entry
+    pub bad_long_names: u32,
+    pub repaired: bool,
+}
+
+impl FileSystemCheck {
+    pub fn errors(&self) -> u32 {
+        self.lost_chains + self.cross_linked_files + self.broken_chains + self.size_mismatches + self.bad_long_names
+    }
+}
+
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Realtime = 0,
+    Normal = 1,
+    Idle = 2,
+}
+
+impl Priority {
+    pub fn from_usize(val: usize) -> Option<Priority> {
+        match val {
+            0 => Some(Priority::Realtime),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::Idle),
+            _ => None,
+        }
+    }
+}
+
 fn to_signed(val: usize) -> isize {
     isize::from_ne_bytes(val.to_ne_bytes())
 }
 
+fn c_string(s: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(s.len() + 1);
+    bytes.extend_from_slice(s.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+// the pointer array refers into the strings, so both have to stay alive until the system call returns
+fn c_string_list(list: &[&str]) -> (Vec<Vec<u8>>, Vec<usize>) {
+    let strings: Vec<Vec<u8>> = list.iter().map(|s| c_string(s)).collect();
+    let mut pointers: Vec<usize> = strings.iter().map(|s| s.as_ptr() as usize).collect();
+    pointers.push(0);
+    (strings, pointers)
+}
+
 #[inline(always)]
 pub extern "C" fn read(handle: u32, buffer: *mut u8, count: usize) -> isize {
     arch::_system_call(SYSTEM_CALL_READ, handle as usize, buffer as usize, count, 0)
@@ -40,8 +243,8 @@ pub extern "C" fn seek(handle: u32, offset: usize, relative: bool) -> isize {
 }
 
 #[inline(always)]
-pub extern "C" fn exit() -> ! {
-    arch::_system_call(SYSTEM_CALL_EXIT, 0, 0, 0, 0);
+pub extern "C" fn exit(code: i32) -> ! {
+    arch::_system_call(SYSTEM_CALL_EXIT, code as usize, 0, 0, 0);
     loop {}
 }
 
@@ -52,11 +255,13 @@ pub extern "C" fn get_process_id() -> u32 {
 
 #[inline(always)]
 pub extern "C" fn create_message_queue(name: &str, endpoint: u32) -> Result<u32, Error> {
+    let name = c_string(name);
     Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_CREATE_MESSAGE_QUEUE, name.as_ptr() as usize, endpoint as usize, 0, 0) as i64)
 }
 
 #[inline(always)]
 pub extern "C" fn acquire_handle(path: &str) -> Result<u32, Error> {
+    let path = c_string(path);
     Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_ACQUIRE_HANDLE, path.as_ptr() as usize, 0, 0, 0) as i64)
 }
 
@@ -65,6 +270,11 @@ pub extern "C" fn release_handle(handle: u32) -> Result<(), Error> {
     Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_RELEASE_HANDLE,handle as usize, 0, 0, 0) as i64)
 }
 
+#[inline(always)]
+pub extern "C" fn duplicate_handle(handle: u32, target: Option<u32>) -> Result<u32, Error> {
+    Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_DUPLICATE_HANDLE, handle as usize, target.unwrap_or(u32::MAX) as usize, 0, 0) as i64)
+}
+
 #[inline(always)]
 pub extern "C" fn available_messages(handle: u32) -> Result<usize, Error> {
     Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_AVAILABLE_MESSAGES, handle as usize, 0, 0, 0) as i64)
@@ -73,4 +283,232 @@ pub extern "C" fn available_messages(handle: u32) -> Result<usize, Error> {
 #[inline(always)]
 pub extern "C" fn available_message_size(handle: u32) -> Result<usize, Error> {
     Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_AVAILABLE_MESSAGE_SIZE, handle as usize, 0, 0, 0) as i64)
-}
\ No newline at end of file
+}
+
+#[inline(always)]
+pub extern "C" fn send_message(handle: u32, message: &[u8]) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_SEND_MESSAGE, handle as usize, message.as_ptr() as usize, message.len(), 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn receive_message(handle: u32, buffer: &mut [u8], timeout: Option<u32>) -> Result<usize, Error> {
+    let timeout = timeout.unwrap_or(TIMEOUT_INFINITE);
+    Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_RECEIVE_MESSAGE, handle as usize, buffer.as_mut_ptr() as usize, buffer.len(), timeout as usize) as i64)
+}
+
+// waits for a message without taking it, and returns the process that sent it
+#[inline(always)]
+pub extern "C" fn wait_message(handle: u32, timeout: Option<u32>) -> Result<u32, Error> {
+    let timeout = timeout.unwrap_or(TIMEOUT_INFINITE);
+    Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_WAIT_MESSAGE, handle as usize, timeout as usize, 0, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn create_shared_memory(name: &str, size: usize) -> Result<u32, Error> {
+    let name = c_string(name);
+    Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_CREATE_SHARED_MEMORY, name.as_ptr() as usize, size, 0, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn grant_shared_memory(handle: u32, process_id: u32, access: SharedMemoryAccess) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_GRANT_SHARED_MEMORY, handle as usize, process_id as usize, access as usize, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn map_shared_memory(handle: u32, access: SharedMemoryAccess) -> Result<*mut u8, Error> {
+    Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_MAP_SHARED_MEMORY, handle as usize, access as usize, 0, 0) as i64).map(|addr| addr as *mut u8)
+}
+
+#[inline(always)]
+pub extern "C" fn unmap_shared_memory(address: *mut u8) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_UNMAP_SHARED_MEMORY, address as usize, 0, 0, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn spawn(path: &str, args: &[&str], env: &[&str]) -> Result<u32, Error> {
+    let path = c_string(path);
+    let (_args, arg_pointers) = c_string_list(args);
+    let (_env, env_pointers) = c_string_list(env);
+    Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_SPAWN, path.as_ptr() as usize, arg_pointers.as_ptr() as usize, env_pointers.as_ptr() as usize, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn wait(process_id: u32) -> Result<i32, Error> {
+    let mut status: i32 = 0;
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_WAIT, process_id as usize, &mut status as *mut i32 as usize, 0, 0) as i64)?;
+    Ok(status)
+}
+
+#[inline(always)]
+pub extern "C" fn create_thread(entry_point: extern "C" fn(usize) -> !, argument: usize) -> Result<u32, Error> {
+    Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_CREATE_THREAD, entry_point as usize, argument, 0, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn join_thread(thread_id: u32) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_JOIN_THREAD, thread_id as usize, 0, 0, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn exit_thread() -> ! {
+    arch::_system_call(SYSTEM_CALL_EXIT_THREAD, 0, 0, 0, 0);
+    loop {}
+}
+
+#[inline(always)]
+pub extern "C" fn sleep(milliseconds: u32) {
+    arch::_system_call(SYSTEM_CALL_SLEEP, milliseconds as usize, 0, 0, 0);
+}
+
+// wall-clock time in seconds since 1970-01-01, in UTC as far as the real-time clock is
+#[inline(always)]
+pub extern "C" fn unix_time() -> u64 {
+    arch::_system_call(SYSTEM_CALL_GET_TIME, 0, 0, 0, 0) as u64
+}
+
+#[inline(always)]
+pub extern "C" fn time_of_day() -> DateTime {
+    DateTime::from_unix_time(unix_time())
+}
+
+// microseconds since boot, never goes back even when the wall clock is set
+#[inline(always)]
+pub extern "C" fn monotonic_time() -> u64 {
+    arch::_system_call(SYSTEM_CALL_GET_MONOTONIC_TIME, 0, 0, 0, 0) as u64
+}
+
+#[inline(always)]
+pub extern "C" fn get_priority(thread_id: Option<u32>) -> Result<Priority, Error> {
+    let priority = Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_GET_PRIORITY, thread_id.unwrap_or(u32::MAX) as usize, 0, 0, 0) as i64)?;
+    Priority::from_usize(priority).ok_or(Error::InvalidData)
+}
+
+#[inline(always)]
+pub extern "C" fn set_priority(thread_id: Option<u32>, priority: Priority) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_SET_PRIORITY, thread_id.unwrap_or(u32::MAX) as usize, priority as usize, 0, 0) as i64)
+}
+
+// anywhere the kernel likes when address is None, the memory reads as zeros
+#[inline(always)]
+pub extern "C" fn map_memory(address: Option<*mut u8>, size: usize, protection: MemoryProtection) -> Result<*mut u8, Error> {
+    let address = address.map_or(0, |address| address as usize);
+    Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_MAP_MEMORY, address, size, protection.bits(), 0) as i64).map(|addr| addr as *mut u8)
+}
+
+#[inline(always)]
+pub extern "C" fn unmap_memory(address: *mut u8, size: usize) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_UNMAP_MEMORY, address as usize, size, 0, 0) as i64)
+}
+
+// the mapping keeps the file open by itself, handle can be released while it is mapped
+#[inline(always)]
+pub extern "C" fn map_file(handle: u32, offset: u64, size: usize, protection: MemoryProtection, flags: MappingFlags) -> Result<*mut u8, Error> {
+    Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_MAP_FILE, handle as usize, offset as usize, size, protection.bits() | (flags.bits() << 8)) as i64).map(|addr| addr as *mut u8)
+}
+
+// writes what was written to shared file mappings in the range back to the files, unmapping does it too
+#[inline(always)]
+pub extern "C" fn sync_memory(address: *mut u8, size: usize) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_SYNC_MEMORY, address as usize, size, 0, 0) as i64)
+}
+
+// every thread starts out with fs pointing at a thread control block built by the kernel,
+// only runtimes that lay out thread-local storage themselves need this
+#[inline(always)]
+pub extern "C" fn set_fs_base(address: *mut u8) -> Result<(), Error> {
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_SET_FS_BASE, address as usize, 0, 0, 0) as i64)
+}
+
+// file_system_type None lets the kernel try every file system it knows
+#[inline(always)]
+pub extern "C" fn mount(device: &str, point: &str, file_system_type: Option<&str>) -> Result<(), Error> {
+    let device = c_string(device);
+    let point = c_string(point);
+    let file_system_type = file_system_type.map(|fs_type| c_string(fs_type));
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_MOUNT, device.as_ptr() as usize, point.as_ptr() as usize, file_system_type.as_ref().map_or(0, |fs_type| fs_type.as_ptr() as usize), 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn unmount(point: &str) -> Result<(), Error> {
+    let point = c_string(point);
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_UNMOUNT, point.as_ptr() as usize, 0, 0, 0) as i64)
+}
+
+// fills entries with the directory's contents starting at index start, returns how many it filled
+#[inline(always)]
+pub extern "C" fn read_directory_at(path: &str, start: usize, entries: &mut [DirectoryEntry]) -> Result<usize, Error> {
+    let path = c_string(path);
+    Error::from_code_to_usize(arch::_system_call(SYSTEM_CALL_READ_DIRECTORY, path.as_ptr() as usize, start, entries.as_mut_ptr() as usize, entries.len()) as i64)
+}
+
+pub fn read_directory(path: &str) -> Result<Vec<DirectoryEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut chunk = [DirectoryEntry {
+        status: FileStatus {
+            size: 0,
+            attributes: FileAttributes::empty(),
+            created: DateTime::default(),
+            modified: DateTime::default(),
+            accessed: DateTime::default(),
+        },
+        name_length: 0,
+        name: [0; MAX_FILE_NAME],
+    }; 8];
+    loop {
+        let count = read_directory_at(path, entries.len(), &mut chunk)?;
+        entries.extend_from_slice(&chunk[..count]);
+        if count < chunk.len() {
+            return Ok(entries);
+        }
+    }
+}
+
+#[inline(always)]
+pub extern "C" fn stat(path: &str) -> Result<FileStatus, Error> {
+    let path = c_string(path);
+    let mut status = core::mem::MaybeUninit::<FileStatus>::uninit();
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_STAT, path.as_ptr() as usize, status.as_mut_ptr() as usize, 0, 0) as i64)?;
+    Ok(unsafe { status.assume_init() })
+}
+
+#[inline(always)]
+pub extern "C" fn remove(path: &str) -> Result<(), Error> {
+    let path = c_string(path);
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_REMOVE, path.as_ptr() as usize, 0, 0, 0) as i64)
+}
+
+// also moves between directories of the same volume
+#[inline(always)]
+pub extern "C" fn rename(from: &str, to: &str) -> Result<(), Error> {
+    let from = c_string(from);
+    let to = c_string(to);
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_RENAME, from.as_ptr() as usize, to.as_ptr() as usize, 0, 0) as i64)
+}
+
+#[inline(always)]
+pub extern "C" fn create_directory(path: &str) -> Result<(), Error> {
+    let path = c_string(path);
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_CREATE_DIRECTORY, path.as_ptr() as usize, 0, 0, 0) as i64)
+}
+
+// only empty directories can be removed
+#[inline(always)]
+pub extern "C" fn remove_directory(path: &str) -> Result<(), Error> {
+    let path = c_string(path);
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_REMOVE_DIRECTORY, path.as_ptr() as usize, 0, 0, 0) as i64)
+}
+
+// point is where the volume is mounted, nothing on it may be open while repairing
+#[inline(always)]
+pub extern "C" fn check_file_system(point: &str, repair: bool) -> Result<FileSystemCheck, Error> {
+    let point = c_string(point);
+    let mut report = FileSystemCheck::default();
+    Error::from_code_to_nothing(arch::_system_call(SYSTEM_CALL_CHECK_FILE_SYSTEM, point.as_ptr() as usize, repair as usize, &mut report as *mut FileSystemCheck as usize, 0) as i64)?;
+    Ok(report)
+}
+
+// returns the child's process id in the parent and 0 in the child, which only has the calling thread
+#[inline(always)]
+pub extern "C" fn fork() -> Result<u32, Error> {
+    Error::from_code_to_u32(arch::_system_call(SYSTEM_CALL_FORK, 0, 0, 0, 0) as i64)
+}
@@ -0,0 +1,138 @@
+use crate::*;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{arch::asm, cell::UnsafeCell, ptr, sync::atomic::{AtomicUsize, Ordering}};
+
+static NEXT_LOCAL_KEY_SLOT: AtomicUsize = AtomicUsize::new(1);
+
+struct Packet<T> {
+    result: UnsafeCell<Option<T>>,
+}
+
+// written once by the thread before it exits, read by the joiner after that
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+pub struct JoinHandle<T> {
+    thread_id: u32,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread_id(&self) -> u32 {
+        self.thread_id
+    }
+
+    pub fn join(self) -> Result<T, Error> {
+        os::join_thread(self.thread_id)?;
+        unsafe { (*self.packet.result.get()).take() }.ok_or(Error::NoData)
+    }
+}
+
+extern "C" fn thread_start(main: usize) -> ! {
+    let main = unsafe { Box::from_raw(main as *mut Box<dyn FnOnce()>) };
+    main();
+    os::exit_thread()
+}
+
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, Error>
+where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let packet = Arc::new(Packet {
+        result: UnsafeCell::new(None),
+    });
+    let their_packet = packet.clone();
+    let main: Box<dyn FnOnce()> = Box::new(move || {
+        unsafe { *their_packet.result.get() = Some(f()); }
+    });
+    let main = Box::into_raw(Box::new(main));
+    match os::create_thread(thread_start, main as usize) {
+        Ok(thread_id) => Ok(JoinHandle {
+            thread_id,
+            packet,
+        }),
+        Err(err) => {
+            drop(unsafe { Box::from_raw(main) });
+            Err(err)
+        }
+    }
+}
+
+pub fn sleep(milliseconds: u32) {
+    os::sleep(milliseconds)
+}
+
+pub fn exit() -> ! {
+    os::exit_thread()
+}
+
+// declared with thread_local!, every thread sees its own value, made by init on its first use
+// and never dropped; needs the thread control block the kernel gives user threads
+pub struct LocalKey<T: 'static> {
+    // 0 until the first use anywhere picks one
+    slot: AtomicUsize,
+    init: fn() -> T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    pub const fn new(init: fn() -> T) -> LocalKey<T> {
+        LocalKey {
+            slot: AtomicUsize::new(0),
+            init,
+        }
+    }
+
+    fn slot(&self) -> usize {
+        let slot = match self.slot.load(Ordering::Acquire) {
+            0 => {
+                let new = NEXT_LOCAL_KEY_SLOT.fetch_add(1, Ordering::Relaxed);
+                match self.slot.compare_exchange(0, new, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => new,
+                    Err(other) => other,
+                }
+            },
+            slot => slot,
+        };
+        slot - 1
+    }
+
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where F: FnOnce(&T) -> R {
+        let slot = self.slot();
+        let slots = thread_slots();
+        if slots.len() <= slot {
+            slots.resize(slot + 1, ptr::null_mut());
+        }
+        if slots[slot].is_null() {
+            slots[slot] = Box::into_raw(Box::new((self.init)())) as *mut u8;
+        }
+        f(unsafe { &*(slots[slot] as *const T) })
+    }
+}
+
+// the second word of the thread control block, fs:0 holds the control block's own address
+fn thread_slots() -> &'static mut Vec<*mut u8> {
+    unsafe {
+        let control_block: *mut usize;
+        asm!("mov {}, fs:0", out(reg) control_block);
+        let slots = control_block.add(1);
+        if *slots == 0 {
+            *slots = Box::into_raw(Box::new(Vec::<*mut u8>::new())) as usize;
+        }
+        &mut *(*slots as *mut Vec<*mut u8>)
+    }
+}
+
+#[macro_export]
+macro_rules! thread_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])* $vis static $name: $crate::thread::LocalKey<$t> = $crate::thread::LocalKey::new({
+            fn init() -> $t {
+                $init
+            }
+            init
+        });
+    };
+}
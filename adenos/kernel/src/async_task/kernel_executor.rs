@@ -1,7 +1,7 @@
 use crate::*;
 use super::*;
 use spin::*;
-use dev::hal::cpu;
+use exec::scheduler;
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use alloc::{ sync::Arc, collections::BTreeMap, task::Wake };
@@ -10,6 +10,8 @@ use core::task::{Waker, Context, Poll};
 pub static KERNEL_TASKS: OnceCell<Mutex<BTreeMap<TaskId, Task>>> = OnceCell::uninit();
 pub static KERNEL_TASK_QUEUE: OnceCell<Arc<ArrayQueue<TaskId>>> = OnceCell::uninit();
 pub static KERNEL_WAKER_CACHE: OnceCell<Mutex<BTreeMap<TaskId, Waker>>> = OnceCell::uninit();
+// the scheduler thread running the executor, suspended while there is nothing to poll
+static mut EXECUTOR_THREAD: Option<u32> = None;
 
 pub fn init() {
     KERNEL_TASKS.init_once(|| Mutex::new(BTreeMap::new()));
@@ -24,6 +26,14 @@ pub fn spawn(task: Task) {
         panic!("KERNEL_TASK_DOUBLE_ID\nTask ID: {:?}", task_id);
     }
     KERNEL_TASK_QUEUE.try_get().expect("KERNEL_EXECUTOR_NOT_INITIALIZED").push(task_id).expect("KERNEL_TASK_QUEUE_FULL");
+    wake_executor();
+}
+
+fn wake_executor() {
+    if let Some(tid) = unsafe { EXECUTOR_THREAD } {
+        // fails harmlessly if the executor is busy anyway
+        let _ = scheduler::resume_thread(tid);
+    }
 }
 
 fn run_ready_tasks() {
@@ -51,16 +61,15 @@ fn run_ready_tasks() {
 }
 
 pub fn run() {
+    let tid = scheduler::current_thread();
+    unsafe {
+        let _ = EXECUTOR_THREAD.insert(tid);
+    }
     loop {
         run_ready_tasks();
         let task_queue = KERNEL_TASK_QUEUE.try_get().expect("\nKERNEL_EXECUTOR_NOT_INITIALIZED");
-        cpu::disable_interrupts();
-        if task_queue.is_empty() {
-            cpu::enable_interrupts();
-            cpu::halt();
-        } else {
-            cpu::enable_interrupts();
-        }
+        // give the cpu to other threads until a waker or spawn resumes us, the idle thread halts if nobody wants it
+        let _ = scheduler::suspend_thread_unless(tid, || !task_queue.is_empty());
     }
 }
 
@@ -79,6 +88,7 @@ impl TaskWaker {
 
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("KERNEL_TASK_QUEUE_FULL");
+        wake_executor();
     }
 }
 
@@ -0,0 +1,29 @@
+#![cfg(target_arch = "x86_64")]
+
+use crate::*;
+use dev::{*, hal::*, framebuffer::*, char::*};
+use bootloader::BootInfo;
+
+// takes over what the bootloader found, before dev::hal::init
+pub fn init(boot_info: &'static mut BootInfo) {
+    unsafe {
+        mem::PHYSICAL_MEMORY_OFFSET = boot_info.physical_memory_offset.into_option().unwrap();
+        mem::BOOT_MEMORY_MAP = Some(&boot_info.memory_regions);
+        let free_mem = boot_info.memory_regions.iter().map(|reg| reg.end - reg.start);
+        let free_mem: u64 = free_mem.sum();
+        mem::FREE_MEMORY = free_mem as usize;
+        let bifb = boot_info.framebuffer.as_mut().unwrap();
+        let bifbi = bifb.info();
+        kernel_console::EARLY_FRAMEBUFFER = Some(VesaVbeFramebuffer::new(bifb.buffer_mut(), bifbi.horizontal_resolution, bifbi.vertical_resolution,
+    match bifbi.pixel_format {
+            bootloader::boot_info::PixelFormat::RGB => framebuffer::PixelFormat::RGB,
+            bootloader::boot_info::PixelFormat::BGR => framebuffer::PixelFormat::BGR,
+            bootloader::boot_info::PixelFormat::U8 => framebuffer::PixelFormat::Monochrome,
+            _ => framebuffer::PixelFormat::RGB,
+        }, bifbi.bytes_per_pixel, bifbi.stride));
+        kernel_console::FRAMEBUFFER = Some(kernel_console::EARLY_FRAMEBUFFER.as_mut().unwrap());
+        kernel_console::EARLY_KERNEL_CONSOLE = Some(FramebufferConsole::new(*kernel_console::FRAMEBUFFER.as_mut().unwrap()));
+        kernel_console::KERNEL_CONSOLE = Some(kernel_console::EARLY_KERNEL_CONSOLE.as_mut().unwrap());
+        acpi::RSDP_ADDRESS = *boot_info.rsdp_addr.as_ref().unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use crate::*;
+use super::*;
+use alloc::{vec, vec::Vec};
+use infinity::os::FileSystemCheck;
+
+// one bit per cluster
+struct ClusterMap {
+    bits: Vec<u64>,
+}
+
+impl ClusterMap {
+    fn new(clusters: u32) -> ClusterMap {
+        ClusterMap {
+            bits: vec![0; (clusters as usize + 63) / 64],
+        }
+    }
+
+    fn get(&self, cluster: u32) -> bool {
+        self.bits[cluster as usize / 64] & (1 << (cluster % 64)) != 0
+    }
+
+    fn set(&mut self, cluster: u32, value: bool) {
+        if value {
+            self.bits[cluster as usize / 64] |= 1 << (cluster % 64);
+        } else {
+            self.bits[cluster as usize / 64] &= !(1 << (cluster % 64));
+        }
+    }
+}
+
+struct Checker<'a> {
+    fat_fs: &'a FATFileSystem,
+    repair: bool,
+    // clusters some file or directory has been found to use
+    used: ClusterMap,
+    report: FileSystemCheck,
+}
+
+impl<'a> Checker<'a> {
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.fat_fs.total_clusters + 2
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_fs.fat_type {
+            FATType::FAT12 => value >= 0xFF8,
+            FATType::FAT16 => value >= 0xFFF8,
+            FATType::FAT32 => value >= 0x0FFFFFF8,
+        }
+    }
+
+    fn is_bad_cluster(&self, value: u32) -> bool {
+        match self.fat_fs.fat_type {
+            FATType::FAT12 => value == 0xFF7,
+            FATType::FAT16 => value == 0xFFF7,
+            FATType::FAT32 => value == 0x0FFFFFF7,
+        }
+    }
+
+    // marks the chain as used and returns its length, None if its first cluster already belongs to something else
+    // a chain running into another one or into a cluster that is not in use ends there after repair
+    fn walk_chain(&mut self, first: u32) -> Result<Option<u64>, Error> {
+        if !self.is_data_cluster(first) {
+            self.report.broken_chains += 1;
+            return Ok(None);
+        }
+        if self.used.get(first) {
+            self.report.cross_linked_files += 1;
+            return Ok(None);
+        }
+        let mut cluster = first;
+        let mut length = 0;
+        loop {
+            self.used.set(cluster, true);
+            length += 1;
+            let next = self.fat_fs.read_fat_entry(cluster)?;
+            if self.is_end_of_chain(next) {
+                return Ok(Some(length));
+            }
+            if self.is_data_cluster(next) && !self.used.get(next) {
+                cluster = next;
+                continue;
+            }
+            if self.is_data_cluster(next) {
+                self.report.cross_linked_files += 1;
+            } else {
+                self.report.broken_chains += 1;
+            }
+            if self.repair {
+                self.fat_fs.write_fat_entry(cluster, self.fat_fs.end_of_chain())?;
+            }
+            return Ok(Some(length));
+        }
+    }
+
+    // keeps the first keep clusters of the chain and frees the rest
+    fn truncate_chain(&mut self, entry: &mut DirectoryEntry, keep: u64) -> Result<(), Error> {
+        let mut cluster = entry.first_cluster();
+        if keep == 0 {
+            entry.update_first_cluster(0);
+            self.fat_fs.in_place_update_directory_entry(entry)?;
+        } else {
+            for _ in 1..keep {
+                cluster = self.fat_fs.next_cluster(cluster).ok_or(Error::InvalidData)?;
+            }
+            let rest = self.fat_fs.read_fat_entry(cluster)?;
+            self.fat_fs.write_fat_entry(cluster, self.fat_fs.end_of_chain())?;
+            cluster = rest;
+        }
+        while self.is_data_cluster(cluster) && self.used.get(cluster) {
+            let next = self.fat_fs.read_fat_entry(cluster)?;
+            self.fat_fs.write_fat_entry(cluster, 0)?;
+            self.used.set(cluster, false);
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    // long file name entries belong to the short entry after them if the checksums and sequence numbers agree
+    fn check_long_name(&mut self, pending: &mut Vec<(LongFileNameEntry, (u32, u32))>, short: Option<&FileDirectoryEntry>) -> Result<Vec<(u32, u32)>, Error> {
+        let entries = core::mem::take(pending);
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let count = entries.len();
+        let valid = match short {
+            Some(short) => {
+                let check_sum = short.name_checksum();
+                entries[0].0.order() & 0x40 != 0 && entries.iter().enumerate().all(|(i, (lfn, _))| {
+                    lfn.checksum() == check_sum && (lfn.order() & 0x3F) as usize == count - i
+                })
+            },
+            None => false,
+        };
+        if valid {
+            return Ok(entries.into_iter().map(|(_, address)| address).collect());
+        }
+        // the short name still works without them
+        self.report.bad_long_names += 1;
+        if self.repair {
+            for (_, (sec, off)) in entries {
+                self.fat_fs.mark_entry_deleted(sec, off)?;
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn check_entry(&mut self, entry: &mut DirectoryEntry, directories: &mut Vec<Option<u32>>) -> Result<(), Error> {
+        let is_directory = entry.metadata.is_directory();
+        let first = entry.first_cluster();
+        if first == 0 {
+            // empty files have no clusters, directories always have at least one
+            if is_directory {
+                self.report.broken_chains += 1;
+                if self.repair {
+                    self.fat_fs.delete_directory_entry(entry)?;
+                }
+            } else if entry.size() != 0 {
+                self.report.size_mismatches += 1;
+                if self.repair {
+                    entry.update_size(0);
+                    self.fat_fs.in_place_update_directory_entry(entry)?;
+                }
+            }
+            return Ok(());
+        }
+        match self.walk_chain(first)? {
+            // whoever was found first keeps the clusters
+            None if self.repair => if is_directory {
+                self.fat_fs.delete_directory_entry(entry)?;
+            } else {
+                entry.update_first_cluster(0);
+                entry.update_size(0);
+                self.fat_fs.in_place_update_directory_entry(entry)?;
+            },
+            None => (),
+            Some(_) if is_directory => directories.push(Some(first)),
+            Some(length) => {
+                let cluster_size = self.fat_fs.cluster_size as u64;
+                let expected = (entry.size() as u64 + cluster_size - 1) / cluster_size;
+                if length != expected {
+                    self.report.size_mismatches += 1;
+                    if self.repair {
+                        if length < expected {
+                            entry.update_size((length * cluster_size) as u32);
+                            self.fat_fs.in_place_update_directory_entry(entry)?;
+                        } else {
+                            self.truncate_chain(entry, expected)?;
+                        }
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn check_directory(&mut self, cluster: Option<u32>, directories: &mut Vec<Option<u32>>) -> Result<(), Error> {
+        let mut raw_iter = DirectoryRawIterator::new(self.fat_fs, cluster)?;
+        let mut long_name = Vec::new();
+        while let Some(ent) = raw_iter.next() {
+            match ent {
+                DirectoryRawEntry::LongFileNameEntry(lfn) => long_name.push((lfn, raw_iter.last_yield_entry_address())),
+                DirectoryRawEntry::FileDirectoryEntry(short) => {
+                    let (sec, off) = raw_iter.last_yield_entry_address();
+                    let long_name_entries = self.check_long_name(&mut long_name, Some(&short))?;
+                    if short.attributes.contains(FileAttributes::VOLUME_ID) || short.is_dot_entry() {
+                        continue;
+                    }
+                    let mut entry = DirectoryEntry {
+                        name: String::new(),
+                        short_directory_entry_sector: Some(sec),
+                        short_directory_entry_offset: Some(off),
+                        long_name_entries,
+                        metadata: short,
+                    };
+                    self.check_entry(&mut entry, directories)?;
+                },
+                DirectoryRawEntry::UnusedEntry(_, _) => {
+                    self.check_long_name(&mut long_name, None)?;
+                },
+                DirectoryRawEntry::FreeEntry(_, _) => {
+                    self.check_long_name(&mut long_name, None)?;
+                    break;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn check_directories(&mut self) -> Result<(), Error> {
+        // directories still to walk by first cluster, None for the FAT12/16 root directory
+        let mut directories = Vec::new();
+        match self.fat_fs.ebpb {
+            eBPB::eBPB32(ebpb) => {
+                let root = ebpb.root_directory_cluster;
+                if self.walk_chain(root)?.is_none() {
+                    return Err(Error::InvalidData);
+                }
+                directories.push(Some(root));
+            },
+            _ => directories.push(None),
+        }
+        while let Some(dir) = directories.pop() {
+            self.check_directory(dir, &mut directories)?;
+        }
+        Ok(())
+    }
+
+    // allocated clusters no file or directory leads to are freed
+    fn check_lost_clusters(&mut self) -> Result<(), Error> {
+        let total = self.fat_fs.total_clusters;
+        let mut lost = Vec::new();
+        let mut free = 0;
+        for cluster in 2..total + 2 {
+            let value = self.fat_fs.read_fat_entry(cluster)?;
+            if value == 0 {
+                free += 1;
+            } else if !self.used.get(cluster) && !self.is_bad_cluster(value) {
+                lost.push(cluster);
+            }
+        }
+        // a lost chain starts at the one lost cluster nothing points to
+        let mut pointed_to = ClusterMap::new(total + 2);
+        for cluster in lost.iter() {
+            let next = self.fat_fs.read_fat_entry(*cluster)?;
+            if self.is_data_cluster(next) {
+                pointed_to.set(next, true);
+            }
+        }
+        self.report.lost_clusters = lost.len() as u32;
+        self.report.lost_chains = lost.iter().filter(|cluster| !pointed_to.get(**cluster)).count() as u32;
+        if !lost.is_empty() && self.report.lost_chains == 0 {
+            // nothing but loops
+            self.report.lost_chains = 1;
+        }
+        if self.repair {
+            for cluster in lost {
+                self.fat_fs.write_fat_entry(cluster, 0)?;
+            }
+        }
+        // the count is exact now, whatever FSInfo said
+        self.fat_fs.allocation.lock().free_count = Some(free + if self.repair { self.report.lost_clusters } else { 0 });
+        Ok(())
+    }
+}
+
+impl FATFileSystem {
+    // walks every directory and the whole FAT, and repairs what it finds if asked to
+    pub fn check_volume(&self, repair: bool) -> Result<FileSystemCheck, Error> {
+        let mut checker = Checker {
+            fat_fs: self,
+            repair,
+            used: ClusterMap::new(self.total_clusters + 2),
+            report: FileSystemCheck::default(),
+        };
+        checker.check_directories()?;
+        checker.check_lost_clusters()?;
+        let mut report = checker.report;
+        if repair {
+            self.write_fs_info()?;
+            report.repaired = report.errors() > 0;
+        }
+        Ok(report)
+    }
+}
@@ -3,7 +3,8 @@ use super::*;
 use modular_bitfield::{bitfield, specifiers::*};
 use bitflags::bitflags;
 use alloc::{vec, vec::Vec, string::ToString, format};
-use core::str;
+use core::{num::Wrapping, str};
+use infinity::os;
 
 use super::{FATType, FATFileSystem};
 
@@ -43,6 +44,22 @@ pub struct FileTimestamp {
     hour: B5,
 }
 
+// FAT dates count years from 1980 and can't go past 2107
+fn fat_date(time: &os::DateTime) -> FileDatestamp {
+    FileDatestamp::new()
+        .with_day(time.day)
+        .with_month(time.month)
+        .with_year((time.year.max(1980) - 1980).min(127) as u8)
+}
+
+// in two-second steps
+fn fat_time(time: &os::DateTime) -> FileTimestamp {
+    FileTimestamp::new()
+        .with_second(time.second / 2)
+        .with_minute(time.minute)
+        .with_hour(time.hour)
+}
+
 impl Debug for FileTimestamp {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_str(format!("{:02}:{:02}:{:02}", self.hour() + 2, self.minute(), self.second() * 2).as_str())
@@ -87,6 +104,66 @@ impl FileDirectoryEntry {
     pub fn cluster(&self) -> u32 {
         ((self.cluster_high as u32) << 16) | self.cluster_low as u32
     }
+
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.cluster_high = (cluster >> 16) as u16;
+        self.cluster_low = (cluster & 0xFFFF) as u16;
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes.contains(FileAttributes::DIRECTORY)
+    }
+
+    // what the long file name entries in front of this one have to carry
+    pub fn name_checksum(&self) -> u8 {
+        let mut check_sum = Wrapping(0_u8);
+        for b in self.file_name {
+            check_sum = (check_sum << 7) + (check_sum >> 1) + Wrapping(b);
+        }
+        check_sum.0
+    }
+
+    pub fn is_dot_entry(&self) -> bool {
+        &self.file_name == b".          " || &self.file_name == b"..         "
+    }
+
+    fn date_time(date: FileDatestamp, time: FileTimestamp) -> os::DateTime {
+        os::DateTime {
+            year: date.year() as u16 + 1980,
+            month: date.month(),
+            day: date.day(),
+            hour: time.hour(),
+            minute: time.minute(),
+            second: time.second() * 2,
+        }
+    }
+
+    // true if the entry changed at the resolution FAT keeps, two seconds for modification and a day for access
+    pub fn set_modified(&mut self, now: &os::DateTime) -> bool {
+        let (time, date) = (fat_time(now), fat_date(now));
+        let (old_time, old_date) = (self.time_modified, self.date_modified);
+        self.time_modified = time;
+        self.date_modified = date;
+        self.set_accessed(now) | (old_time.into_bytes() != time.into_bytes() || old_date.into_bytes() != date.into_bytes())
+    }
+
+    pub fn set_accessed(&mut self, now: &os::DateTime) -> bool {
+        let date = fat_date(now);
+        let old_date = self.date_accessed;
+        self.date_accessed = date;
+        old_date.into_bytes() != date.into_bytes()
+    }
+
+    pub fn status(&self) -> os::FileStatus {
+        os::FileStatus {
+            size: self.size as u64,
+            attributes: os::FileAttributes::from_bits_truncate(self.attributes.bits() as u32),
+            created: Self::date_time(self.date_created, self.time_created),
+            modified: Self::date_time(self.date_modified, self.time_modified),
+            // only the day is kept
+            accessed: Self::date_time(self.date_accessed, FileTimestamp::new()),
+        }
+    }
 }
 
 impl FileDirectoryEntry {
@@ -98,16 +175,19 @@ impl FileDirectoryEntry {
                 shortnm += " ";
             }
         }
+        let microseconds = time::unix_time_microseconds();
+        let now = os::DateTime::from_unix_time(microseconds / 1_000_000);
         FileDirectoryEntry {
             file_name: shortnm.as_bytes().try_into().unwrap(),
             _reserved: 0,
-            creation_time_millis: 69,
-            time_created: FileTimestamp::new(),
-            date_created: FileDatestamp::new(),
+            // hundredths of a second on top of the two-second creation time
+            creation_time_millis: ((now.second % 2) as u64 * 100 + microseconds / 10_000 % 100) as u8,
+            time_created: fat_time(&now),
+            date_created: fat_date(&now),
             attributes: FileAttributes::READ_ONLY,
-            date_accessed: FileDatestamp::new(),
-            time_modified: FileTimestamp::new(),
-            date_modified: FileDatestamp::new(),
+            date_accessed: fat_date(&now),
+            time_modified: fat_time(&now),
+            date_modified: fat_date(&now),
             size,
             cluster_high: (first_cluster >> 16) as u16,
             cluster_low: (first_cluster & 0xFFFF) as u16,
@@ -129,6 +209,15 @@ pub struct LongFileNameEntry {
 }
 
 impl LongFileNameEntry {
+    // sequence number of this part of the name, 0x40 is set on the last one
+    pub fn order(&self) -> u8 {
+        self.order
+    }
+
+    pub fn checksum(&self) -> u8 {
+        self.checksum
+    }
+
     pub fn new(name_part: &[u16], order: u8, checksum: u8) -> LongFileNameEntry {
         let mut name_part = name_part.clone().to_vec();
         for _ in 0..13 - name_part.len() {
@@ -197,9 +286,9 @@ impl<'a> Iterator for DirectoryRawIterator<'a> {
             match self.cluster {
                 Some(cluster) => {
                     if self.sector >= self.fat_fs.sectors_per_cluster as usize {
-                        self.cluster = match self.fat_fs.fat_iter(cluster).unwrap().next() {
-                            Some(FATEntry::Cluster(cluster)) => Some(cluster),
-                            _ => return None,
+                        self.cluster = match self.fat_fs.next_cluster(cluster) {
+                            Some(cluster) => Some(cluster),
+                            None => return None,
                         };
                         self.sector = 0;
                         self.fat_fs.drive.lock().read_block(self.fat_fs.cluster_to_sector(self.cluster.unwrap()) as u64, self.buffer.as_mut_ptr());
@@ -208,7 +297,7 @@ impl<'a> Iterator for DirectoryRawIterator<'a> {
                     }
                 },
                 None => { // root dir in FAT12/16
-                    if self.sector >= self.fat_fs.root_dir_sectors as usize + self.fat_fs.root_dir_sector as usize {
+                    if self.sector >= self.fat_fs.root_dir_sectors as usize {
                         return None;
                     }
                     self.fat_fs.drive.lock().read_block(self.fat_fs.root_dir_sector as u64 + self.sector as u64, self.buffer.as_mut_ptr());
@@ -250,6 +339,8 @@ pub struct DirectoryEntry {
     pub name: String,
     pub short_directory_entry_sector: Option<u32>,
     pub short_directory_entry_offset: Option<u32>,
+    // sector and offset of every long file name entry in front of the short one
+    pub long_name_entries: Vec<(u32, u32)>,
     pub metadata: FileDirectoryEntry,
 }
 
@@ -260,6 +351,7 @@ impl DirectoryEntry {
             name,
             short_directory_entry_sector: None,
             short_directory_entry_offset: None,
+            long_name_entries: Vec::new(),
             metadata: FileDirectoryEntry::new(nm_str, first_cluster, size),
         }
     }
@@ -274,21 +366,37 @@ impl DirectoryEntry {
 
     pub fn update_size(&mut self, size: u32) {
         let os = self.metadata.size;
-        serial_println!("Old size {} new size {}", os, size);
+        fat_trace!("Old size {} new size {}", os, size);
         self.metadata.size = size;
     }
 
     pub fn update_first_cluster(&mut self, first_cluster: u32) {
-        let hi = (first_cluster >> 16) as u16;
-        let lo = (first_cluster & 0xFFFF) as u16;
-        self.metadata.cluster_high = hi;
-        self.metadata.cluster_low = lo;
+        self.metadata.set_cluster(first_cluster);
+    }
+
+    // true if the entry has to be written back for it
+    pub fn touch(&mut self, modified: bool) -> bool {
+        let now = time::now();
+        if modified {
+            self.metadata.set_modified(&now)
+        } else {
+            self.metadata.set_accessed(&now)
+        }
+    }
+
+    pub fn entry_addresses(&self) -> Vec<(u32, u32)> {
+        let mut addresses = self.long_name_entries.clone();
+        if let (Some(sec), Some(off)) = (self.short_directory_entry_sector, self.short_directory_entry_offset) {
+            addresses.push((sec, off));
+        }
+        addresses
     }
 }
 
 pub struct DirectoryIterator<'a> {
     raw_iter: DirectoryRawIterator<'a>,
     name_buffer: String,
+    name_entries: Vec<(u32, u32)>,
 }
 
 impl<'a> DirectoryIterator<'a> {
@@ -296,6 +404,7 @@ impl<'a> DirectoryIterator<'a> {
         DirectoryIterator {
             raw_iter,
             name_buffer: String::new(),
+            name_entries: Vec::new(),
         }
     }
 
@@ -319,6 +428,7 @@ impl<'a> Iterator for DirectoryIterator<'a> {
             let ent = self.raw_iter.next();
             if let Some(ent) = ent {
                 if let DirectoryRawEntry::LongFileNameEntry(ent) = ent {
+                    self.name_entries.push(self.raw_iter.last_yield_entry_address());
                     let nm0 = ent.name_0;
                     let nm1 = ent.name_1;
                     let nm2 = ent.name_2;
@@ -339,6 +449,7 @@ impl<'a> Iterator for DirectoryIterator<'a> {
                         },
                         short_directory_entry_sector: Some(esec),
                         short_directory_entry_offset: Some(eoff),
+                        long_name_entries: core::mem::take(&mut self.name_entries),
                         metadata: ent,
                     };
                     self.name_buffer = String::new();
@@ -1,6 +1,5 @@
 use crate::*;
 use super::*;
-use alloc::{vec, vec::Vec};
 
 use super::FATFileSystem;
 
@@ -16,49 +15,26 @@ pub struct FATIterator<'a> {
     fat_fs: &'a FATFileSystem,
     pub cluster: u32,
     pub no_more: bool,
-    sector_size: usize,
-    fat_buffer: Vec<u8>,
 }
 
 impl<'a> FATIterator<'a> {
     pub fn new(fat_fs: &'a FATFileSystem, cluster: u32) -> Result<FATIterator<'a>, Error> {
-        let sector_size = fat_fs.drive.lock().block_size();
-        let mut iter = FATIterator {
+        Ok(FATIterator {
             fat_fs,
             cluster,
             no_more: false,
-            sector_size,
-            fat_buffer: vec![0; sector_size],
-        };
-        iter.fat_fs.drive.lock().read_block(iter.cluster_index_to_sector_offset(cluster).0, iter.fat_buffer.as_mut_ptr())?;
-        Ok(iter)
+        })
     }
 
-    pub fn seek(&mut self, cluster: u32) -> Result<(), Error> {
+    pub fn seek(&mut self, cluster: u32) {
         self.cluster = cluster;
-        self.fat_fs.drive.lock().read_block(self.cluster_index_to_sector_offset(cluster).0, self.fat_buffer.as_mut_ptr())
     }
 
-    pub fn refresh_fat(&mut self) -> Result<(), Error> {
-        self.fat_fs.drive.lock().read_block(self.cluster_index_to_sector_offset(self.cluster).0, self.fat_buffer.as_mut_ptr())
-    }
-
-    fn cluster_index_to_sector_offset(&self, cluster: u32) -> (u64, usize) {
-        let fat_off = match self.fat_fs.fat_type {
-            FATType::FAT12 => cluster as usize + (cluster as usize / 2),
-            FATType::FAT16 => (cluster * 2) as usize,
-            FATType::FAT32 => (cluster * 4) as usize,
-        };
-        let sector = self.fat_fs.first_fat_sector as usize + (fat_off / self.sector_size);
-        let offset = fat_off % self.sector_size;
-        (sector as u64, offset)
-    }
-    
     fn cluster_ok(&self, cluster: u32) -> bool {
         match self.fat_fs.fat_type {
             FATType::FAT12 => !(cluster == 0 || (cluster >= 0xFF7 && cluster <= 0xFFF)),
             FATType::FAT16 => !(cluster == 0 || (cluster >= 0xFFF7)),
-            FATType::FAT32 => !(cluster == 0 || (cluster >= 0xFFFFFFF7)),
+            FATType::FAT32 => !(cluster == 0 || (cluster >= 0x0FFFFFF7)),
         }
     }
 }
@@ -74,43 +50,25 @@ impl<'a> Iterator for FATIterator<'a> {
         if !self.cluster_ok(rv) {
             self.no_more = true;
         } else {
-            let (sec, sec_off) = self.cluster_index_to_sector_offset(self.cluster);
-
-
-            match self.fat_fs.fat_type {
-                FATType::FAT12 => {
-                    let packed_val = (self.fat_buffer[sec_off + 1] as u16) << 8 | self.fat_buffer[sec_off] as u16;
-                    self.cluster = match self.cluster & 1 {
-                        0 => (packed_val & 0xFFF) as u32,
-                        _ => (packed_val >> 4) as u32,
-                    };
-                },
-                FATType::FAT16 => {
-                    self.cluster = (((self.fat_buffer[sec_off as usize + 1] as u16) << 8) | self.fat_buffer[sec_off as usize] as u16) as u32;
+            match self.fat_fs.read_fat_entry(self.cluster) {
+                Ok(next) => self.cluster = next,
+                Err(_) => {
+                    self.no_more = true;
+                    return None;
                 },
-                FATType::FAT32 => {
-                    self.cluster = (((self.fat_buffer[sec_off as usize + 3] as u32) << 24) | ((self.fat_buffer[sec_off as usize + 2] as u32) << 16) | ((self.fat_buffer[sec_off as usize + 1] as u32) << 8) | (self.fat_buffer[sec_off as usize] as u32)) & 0x0FFFFFFF;
-                }
-            };
-
-            if self.cluster_ok(self.cluster) {
-                let (sec_new, _) = self.cluster_index_to_sector_offset(self.cluster);
-                if sec != sec_new {
-                    self.fat_fs.drive.lock().read_block(sec_new, self.fat_buffer.as_mut_ptr()).unwrap();
-                }
             }
         }
 
         Some(match self.fat_fs.fat_type {
             FATType::FAT12 => match rv {
                 0 => FATEntry::Free(self.cluster),
-                0xFF8 => FATEntry::Bad,
+                0xFF7 => FATEntry::Bad,
                 0xFF8..=0xFFF => FATEntry::EndOfChain,
                 n => FATEntry::Cluster(n as u32),
             },
             FATType::FAT16 => match rv {
                 0 => FATEntry::Free(self.cluster),
-                0xFFF8 => FATEntry::Bad,
+                0xFFF7 => FATEntry::Bad,
                 0xFFF8..=0xFFFF => FATEntry::EndOfChain,
                 n => FATEntry::Cluster(n as u32),
             },
@@ -200,123 +158,56 @@ pub enum ClusterAllocatorMode {
 pub struct ClusterAllocator<'a> {
     fat_fs: &'a FATFileSystem,
     pub prev_free_cluster: Option<u32>,
-    sector_size: usize,
-    fat_sector: u64,
     mode: ClusterAllocatorMode,
-    fat_buffer: Vec<u8>,
 }
 
 impl<'a> ClusterAllocator<'a> {
     pub fn new(fat_fs: &'a FATFileSystem, first_cluster: Option<u32>, mode: ClusterAllocatorMode) -> Result<ClusterAllocator<'a>, Error> {
-        let sector_size = fat_fs.drive.lock().block_size();
-        let mut iter = ClusterAllocator {
+        Ok(ClusterAllocator {
             fat_fs,
             prev_free_cluster: first_cluster,
-            sector_size,
-            fat_sector: 0,
             mode,
-            fat_buffer: vec![0; sector_size],
-        };
-        iter.fat_sector = iter.cluster_index_to_sector_offset(match first_cluster {
-            Some(c) => c,
-            None => 3,
-        }).0;
-        iter.fat_fs.drive.lock().read_block(iter.fat_sector, iter.fat_buffer.as_mut_ptr())?;
-        Ok(iter)
-    }
-
-    fn cluster_index_to_sector_offset(&self, cluster: u32) -> (u64, usize) {
-        let fat_off = match self.fat_fs.fat_type {
-            FATType::FAT12 => cluster as usize + (cluster as usize / 2),
-            FATType::FAT16 => (cluster * 2) as usize,
-            FATType::FAT32 => (cluster * 4) as usize,
-        };
-        let sector = self.fat_fs.first_fat_sector as usize + (fat_off / self.sector_size);
-        let offset = fat_off % self.sector_size;
-        (sector as u64, offset)
+        })
     }
 
+    // a chain grows from right after its last cluster, a new one from wherever the volume last allocated
     fn find_free_cluster(&mut self) -> Option<u32> {
-        let mut i = match self.prev_free_cluster {
-            Some(c) => c,
-            None => 3,
-        } + 1;
-        let mut wraparound = false;
-        loop {
-            let (sec, sec_off) = self.cluster_index_to_sector_offset(i);
-            if sec != self.fat_sector {
-                self.fat_fs.drive.lock().read_block(sec, self.fat_buffer.as_mut_ptr()).unwrap();
-                self.fat_sector = sec;
-            }
-            let clu = match self.fat_fs.fat_type {
-                FATType::FAT12 => {
-                    let packed_val = (self.fat_buffer[sec_off + 1] as u16) << 8 | self.fat_buffer[sec_off] as u16;
-                    panic!("Not implemented");
-                    /*match self.prev_free_cluster & 1 {
-                        0 => (packed_val & 0xFFF) as u32,
-                        _ => (packed_val >> 4) as u32,
-                    }*/
-                },
-                FATType::FAT16 => (((self.fat_buffer[sec_off as usize + 1] as u16) << 8) | self.fat_buffer[sec_off as usize] as u16) as u32,
-                FATType::FAT32 => (((self.fat_buffer[sec_off as usize + 3] as u32) << 24) | ((self.fat_buffer[sec_off as usize + 2] as u32) << 16) | ((self.fat_buffer[sec_off as usize + 1] as u32) << 8) | (self.fat_buffer[sec_off as usize] as u32)) & 0x0FFFFFFF,
-            };
-            if clu == 0 {
-                return Some(i);
+        let start = match self.prev_free_cluster {
+            Some(c) => c + 1,
+            None => self.fat_fs.next_free_hint(),
+        };
+        let total = self.fat_fs.total_clusters;
+        for i in 0..total {
+            let cluster = 2 + (start.wrapping_sub(2).wrapping_add(i)) % total;
+            if Some(cluster) == self.prev_free_cluster {
+                continue;
             }
-            i += 1;
-            if i >= self.fat_fs.total_clusters {
-                if wraparound {
-                    return None;
-                } else {
-                    i = 3;
-                    wraparound = true;
-                }
+            match self.fat_fs.read_fat_entry(cluster) {
+                Ok(0) => return Some(cluster),
+                Ok(_) => (),
+                Err(_) => return None,
             }
         }
+        None
     }
 
-    pub fn finish(mut self) -> Result<(), Error> {
-        let (sec, sec_off) = self.cluster_index_to_sector_offset(match self.prev_free_cluster {
-            Some(clu) => clu,
-            None => return Err(Error::OutOfSpace),
-        });
-        self.fat_fs.drive.lock().read_block(sec, self.fat_buffer.as_mut_ptr())?;
-        match self.fat_fs.fat_type {
-            FATType::FAT12 => panic!("Not implemented"),
-            FATType::FAT16 => self.fat_buffer[sec_off..sec_off + 2].copy_from_slice(&(0xFFFF as u16).to_le_bytes()),
-            FATType::FAT32 => self.fat_buffer[sec_off..sec_off + 4].copy_from_slice(&(0xFFFFFFFF as u32).to_le_bytes()),
-        };
-        self.fat_fs.drive.lock().write_block(sec, self.fat_buffer.as_mut_slice())?;
-        Ok(())
+    pub fn finish(self) -> Result<(), Error> {
+        match self.prev_free_cluster {
+            Some(clu) => self.fat_fs.write_fat_entry(clu, self.fat_fs.end_of_chain()),
+            None => Err(Error::OutOfSpace),
+        }
     }
 }
 
 impl<'a> Iterator for ClusterAllocator<'a> {
     type Item = u32;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(clu) = self.find_free_cluster() {
-            let (sec, sec_off) = self.cluster_index_to_sector_offset(match self.prev_free_cluster {
-                Some(clu) => clu,
-                None => 3,
-            });
-            if sec != self.fat_sector {
-                self.fat_fs.drive.lock().read_block(sec, self.fat_buffer.as_mut_ptr()).unwrap();
-                self.fat_sector = sec;
-            }
-            if let Some(cl) = self.prev_free_cluster { // do not overwrite cluster entry 3 if we did not provide chain end
-                match self.fat_fs.fat_type {
-                    FATType::FAT12 => panic!("Not implemented"),
-                    FATType::FAT16 => self.fat_buffer[sec_off..sec_off + 2].copy_from_slice(&(clu as u16).to_le_bytes()),
-                    FATType::FAT32 => self.fat_buffer[sec_off..sec_off + 4].copy_from_slice(&clu.to_le_bytes()),
-                };
-                if let ClusterAllocatorMode::Allocate = self.mode {
-                    self.fat_fs.drive.lock().write_block(sec, self.fat_buffer.as_mut_slice());
-                }
-            }
-            self.prev_free_cluster = Some(clu);
-            self.prev_free_cluster
-        } else {
-            None
+        let clu = self.find_free_cluster()?;
+        if let (Some(prev), ClusterAllocatorMode::Allocate) = (self.prev_free_cluster, &self.mode) {
+            // do not link anything if we did not provide the chain end
+            self.fat_fs.write_fat_entry(prev, clu).ok()?;
         }
+        self.prev_free_cluster = Some(clu);
+        self.prev_free_cluster
     }
 }
@@ -92,9 +92,8 @@ impl FATFile {
             if i < sectors_to_write - 1 {
                 if let None = self.sec_iter.next() {
                     self.fat_fs.allocate_clusters(Some(self.sec_iter.last_good_cluster), 1)?;
-                    self.sec_iter.cluster_iter.cluster = self.sec_iter.last_good_cluster;
+                    self.sec_iter.cluster_iter.seek(self.sec_iter.last_good_cluster);
                     self.sec_iter.cluster_iter.no_more = false;
-                    self.sec_iter.cluster_iter.refresh_fat()?;
                     if let None = self.sec_iter.next() {
                         return Err(Error::IOFailure);
                     }
@@ -135,7 +134,11 @@ impl Seek for FATFile {
 
 impl Read for FATFile {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let _volume = self.fat_fs.volume.lock();
         self.calculate_actual_position(false)?;
+        if self.directory_entry.touch(false) {
+            self.fat_fs.in_place_update_directory_entry(&self.directory_entry)?;
+        }
         let buf = if self.offset() + buf.len() as u64 >= self.size() {
             let end = buf.len() - (self.offset() + buf.len() as u64 - self.size()) as usize;
             &mut buf[..end]
@@ -179,7 +182,12 @@ impl Read for FATFile {
 
 impl Write for FATFile {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let _volume = self.fat_fs.volume.lock();
         self.calculate_actual_position(true)?;
+        // at most once every two seconds, unless the size changes too
+        if self.directory_entry.touch(true) && self.offset() + (buf.len() as u64) < self.size() {
+            self.fat_fs.in_place_update_directory_entry(&self.directory_entry)?;
+        }
         self._write(buf)
     }
 }
\ No newline at end of file
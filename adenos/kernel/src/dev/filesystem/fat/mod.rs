@@ -2,13 +2,24 @@ use crate::file::{File, FilePermissions};
 use crate::*;
 use alloc::{boxed::Box, string::ToString};
 use alloc::{string::String, sync::Arc, vec, vec::Vec};
-use core::num;
 use core::{fmt::Debug, str};
 use dev::{filesystem::*, *};
 use modular_bitfield::{bitfield, specifiers::*};
 use namespace::{self, *};
 use spin::Mutex;
+use infinity::os;
+
+// the driver's tracing goes to the serial port, built into the host fsck there is none so the arguments are only evaluated
+macro_rules! fat_trace {
+    ($($arg:tt)*) => {
+        #[cfg(target_os = "none")]
+        serial_println!($($arg)*);
+        #[cfg(not(target_os = "none"))]
+        let _ = format_args!($($arg)*);
+    };
+}
 
+mod check;
 mod dir;
 mod fat;
 mod file;
@@ -73,6 +84,38 @@ enum eBPB {
     eBPB32(eBPB32),
 }
 
+const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FSINFO_STRUCTURE_SIGNATURE: u32 = 0x61417272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA550000;
+// free_count and next_free when they are not known
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct FSInfo {
+    lead_signature: u32,
+    _reserved0: [u8; 480],
+    structure_signature: u32,
+    free_count: u32,
+    next_free: u32,
+    _reserved1: [u8; 12],
+    trail_signature: u32,
+}
+
+impl FSInfo {
+    fn is_valid(&self) -> bool {
+        let (lead, structure, trail) = (self.lead_signature, self.structure_signature, self.trail_signature);
+        lead == FSINFO_LEAD_SIGNATURE && structure == FSINFO_STRUCTURE_SIGNATURE && trail == FSINFO_TRAIL_SIGNATURE
+    }
+}
+
+// where to start looking for free clusters and how many there are, from FSInfo on FAT32
+#[derive(Debug)]
+struct AllocationHint {
+    next_free: u32,
+    free_count: Option<u32>,
+}
+
 pub struct FATFileSystem {
     drive: Arc<Mutex<&'static mut dyn BlockReadWrite>>,
     bpb: BPB,
@@ -89,6 +132,14 @@ pub struct FATFileSystem {
     sector_size: u32,
     cluster_size: u32,
     sectors_per_cluster: u32,
+    mount_point: Vec<String>,
+    // the two FAT sectors last read, FAT writes go through it
+    fat_cache: Mutex<Option<(u64, Vec<u8>)>>,
+    allocation: Mutex<AllocationHint>,
+    mounted_clean: bool,
+    // held through every operation on the volume, so that allocations and directory updates made
+    // from different cpus cannot interleave
+    volume: Mutex<()>,
 }
 
 impl Debug for FATFileSystem {
@@ -106,127 +157,162 @@ impl Debug for FATFileSystem {
             .field("root_dir_sector", &self.root_dir_sector)
             .field("bytes_per_sector", &self.sector_size)
             .field("sectors_per_cluster", &self.sectors_per_cluster)
+            .field("allocation", &*self.allocation.lock())
+            .field("mounted_clean", &self.mounted_clean)
             .finish()
     }
 }
 
+pub fn probe(device_path: String) -> Result<Option<Box<dyn Resource>>, Error> {
+    match FATFileSystem::new(device_path.clone())? {
+        Some(fs) => {
+            if !fs.mounted_clean() {
+                println!("{} was not unmounted cleanly, checking it", device_path);
+                match fs.check_volume(true) {
+                    Ok(report) if report.errors() > 0 => println!("{} repaired: {:?}", device_path, report),
+                    Ok(_) => (),
+                    Err(err) => println!("{} check failed: {:?}", device_path, err),
+                }
+            }
+            // stays dirty until it is unmounted
+            fs.set_clean(false)?;
+            Ok(Some(Box::new(fs)))
+        },
+        None => Ok(None),
+    }
+}
+
 impl FATFileSystem {
     pub fn new(drive_path: String) -> Result<Option<Self>, Error> {
-        if let Some(drive) = namespace::get_block_device(drive_path.clone()) {
-            let mut bpb = [0; 512];
-            drive.read_block(0, bpb.as_mut_ptr())?;
-            let bpb = unsafe { (&bpb as *const _ as *const BPB).as_ref().unwrap() };
-            let ebpb16 = unsafe {
-                (&bpb.extension as *const _ as *const eBPB16)
-                    .as_ref()
-                    .unwrap()
-            };
-            let ebpb32 = unsafe {
-                (&bpb.extension as *const _ as *const eBPB32)
-                    .as_ref()
-                    .unwrap()
-            };
+        match namespace::get_block_device(drive_path) {
+            Some(drive) => Self::from_drive(drive),
+            None => Err(Error::InvalidDevice),
+        }
+    }
 
-            if bpb._jmp[0] != 0xEB || bpb._jmp[2] != 0x90 {
-                return Ok(None);
-            }
+    // None if there is no FAT file system on the drive
+    pub fn from_drive(drive: &'static mut dyn BlockReadWrite) -> Result<Option<Self>, Error> {
+        let mut bpb = [0; 512];
+        drive.read_block(0, bpb.as_mut_ptr())?;
+        let bpb = unsafe { (&bpb as *const _ as *const BPB).as_ref().unwrap() };
+        let ebpb16 = unsafe {
+            (&bpb.extension as *const _ as *const eBPB16)
+                .as_ref()
+                .unwrap()
+        };
+        let ebpb32 = unsafe {
+            (&bpb.extension as *const _ as *const eBPB32)
+                .as_ref()
+                .unwrap()
+        };
 
-            let total_sectors = match bpb.sector_count {
-                0 => bpb.large_sector_count,
-                _ => bpb.sector_count as u32,
-            };
-            let fat_size = match bpb.fat_size {
-                0 => ebpb32.fat_size as u32,
-                _ => bpb.fat_size as u32,
-            };
-            let root_dir_sectors = ((bpb.root_directory_entries as u32 * 32)
-                + (bpb.bytes_per_sector as u32 - 1))
-                / bpb.bytes_per_sector as u32;
-            let first_data_sector = bpb.reserved_sector_count as u32
-                + (bpb.file_allocation_tables as u32 * fat_size)
-                + root_dir_sectors;
-            let first_fat_sector = bpb.reserved_sector_count as u32;
-            let data_sectors = match bpb.sector_count {
-                0 => bpb.large_sector_count,
-                c => c as u32,
-            } - (bpb.reserved_sector_count as u32
-                + (bpb.file_allocation_tables as u32 * fat_size)
-                + root_dir_sectors);
-            let total_clusters = data_sectors / bpb.sectors_per_cluster as u32;
-            let sector_size = drive.block_size() as u32;
-            let cluster_size = bpb.sectors_per_cluster as u32 * sector_size;
-
-            let mut fat_type = if total_clusters < 4085 {
-                FATType::FAT12
-            } else if total_clusters < 65525 {
-                FATType::FAT16
-            } else {
-                FATType::FAT32
-            };
+        if bpb._jmp[0] != 0xEB || bpb._jmp[2] != 0x90 {
+            return Ok(None);
+        }
 
-            let (signature, check_signature) = match fat_type {
-                FATType::FAT12 => (&ebpb16.fat_type, "FAT12"),
-                FATType::FAT16 => (&ebpb16.fat_type, "FAT16"),
-                FATType::FAT32 => (&ebpb32.extension.fat_type, "FAT32"),
-            };
+        let total_sectors = match bpb.sector_count {
+            0 => bpb.large_sector_count,
+            _ => bpb.sector_count as u32,
+        };
+        let fat_size = match bpb.fat_size {
+            0 => ebpb32.fat_size as u32,
+            _ => bpb.fat_size as u32,
+        };
+        let root_dir_sectors = ((bpb.root_directory_entries as u32 * 32)
+            + (bpb.bytes_per_sector as u32 - 1))
+            / bpb.bytes_per_sector as u32;
+        let first_data_sector = bpb.reserved_sector_count as u32
+            + (bpb.file_allocation_tables as u32 * fat_size)
+            + root_dir_sectors;
+        let first_fat_sector = bpb.reserved_sector_count as u32;
+        let data_sectors = match bpb.sector_count {
+            0 => bpb.large_sector_count,
+            c => c as u32,
+        } - (bpb.reserved_sector_count as u32
+            + (bpb.file_allocation_tables as u32 * fat_size)
+            + root_dir_sectors);
+        let total_clusters = data_sectors / bpb.sectors_per_cluster as u32;
+        let sector_size = drive.block_size() as u32;
+        let cluster_size = bpb.sectors_per_cluster as u32 * sector_size;
+
+        let mut fat_type = if total_clusters < 4085 {
+            FATType::FAT12
+        } else if total_clusters < 65525 {
+            FATType::FAT16
+        } else {
+            FATType::FAT32
+        };
 
-            // check signature
-            if let Ok(signature) = str::from_utf8(signature) {
-                if signature != check_signature {
-                    if let FATType::FAT16 = fat_type {
-                        let signature = &ebpb32.extension.fat_type;
-                        match str::from_utf8(signature) {
-                            Ok(signature) => {
-                                if signature == "FAT32" {
-                                    fat_type = FATType::FAT32;
-                                } else {
-                                    return Ok(None);
-                                }
+        let (signature, check_signature) = match fat_type {
+            FATType::FAT12 => (&ebpb16.fat_type, "FAT12"),
+            FATType::FAT16 => (&ebpb16.fat_type, "FAT16"),
+            FATType::FAT32 => (&ebpb32.extension.fat_type, "FAT32"),
+        };
+
+        // check signature
+        if let Ok(signature) = str::from_utf8(signature) {
+            if signature != check_signature {
+                if let FATType::FAT16 = fat_type {
+                    let signature = &ebpb32.extension.fat_type;
+                    match str::from_utf8(signature) {
+                        Ok(signature) => {
+                            if signature == "FAT32" {
+                                fat_type = FATType::FAT32;
+                            } else {
+                                return Ok(None);
                             }
-                            Err(_) => return Ok(None),
-                        };
-                    } else {
-                        return Ok(None);
-                    }
+                        }
+                        Err(_) => return Ok(None),
+                    };
+                } else {
+                    return Ok(None);
                 }
-            } else {
-                return Ok(None);
             }
+        } else {
+            return Ok(None);
+        }
 
-            let root_dir_sector = match fat_type {
-                FATType::FAT32 => {
-                    ebpb32.root_directory_cluster as u32 * bpb.sectors_per_cluster as u32
-                }
-                _ => first_data_sector - root_dir_sectors,
-            };
+        let root_dir_sector = match fat_type {
+            FATType::FAT32 => {
+                ebpb32.root_directory_cluster as u32 * bpb.sectors_per_cluster as u32
+            }
+            _ => first_data_sector - root_dir_sectors,
+        };
 
-            let ebpb = match fat_type {
-                FATType::FAT32 => eBPB::eBPB32(ebpb32.clone()),
-                _ => eBPB::eBPB16(ebpb16.clone()),
-            };
+        let ebpb = match fat_type {
+            FATType::FAT32 => eBPB::eBPB32(ebpb32.clone()),
+            _ => eBPB::eBPB16(ebpb16.clone()),
+        };
 
-            let fat_fs = FATFileSystem {
-                drive: Arc::new(Mutex::new(drive)),
-                bpb: bpb.clone(),
-                ebpb,
-                fat_type,
-                total_sectors,
-                fat_size,
-                root_dir_sectors,
-                first_data_sector,
-                first_fat_sector,
-                data_sectors,
-                total_clusters,
-                root_dir_sector,
-                sector_size,
-                cluster_size,
-                sectors_per_cluster: bpb.sectors_per_cluster as u32,
-            };
+        let mut fat_fs = FATFileSystem {
+            drive: Arc::new(Mutex::new(drive)),
+            bpb: bpb.clone(),
+            ebpb,
+            fat_type,
+            total_sectors,
+            fat_size,
+            root_dir_sectors,
+            first_data_sector,
+            first_fat_sector,
+            data_sectors,
+            total_clusters,
+            root_dir_sector,
+            sector_size,
+            cluster_size,
+            sectors_per_cluster: bpb.sectors_per_cluster as u32,
+            mount_point: Vec::new(),
+            fat_cache: Mutex::new(None),
+            allocation: Mutex::new(AllocationHint {
+                next_free: 2,
+                free_count: None,
+            }),
+            mounted_clean: true,
+            volume: Mutex::new(()),
+        };
+        fat_fs.read_fs_info()?;
+        fat_fs.mounted_clean = fat_fs.is_clean()?;
 
-            Ok(Some(fat_fs))
-        } else {
-            Err(Error::InvalidDevice)
-        }
+        Ok(Some(fat_fs))
     }
 
     fn cluster_to_sector(&self, cluster: u32) -> u32 {
@@ -292,8 +378,9 @@ impl FATFileSystem {
             return Err(Error::OutOfSpace);
         }
         let last = iter.prev_free_cluster.unwrap();
-        serial_println!("Last in chain: {}", last);
+        fat_trace!("Last in chain: {}", last);
         iter.finish()?;
+        self.write_fs_info()?;
         Ok(first)
     }
 
@@ -305,14 +392,11 @@ impl FATFileSystem {
         let name_entries_needed = (ent.name.len() + 13) / 13;
         let mut dir_entries = Vec::new();
         dir_entries.push(DirectoryRawEntry::FileDirectoryEntry(ent.metadata));
-        let mut check_sum = num::Wrapping(0_u8);
-        for b in ent.metadata.file_name {
-            check_sum = (check_sum << 7) + (check_sum >> 1) + num::Wrapping(b);
-        }
+        let check_sum = ent.metadata.name_checksum();
         let mut uniname = ent.name.encode_utf16().collect::<Vec<u16>>();
         uniname.push(0);
         for i in (0..ent.name.len()).step_by(13) {
-            let ord = if i >= ent.name.len() / 13 * 13 {
+            let ord = if i + 13 >= ent.name.len() {
                 0x40 | (i / 13 + 1) as u8
             } else {
                 (i / 13 + 1) as u8
@@ -323,7 +407,7 @@ impl FATFileSystem {
                 &uniname[i..i + 13]
             };
             dir_entries.push(DirectoryRawEntry::LongFileNameEntry(
-                LongFileNameEntry::new(name_part, ord, check_sum.0),
+                LongFileNameEntry::new(name_part, ord, check_sum),
             ));
         }
         dir_entries.reverse();
@@ -349,6 +433,7 @@ impl FATFileSystem {
         }
 
         let (mut short_sec, mut short_off) = (0, 0);
+        let mut long_name_entries = Vec::new();
 
         let mut buf = vec![0; self.sector_size as usize];
         for ((sec, off), ent) in slot.into_iter().zip(dir_entries.into_iter()) {
@@ -368,7 +453,8 @@ impl FATFileSystem {
                         (buf.as_mut_ptr().offset(off as isize) as *mut LongFileNameEntry)
                             .as_mut()
                             .unwrap()
-                    } = ent.clone()
+                    } = ent.clone();
+                    long_name_entries.push((sec, off));
                 }
                 _ => (),
             }
@@ -380,6 +466,7 @@ impl FATFileSystem {
         let mut updated_ent = ent;
         updated_ent.short_directory_entry_sector = Some(short_sec);
         updated_ent.short_directory_entry_offset = Some(short_off);
+        updated_ent.long_name_entries = long_name_entries;
 
         Ok(updated_ent)
     }
@@ -389,7 +476,7 @@ impl FATFileSystem {
             ent.short_directory_entry_sector.unwrap(),
             ent.short_directory_entry_offset.unwrap(),
         );
-        serial_println!("Upd dir ent {} {}", sec, off);
+        fat_trace!("Upd dir ent {} {}", sec, off);
         let mut buf = vec![0; self.sector_size as usize];
         self.drive.lock().read_block(sec as u64, buf.as_mut_ptr())?;
         *unsafe {
@@ -402,9 +489,289 @@ impl FATFileSystem {
             .write_block(sec as u64, buf.as_mut_slice())?;
         Ok(())
     }
+
+    // sector and byte offset of a cluster's FAT entry, in the first FAT
+    fn fat_entry_location(&self, cluster: u32) -> (u64, usize) {
+        let fat_off = match self.fat_type {
+            FATType::FAT12 => cluster as usize + (cluster as usize / 2),
+            FATType::FAT16 => (cluster * 2) as usize,
+            FATType::FAT32 => (cluster * 4) as usize,
+        };
+        let sector = self.first_fat_sector as usize + fat_off / self.sector_size as usize;
+        (sector as u64, fat_off % self.sector_size as usize)
+    }
+
+    // FAT32 can turn mirroring off and keep only one FAT up to date
+    fn active_fat(&self) -> Option<u32> {
+        match self.ebpb {
+            eBPB::eBPB32(ebpb) if ebpb.flags & 0x80 != 0 => Some((ebpb.flags & 0xF) as u32),
+            _ => None,
+        }
+    }
+
+    fn fat_copies(&self) -> core::ops::Range<u32> {
+        match self.active_fat() {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.bpb.file_allocation_tables as u32,
+        }
+    }
+
+    // FAT12 entries can straddle two sectors, so two are always read
+    fn with_fat_sectors<R>(&self, sector: u64, f: impl FnOnce(&mut Vec<u8>) -> R) -> Result<R, Error> {
+        let mut cache = self.fat_cache.lock();
+        if cache.as_ref().map_or(true, |(cached, _)| *cached != sector) {
+            let mut buf = vec![0; self.sector_size as usize * 2];
+            let fat = self.active_fat().unwrap_or(0);
+            self.drive.lock().read_blocks(sector + (fat * self.fat_size) as u64, 2, buf.as_mut_ptr())?;
+            *cache = Some((sector, buf));
+        }
+        Ok(f(&mut cache.as_mut().unwrap().1))
+    }
+
+    fn decode_fat_entry(&self, buf: &[u8], cluster: u32, off: usize) -> u32 {
+        match self.fat_type {
+            FATType::FAT12 => {
+                let packed = u16::from_le_bytes([buf[off], buf[off + 1]]);
+                match cluster & 1 {
+                    0 => (packed & 0xFFF) as u32,
+                    _ => (packed >> 4) as u32,
+                }
+            },
+            FATType::FAT16 => u16::from_le_bytes([buf[off], buf[off + 1]]) as u32,
+            FATType::FAT32 => u32::from_le_bytes(buf[off..off + 4].try_into().unwrap()) & 0x0FFFFFFF,
+        }
+    }
+
+    fn read_fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+        let (sector, off) = self.fat_entry_location(cluster);
+        self.with_fat_sectors(sector, |buf| self.decode_fat_entry(buf, cluster, off))
+    }
+
+    // goes to every FAT that is kept up to date
+    fn write_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        let (sector, off) = self.fat_entry_location(cluster);
+        let old = self.with_fat_sectors(sector, |buf| -> Result<u32, Error> {
+            let old = self.decode_fat_entry(buf, cluster, off);
+            match self.fat_type {
+                FATType::FAT12 => {
+                    let packed = u16::from_le_bytes([buf[off], buf[off + 1]]);
+                    let packed = match cluster & 1 {
+                        0 => (packed & 0xF000) | (value as u16 & 0xFFF),
+                        _ => (packed & 0x000F) | ((value as u16 & 0xFFF) << 4),
+                    };
+                    buf[off..off + 2].copy_from_slice(&packed.to_le_bytes());
+                },
+                FATType::FAT16 => buf[off..off + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+                FATType::FAT32 => {
+                    // the top four bits are reserved and kept as they are
+                    let raw = u32::from_le_bytes(buf[off..off + 4].try_into().unwrap());
+                    buf[off..off + 4].copy_from_slice(&((raw & 0xF0000000) | (value & 0x0FFFFFFF)).to_le_bytes());
+                },
+            };
+            // the second sector is only written when the entry reaches into it
+            let sectors = if off + 1 >= self.sector_size as usize { 2 } else { 1 };
+            for fat in self.fat_copies() {
+                self.drive.lock().write_blocks(sector + (fat * self.fat_size) as u64, &mut buf[..sectors * self.sector_size as usize])?;
+            }
+            Ok(old)
+        })??;
+        if cluster >= 2 {
+            let mut allocation = self.allocation.lock();
+            if old == 0 && value != 0 {
+                allocation.next_free = cluster + 1;
+                allocation.free_count = allocation.free_count.map(|count| count.saturating_sub(1));
+            } else if old != 0 && value == 0 {
+                allocation.free_count = allocation.free_count.map(|count| count + 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FATType::FAT12 => 0xFFF,
+            FATType::FAT16 => 0xFFFF,
+            FATType::FAT32 => 0x0FFFFFFF,
+        }
+    }
+
+    // the cluster after this one in its chain, None at the end or at anything that is not a data cluster
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        match self.read_fat_entry(cluster) {
+            Ok(next) if next >= 2 && next < self.total_clusters + 2 => Some(next),
+            _ => None,
+        }
+    }
+
+    fn next_free_hint(&self) -> u32 {
+        let next_free = self.allocation.lock().next_free;
+        if next_free >= 2 && next_free < self.total_clusters + 2 {
+            next_free
+        } else {
+            2
+        }
+    }
+
+    fn fs_info_sector(&self) -> Option<u64> {
+        match self.ebpb {
+            eBPB::eBPB32(ebpb) if ebpb.fsinfo_sector != 0 && ebpb.fsinfo_sector != 0xFFFF => Some(ebpb.fsinfo_sector as u64),
+            _ => None,
+        }
+    }
+
+    // the hints are only taken if they make sense for this volume
+    fn read_fs_info(&self) -> Result<(), Error> {
+        if let Some(sector) = self.fs_info_sector() {
+            let mut buf = vec![0; self.sector_size as usize];
+            self.drive.lock().read_block(sector, buf.as_mut_ptr())?;
+            let fs_info = unsafe { (buf.as_ptr() as *const FSInfo).read_unaligned() };
+            if fs_info.is_valid() {
+                let mut allocation = self.allocation.lock();
+                if fs_info.free_count <= self.total_clusters {
+                    allocation.free_count = Some(fs_info.free_count);
+                }
+                if fs_info.next_free >= 2 && fs_info.next_free < self.total_clusters + 2 {
+                    allocation.next_free = fs_info.next_free;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_fs_info(&self) -> Result<(), Error> {
+        if let Some(sector) = self.fs_info_sector() {
+            let mut buf = vec![0; self.sector_size as usize];
+            self.drive.lock().read_block(sector, buf.as_mut_ptr())?;
+            let mut fs_info = unsafe { (buf.as_ptr() as *const FSInfo).read_unaligned() };
+            if !fs_info.is_valid() {
+                return Ok(());
+            }
+            let allocation = self.allocation.lock();
+            fs_info.free_count = allocation.free_count.unwrap_or(FSINFO_UNKNOWN);
+            fs_info.next_free = allocation.next_free;
+            drop(allocation);
+            unsafe { (buf.as_mut_ptr() as *mut FSInfo).write_unaligned(fs_info); }
+            self.drive.lock().write_block(sector, buf.as_mut_slice())?;
+        }
+        Ok(())
+    }
+
+    // entry 1 of the FAT has a bit that is cleared while the volume is mounted, FAT12 has no room for it
+    fn clean_bit(&self) -> Option<u32> {
+        match self.fat_type {
+            FATType::FAT12 => None,
+            FATType::FAT16 => Some(0x8000),
+            FATType::FAT32 => Some(0x08000000),
+        }
+    }
+
+    pub fn is_clean(&self) -> Result<bool, Error> {
+        match self.clean_bit() {
+            Some(bit) => Ok(self.read_fat_entry(1)? & bit != 0),
+            None => Ok(true),
+        }
+    }
+
+    pub fn set_clean(&self, clean: bool) -> Result<(), Error> {
+        if let Some(bit) = self.clean_bit() {
+            let entry = self.read_fat_entry(1)?;
+            self.write_fat_entry(1, if clean { entry | bit } else { entry & !bit })?;
+        }
+        Ok(())
+    }
+
+    // whether the volume was cleanly unmounted before this mount
+    pub fn mounted_clean(&self) -> bool {
+        self.mounted_clean
+    }
+
+    // stops at the end of the chain or at anything that is not a data cluster
+    fn free_cluster_chain(&self, first_cluster: u32) -> Result<(), Error> {
+        let mut cluster = first_cluster;
+        while cluster >= 2 && cluster < self.total_clusters + 2 {
+            let next = self.read_fat_entry(cluster)?;
+            self.write_fat_entry(cluster, 0)?;
+            cluster = next;
+        }
+        self.write_fs_info()
+    }
+
+    fn mark_entry_deleted(&self, sec: u32, off: u32) -> Result<(), Error> {
+        let mut buf = vec![0; self.sector_size as usize];
+        self.drive.lock().read_block(sec as u64, buf.as_mut_ptr())?;
+        buf[off as usize] = 0xE5;
+        self.drive.lock().write_block(sec as u64, buf.as_mut_slice())
+    }
+
+    fn delete_directory_entry(&self, ent: &DirectoryEntry) -> Result<(), Error> {
+        for (sec, off) in ent.entry_addresses() {
+            self.mark_entry_deleted(sec, off)?;
+        }
+        Ok(())
+    }
+
+    // the directory at path and its first cluster, 0 for the root directory
+    fn directory(&self, path: &[String]) -> Result<(DirectoryIterator<'_>, u32), Error> {
+        let mut dir = self.root_dir_iter()?;
+        let mut cluster = 0;
+        for part in path {
+            match dir.find(|ent| ent.name.trim() == part.trim()) {
+                Some(ent) if ent.metadata.is_directory() => {
+                    cluster = ent.first_cluster();
+                    dir = DirectoryIterator::from_entry(self, ent)?;
+                },
+                Some(_) => return Err(Error::NotDirectory),
+                None => return Err(Error::EntryNotFound),
+            }
+        }
+        Ok((dir, cluster))
+    }
+
+    // the entry at path and the first cluster of the directory holding it
+    fn entry(&self, path: &[String]) -> Result<(DirectoryEntry, u32), Error> {
+        let (name, parent) = path.split_last().ok_or(Error::Permissions)?;
+        let (mut dir, parent_cluster) = self.directory(parent)?;
+        let ent = dir.find(|ent| ent.name.trim() == name.trim()).ok_or(Error::EntryNotFound)?;
+        Ok((ent, parent_cluster))
+    }
+
+    fn exists(&self, path: &[String]) -> Result<bool, Error> {
+        match self.entry(path) {
+            Ok(_) => Ok(true),
+            Err(Error::EntryNotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // the . and .. entries a new directory starts with
+    fn write_dot_entries(&self, cluster: u32, parent_cluster: u32) -> Result<(), Error> {
+        let mut buf = vec![0; self.cluster_size as usize];
+        for (i, (name, target)) in [(".", cluster), ("..", parent_cluster)].into_iter().enumerate() {
+            let mut ent = FileDirectoryEntry::new(String::from(name), target, 0);
+            ent.attributes = FileAttributes::DIRECTORY;
+            *unsafe {
+                (buf.as_mut_ptr().offset(i as isize * 32) as *mut FileDirectoryEntry)
+                    .as_mut()
+                    .unwrap()
+            } = ent;
+        }
+        self.drive
+            .lock()
+            .write_blocks(self.cluster_to_sector(cluster) as u64, buf.as_mut_slice())
+    }
 }
 
 impl FileSystem for FATFileSystem {
+    fn set_mount_point(&mut self, path: Vec<String>) {
+        self.mount_point = path;
+    }
+
+    fn unmount(&mut self) -> Result<(), Error> {
+        let _volume = self.volume.lock();
+        self.write_fs_info()?;
+        self.set_clean(true)
+    }
+
     fn volume_label(&self) -> String {
         String::from_utf8(
             match self.ebpb {
@@ -419,6 +786,7 @@ impl FileSystem for FATFileSystem {
     }
 
     fn create_file(&self, path: String) -> Result<File, Error> {
+        let _volume = self.volume.lock();
         let mut path_parts = namespace::split_resource_path(path.clone());
         let file_name = path_parts.pop().unwrap();
         let mut dir = self.root_dir_iter()?;
@@ -444,6 +812,7 @@ impl FileSystem for FATFileSystem {
     }
 
     fn open_file(&self, path: String) -> Result<File, Error> {
+        let _volume = self.volume.lock();
         let mut path_parts = namespace::split_resource_path(path.clone());
         let file_name = path_parts.pop().unwrap();
         let mut dir = self.root_dir_iter()?;
@@ -474,6 +843,128 @@ impl FileSystem for FATFileSystem {
             Err(Error::EntryNotFound)
         }
     }
+
+    fn read_dir(&self, path: String) -> Result<Vec<FileInfo>, Error> {
+        let _volume = self.volume.lock();
+        let (dir, _) = self.directory(&namespace::split_resource_path(path))?;
+        Ok(dir
+            .filter(|ent| {
+                !ent.metadata.attributes.contains(FileAttributes::VOLUME_ID)
+                    && ent.name != "."
+                    && ent.name != ".."
+            })
+            .map(|ent| FileInfo {
+                status: ent.metadata.status(),
+                name: ent.name,
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: String) -> Result<os::FileStatus, Error> {
+        let _volume = self.volume.lock();
+        let path = namespace::split_resource_path(path);
+        if path.is_empty() {
+            // the root directory has no entry of its own
+            return Ok(os::FileStatus {
+                size: 0,
+                attributes: os::FileAttributes::DIRECTORY,
+                created: os::DateTime::default(),
+                modified: os::DateTime::default(),
+                accessed: os::DateTime::default(),
+            });
+        }
+        Ok(self.entry(&path)?.0.metadata.status())
+    }
+
+    fn remove(&self, path: String) -> Result<(), Error> {
+        let _volume = self.volume.lock();
+        let (ent, _) = self.entry(&namespace::split_resource_path(path))?;
+        if ent.metadata.is_directory() {
+            return Err(Error::IsDirectory);
+        }
+        // the entry goes first, so that a crash in between leaks clusters instead of cross-linking them
+        self.delete_directory_entry(&ent)?;
+        self.free_cluster_chain(ent.first_cluster())
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<(), Error> {
+        let _volume = self.volume.lock();
+        let from = namespace::split_resource_path(from);
+        let to = namespace::split_resource_path(to);
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&from) {
+            // a directory cannot be moved into itself
+            return Err(Error::InvalidData);
+        }
+        let (ent, from_parent) = self.entry(&from)?;
+        if self.exists(&to)? {
+            return Err(Error::AlreadyExists);
+        }
+        let (name, parent) = to.split_last().ok_or(Error::Permissions)?;
+        let (dir, to_parent) = self.directory(parent)?;
+        let mut new_ent = DirectoryEntry::new(name.clone(), ent.first_cluster(), ent.size());
+        let short_name = new_ent.metadata.file_name;
+        new_ent.metadata = ent.metadata;
+        new_ent.metadata.file_name = short_name;
+        self.create_directory_entry(dir.raw_iter(), new_ent)?;
+        self.delete_directory_entry(&ent)?;
+        if ent.metadata.is_directory() && from_parent != to_parent {
+            // .. is the second entry of the directory's first cluster
+            let sec = self.cluster_to_sector(ent.first_cluster());
+            let mut buf = vec![0; self.sector_size as usize];
+            self.drive.lock().read_block(sec as u64, buf.as_mut_ptr())?;
+            unsafe {
+                (buf.as_mut_ptr().offset(32) as *mut FileDirectoryEntry)
+                    .as_mut()
+                    .unwrap()
+            }
+            .set_cluster(to_parent);
+            self.drive.lock().write_block(sec as u64, buf.as_mut_slice())?;
+        }
+        Ok(())
+    }
+
+    fn create_directory(&self, path: String) -> Result<(), Error> {
+        let _volume = self.volume.lock();
+        let path = namespace::split_resource_path(path);
+        if self.exists(&path)? {
+            return Err(Error::AlreadyExists);
+        }
+        let (name, parent) = path.split_last().ok_or(Error::AlreadyExists)?;
+        let (dir, parent_cluster) = self.directory(parent)?;
+        let cluster = self.allocate_clusters(None, 0)?;
+        let mut ent = DirectoryEntry::new(name.clone(), cluster, 0);
+        ent.metadata.attributes = FileAttributes::DIRECTORY;
+        let result = self
+            .write_dot_entries(cluster, parent_cluster)
+            .and_then(|_| self.create_directory_entry(dir.raw_iter(), ent));
+        if let Err(err) = result {
+            self.free_cluster_chain(cluster)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn remove_directory(&self, path: String) -> Result<(), Error> {
+        let _volume = self.volume.lock();
+        let (ent, _) = self.entry(&namespace::split_resource_path(path))?;
+        if !ent.metadata.is_directory() {
+            return Err(Error::NotDirectory);
+        }
+        let mut contents = DirectoryIterator::new(DirectoryRawIterator::new(self, Some(ent.first_cluster()))?);
+        if contents.any(|child| child.name != "." && child.name != "..") {
+            return Err(Error::DirectoryNotEmpty);
+        }
+        self.delete_directory_entry(&ent)?;
+        self.free_cluster_chain(ent.first_cluster())
+    }
+
+    fn check(&self, repair: bool) -> Result<os::FileSystemCheck, Error> {
+        let _volume = self.volume.lock();
+        self.check_volume(repair)
+    }
 }
 
 impl namespace::Resource for FATFileSystem {
@@ -482,6 +973,6 @@ impl namespace::Resource for FATFileSystem {
     }
 
     fn resource_path(&self) -> Vec<String> {
-        vec![String::from("Files"), self.volume_label()]
+        self.mount_point.clone()
     }
 }
@@ -1,11 +1,32 @@
 use crate::*;
 use dev::*;
 use file::*;
+use infinity::os::{FileStatus, FileSystemCheck};
 
 pub mod fat;
 
+pub struct FileInfo {
+    pub name: String,
+    pub status: FileStatus,
+}
+
+// paths are relative to the root of the volume
 pub trait FileSystem: Resource {
+    // where the volume sits under /Files, decided by whoever mounts it
+    fn set_mount_point(&mut self, path: Vec<String>);
+    // writes back what is kept in memory and marks the volume clean
+    fn unmount(&mut self) -> Result<(), Error>;
     fn volume_label(&self) -> String;
     fn create_file(&self, path: String) -> Result<File, Error>;
     fn open_file(&self, path: String) -> Result<File, Error>;
-}
\ No newline at end of file
+    fn read_dir(&self, path: String) -> Result<Vec<FileInfo>, Error>;
+    fn stat(&self, path: String) -> Result<FileStatus, Error>;
+    fn remove(&self, path: String) -> Result<(), Error>;
+    // moves as well, within the volume
+    fn rename(&self, from: String, to: String) -> Result<(), Error>;
+    fn create_directory(&self, path: String) -> Result<(), Error>;
+    // fails unless the directory is empty
+    fn remove_directory(&self, path: String) -> Result<(), Error>;
+    // repairing leaves the volume consistent, possibly with some data lost
+    fn check(&self, repair: bool) -> Result<FileSystemCheck, Error>;
+}
@@ -1,4 +1,4 @@
-use crate::{*, dev::hal::mem::{self, page_mapper}};
+use crate::{*, dev::hal::mem::{self, page_mapper, frame_allocator::Zone}};
 use alloc::{vec, vec::Vec, string::String};
 use dev::{*, framebuffer::*};
 use namespace::*;
@@ -48,15 +48,8 @@ impl Device for VesaVbeFramebuffer {
     fn init_device(&mut self) -> Result<(), Error> {
         let size = self.line_length * self.height * self.bytes_per_pixel;
         let pages_needed = (size / 0x1000) + 1;
-        // this works only with frames each after one another... so init it ASAP
-        let virt_addr = unsafe { mem::FRAME_ALLOCATOR.allocate_frame() + mem::PHYSICAL_MEMORY_OFFSET };
-        //serial_println!("{:x}", virt_addr);
-        unsafe {
-            for _ in 1..pages_needed {
-                let _frm = mem::FRAME_ALLOCATOR.allocate_frame();
-                //serial_println!("{:x}", frm);
-            }
-        }
+        // the back buffer is used through the physical memory mapping, so its frames have to be contiguous
+        let virt_addr = unsafe { mem::FRAME_ALLOCATOR.allocate_frames(pages_needed, 0x1000, Zone::Normal).ok_or(Error::OutOfSpace)? + mem::PHYSICAL_MEMORY_OFFSET };
         self.ram_buffer = Some(unsafe { slice::from_raw_parts_mut(virt_addr as *mut u8, size) });
         self.ram_buffer.as_mut().unwrap().copy_from_slice(self.buffer);
         page_mapper::set_write_combining(self.buffer.as_ptr(), size);
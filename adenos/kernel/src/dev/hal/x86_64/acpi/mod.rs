@@ -1,6 +1,6 @@
 use crate::*;
 use self::tables::{RSDPHeader, ACPITable};
-use dev::{hal::{apic::{madt::*, lapic::*}, pci::*}, storage};
+use dev::{hal::{smp, apic::{madt::*, lapic::{self, *}, ioapic::{self, *}}, pci::*}, storage};
 use namespace;
 use alloc::boxed::Box;
 
@@ -17,13 +17,25 @@ pub fn init() {
     };
 
     let madt: &MADTTable = rxsdt.get_table("APIC").unwrap().into();
-    namespace::register_resource(LAPIC::new(madt.lapic_address, madt.flags & 1 > 0));
-    let ioapics = 0;
+    lapic::set_local(namespace::register_resource(LAPIC::new(madt.lapic_address, madt.flags & 1 > 0)));
     for apic in madt {
         match apic.entry_type {
-            MADTEntryType::LAPIC => {},
-            MADTEntryType::IOAPIC => {},
-            MADTEntryType::IOAPICInterruptSourceOverride => {},
+            MADTEntryType::LAPIC => {
+                let lapic_ent: &MADTEntryLAPIC = apic.into();
+                // processors that are neither enabled nor online capable are left alone
+                if lapic_ent.flags & 0b11 > 0 {
+                    smp::register_processor(lapic_ent.acpi_processor_id, lapic_ent.apic_id);
+                }
+            },
+            MADTEntryType::IOAPIC => {
+                let ioapic_ent: &MADTEntryIOAPIC = apic.into();
+                ioapic::register(namespace::register_resource(IOAPIC::new(ioapic_ent.ioapic_id, ioapic_ent.ioapic_address, ioapic_ent.global_system_interrupt_base)));
+            },
+            MADTEntryType::IOAPICInterruptSourceOverride => {
+                let over_ent: &MADTEntryIOAPICInterruptSourceOverride = apic.into();
+                let flags = over_ent.flags;
+                ioapic::register_source_override(SourceOverride::new(over_ent.irq_source, over_ent.global_system_interrupt, flags.polarity(), flags.trigger_mode()));
+            },
             MADTEntryType::IOAPICNMISource => {},
             MADTEntryType::LAPICNonMaskableInterrupts => {},
             MADTEntryType::LAPICAddressOverride => {
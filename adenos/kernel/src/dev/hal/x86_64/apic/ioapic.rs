@@ -0,0 +1,165 @@
+use dev::*;
+use alloc::{string::String, format, vec, vec::Vec};
+use crate::*;
+use core::ptr;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+// MADT flag values, 0 means conforming to the bus (active high and edge triggered for ISA)
+const POLARITY_ACTIVE_LOW: u8 = 0b11;
+const TRIGGER_MODE_LEVEL: u8 = 0b11;
+
+static mut IOAPICS: Vec<&'static mut IOAPIC> = Vec::new();
+static mut SOURCE_OVERRIDES: Vec<SourceOverride> = Vec::new();
+
+#[derive(Copy, Clone, Debug)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub global_system_interrupt: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl SourceOverride {
+    pub fn new(irq: u8, global_system_interrupt: u32, polarity: u8, trigger_mode: u8) -> SourceOverride {
+        SourceOverride {
+            irq,
+            global_system_interrupt,
+            active_low: polarity == POLARITY_ACTIVE_LOW,
+            level_triggered: trigger_mode == TRIGGER_MODE_LEVEL,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IOAPIC {
+    id: u8,
+    registers: u64,
+    global_system_interrupt_base: u32,
+    redirection_entries: u32,
+}
+
+impl IOAPIC {
+    pub fn new(id: u8, base_address: u32, global_system_interrupt_base: u32) -> IOAPIC {
+        let mut ioapic = IOAPIC {
+            id,
+            registers: super::map_registers(base_address as u64),
+            global_system_interrupt_base,
+            redirection_entries: 0,
+        };
+        ioapic.redirection_entries = ((ioapic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        // nothing gets through until a driver asks for its line
+        ioapic.mask_all();
+        ioapic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.registers + IOREGSEL) as *mut u32, register);
+            ptr::read_volatile((self.registers + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.registers + IOREGSEL) as *mut u32, register);
+            ptr::write_volatile((self.registers + IOWIN) as *mut u32, value);
+        }
+    }
+
+    pub fn handles(&self, global_system_interrupt: u32) -> bool {
+        global_system_interrupt >= self.global_system_interrupt_base && global_system_interrupt < self.global_system_interrupt_base + self.redirection_entries
+    }
+
+    fn redirection_register(&self, global_system_interrupt: u32) -> u32 {
+        IOAPIC_REDIRECTION_TABLE + (global_system_interrupt - self.global_system_interrupt_base) * 2
+    }
+
+    pub fn set_redirection(&mut self, global_system_interrupt: u32, vector: u8, lapic_id: u8, active_low: bool, level_triggered: bool) {
+        let register = self.redirection_register(global_system_interrupt);
+        let mut low = vector as u32;
+        if active_low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level_triggered {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        // mask while the entry is half written
+        self.write(register, REDIRECTION_MASKED);
+        self.write(register + 1, (lapic_id as u32) << 24);
+        self.write(register, low);
+    }
+
+    pub fn set_masked(&mut self, global_system_interrupt: u32, masked: bool) {
+        let register = self.redirection_register(global_system_interrupt);
+        let low = self.read(register);
+        self.write(register, if masked { low | REDIRECTION_MASKED } else { low & !REDIRECTION_MASKED });
+    }
+
+    pub fn mask_all(&mut self) {
+        for gsi in self.global_system_interrupt_base..self.global_system_interrupt_base + self.redirection_entries {
+            self.set_masked(gsi, true);
+        }
+    }
+}
+
+impl Device for IOAPIC {
+    fn init_device(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn deinit_device(&mut self) -> Result<(), Error> {
+        self.mask_all();
+        Ok(())
+    }
+
+    fn device_path(&self) -> Vec<String> {
+        vec![String::from("System"), format!("IOAPIC{}", self.id)]
+    }
+
+    fn unwrap(&mut self) -> DeviceClass {
+        DeviceClass::Other
+    }
+}
+
+pub fn register(ioapic: &'static mut IOAPIC) {
+    unsafe { IOAPICS.push(ioapic); }
+}
+
+pub fn register_source_override(source_override: SourceOverride) {
+    unsafe { SOURCE_OVERRIDES.push(source_override); }
+}
+
+pub fn available() -> bool {
+    unsafe { !IOAPICS.is_empty() }
+}
+
+fn ioapic_for(global_system_interrupt: u32) -> Result<&'static mut IOAPIC, Error> {
+    unsafe { IOAPICS.iter_mut().find(|ioapic| ioapic.handles(global_system_interrupt)).map(|ioapic| &mut **ioapic).ok_or(Error::InvalidDevice) }
+}
+
+// ISA interrupts are identity mapped to global system interrupts unless the MADT says otherwise
+pub fn isa_override(irq: u8) -> SourceOverride {
+    unsafe { SOURCE_OVERRIDES.iter().find(|over| over.irq == irq).copied() }
+        .unwrap_or(SourceOverride { irq, global_system_interrupt: irq as u32, active_low: false, level_triggered: false })
+}
+
+pub fn route_isa_irq(irq: u8, vector: u8, lapic_id: u8) -> Result<(), Error> {
+    let over = isa_override(irq);
+    ioapic_for(over.global_system_interrupt)?.set_redirection(over.global_system_interrupt, vector, lapic_id, over.active_low, over.level_triggered);
+    Ok(())
+}
+
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> Result<(), Error> {
+    let over = isa_override(irq);
+    ioapic_for(over.global_system_interrupt)?.set_masked(over.global_system_interrupt, masked);
+    Ok(())
+}
@@ -1,15 +1,34 @@
 #![allow(unaligned_references)]
 use dev::*;
 use alloc::{string::String, vec, vec::Vec};
-use x86_64::structures::paging::PageTableFlags;
 use modular_bitfield::{bitfield, specifiers::*};
 use namespace::*;
-use crate::{*, dev::hal::{mem::{self, page_mapper}}};
-use core::arch::asm;
+use crate::{*, dev::hal::pic};
+use core::{arch::asm, ptr, sync::atomic::{AtomicU32, Ordering}};
 
 const IA32_APIC_BASE_MSR: u32 = 0x1B;
 const IA32_APIC_BASE_MSR_ENABLE: u32 = 0b100000000000;
 
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+// microseconds the PIT is used for when measuring the timer
+const TIMER_CALIBRATION_TIME: u64 = 10_000;
+
+// all local APICs run off the same bus clock, so one calibration is enough
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+static mut LOCAL_APIC: Option<&'static mut LAPIC> = None;
+
+pub fn set_local(lapic: &'static mut LAPIC) {
+    unsafe { LOCAL_APIC = Some(lapic); }
+}
+
+// every processor sees its own local APIC at the same address
+pub fn local() -> Option<&'static mut LAPIC> {
+    unsafe { LOCAL_APIC.as_deref_mut() }
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 struct LAPICRegisters {
@@ -146,43 +165,130 @@ pub struct LAPIC {
 impl LAPIC {
     pub fn new(base_address: u32, disable_pic_on_init: bool) -> LAPIC {
         LAPIC {
-            registers: unsafe { (base_address as u64 + mem::PHYSICAL_MEMORY_OFFSET) as *mut LAPICRegisters },
+            registers: super::map_registers(base_address as u64) as *mut LAPICRegisters,
             disable_pic_on_init
         }
     }
 
-    fn send_interrupt(&mut self, int: LAPICInterrupt) {
+    // the 8259s are still wired up and have to be masked when switching over
+    pub fn has_legacy_pic(&self) -> bool {
+        self.disable_pic_on_init
+    }
+
+    pub fn id(&self) -> u8 {
+        unsafe { (ptr::read_volatile(ptr::addr_of!((*self.registers).lapic_id)) >> 24) as u8 }
+    }
+
+    pub fn enable(&mut self) {
+        let mut rax: u32;
+        let mut rdx: u32;
+        unsafe {
+            asm!("rdmsr", in("rcx") IA32_APIC_BASE_MSR, out("rax") rax, out("rdx") rdx);
+            rax |= IA32_APIC_BASE_MSR_ENABLE;
+            asm!("wrmsr", in("rcx") IA32_APIC_BASE_MSR, in("rax") rax, in("rdx") rdx);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).task_priority), 0);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).spurious_interrupt_vector), 0b00000000000000000000000111111111);
+        }
+    }
+
+    pub fn calibrate_timer(&mut self) -> Result<(), Error> {
+        unsafe {
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).timer_divide_configuration), TIMER_DIVIDE_BY_16);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).lvt_timer), TIMER_MASKED);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).timer_initial_count), u32::MAX);
+            pic::busy_wait(TIMER_CALIBRATION_TIME);
+            let remaining = ptr::read_volatile(ptr::addr_of!((*self.registers).timer_current_count));
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).timer_initial_count), 0);
+            let ticks_per_ms = (u32::MAX - remaining) as u64 * 1000 / TIMER_CALIBRATION_TIME;
+            if ticks_per_ms == 0 {
+                return Err(Error::InitFailure);
+            }
+            TIMER_TICKS_PER_MS.store(ticks_per_ms as u32, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn timer_count(microseconds: u64) -> Result<u32, Error> {
+        let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Relaxed) as u64;
+        if ticks_per_ms == 0 {
+            return Err(Error::InitFailure);
+        }
+        Ok((ticks_per_ms * microseconds / 1000).clamp(1, u32::MAX as u64) as u32)
+    }
+
+    fn start_timer(&mut self, vector: u8, microseconds: u64, mode: u32) -> Result<(), Error> {
+        let count = Self::timer_count(microseconds)?;
+        unsafe {
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).timer_divide_configuration), TIMER_DIVIDE_BY_16);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).lvt_timer), vector as u32 | mode);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).timer_initial_count), count);
+        }
+        Ok(())
+    }
+
+    // fires every period until stopped
+    pub fn start_periodic_timer(&mut self, vector: u8, microseconds: u64) -> Result<(), Error> {
+        self.start_timer(vector, microseconds, TIMER_PERIODIC)
+    }
+
+    // fires once
+    pub fn start_oneshot_timer(&mut self, vector: u8, microseconds: u64) -> Result<(), Error> {
+        self.start_timer(vector, microseconds, 0)
+    }
+
+    pub fn stop_timer(&mut self) {
+        unsafe {
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).lvt_timer), TIMER_MASKED);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).timer_initial_count), 0);
+        }
+    }
+
+    pub fn end_of_interrupt(&mut self) {
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*self.registers).eoi_register), 0); }
+    }
+
+    pub fn send_init(&mut self, lapic_id: u8) {
+        let int = LAPICInterrupt::new()
+            .with_delivery_mode(DeliveryMode::Init as u8)
+            .with_level(true);
+        self.send_interrupt(lapic_id, int);
+    }
+
+    pub fn send_startup(&mut self, lapic_id: u8, page: u8) {
+        let int = LAPICInterrupt::new()
+            .with_vector(page)
+            .with_delivery_mode(DeliveryMode::StartUp as u8)
+            .with_level(true);
+        self.send_interrupt(lapic_id, int);
+    }
+
+    pub fn send_fixed(&mut self, lapic_id: u8, vector: u8) {
+        let int = LAPICInterrupt::new()
+            .with_vector(vector)
+            .with_delivery_mode(DeliveryMode::Fixed as u8)
+            .with_level(true);
+        self.send_interrupt(lapic_id, int);
+    }
+
+    fn send_interrupt(&mut self, lapic_id: u8, int: LAPICInterrupt) {
         let un = LAPICInterruptUnion {
             int,
         };
         unsafe {
-            let (low, high) = un.data;
-            (*self.registers).interrupt_command_high = high;
-            (*self.registers).interrupt_command_low = low;
+            let (low, _) = un.data;
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).interrupt_command_high), (lapic_id as u32) << 24);
+            ptr::write_volatile(ptr::addr_of_mut!((*self.registers).interrupt_command_low), low);
+            // wait for the delivery status bit to clear
+            while ptr::read_volatile(ptr::addr_of!((*self.registers).interrupt_command_low)) & (1 << 12) != 0 {
+                core::hint::spin_loop();
+            }
         }
     }
 }
 
 impl Device for LAPIC {
     fn init_device(&mut self) -> Result<(), Error> {
-        return Ok(());
-        if self.disable_pic_on_init {
-            //pic::deinit();
-        }
-        let mut rax: u32;
-        let mut rdx: u32;
-        unsafe {
-            let phys_addr = self.registers as u64 - mem::PHYSICAL_MEMORY_OFFSET;
-            let virt_addr = 0x90000000;
-            // map to strong UC memory
-            page_mapper::map_addr(page_mapper::get_l4_table(), virt_addr, phys_addr, Some(PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE));
-            self.registers = virt_addr as *mut LAPICRegisters;
-            asm!("rdmsr", in("rcx") IA32_APIC_BASE_MSR, out("rax") rax, out("rdx") rdx);
-            rax |= IA32_APIC_BASE_MSR_ENABLE;
-            asm!("wrmsr", in("rcx") IA32_APIC_BASE_MSR, in("rax") rax, in("rdx") rdx);
-            (*self.registers).task_priority = 0;
-            (*self.registers).spurious_interrupt_vector = 0b00000000000000000000000111111111;
-        }
+        self.enable();
         Ok(())
     }
 
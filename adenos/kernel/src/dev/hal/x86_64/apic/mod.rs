@@ -1,2 +1,16 @@
+use crate::*;
+use dev::hal::mem::{self, page_mapper};
+use x86_64::structures::paging::PageTableFlags;
+
 pub mod lapic;
-pub mod madt;
\ No newline at end of file
+pub mod ioapic;
+pub mod madt;
+
+// the physical memory mapping does not necessarily reach up to the APIC registers
+pub fn map_registers(base_address: u64) -> u64 {
+    let virt_addr = unsafe { base_address + mem::PHYSICAL_MEMORY_OFFSET };
+    if page_mapper::translate_addr(virt_addr as usize).is_none() {
+        page_mapper::map_addr(page_mapper::get_l4_table(), virt_addr, base_address, Some(PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE));
+    }
+    virt_addr
+}
@@ -1,8 +1,7 @@
 use crate::*;
-use dev::hal::{interrupts, pic, task};
+use dev::hal::{interrupts, task, smp};
 use x86_64::registers;
 use x86_64::structures::gdt::SegmentSelector;
-use x86_64::structures::idt::InterruptStackFrame;
 use crate::exec::scheduler;
 use x86_64;
 use x86_64::PrivilegeLevel;
@@ -17,14 +16,19 @@ use x86_64::instructions::tables;
 use x86_64::instructions::segmentation;
 use x86_64::registers::{model_specific::{Efer, Star}};
 use x86_64::instructions::segmentation::Segment;
+use x86_64::instructions::random::RdRand;
 use lazy_static::lazy_static;
 use core::arch::asm;
-use alloc::{alloc::{alloc, dealloc, Layout}};
+use alloc::{vec, boxed::Box, alloc::{alloc, dealloc, Layout}};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use raw_cpuid::{CpuId, CpuIdResult};
 
 const INTERRUPT_IST_INDEX: u16 = 0;
 const SCHEDULER_INTERRUPT_IST_INDEX: u16 = 0;
 
+// whether page table entries may carry the no-execute bit
+pub static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
 #[derive(Copy, Clone)]
 struct Selectors {
     kernel_code_selector: gdt::SegmentSelector,
@@ -38,7 +42,11 @@ static mut IDT: idt::InterruptDescriptorTable = idt::InterruptDescriptorTable::n
 static mut INTERRUPT_STACK: [u8; 0x4000] = [0; 0x4000];
 static mut SCHEDULER_INTERRUPT_STACK: [u8; 0x4000] = [0; 0x4000];
 
-pub static mut DO_CONTEXT_SWITCH_NEXT_TIME: bool = false;
+const PREEMPTIBLE: AtomicUsize = AtomicUsize::new(0);
+const NOTHING_PENDING: AtomicBool = AtomicBool::new(false);
+// per cpu, how many atomic_no_preempt calls deep it is, and whether a tick wanted to switch in the meantime
+static PREEMPT_DISABLED: [AtomicUsize; smp::MAX_PROCESSORS] = [PREEMPTIBLE; smp::MAX_PROCESSORS];
+static PREEMPT_PENDING: [AtomicBool; smp::MAX_PROCESSORS] = [NOTHING_PENDING; smp::MAX_PROCESSORS];
 
 lazy_static! {
     #[derive(Debug)]
@@ -82,16 +90,8 @@ pub fn init() {
     unsafe {
         segmentation::CS::set_reg(GDT.1.kernel_code_selector);
         tables::load_tss(GDT.1.tss_selector);
-        let handler_addr = system_call_trap_handler as *const () as u64;
-        asm!("\
-        xor rdx, rdx
-        mov rax, 0x200
-        wrmsr",
-        in("rcx") 0xc0000084 as u32);
-        LStar::write(VirtAddr::new(handler_addr));
-        Star::write(GDT.1.user_code_selector, GDT.1.user_data_selector, GDT.1.kernel_code_selector, GDT.1.kernel_data_selector).expect("GDT_CONFIG_FAILURE");
-        Efer::write(Efer::read() | EferFlags::SYSTEM_CALL_EXTENSIONS);
     }
+    init_system_calls(&GDT.1);
 
     unsafe {
         IDT.breakpoint.set_handler_fn(interrupts::breakpoint::breakpoint_handler).set_stack_index(INTERRUPT_IST_INDEX);
@@ -105,6 +105,28 @@ pub fn init() {
         IDT.load();
     }
 
+    init_pat();
+}
+
+fn init_system_calls(selectors: &Selectors) {
+    unsafe {
+        let handler_addr = system_call_trap_handler as *const () as u64;
+        asm!("\
+        xor rdx, rdx
+        mov rax, 0x200
+        wrmsr",
+        in("rcx") 0xc0000084 as u32);
+        LStar::write(VirtAddr::new(handler_addr));
+        Star::write(selectors.user_code_selector, selectors.user_data_selector, selectors.kernel_code_selector, selectors.kernel_data_selector).expect("GDT_CONFIG_FAILURE");
+        Efer::write(Efer::read() | EferFlags::SYSTEM_CALL_EXTENSIONS);
+        if cpuid().get_extended_processor_and_feature_identifiers().map_or(false, |features| features.has_execute_disable()) {
+            Efer::write(Efer::read() | EferFlags::NO_EXECUTE_ENABLE);
+            NO_EXECUTE.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn init_pat() {
     // set up write combining (PAT field PA7)
     let pat_mask: u64 = 1 << 56;
     let pat_antimask: u64 = !(3 << 57);
@@ -113,6 +135,31 @@ pub fn init() {
     unsafe { pat_msr.write(pat_val) };
 }
 
+// application processors share the IDT but need their own TSS and interrupt stack, the selectors stay the same
+pub fn init_application_processor() {
+    let stack: &'static mut [u8] = vec![0u8; 0x4000].leak();
+    let mut tss = tss::TaskStateSegment::new();
+    tss.interrupt_stack_table[INTERRUPT_IST_INDEX as usize] = VirtAddr::new(stack.as_ptr() as u64 + 0x4000);
+    let tss: &'static tss::TaskStateSegment = Box::leak(Box::new(tss));
+    let gdt: &'static mut gdt::GlobalDescriptorTable = Box::leak(Box::new(gdt::GlobalDescriptorTable::new()));
+    let selectors = Selectors {
+        kernel_code_selector: gdt.add_entry(gdt::Descriptor::kernel_code_segment()),
+        kernel_data_selector: gdt.add_entry(gdt::Descriptor::kernel_data_segment()),
+        tss_selector: gdt.add_entry(gdt::Descriptor::tss_segment(tss)),
+        user_data_selector: gdt.add_entry(gdt::Descriptor::user_data_segment()),
+        user_code_selector: gdt.add_entry(gdt::Descriptor::user_code_segment()),
+    };
+    gdt.load();
+    unsafe {
+        segmentation::CS::set_reg(selectors.kernel_code_selector);
+        segmentation::SS::set_reg(selectors.kernel_data_selector);
+        tables::load_tss(selectors.tss_selector);
+        IDT.load();
+    }
+    init_system_calls(&selectors);
+    init_pat();
+}
+
 pub fn cpuid() -> CpuId {
     CpuId::with_cpuid_fn(|a, c| {
         let result = unsafe { core::arch::x86_64::__cpuid_count(a, c) };
@@ -129,6 +176,7 @@ pub fn enable_scheduler() {
     disable_interrupts();
     unsafe {
         IDT[interrupts::HardwareInterrupt::Timer.as_usize()].set_handler_addr(VirtAddr::new(task::timer_handler_save_context as u64)).set_stack_index(SCHEDULER_INTERRUPT_IST_INDEX);
+        smp::release_application_processors();
         scheduler::context_switch(None, false);
     }
 }
@@ -171,7 +219,7 @@ unsafe extern "C" fn system_call_trap_handler() {
     pop r8       // arg3
     mov rsp, rax // set syscall stack
     push r9 // save user stack
-    call system_call
+    call user_system_call // user stack is the sixth argument
     pop r9  // user stack
     sub rsp, 0x2000
     mov rdi, rsp // argument to drop_syscall_stack
@@ -195,27 +243,32 @@ pub fn trigger_breakpoint() {
     instructions::interrupts::int3();
 }
 
-unsafe extern "x86-interrupt" fn flag_preempt(_stack_frame: InterruptStackFrame) {
-    DO_CONTEXT_SWITCH_NEXT_TIME = true;
-    pic::end_of_interrupt(interrupts::HardwareInterrupt::Timer);
-}
-
+// interrupts keep coming, only the switch a tick would cause waits until f is done, and only on this cpu
 pub fn atomic_no_preempt<F>(f: F)
 where F: FnOnce() {
-    unsafe {
-        asm!("cli");
-        IDT[interrupts::HardwareInterrupt::Timer.as_usize()].set_handler_addr(VirtAddr::new(flag_preempt as u64));
-        asm!("sti");
+    let enabled = intflag();
+    disable_interrupts();
+    // the thread cannot move to another cpu from here on
+    let cpu = smp::current_cpu();
+    PREEMPT_DISABLED[cpu].fetch_add(1, Ordering::Relaxed);
+    if enabled {
+        enable_interrupts();
     }
     f();
-    unsafe {
-        asm!("cli");
-        IDT[interrupts::HardwareInterrupt::Timer.as_usize()].set_handler_addr(VirtAddr::new(task::timer_handler_save_context as u64)).set_stack_index(SCHEDULER_INTERRUPT_IST_INDEX);
-        asm!("sti");
-        while DO_CONTEXT_SWITCH_NEXT_TIME {}
+    if PREEMPT_DISABLED[cpu].fetch_sub(1, Ordering::Relaxed) == 1 && PREEMPT_PENDING[cpu].swap(false, Ordering::Relaxed) {
+        task::trigger_context_switch();
     }
 }
 
+// called by the tick handlers, true means the tick is remembered and the current thread goes on
+pub fn defer_preemption(cpu: usize) -> bool {
+    if PREEMPT_DISABLED[cpu].load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    PREEMPT_PENDING[cpu].store(true, Ordering::Relaxed);
+    true
+}
+
 pub fn atomic_no_interrupts<F>(f: F)
 where F: FnOnce() {
     let flags = rflags::read();
@@ -251,9 +304,32 @@ pub fn disable_interrupts() {
     unsafe { asm!("cli"); }
 }
 
+pub fn register_vector_handler(vector: u8, handler: extern "x86-interrupt" fn(idt::InterruptStackFrame)) {
+    unsafe {
+        IDT[vector as usize].set_handler_fn(handler).set_stack_index(INTERRUPT_IST_INDEX);
+    }
+}
+
+pub fn register_vector_handler_addr(vector: u8, handler: u64) {
+    unsafe {
+        IDT[vector as usize].set_handler_addr(VirtAddr::new(handler)).set_stack_index(SCHEDULER_INTERRUPT_IST_INDEX);
+    }
+}
+
 pub fn register_interrupt_handler(int: interrupts::HardwareInterrupt, handler: extern "x86-interrupt" fn(idt::InterruptStackFrame)) {
     unsafe {
         IDT[int.as_usize()].set_handler_fn(handler).set_stack_index(INTERRUPT_IST_INDEX);
         IDT.load();
     }
 }
+
+// falls back to the time stamp counter without rdrand, good enough to keep load addresses apart but not for secrets
+pub fn random() -> u64 {
+    if let Some(value) = RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        return value;
+    }
+    let mut z = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
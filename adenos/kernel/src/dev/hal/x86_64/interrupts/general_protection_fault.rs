@@ -5,6 +5,9 @@ use core::arch::asm;
 use super::*;
 
 pub extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: idt::InterruptStackFrame, error_code: u64) {
+    if is_user_fault(&stack_frame) {
+        kill_faulting_process(Exception::EXCEPTION_GENERAL_PROTECTION_FAULT, &stack_frame, format_args!("error code {:#010x}", error_code));
+    }
     unsafe {
         asm!("add rsp, 24
         pop rsp");
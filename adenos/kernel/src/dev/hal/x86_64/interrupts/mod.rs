@@ -7,7 +7,22 @@ pub mod stack_segment_fault;
 pub mod segment_not_present;
 pub mod page_fault;
 
-use super::pic;
+use crate::*;
+use super::{pic, cpu, task, apic::{lapic, ioapic}};
+use exec::scheduler;
+use x86_64::structures::idt;
+use core::fmt;
+
+// microseconds between timer interrupts, the same rate the PIT is programmed for
+pub const TIMER_PERIOD: u64 = 1000;
+
+static mut CONTROLLER: InterruptController = InterruptController::PIC;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptController {
+    PIC,
+    APIC,
+}
 
 #[derive(Debug)]
 #[allow(non_camel_case_types)]
@@ -37,4 +52,100 @@ impl HardwareInterrupt {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    // the ISA line it comes in on
+    pub fn irq(self) -> u8 {
+        self.as_u8() - pic::PIC_MASTER_OFFSET
+    }
+}
+
+// the exception came from ring 3
+pub fn is_user_fault(stack_frame: &idt::InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+// the process that caused it goes away, the kernel and everyone else carry on
+pub fn kill_faulting_process(exception: Exception, stack_frame: &idt::InterruptStackFrame, detail: fmt::Arguments) -> ! {
+    let pid = scheduler::current_process();
+    println!("Process {} killed: {:?} ({}) at {:#x}", pid, exception, detail, stack_frame.instruction_pointer.as_u64());
+    scheduler::exit_process(pid, scheduler::EXIT_CODE_FAULT);
+    // only reached if the process was already on its way out
+    loop {
+        task::trigger_context_switch();
+    }
+}
+
+pub fn controller() -> InterruptController {
+    unsafe { CONTROLLER }
+}
+
+// moves from the 8259s to the local APIC timer and the IO-APICs, if the firmware reported both
+pub fn init_apic() -> Result<(), Error> {
+    let lapic = lapic::local().ok_or(Error::DriverNotFound)?;
+    if !ioapic::available() {
+        return Err(Error::DriverNotFound);
+    }
+    lapic.enable();
+    lapic.calibrate_timer()?;
+    let flags_pic = lapic.has_legacy_pic();
+    let mut result = Ok(());
+    cpu::atomic_no_interrupts(|| {
+        if flags_pic {
+            pic::deinit();
+        }
+        unsafe { CONTROLLER = InterruptController::APIC; }
+        result = lapic.start_periodic_timer(HardwareInterrupt::Timer.as_u8(), TIMER_PERIOD);
+    });
+    result
+}
+
+// starts the timer on an application processor, with the PIC they only get ticks forwarded from the bootstrap processor
+pub fn init_application_processor() -> Result<(), Error> {
+    if controller() == InterruptController::APIC {
+        if let Some(lapic) = lapic::local() {
+            lapic.start_periodic_timer(HardwareInterrupt::Timer.as_u8(), TIMER_PERIOD)?;
+        }
+    }
+    Ok(())
+}
+
+// installs the handler and lets the line through on whichever controller is active
+pub fn request_irq(int: HardwareInterrupt, handler: extern "x86-interrupt" fn(idt::InterruptStackFrame)) -> Result<(), Error> {
+    cpu::register_interrupt_handler(int, handler);
+    match controller() {
+        InterruptController::PIC => pic::unmask(int.irq()),
+        InterruptController::APIC => {
+            // the local APIC timer takes the place of the PIT
+            if let HardwareInterrupt::Timer = int {
+                return Ok(());
+            }
+            let lapic = lapic::local().ok_or(Error::DriverNotFound)?;
+            ioapic::route_isa_irq(int.irq(), int.as_u8(), lapic.id())?;
+        },
+    }
+    Ok(())
+}
+
+pub fn set_masked(int: HardwareInterrupt, masked: bool) -> Result<(), Error> {
+    match controller() {
+        InterruptController::PIC => if masked {
+            pic::mask(int.irq());
+        } else {
+            pic::unmask(int.irq());
+        },
+        InterruptController::APIC => match int {
+            HardwareInterrupt::Timer => {},
+            _ => ioapic::set_isa_irq_masked(int.irq(), masked)?,
+        },
+    }
+    Ok(())
+}
+
+pub fn end_of_interrupt(int: HardwareInterrupt) {
+    match controller() {
+        InterruptController::PIC => pic::end_of_interrupt(int),
+        InterruptController::APIC => if let Some(lapic) = lapic::local() {
+            lapic.end_of_interrupt();
+        },
+    }
 }
\ No newline at end of file
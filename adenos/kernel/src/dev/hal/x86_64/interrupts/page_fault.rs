@@ -2,8 +2,20 @@ use crate::*;
 use panic;
 use x86_64::structures::idt;
 use x86_64::registers::control::Cr2;
+use dev::hal::mem::vma;
 use super::*;
 
 pub extern "x86-interrupt" fn page_fault_handler(stack_frame: idt::InterruptStackFrame, error_code: idt::PageFaultErrorCode) {
-    panic!("\n{:?}\nError code: {:?}\nAccessed address: {:?}\nStack frame: {:#?})", Exception::EXCEPTION_PAGE_FAULT, error_code, Cr2::read(), stack_frame);
-}
\ No newline at end of file
+    let addr = Cr2::read();
+    // a page that was never touched or a write to a copy-on-write page, the access is retried once it is mapped
+    let write = error_code.contains(idt::PageFaultErrorCode::CAUSED_BY_WRITE);
+    if write || !error_code.contains(idt::PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if let Ok(()) = vma::handle_page_fault(addr.as_u64(), write) {
+            return;
+        }
+    }
+    if is_user_fault(&stack_frame) {
+        kill_faulting_process(Exception::EXCEPTION_PAGE_FAULT, &stack_frame, format_args!("{:?} accessing {:#x}", error_code, addr.as_u64()));
+    }
+    panic!("\n{:?}\nError code: {:?}\nAccessed address: {:?}\nStack frame: {:#?})", Exception::EXCEPTION_PAGE_FAULT, error_code, addr, stack_frame);
+}
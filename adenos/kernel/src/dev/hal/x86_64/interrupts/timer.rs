@@ -1,7 +1,7 @@
 use crate::*;
-use dev::hal::pic;
+use dev::hal::interrupts;
 use x86_64::structures::idt;
 
 pub extern "x86-interrupt" fn timer_handler(_stack_frame: idt::InterruptStackFrame) {
-    pic::end_of_interrupt(dev::hal::interrupts::HardwareInterrupt::Timer);
+    interrupts::end_of_interrupt(interrupts::HardwareInterrupt::Timer);
 }
@@ -1,160 +1,343 @@
 use crate::*;
 use dev::hal::mem;
 use bootloader::boot_info;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use alloc::collections::BTreeMap;
 
-pub struct BitmapFrameAllocator {
-    bitmap: *mut u8,
+pub const FRAME_SIZE: u64 = 0x1000;
+// blocks of up to 4 MiB, enough for a huge page and anything a driver asks for
+pub const MAX_ORDER: usize = 10;
+pub const HUGE_PAGE_ORDER: usize = 9;
+pub const HUGE_PAGE_SIZE: u64 = FRAME_SIZE << HUGE_PAGE_ORDER;
+// devices that can only address 32 bits get their memory from below this
+pub const DMA32_LIMIT: u64 = 0x1_0000_0000;
+
+const ZONE_COUNT: usize = 2;
+const NO_FRAME: u64 = u64::MAX;
+
+// one byte of state per frame, the first frame of a free block also holds its order
+const FRAME_RESERVED: u8 = 0xFF;
+const FRAME_USED: u8 = 0xFE;
+const FRAME_FREE: u8 = 0x80;
+const FRAME_FREE_TAIL: u8 = 0x40;
+
+// every cpu allocates from the same free lists
+static FRAME_ALLOCATOR_LOCK: Mutex<()> = Mutex::new(());
+// extra references to frames mapped copy-on-write by several processes, kept apart from the allocator lock
+// because growing the kernel heap allocates frames
+static SHARED_FRAMES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Zone {
+    // below DMA32_LIMIT
+    DMA32,
+    // anywhere, memory above DMA32_LIMIT is used first
+    Normal,
+}
+
+impl Zone {
+    fn of(frame: u64) -> usize {
+        if frame < DMA32_LIMIT {
+            0
+        } else {
+            1
+        }
+    }
+
+    // the zones an allocation may come from, in the order they are tried
+    fn candidates(&self) -> &'static [usize] {
+        match self {
+            Zone::DMA32 => &[0],
+            Zone::Normal => &[1, 0],
+        }
+    }
+}
+
+// the links of a free list live in the free blocks themselves
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+// buddy allocator over every usable region of the boot memory map, split into a DMA32 and a normal zone
+pub struct BuddyFrameAllocator {
+    frames: *mut u8,
+    number_of_frames: usize,
+    free_lists: [[u64; MAX_ORDER + 1]; ZONE_COUNT],
     free_pages: usize,
     used_pages: usize,
-    number_of_pages: usize,
-    next_free: usize,
-    first_usable_address: u64,
 }
 
-impl BitmapFrameAllocator {
-    pub const fn new_uninit() -> BitmapFrameAllocator {
-        BitmapFrameAllocator {
-            bitmap: 0 as *mut u8,
+impl BuddyFrameAllocator {
+    pub const fn new_uninit() -> BuddyFrameAllocator {
+        BuddyFrameAllocator {
+            frames: 0 as *mut u8,
+            number_of_frames: 0,
+            free_lists: [[NO_FRAME; MAX_ORDER + 1]; ZONE_COUNT],
             free_pages: 0,
             used_pages: 0,
-            number_of_pages: 0,
-            next_free: 0,
-            first_usable_address: 0,
         }
     }
 
-    pub fn new() -> BitmapFrameAllocator {
-        let available_pages = unsafe { mem::FREE_MEMORY / 0x1000 };
-        let mut new = BitmapFrameAllocator {
-            bitmap: 0 as *mut u8,
-            free_pages: available_pages,
-            used_pages: 0,
-            number_of_pages: available_pages,
-            next_free: 0,
-            first_usable_address: 0,
-        };
-        new.allocate_bitmap(available_pages);
+    pub fn new() -> BuddyFrameAllocator {
+        let mut new = BuddyFrameAllocator::new_uninit();
+        let usable_regions = || unsafe { mem::BOOT_MEMORY_MAP.unwrap().iter() }.filter(|r| r.kind == boot_info::MemoryRegionKind::Usable);
+        let highest_address = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        new.number_of_frames = (highest_address / FRAME_SIZE) as usize;
+        // the frame states go into the first region big enough to hold them
+        let state_size = Self::align_up(new.number_of_frames as u64, FRAME_SIZE);
+        let state_start = usable_regions()
+            .map(|r| (Self::align_up(r.start, FRAME_SIZE), r.end))
+            .find(|(start, end)| start + state_size <= *end)
+            .map(|(start, _)| start)
+            .unwrap_or_else(|| panic!("NOT_ENOUGH_MEMORY"));
+        new.frames = (state_start + unsafe { mem::PHYSICAL_MEMORY_OFFSET }) as *mut u8;
+        for i in 0..new.number_of_frames as u64 {
+            new.set_state(i * FRAME_SIZE, FRAME_RESERVED);
+        }
+        for region in usable_regions() {
+            let start = Self::align_up(region.start, FRAME_SIZE);
+            let end = region.end / FRAME_SIZE * FRAME_SIZE;
+            // skip the frame states themselves
+            if start < state_start + state_size && end > state_start {
+                new.add_range(start, state_start);
+                new.add_range(state_start + state_size, end);
+            } else {
+                new.add_range(start, end);
+            }
+        }
         new
     }
 
-    fn allocate_bitmap(&mut self, size: usize) {
-        let bytes = (size / 8) as u64;
-        let frames_needed = (bytes / 0x1000 + 1) as u64;
+    fn add_range(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        self.free_pages += ((end - start) / FRAME_SIZE) as usize;
+        self.release_range(start, end);
+    }
+
+    #[inline(always)]
+    fn align_up(value: u64, align: u64) -> u64 {
+        (value + align - 1) / align * align
+    }
+
+    #[inline(always)]
+    fn state(&self, frame: u64) -> u8 {
+        unsafe { *self.frames.add((frame / FRAME_SIZE) as usize) }
+    }
 
-        let regions = unsafe { mem::BOOT_MEMORY_MAP.unwrap().iter() };
-        let usable_regions = regions
-        .filter(|r| r.kind == boot_info::MemoryRegionKind::Usable);
+    #[inline(always)]
+    fn set_state(&mut self, frame: u64, state: u8) {
+        unsafe { *self.frames.add((frame / FRAME_SIZE) as usize) = state; }
+    }
 
-        let mut alloc_first_phys = 0;
-        let mut alloc_last_phys = 0;
-        let mut found_mem = false;
+    #[inline(always)]
+    fn block(frame: u64) -> &'static mut FreeBlock {
+        unsafe { &mut *((frame + mem::PHYSICAL_MEMORY_OFFSET) as *mut FreeBlock) }
+    }
 
-        for reg in usable_regions {
-            if reg.end - reg.start >= frames_needed {
-                alloc_first_phys = reg.start;
-                alloc_last_phys = ((alloc_first_phys + bytes) / 0x1000 + 1) * 0x1000;
-                found_mem = true;
-                break;
-            }
-        }
-        if !found_mem {
-            panic!("NOT_ENOUGH_MEMORY");
+    fn is_free_block(&self, frame: u64, order: usize) -> bool {
+        frame / FRAME_SIZE < self.number_of_frames as u64 && self.state(frame) == FRAME_FREE | order as u8
+    }
+
+    fn link(&mut self, frame: u64, order: usize) {
+        let list = &mut self.free_lists[Zone::of(frame)][order];
+        let block = Self::block(frame);
+        block.next = *list;
+        block.prev = NO_FRAME;
+        if *list != NO_FRAME {
+            Self::block(*list).prev = frame;
         }
-        //serial_println!("Bitmap size: {}\nStart address: {:x}\nEnd address: {:x}", alloc_last_phys - alloc_first_phys, alloc_first_phys, alloc_last_phys);
-        // clear bitmap memory
-        let (alloc_first_virt, alloc_last_virt) = (alloc_first_phys + unsafe { mem::PHYSICAL_MEMORY_OFFSET }, alloc_last_phys + unsafe { mem::PHYSICAL_MEMORY_OFFSET });
-        for addr in alloc_first_virt..alloc_last_virt {
-            unsafe { *(addr as *mut u8) = 0; }
+        *list = frame;
+        self.set_state(frame, FRAME_FREE | order as u8);
+    }
+
+    fn unlink(&mut self, frame: u64, order: usize) {
+        let (next, prev) = {
+            let block = Self::block(frame);
+            (block.next, block.prev)
+        };
+        if prev == NO_FRAME {
+            self.free_lists[Zone::of(frame)][order] = next;
+        } else {
+            Self::block(prev).next = next;
         }
-        self.bitmap = alloc_first_virt as *mut u8;
-        // reserve bitmap frames
-        let bit_index_first = Self::address_to_bit_index(alloc_first_phys);
-        let bit_index_last = Self::address_to_bit_index(alloc_last_phys);
-        for bit in bit_index_first..bit_index_last {
-            self.reserve_frame(bit);
+        if next != NO_FRAME {
+            Self::block(next).prev = prev;
         }
-        self.first_usable_address = alloc_last_phys + 0x1000;
-        self.next_free = bit_index_last + 1;
-        // reserve reserved frames
-        let _ = unsafe { mem::BOOT_MEMORY_MAP.unwrap().iter() }
-        .filter(|r| r.kind != boot_info::MemoryRegionKind::Usable)
-        .map(|reg| reg.start..reg.end)
-        .flat_map(|reg| reg.step_by(0x1000))
-        .map(|reg| self.reserve_frame(reg as usize));
+        self.set_state(frame, FRAME_FREE_TAIL);
     }
 
-    #[inline(always)]
-    pub fn bitmap_get(&self, index: usize) -> bool {
-        unsafe {
-            (((*self.bitmap.offset((index / 8) as isize)) << (index % 8)) >> 7) & 1 == 1
+    // frees a block, merging it with its buddy for as long as that is free as a whole
+    fn release_block(&mut self, frame: u64, order: usize) {
+        let mut frame = frame;
+        let mut order = order;
+        for i in 0..(1u64 << order) {
+            self.set_state(frame + i * FRAME_SIZE, FRAME_FREE_TAIL);
+        }
+        while order < MAX_ORDER {
+            let buddy = frame ^ (FRAME_SIZE << order);
+            // zones are aligned to far more than the largest block, so buddies never cross them
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.unlink(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
         }
+        self.link(frame, order);
     }
 
-    #[inline(always)]
-    pub fn bitmap_set(&mut self, index: usize) {
-        unsafe {
-            (*(self.bitmap.offset((index / 8) as isize))) |= 0b10000000 >> (index % 8)
+    // splits [start, end) into the largest aligned blocks that fit
+    fn release_range(&mut self, start: u64, end: u64) {
+        let mut frame = start;
+        while frame < end {
+            let mut order = 0;
+            while order < MAX_ORDER && frame % (FRAME_SIZE << (order + 1)) == 0 && frame + (FRAME_SIZE << (order + 1)) <= end {
+                order += 1;
+            }
+            self.release_block(frame, order);
+            frame += FRAME_SIZE << order;
         }
     }
 
-    #[inline(always)]
-    pub fn bitmap_clear(&mut self, index: usize) {
-        unsafe {
-            (*(self.bitmap.offset((index / 8) as isize))) &= !(0b10000000 >> (index % 8));
+    fn allocate_block(&mut self, order: usize, zone: Zone) -> Option<u64> {
+        for &zone in zone.candidates() {
+            if let Some(mut current) = (order..=MAX_ORDER).find(|&o| self.free_lists[zone][o] != NO_FRAME) {
+                let frame = self.free_lists[zone][current];
+                self.unlink(frame, current);
+                // hand the upper halves back until the block is as small as asked for
+                while current > order {
+                    current -= 1;
+                    self.link(frame + (FRAME_SIZE << current), current);
+                }
+                return Some(frame);
+            }
         }
+        None
     }
 
-    pub fn lock_all(&mut self) {
-        self.next_free += 1;
-        self.first_usable_address = Self::bit_index_to_address(self.next_free);
-        self.next_free += 1;
+    fn locked<R, F>(&mut self, f: F) -> R
+    where F: FnOnce(&mut Self) -> R {
+        interrupts::without_interrupts(|| {
+            let _guard = FRAME_ALLOCATOR_LOCK.lock();
+            f(self)
+        })
     }
 
-    #[inline(always)]
-    fn address_to_bit_index(address: u64) -> usize {
-        (address / 0x1000) as usize
+    // count physically contiguous frames starting at a multiple of align, which has to be a power of two
+    pub fn allocate_frames(&mut self, count: usize, align: u64, zone: Zone) -> Option<u64> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let mut order = 0;
+        while (1usize << order) < count || (FRAME_SIZE << order) < align {
+            order += 1;
+        }
+        if order > MAX_ORDER {
+            return None;
+        }
+        self.locked(|alloc| {
+            let frame = alloc.allocate_block(order, zone)?;
+            for i in 0..count as u64 {
+                alloc.set_state(frame + i * FRAME_SIZE, FRAME_USED);
+            }
+            // whatever rounding up to a power of two added goes straight back
+            alloc.release_range(frame + count as u64 * FRAME_SIZE, frame + (FRAME_SIZE << order));
+            alloc.free_pages -= count;
+            alloc.used_pages += count;
+            Some(frame)
+        })
     }
 
-    #[inline(always)]
-    fn bit_index_to_address(index: usize) -> u64 {
-        (index * 0x1000) as u64
+    pub fn allocate_huge_page(&mut self, zone: Zone) -> Option<u64> {
+        self.allocate_frames(1 << HUGE_PAGE_ORDER, HUGE_PAGE_SIZE, zone)
     }
 
-    pub fn reserve_frame(&mut self, frame_index: usize) {
-        self.bitmap_set(frame_index);
-        self.free_pages -= 1;
-        self.used_pages += 1;
+    pub fn allocate_frame(&mut self) -> u64 {
+        self.allocate_frames(1, FRAME_SIZE, Zone::Normal).unwrap_or_else(|| panic!("OUT_OF_PHYSICAL_MEMORY"))
     }
 
-    pub fn allocate_frame(&mut self) -> u64 {
-        let frame = Self::bit_index_to_address(self.next_free);
-        self.bitmap_set(self.next_free);
-        self.free_pages -= 1;
-        self.used_pages += 1;
-        while {
-            self.next_free += 1;
-            if self.next_free >= self.number_of_pages {
-                self.next_free = Self::address_to_bit_index(self.first_usable_address);
+    // frames that were never handed out are left alone
+    pub fn free_frames(&mut self, start: u64, count: usize) {
+        let start = start / FRAME_SIZE * FRAME_SIZE;
+        self.locked(|alloc| {
+            let mut run_start = start;
+            for i in 0..=count as u64 {
+                let frame = start + i * FRAME_SIZE;
+                let freeable = i < count as u64 && frame / FRAME_SIZE < alloc.number_of_frames as u64 && alloc.state(frame) == FRAME_USED;
+                if !freeable {
+                    if run_start < frame {
+                        let pages = ((frame - run_start) / FRAME_SIZE) as usize;
+                        alloc.release_range(run_start, frame);
+                        alloc.free_pages += pages;
+                        alloc.used_pages -= pages;
+                    }
+                    run_start = frame + FRAME_SIZE;
+                }
             }
-            self.bitmap_get(self.next_free)
-        } {}
-        frame
+        })
     }
 
     pub fn free_frame(&mut self, frame: u64) {
-        if frame <= self.first_usable_address {
-            return;
-        }
-        let bit = Self::address_to_bit_index(frame);
-        if !self.bitmap_get(bit) {
+        // a shared frame stays until its last mapping lets go of it
+        if self.release_shared_frame(frame) {
             return;
         }
-        self.bitmap_clear(bit);
-        self.free_pages += 1;
-        self.used_pages -= 1;
-        if self.next_free > bit {
-            self.next_free = bit;
-        }
+        self.free_frames(frame, 1);
+    }
+
+    // keeps a specific physical frame away from the allocator for good
+    pub fn reserve_address(&mut self, address: u64) {
+        let target = address / FRAME_SIZE * FRAME_SIZE;
+        self.locked(|alloc| {
+            let found = (0..=MAX_ORDER)
+                .map(|order| (target & !((FRAME_SIZE << order) - 1), order))
+                .find(|&(frame, order)| alloc.is_free_block(frame, order));
+            if let Some((mut frame, mut order)) = found {
+                alloc.unlink(frame, order);
+                // split around the frame, freeing the halves it is not in
+                while order > 0 {
+                    order -= 1;
+                    let upper = frame + (FRAME_SIZE << order);
+                    if target >= upper {
+                        alloc.link(frame, order);
+                        frame = upper;
+                    } else {
+                        alloc.link(upper, order);
+                    }
+                }
+                alloc.set_state(target, FRAME_RESERVED);
+                alloc.free_pages -= 1;
+            }
+        })
+    }
+
+    pub fn share_frame(&mut self, frame: u64) {
+        interrupts::without_interrupts(|| {
+            *SHARED_FRAMES.lock().entry(frame).or_insert(0) += 1;
+        })
+    }
+
+    pub fn is_frame_shared(&self, frame: u64) -> bool {
+        interrupts::without_interrupts(|| SHARED_FRAMES.lock().contains_key(&frame))
+    }
+
+    fn release_shared_frame(&mut self, frame: u64) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut shared = SHARED_FRAMES.lock();
+            match shared.get_mut(&frame) {
+                Some(references) if *references > 1 => *references -= 1,
+                Some(_) => {
+                    shared.remove(&frame);
+                },
+                None => return false,
+            }
+            true
+        })
     }
 
     pub fn get_free_pages(&self) -> usize {
@@ -168,4 +351,4 @@ impl BitmapFrameAllocator {
     pub fn get_pages(&self) -> usize {
         self.free_pages + self.used_pages
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,160 @@
+use crate::*;
+use super::{page_mapper, FRAME_ALLOCATOR, KERNEL_HEAP_START, KERNEL_PAGE_TABLE};
+use dev::*;
+use exec::scheduler;
+use infinity::allocator::{self, ALLOCATOR};
+use alloc::{alloc::Layout, format, string::String, vec, vec::Vec};
+use x86_64::structures::paging::PageTableFlags;
+
+// stays inside the heap's level 4 entry, so processes created earlier see the growth through the shared tables
+pub const KERNEL_HEAP_MAX_SIZE: usize = 0x1000_0000;
+
+static mut HEAP_MAPPED: usize = 0;
+// before the scheduler runs there is no thread to keep it on
+static mut BOOT_SUBSYSTEM: Subsystem = Subsystem::Kernel;
+
+// what a thread is allocating for, so that running out of memory can be blamed on someone
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Subsystem {
+    Kernel,
+    Namespace,
+    Devices,
+    IPC,
+    FileSystem,
+    Executable,
+    SystemCall,
+}
+
+pub struct SubsystemScope {
+    previous: Subsystem,
+}
+
+impl Drop for SubsystemScope {
+    fn drop(&mut self) {
+        set_subsystem(self.previous);
+    }
+}
+
+fn set_subsystem(subsystem: Subsystem) {
+    match scheduler::try_current_task_mut() {
+        Some(task) => task.subsystem = subsystem,
+        None => unsafe { BOOT_SUBSYSTEM = subsystem },
+    }
+}
+
+pub fn current_subsystem() -> Subsystem {
+    match scheduler::try_current_task_mut() {
+        Some(task) => task.subsystem,
+        None => unsafe { BOOT_SUBSYSTEM },
+    }
+}
+
+// allocations are blamed on subsystem until the scope is dropped
+pub fn enter(subsystem: Subsystem) -> SubsystemScope {
+    let previous = current_subsystem();
+    set_subsystem(subsystem);
+    SubsystemScope {
+        previous,
+    }
+}
+
+fn map_pages(start: usize, size: usize) {
+    let l4_table = page_mapper::addr_to_page_table(unsafe { KERNEL_PAGE_TABLE });
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
+    for page in (start..start + size).step_by(0x1000) {
+        let frame = unsafe { FRAME_ALLOCATOR.allocate_frame() };
+        page_mapper::map_page_to_frame(l4_table, page as u64, frame, Some(flags));
+    }
+}
+
+pub fn init(size: usize) {
+    map_pages(KERNEL_HEAP_START, size);
+    unsafe {
+        HEAP_MAPPED = size;
+        ALLOCATOR.init(KERNEL_HEAP_START, size);
+    }
+    ALLOCATOR.set_grow_handler(grow);
+}
+
+// called by the allocator with its lock held, so it must not allocate
+fn grow(top: Option<*mut u8>, size: usize) -> Option<*mut u8> {
+    let start = KERNEL_HEAP_START + unsafe { HEAP_MAPPED };
+    if top.map_or(false, |top| top as usize != start) {
+        return None;
+    }
+    if unsafe { HEAP_MAPPED } + size > KERNEL_HEAP_MAX_SIZE || unsafe { FRAME_ALLOCATOR.get_free_pages() } <= size / 0x1000 {
+        return None;
+    }
+    map_pages(start, size);
+    unsafe { HEAP_MAPPED += size; }
+    Some(start as *mut u8)
+}
+
+pub fn out_of_memory(layout: Layout) -> ! {
+    let stats = ALLOCATOR.statistics();
+    panic!("\nMEMORY_ALLOCATION_ERROR\nSubsystem: {:?}\nProcess: {:?}\n{:#?}\nHeap size: {} bytes, in use: {} bytes, peak: {} bytes",
+        current_subsystem(), scheduler::try_current_process(), layout, stats.heap_size, stats.in_use, stats.peak)
+}
+
+fn statistics_report() -> String {
+    let stats = ALLOCATOR.statistics();
+    let mut report = format!("Heap size: {}\nIn use: {}\nPeak: {}\nFailed allocations: {}\n", stats.heap_size, stats.in_use, stats.peak, stats.failed);
+    for (i, count) in stats.allocations.iter().enumerate() {
+        match allocator::SIZE_CLASSES.get(i) {
+            Some(class) => report += format!("<= {}: {}\n", class, count).as_str(),
+            None => report += format!("> {}: {}\n", allocator::SIZE_CLASSES[i - 1], count).as_str(),
+        }
+    }
+    report
+}
+
+// reads back a text report of the allocator statistics, from the start again after each end of file
+#[derive(Debug)]
+pub struct KernelHeap {
+    report: Option<String>,
+    offset: usize,
+}
+
+impl KernelHeap {
+    pub const fn new() -> KernelHeap {
+        KernelHeap {
+            report: None,
+            offset: 0,
+        }
+    }
+}
+
+impl Device for KernelHeap {
+    fn device_path(&self) -> Vec<String> {
+        vec![String::from("System"), String::from("KernelHeap")]
+    }
+
+    fn is_in_use(&self) -> bool {
+        false
+    }
+
+    fn unwrap(&mut self) -> DeviceClass {
+        DeviceClass::ReadWriteDevice(self)
+    }
+}
+
+impl Read for KernelHeap {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let report = self.report.get_or_insert_with(statistics_report);
+        if self.offset >= report.len() {
+            self.report = None;
+            self.offset = 0;
+            return Err(Error::EndOfFile);
+        }
+        let count = buf.len().min(report.len() - self.offset);
+        buf[..count].copy_from_slice(&report.as_bytes()[self.offset..(self.offset + count)]);
+        self.offset += count;
+        Ok(count)
+    }
+}
+
+impl Write for KernelHeap {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::Permissions)
+    }
+}
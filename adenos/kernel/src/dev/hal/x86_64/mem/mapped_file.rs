@@ -0,0 +1,147 @@
+use crate::*;
+use super::{page_mapper, FRAME_ALLOCATOR, PHYSICAL_MEMORY_OFFSET};
+use dev::*;
+use namespace::{Handle, Resource, ResourceType};
+use file::File;
+use alloc::{boxed::Box, collections::BTreeMap, sync::{Arc, Weak}};
+use spin::Mutex;
+use core::{fmt, ptr, slice};
+
+// every mapping of the same open file shares one of these, so that shared mappings see the same frames
+static MAPPED_FILES: Mutex<BTreeMap<usize, Weak<MappedFile>>> = Mutex::new(BTreeMap::new());
+
+pub struct MappedFile {
+    key: usize,
+    inner: Mutex<MappedFileInner>,
+}
+
+struct MappedFileInner {
+    // keeps the file open for as long as anything maps it, even after the process releases its own handle
+    handle: Option<Handle>,
+    // frames of shared mappings by file offset, also referenced once by every page table they are mapped in
+    cache: BTreeMap<u64, u64>,
+}
+
+// the handle is only ever touched with the inner lock held
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn get(handle: &mut Handle, owner: u32) -> Result<Arc<MappedFile>, Error> {
+        if let ResourceType::File(_) = handle.unwrap().unwrap() {} else {
+            return Err(Error::InvalidHandle);
+        }
+        let key = &*handle.resource as *const Box<dyn Resource> as usize;
+        let mut files = MAPPED_FILES.lock();
+        if let Some(file) = files.get(&key).and_then(|file| file.upgrade()) {
+            return Ok(file);
+        }
+        let file = Arc::new(MappedFile {
+            key,
+            inner: Mutex::new(MappedFileInner {
+                handle: Some(handle.duplicate(handle.id, owner)),
+                cache: BTreeMap::new(),
+            }),
+        });
+        files.insert(key, Arc::downgrade(&file));
+        Ok(file)
+    }
+
+    fn with_file<R, F>(inner: &mut MappedFileInner, f: F) -> Result<R, Error>
+    where F: FnOnce(&mut File) -> Result<R, Error> {
+        match inner.handle.as_mut().ok_or(Error::InvalidHandle)?.unwrap().unwrap() {
+            ResourceType::File(file) => {
+                // the process may be reading the file through its own handle at the same time
+                let position = file.offset();
+                let result = f(file);
+                file.seek(position)?;
+                result
+            },
+            _ => Err(Error::InvalidHandle),
+        }
+    }
+
+    fn frame_buffer(frame: u64) -> &'static mut [u8] {
+        unsafe { slice::from_raw_parts_mut((frame + PHYSICAL_MEMORY_OFFSET) as *mut u8, 0x1000) }
+    }
+
+    // past the end of the file the page reads as zeros
+    fn read_page(inner: &mut MappedFileInner, offset: u64, frame: u64) -> Result<(), Error> {
+        Self::with_file(inner, |file| {
+            if offset < file.size() {
+                let len = (file.size() - offset).min(0x1000) as usize;
+                file.seek(offset)?;
+                file.read(&mut Self::frame_buffer(frame)[..len])?;
+            }
+            Ok(())
+        })
+    }
+
+    // a frame of its own holding the page at offset, for private mappings
+    pub fn read_private(&self, offset: u64) -> Result<u64, Error> {
+        let mut inner = self.inner.lock();
+        let frame = page_mapper::new_frame_zeroed();
+        // whatever shared mappings wrote is the current content, synced or not
+        if let Some(cached) = inner.cache.get(&offset) {
+            unsafe { ptr::copy_nonoverlapping((cached + PHYSICAL_MEMORY_OFFSET) as *const u8, (frame + PHYSICAL_MEMORY_OFFSET) as *mut u8, 0x1000); }
+        } else if let Err(err) = Self::read_page(&mut inner, offset, frame) {
+            page_mapper::free_frame(frame);
+            return Err(err);
+        }
+        Ok(frame)
+    }
+
+    // the frame all shared mappings use for the page at offset, with a reference taken for the caller's page table
+    pub fn get_shared(&self, offset: u64) -> Result<u64, Error> {
+        let mut inner = self.inner.lock();
+        let frame = match inner.cache.get(&offset) {
+            Some(frame) => *frame,
+            None => {
+                let frame = page_mapper::new_frame_zeroed();
+                if let Err(err) = Self::read_page(&mut inner, offset, frame) {
+                    page_mapper::free_frame(frame);
+                    return Err(err);
+                }
+                inner.cache.insert(offset, frame);
+                frame
+            },
+        };
+        unsafe { FRAME_ALLOCATOR.share_frame(frame); }
+        Ok(frame)
+    }
+
+    // the file does not grow, the part of the page past its end is dropped
+    pub fn write_back(&self, offset: u64, frame: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+        Self::with_file(&mut inner, |file| {
+            if offset < file.size() {
+                let len = (file.size() - offset).min(0x1000) as usize;
+                file.seek(offset)?;
+                file.write(&Self::frame_buffer(frame)[..len])?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        for frame in inner.cache.values() {
+            page_mapper::free_frame(*frame);
+        }
+        if let Some(handle) = inner.handle.take() {
+            handle.close();
+        }
+        let mut files = MAPPED_FILES.lock();
+        if files.get(&self.key).map_or(false, |file| file.strong_count() == 0) {
+            files.remove(&self.key);
+        }
+    }
+}
+
+impl fmt::Debug for MappedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedFile").field("cached_pages", &self.inner.lock().cache.len()).finish()
+    }
+}
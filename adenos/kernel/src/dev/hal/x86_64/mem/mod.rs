@@ -1,17 +1,20 @@
 use crate::*;
+use dev::hal::smp;
 use bootloader::boot_info;
 use frame_allocator::*;
-use x86_64::{ structures::paging::{Page, PageTable, PhysFrame, PageTableFlags, Size4KiB}, VirtAddr, PhysAddr, registers::control::{Cr3, Cr3Flags}, instructions::tlb };
-
-use self::page_mapper::addr_to_page_table;
+use x86_64::{ structures::paging::{PageTable, PhysFrame}, VirtAddr, PhysAddr, registers::control::{Cr3, Cr3Flags}, instructions::tlb };
 
 pub mod frame_allocator;
+pub mod heap;
+pub mod mapped_file;
 pub mod page_mapper;
+pub mod user_memory;
+pub mod vma;
 
 pub static mut PHYSICAL_MEMORY_OFFSET: u64 = 0;
 pub static mut BOOT_MEMORY_MAP: Option<&boot_info::MemoryRegions> = None;
 pub static mut FREE_MEMORY: usize = 0;
-pub static mut FRAME_ALLOCATOR: BitmapFrameAllocator = BitmapFrameAllocator::new_uninit();
+pub static mut FRAME_ALLOCATOR: BuddyFrameAllocator = BuddyFrameAllocator::new_uninit();
 pub static mut KERNEL_PAGE_TABLE: u64 = 0;
 
 pub const KERNEL_HEAP_START: usize = 0x_4444_4444_0000;
@@ -20,7 +23,9 @@ pub const KERNEL_HEAP_SIZE: usize = 0x1000 * 64;
 pub fn init() {
     let (pt, _) = Cr3::read();
     unsafe { KERNEL_PAGE_TABLE = pt.start_address().as_u64(); }
-    unsafe { FRAME_ALLOCATOR = BitmapFrameAllocator::new() };
+    unsafe { FRAME_ALLOCATOR = BuddyFrameAllocator::new() };
+    // application processors start from here in real mode
+    unsafe { FRAME_ALLOCATOR.reserve_address(smp::TRAMPOLINE_ADDRESS) };
     init_heap();
 }
 
@@ -31,26 +36,7 @@ pub unsafe fn enable_page_table(page_table: &'static PageTable) {
 }
 
 pub fn init_heap() {
-    let page_range = {
-        let heap_start = VirtAddr::new(KERNEL_HEAP_START as u64);
-        let heap_end = heap_start + KERNEL_HEAP_SIZE - 1u64;
-        let heap_start_page: Page<Size4KiB> = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    }.map(|frm| frm.start_address().as_u64());
-
-    let l4_table = addr_to_page_table(unsafe { KERNEL_PAGE_TABLE });
-
-    for page in page_range {
-        let frame = unsafe { FRAME_ALLOCATOR.allocate_frame() };
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
-        page_mapper::map_page_to_frame(l4_table, page, frame, Some(flags));
-    }
-
-    unsafe { infinity::allocator::ALLOCATOR.init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE); }
-
-    // kernel heap is initialized, we can set up bitmap
-    unsafe { FRAME_ALLOCATOR.lock_all() };
+    heap::init(KERNEL_HEAP_SIZE);
 }
 
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable
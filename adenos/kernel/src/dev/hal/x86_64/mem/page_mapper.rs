@@ -11,6 +11,12 @@ pub fn align(addr: u64) -> u64 {
 pub fn unmap_userspace_page_tables(page_table_addr: u64) {
     let page_table = addr_to_page_table(page_table_addr);
     let l3_page_table = addr_to_page_table(page_table[0].addr().as_u64());
+    // nothing was ever mapped in user space
+    if l3_page_table[1].is_unused() {
+        free_frame(page_table[0].addr().as_u64());
+        free_frame(page_table_addr);
+        return;
+    }
     let l2_page_table = addr_to_page_table(l3_page_table[1].addr().as_u64());
     for (_, ent) in l2_page_table.iter_mut().enumerate() {
         if !ent.is_unused() {
@@ -54,6 +60,27 @@ pub fn get_l1_entry(addr: u64) -> Option<&'static mut PageTableEntry> {
     Some(ent)
 }
 
+pub fn get_l1_entry_using_table(page_table: &PageTable, addr: u64) -> Option<&'static mut PageTableEntry> {
+    let mut frame = page_table as *const _ as u64 - unsafe { PHYSICAL_MEMORY_OFFSET };
+    let virt_addr = VirtAddr::new(addr);
+    let table_indexes = [
+        virt_addr.p4_index(), virt_addr.p3_index(), virt_addr.p2_index(), virt_addr.p1_index()
+    ];
+    for i in 0..4 {
+        let table_virt_addr = frame + unsafe { PHYSICAL_MEMORY_OFFSET };
+        let table = unsafe { &mut *(table_virt_addr as *mut PageTable) };
+        let ent = &mut table[table_indexes[i]];
+        if ent.is_unused() {
+            return None;
+        }
+        if i == 3 {
+            return Some(ent);
+        }
+        frame = align(ent.addr().as_u64());
+    }
+    None
+}
+
 pub fn get_l4_table() -> &'static mut PageTable {
     let (frame, _) = Cr3::read();
     let frame = frame.start_address().as_u64() + unsafe { PHYSICAL_MEMORY_OFFSET };
@@ -166,6 +193,12 @@ pub fn unmap_addr(page_table: &mut PageTable, virt_addr: u64) {
     }
 }
 
+// the tables above a user page have to allow everything the page itself may allow
+pub fn map_user_page(l4_table: &mut PageTable, page: u64, frame: u64, flags: PageTableFlags) {
+    let page_table = map_l1_table(l4_table, align(page), Some(PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)).unwrap();
+    unsafe { table_entry(page_table, VirtAddr::new(page).p1_index(), frame, Some(flags), Some(page)); }
+}
+
 pub fn map_page_to_frame(l4_table: &mut PageTable,page: u64, frame: u64, flags: Option<PageTableFlags>) {
     let page_table = map_l1_table(l4_table, page, flags).unwrap();
     unsafe { table_entry(page_table, VirtAddr::new(page).p1_index(), frame, flags, Some(page)); }
@@ -216,15 +249,54 @@ pub fn translate_addr_using_table(page_table: &PageTable, virt_addr: usize) -> O
         let table_virt_addr = frame + unsafe { PHYSICAL_MEMORY_OFFSET };
         let table = unsafe { &*(table_virt_addr as *const PageTable) };
         let ent = &table[table_indexes[i]];
-        frame = if ent.is_unused() {
-            return None
-        } else {
-            align(ent.addr().as_u64())
-        };
+        if ent.is_unused() {
+            return None;
+        }
+        // a 1 GiB or 2 MiB page in an L3 or L2 entry ends the walk early, the rest of the address is the offset into it
+        if (1..3).contains(&i) && ent.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some(huge_page_base(ent, i) + (virt_addr.as_u64() & (huge_page_size(i) - 1)));
+        }
+        frame = align(ent.addr().as_u64());
     }
     Some(frame + u64::from(virt_addr.page_offset()))
 }
 
+#[inline(always)]
+fn huge_page_size(level: usize) -> u64 {
+    1 << (12 + 9 * (3 - level))
+}
+
+#[inline(always)]
+fn huge_page_base(ent: &PageTableEntry, level: usize) -> u64 {
+    ent.addr().as_u64() & !(huge_page_size(level) - 1)
+}
+
+pub fn effective_flags_using_table(page_table: &PageTable, virt_addr: usize) -> Option<PageTableFlags> {
+    let mut frame = page_table as *const _ as u64 - unsafe { PHYSICAL_MEMORY_OFFSET };
+    let virt_addr = VirtAddr::new(virt_addr as u64);
+    let table_indexes = [
+        virt_addr.p4_index(), virt_addr.p3_index(), virt_addr.p2_index(), virt_addr.p1_index()
+    ];
+    // access rights have to be granted on every level of the walk
+    let mut access = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let mut flags = PageTableFlags::empty();
+    for i in 0..4 {
+        let table_virt_addr = frame + unsafe { PHYSICAL_MEMORY_OFFSET };
+        let table = unsafe { &*(table_virt_addr as *const PageTable) };
+        let ent = &table[table_indexes[i]];
+        if ent.is_unused() || !ent.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        access &= ent.flags();
+        flags = ent.flags();
+        if (1..3).contains(&i) && flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        frame = align(ent.addr().as_u64());
+    }
+    Some((flags - (PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE)) | access)
+}
+
 pub unsafe fn show_which_page_tables(address: usize) {
     let virt_addr = VirtAddr::new(address as u64);
     let table_indexes = [
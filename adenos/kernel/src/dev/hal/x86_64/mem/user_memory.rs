@@ -0,0 +1,161 @@
+use crate::*;
+use super::{page_mapper, vma, PHYSICAL_MEMORY_OFFSET};
+use exec::scheduler;
+use alloc::{vec, vec::Vec, string::String};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use core::ptr;
+
+// the second GiB, L3 entry 1 of L4 entry 0, is all a process gets, everything else belongs to the kernel
+pub const USER_SPACE_START: usize = 0x4000_0000;
+pub const USER_SPACE_END: usize = 0x8000_0000;
+
+pub const MAX_USER_TRANSFER: usize = 0x10000;
+pub const MAX_USER_STRING: usize = 0x1000;
+
+fn caller_page_table() -> Result<Option<&'static PageTable>, Error> {
+    if !scheduler::current_task().user_mode {
+        // kernel threads call into the system call layer with kernel buffers
+        return Ok(None);
+    }
+    let process = scheduler::get_process(scheduler::current_process())?;
+    Ok(Some(page_mapper::addr_to_page_table(process.page_table())))
+}
+
+pub fn check_user_range(addr: usize, len: usize) -> Result<(), Error> {
+    // nothing gets touched, so an empty buffer may point anywhere, null included
+    if len == 0 {
+        return Ok(());
+    }
+    match addr.checked_add(len) {
+        Some(end) if addr >= USER_SPACE_START && end <= USER_SPACE_END => Ok(()),
+        _ => Err(Error::InvalidAddress),
+    }
+}
+
+fn translate_user_addr(page_table: &PageTable, addr: usize, write: bool) -> Result<u64, Error> {
+    let flags = page_mapper::effective_flags_using_table(page_table, addr).ok_or(Error::InvalidAddress)?;
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) || (write && !flags.contains(PageTableFlags::WRITABLE)) {
+        return Err(Error::InvalidAddress);
+    }
+    page_mapper::translate_addr_using_table(page_table, addr).ok_or(Error::InvalidAddress)
+}
+
+// calls f with the kernel virtual address of every page-sized chunk of the user range
+fn for_each_user_chunk<F>(page_table: &PageTable, addr: usize, len: usize, write: bool, mut f: F) -> Result<(), Error>
+where F: FnMut(usize, *mut u8, usize) -> bool {
+    check_user_range(addr, len)?;
+    let mut done = 0;
+    while done < len {
+        let virt = addr + done;
+        let chunk = (0x1000 - (virt & 0xFFF)).min(len - done);
+        let phys = match translate_user_addr(page_table, virt, write) {
+            Ok(phys) => phys,
+            // may just not have been touched yet
+            Err(_) => {
+                vma::populate_current(page_table, virt as u64, write)?;
+                translate_user_addr(page_table, virt, write)?
+            },
+        };
+        if !f(done, (phys + unsafe { PHYSICAL_MEMORY_OFFSET }) as *mut u8, chunk) {
+            break;
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+pub fn check_user_buffer(addr: usize, len: usize, write: bool) -> Result<(), Error> {
+    if let Some(page_table) = caller_page_table()? {
+        for_each_user_chunk(page_table, addr, len, write, |_, _, _| true)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn copy_from_user(addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+    if let Some(page_table) = caller_page_table()? {
+        for_each_user_chunk(page_table, addr, buf.len(), false, |off, src, len| {
+            unsafe { ptr::copy_nonoverlapping(src, buf[off..].as_mut_ptr(), len); }
+            true
+        })
+    } else {
+        unsafe { ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len()); }
+        Ok(())
+    }
+}
+
+// also used to fill in memory of processes other than the caller
+pub fn copy_to_user_table(page_table: &PageTable, addr: usize, buf: &[u8]) -> Result<(), Error> {
+    for_each_user_chunk(page_table, addr, buf.len(), true, |off, dst, len| {
+        unsafe { ptr::copy_nonoverlapping(buf[off..].as_ptr(), dst, len); }
+        true
+    })
+}
+
+pub fn copy_from_user_table(page_table: &PageTable, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+    for_each_user_chunk(page_table, addr, buf.len(), false, |off, src, len| {
+        unsafe { ptr::copy_nonoverlapping(src, buf[off..].as_mut_ptr(), len); }
+        true
+    })
+}
+
+pub fn copy_to_user(addr: usize, buf: &[u8]) -> Result<(), Error> {
+    if let Some(page_table) = caller_page_table()? {
+        copy_to_user_table(page_table, addr, buf)
+    } else {
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), addr as *mut u8, buf.len()); }
+        Ok(())
+    }
+}
+
+pub fn read_user_vec(addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; len];
+    copy_from_user(addr, buf.as_mut_slice())?;
+    Ok(buf)
+}
+
+pub fn read_user_value<T: Copy>(addr: usize) -> Result<T, Error> {
+    let buf = read_user_vec(addr, core::mem::size_of::<T>())?;
+    Ok(unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) })
+}
+
+pub fn write_user_value<T: Copy>(addr: usize, value: T) -> Result<(), Error> {
+    let buf = unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, core::mem::size_of::<T>()) };
+    copy_to_user(addr, buf)
+}
+
+pub fn copy_c_str_from_user(addr: usize, max_len: usize) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    let mut terminated = false;
+    if let Some(page_table) = caller_page_table()? {
+        if addr < USER_SPACE_START || addr >= USER_SPACE_END {
+            return Err(Error::InvalidAddress);
+        }
+        // the string may end before the range does, so only the first byte has to be in user space
+        let len = max_len.min(USER_SPACE_END - addr);
+        for_each_user_chunk(page_table, addr, len, false, |_, src, len| {
+            for i in 0..len {
+                let b = unsafe { *src.add(i) };
+                if b == 0 {
+                    terminated = true;
+                    return false;
+                }
+                bytes.push(b);
+            }
+            true
+        })?;
+    } else {
+        for i in 0..max_len {
+            let b = unsafe { *(addr as *const u8).add(i) };
+            if b == 0 {
+                terminated = true;
+                break;
+            }
+            bytes.push(b);
+        }
+    }
+    if !terminated {
+        return Err(Error::BufferTooSmall);
+    }
+    String::from_utf8(bytes).map_err(|_| Error::InvalidData)
+}
@@ -0,0 +1,436 @@
+use crate::*;
+use super::{page_mapper, mapped_file::MappedFile, FRAME_ALLOCATOR, PHYSICAL_MEMORY_OFFSET};
+use dev::hal::{cpu, smp, task};
+use exec::scheduler;
+use alloc::{sync::Arc, vec::Vec};
+use core::{ptr, sync::atomic::Ordering};
+use x86_64::{structures::paging::{PageTable, PageTableFlags, page_table::PageTableEntry}, PhysAddr, VirtAddr, instructions::tlb};
+
+// anonymous memory and file mappings handed out by the map system calls, below the thread stacks
+pub const ANONYMOUS_START: u64 = infinity::os::HEAP_BASE as u64;
+pub const ANONYMOUS_END: u64 = 0x6000_0000;
+pub const MAX_ANONYMOUS_SIZE: u64 = ANONYMOUS_END - ANONYMOUS_START;
+// left to software by the cpu, set on pages of writable areas that are read-only until their frame is copied
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AreaKind {
+    // loaded from the executable, the pages past the file contents are zero filled
+    Image,
+    // zero filled on first touch
+    Anonymous,
+    Stack,
+    // never mapped, touching it is a fault
+    Guard,
+    // read from a file on first touch
+    File,
+    // a thread's copy of the thread-local storage image and its control block
+    ThreadLocal,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileBacking {
+    pub file: Arc<MappedFile>,
+    // where the first page of the area is in the file
+    pub offset: u64,
+    // writes end up in the file instead of a private copy
+    pub shared: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct VirtualMemoryArea {
+    pub start: u64,
+    pub end: u64,
+    pub writable: bool,
+    pub executable: bool,
+    pub kind: AreaKind,
+    pub file: Option<FileBacking>,
+}
+
+impl VirtualMemoryArea {
+    pub fn new(start: u64, end: u64, writable: bool, executable: bool, kind: AreaKind) -> VirtualMemoryArea {
+        VirtualMemoryArea {
+            start,
+            end,
+            writable,
+            executable,
+            kind,
+            file: None,
+        }
+    }
+
+    pub fn file(start: u64, end: u64, writable: bool, executable: bool, backing: FileBacking) -> VirtualMemoryArea {
+        VirtualMemoryArea {
+            start,
+            end,
+            writable,
+            executable,
+            kind: AreaKind::File,
+            file: Some(backing),
+        }
+    }
+
+    // the part of the area inside [start, end)
+    pub fn slice(&self, start: u64, end: u64) -> VirtualMemoryArea {
+        let mut area = self.clone();
+        area.start = start.max(self.start);
+        area.end = end.min(self.end);
+        if let Some(backing) = area.file.as_mut() {
+            backing.offset += area.start - self.start;
+        }
+        area
+    }
+
+    pub fn is_shared_file(&self) -> bool {
+        self.file.as_ref().map_or(false, |backing| backing.shared)
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end && end > self.start
+    }
+
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable && cpu::NO_EXECUTE.load(Ordering::Relaxed) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+// sorted by start address, areas never overlap
+#[derive(Debug, Clone)]
+pub struct AddressSpace {
+    areas: Vec<VirtualMemoryArea>,
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        AddressSpace {
+            areas: Vec::new(),
+        }
+    }
+
+    pub fn areas(&self) -> &[VirtualMemoryArea] {
+        self.areas.as_slice()
+    }
+
+    pub fn insert(&mut self, area: VirtualMemoryArea) -> Result<(), Error> {
+        if area.start >= area.end || area.start % 0x1000 != 0 || area.end % 0x1000 != 0 {
+            return Err(Error::InvalidAddress);
+        }
+        if self.areas.iter().any(|other| other.overlaps(area.start, area.end)) {
+            return Err(Error::AlreadyOpen);
+        }
+        let index = self.areas.iter().position(|other| other.start > area.start).unwrap_or(self.areas.len());
+        self.areas.insert(index, area);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, start: u64) -> Option<VirtualMemoryArea> {
+        let index = self.areas.iter().position(|area| area.start == start)?;
+        Some(self.areas.remove(index))
+    }
+
+    pub fn find(&self, addr: u64) -> Option<&VirtualMemoryArea> {
+        self.areas.iter().find(|area| area.contains(addr))
+    }
+
+    // lowest gap of at least size bytes between from and to
+    pub fn find_free(&self, size: u64, from: u64, to: u64) -> Option<u64> {
+        let mut candidate = from;
+        for area in self.areas.iter().filter(|area| area.end > from && area.start < to) {
+            if area.start >= candidate + size {
+                break;
+            }
+            candidate = candidate.max(area.end);
+        }
+        if candidate + size <= to {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    // highest gap of at least size bytes between from and to, so that whatever grows up from from keeps its room
+    pub fn find_free_below(&self, size: u64, from: u64, to: u64) -> Option<u64> {
+        let mut candidate = to.checked_sub(size)?;
+        for area in self.areas.iter().rev().filter(|area| area.end > from && area.start < to) {
+            if area.end <= candidate {
+                break;
+            }
+            candidate = area.start.checked_sub(size)?;
+        }
+        if candidate >= from {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    // takes [start, end) out of the areas, which have to cover all of it and all be removable,
+    // and returns the pieces that were taken out
+    pub fn remove_range<F>(&mut self, start: u64, end: u64, removable: F) -> Result<Vec<VirtualMemoryArea>, Error>
+    where F: Fn(&VirtualMemoryArea) -> bool {
+        let mut covered = start;
+        for area in self.areas.iter().filter(|area| area.overlaps(start, end)) {
+            if !removable(area) || area.start > covered {
+                return Err(Error::InvalidAddress);
+            }
+            covered = area.end;
+        }
+        if covered < end {
+            return Err(Error::InvalidAddress);
+        }
+        let mut kept = Vec::new();
+        let mut removed = Vec::new();
+        self.areas.retain(|area| {
+            if !area.overlaps(start, end) {
+                return true;
+            }
+            if area.start < start {
+                kept.push(area.slice(area.start, start));
+            }
+            if area.end > end {
+                kept.push(area.slice(end, area.end));
+            }
+            removed.push(area.slice(start, end));
+            false
+        });
+        for area in kept {
+            self.insert(area)?;
+        }
+        Ok(removed)
+    }
+
+    // maps a zeroed or file backed frame under a page that is part of an area but has not been touched yet,
+    // or gives a copy-on-write page a frame of its own when it is written to
+    pub fn populate(&self, page_table: &mut PageTable, addr: u64, write: bool) -> Result<(), Error> {
+        let area = self.find(addr).ok_or(Error::InvalidAddress)?;
+        if area.kind == AreaKind::Guard || (write && !area.writable) {
+            return Err(Error::InvalidAddress);
+        }
+        let page = page_mapper::align(addr);
+        if let Some(entry) = page_mapper::get_l1_entry_using_table(page_table, page) {
+            if write && entry.flags().contains(COPY_ON_WRITE) {
+                copy_on_write(entry, page);
+                return Ok(());
+            }
+            if write && entry.flags().contains(PageTableFlags::WRITABLE) {
+                // another thread got here first, this cpu still had the read-only entry cached
+                tlb::flush(VirtAddr::new(page));
+                return Ok(());
+            }
+            // already there, so this was an access the page does not allow
+            return Err(Error::Permissions);
+        }
+        let frame = match area.file.as_ref() {
+            Some(backing) => {
+                let offset = backing.offset + (page - area.start);
+                if backing.shared {
+                    backing.file.get_shared(offset)?
+                } else {
+                    backing.file.read_private(offset)?
+                }
+            },
+            None => page_mapper::new_frame_zeroed(),
+        };
+        page_mapper::map_user_page(page_table, page, frame, area.page_flags());
+        Ok(())
+    }
+
+    // maps every page touched so far into child_page_table as well, both sides lose write access
+    // until they write and get a copy of their own, except in shared file mappings which stay shared
+    pub fn share_copy_on_write(&self, page_table: &mut PageTable, child_page_table: &mut PageTable) {
+        for area in self.areas.iter().filter(|area| area.kind != AreaKind::Guard) {
+            for page in (area.start..area.end).step_by(0x1000) {
+                let entry = match page_mapper::get_l1_entry_using_table(page_table, page) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let frame = entry.addr().as_u64();
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) && !area.is_shared_file() {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                unsafe { FRAME_ALLOCATOR.share_frame(frame); }
+                page_mapper::map_user_page(child_page_table, page, frame, flags);
+            }
+        }
+        // the parent's threads may still have writable entries cached elsewhere
+        smp::shoot_down_tlb(None);
+    }
+}
+
+// writes the pages of a shared file mapping that were written to back to the file,
+// the caller flushes the tlb so that the next write marks them dirty again
+fn write_back(area: &VirtualMemoryArea, page_table: &PageTable) -> Result<(), Error> {
+    let backing = match area.file.as_ref() {
+        Some(backing) if backing.shared => backing,
+        _ => return Ok(()),
+    };
+    for page in (area.start..area.end).step_by(0x1000) {
+        if let Some(entry) = page_mapper::get_l1_entry_using_table(page_table, page) {
+            if entry.flags().contains(PageTableFlags::DIRTY) {
+                backing.file.write_back(backing.offset + (page - area.start), entry.addr().as_u64())?;
+                entry.set_flags(entry.flags() - PageTableFlags::DIRTY);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn copy_on_write(entry: &mut PageTableEntry, page: u64) {
+    let frame = entry.addr().as_u64();
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if unsafe { FRAME_ALLOCATOR.is_frame_shared(frame) } {
+        let copy = unsafe { FRAME_ALLOCATOR.allocate_frame() };
+        unsafe { ptr::copy_nonoverlapping((frame + PHYSICAL_MEMORY_OFFSET) as *const u8, (copy + PHYSICAL_MEMORY_OFFSET) as *mut u8, 0x1000); }
+        entry.set_addr(PhysAddr::new(copy), flags);
+        page_mapper::free_frame(frame);
+        // threads of the process on other cpus must not keep reading the old frame
+        smp::shoot_down_tlb(Some(page));
+    } else {
+        // everyone else has copied already
+        entry.set_flags(flags);
+        tlb::flush(VirtAddr::new(page));
+    }
+}
+
+// resolves a fault on a lazily allocated page, as long as page_table belongs to the current process
+pub fn populate_current(page_table: &PageTable, addr: u64, write: bool) -> Result<(), Error> {
+    let process = scheduler::get_process(scheduler::try_current_process().ok_or(Error::InvalidAddress)?)?;
+    let page_table_phys = page_table as *const PageTable as u64 - unsafe { super::PHYSICAL_MEMORY_OFFSET };
+    if process.page_table() != page_table_phys || !scheduler::current_task().user_mode {
+        return Err(Error::InvalidAddress);
+    }
+    // two threads faulting on the same page find out who came first under the lock
+    process.with_address_space(|address_space| address_space.populate(page_mapper::addr_to_page_table(page_table_phys), addr, write))
+}
+
+pub fn handle_page_fault(addr: u64, write: bool) -> Result<(), Error> {
+    let process = scheduler::get_process(scheduler::try_current_process().ok_or(Error::InvalidAddress)?)?;
+    populate_current(page_mapper::addr_to_page_table(process.page_table()), addr, write)
+}
+
+fn current_user_process() -> Result<scheduler::ProcessRef, Error> {
+    if !scheduler::current_task().user_mode {
+        return Err(Error::Permissions);
+    }
+    scheduler::get_process(scheduler::current_process())
+}
+
+// the pages are only backed by frames once they are touched
+pub fn map_anonymous(address: Option<u64>, size: u64, writable: bool, executable: bool) -> Result<u64, Error> {
+    if size == 0 || size > MAX_ANONYMOUS_SIZE {
+        return Err(Error::InvalidData);
+    }
+    let size = page_mapper::align(size + 0xFFF);
+    let process = current_user_process()?;
+    process.with_address_space(|address_space| {
+        let start = match address {
+            Some(address) => {
+                if address % 0x1000 != 0 || address < ANONYMOUS_START || address.checked_add(size).map_or(true, |end| end > ANONYMOUS_END) {
+                    return Err(Error::InvalidAddress);
+                }
+                address
+            },
+            None => address_space.find_free_below(size, ANONYMOUS_START, ANONYMOUS_END).ok_or(Error::OutOfSpace)?,
+        };
+        address_space.insert(VirtualMemoryArea::new(start, start + size, writable, executable, AreaKind::Anonymous))?;
+        Ok(start)
+    })
+}
+
+// pages are read from the file when they are first touched, the mapping keeps the file open until it is unmapped
+pub fn map_file(handle: u32, offset: u64, size: u64, writable: bool, executable: bool, shared: bool) -> Result<u64, Error> {
+    if size == 0 || size > MAX_ANONYMOUS_SIZE {
+        return Err(Error::InvalidData);
+    }
+    if offset % 0x1000 != 0 {
+        return Err(Error::InvalidAddress);
+    }
+    let size = page_mapper::align(size + 0xFFF);
+    let mut process = current_user_process()?;
+    let owner = scheduler::current_process();
+    let handle = process.handles.get_mut(handle).ok_or(Error::InvalidHandle)?;
+    if let namespace::ResourceType::File(file) = handle.unwrap().unwrap() {
+        if shared && writable && !file.permissions().contains(file::FilePermissions::WRITE) {
+            return Err(Error::Permissions);
+        }
+    }
+    let file = MappedFile::get(handle, owner)?;
+    process.with_address_space(|address_space| {
+        let start = address_space.find_free_below(size, ANONYMOUS_START, ANONYMOUS_END).ok_or(Error::OutOfSpace)?;
+        address_space.insert(VirtualMemoryArea::file(start, start + size, writable, executable, FileBacking {
+            file,
+            offset,
+            shared,
+        }))?;
+        Ok(start)
+    })
+}
+
+// unmaps anonymous memory and file mappings, dirty pages of shared file mappings go back to the file first
+pub fn unmap(address: u64, size: u64) -> Result<(), Error> {
+    if address % 0x1000 != 0 || size == 0 || size > MAX_ANONYMOUS_SIZE {
+        return Err(Error::InvalidAddress);
+    }
+    let end = address.checked_add(page_mapper::align(size + 0xFFF)).ok_or(Error::InvalidAddress)?;
+    let process = current_user_process()?;
+    let page_table = page_mapper::addr_to_page_table(process.page_table());
+    process.with_address_space(|address_space| {
+        let removed = address_space.remove_range(address, end, |area| area.kind == AreaKind::Anonymous || area.kind == AreaKind::File)?;
+        let mut frames = Vec::new();
+        for area in removed.iter() {
+            // the mapping goes away either way, like it would if the process exited
+            let _ = write_back(area, page_table);
+            for page in (area.start..area.end).step_by(0x1000) {
+                if let Some(frame) = page_mapper::translate_addr_using_table(page_table, page as usize) {
+                    page_mapper::unmap_addr(page_table, page);
+                    frames.push(frame);
+                }
+            }
+        }
+        // other threads of the process may be running elsewhere, and must be done with the frames before they are reused
+        smp::shoot_down_tlb(None);
+        for frame in frames {
+            page_mapper::free_frame(frame);
+        }
+        Ok(())
+    })
+}
+
+// writes the dirty pages of the shared file mappings in [address, address + size) back to their files
+pub fn sync(address: u64, size: u64) -> Result<(), Error> {
+    if address % 0x1000 != 0 || size == 0 || size > MAX_ANONYMOUS_SIZE {
+        return Err(Error::InvalidAddress);
+    }
+    let end = address.checked_add(page_mapper::align(size + 0xFFF)).ok_or(Error::InvalidAddress)?;
+    let process = current_user_process()?;
+    let page_table = page_mapper::addr_to_page_table(process.page_table());
+    process.with_address_space(|address_space| {
+        let mut result = Ok(());
+        for area in address_space.areas().iter().filter(|area| area.overlaps(address, end)) {
+            result = result.and(write_back(&area.slice(address, end), page_table));
+        }
+        smp::shoot_down_tlb(None);
+        result
+    })
+}
+
+// for a process that is going away, its shared file mappings are written back and the files let go
+pub fn release(address_space: &mut AddressSpace, page_table: &PageTable) {
+    for area in address_space.areas() {
+        let _ = write_back(area, page_table);
+    }
+    *address_space = AddressSpace::new();
+}
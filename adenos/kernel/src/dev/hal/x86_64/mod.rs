@@ -11,8 +11,10 @@ pub mod acpi;
 pub mod pic;
 pub mod apic;
 pub mod cpu;
+pub mod smp;
 pub mod mem;
 pub mod pci;
+pub mod rtc;
 
 pub fn init() {
     early_print!("x86_64 ");
@@ -20,8 +22,14 @@ pub fn init() {
     pic::init();
     mem::init();
     acpi::init();
+    match interrupts::init_apic() {
+        Ok(()) => early_print!("APIC "),
+        Err(_) => early_print!("PIC "),
+    }
     unsafe {
         namespace::register_resource(dev::char::KernelLogger::new());
+        namespace::register_resource(mem::heap::KernelHeap::new());
+        namespace::register_resource(rtc::RealTimeClock::new());
         namespace::register_resource(kernel_console::EARLY_FRAMEBUFFER.take().unwrap());
         let fb = kernel_console::FRAMEBUFFER.insert(namespace::get_resource(String::from("/Devices/Framebuffer/VesaVbeFramebuffer")).unwrap());
         namespace::register_resource(kernel_console::EARLY_KERNEL_CONSOLE.take().unwrap());
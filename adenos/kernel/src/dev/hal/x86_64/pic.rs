@@ -20,6 +20,11 @@ pub const PIC_SLAVE_OFFSET: u8 = PIC_MASTER_OFFSET + 0x08;
 
 const END_OF_INTERRUPT: u8 = 0x20;
 
+const PIT_FREQUENCY: u64 = 1193180;
+const PIT_COMMAND_PORT: u16 = 0x43;
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_GATE_PORT: u16 = 0x61;
+
 pub fn init() {
     let mut master_cmd: port::Port = port::Port::new(PIC_MASTER_PORT);
     let mut master_data: port::Port = port::Port::new(PIC_MASTER_PORT + 1);
@@ -53,7 +58,7 @@ pub fn init() {
     for _i in 2..16 {
         //mask(i);
     }
-    let mut pit_cmd: port::Port = port::Port::new(0x43);
+    let mut pit_cmd: port::Port = port::Port::new(PIT_COMMAND_PORT);
     let mut pit_data: port::Port = port::Port::new(0x40);
     let divisor: u16 = (1193180 / 1000) as u16;
     cpu::atomic_no_interrupts(|| {
@@ -101,4 +106,28 @@ pub fn end_of_interrupt(interrupt_id: interrupts::HardwareInterrupt) {
         write_one!(port::Port::new(PIC_SLAVE_PORT), END_OF_INTERRUPT).unwrap();
     }
     write_one!(port::Port::new(PIC_MASTER_PORT), END_OF_INTERRUPT).unwrap();
-}
\ No newline at end of file
+}
+
+// spins on PIT channel 2 in one-shot mode, so the system timer on channel 0 keeps running
+pub fn busy_wait(microseconds: u64) {
+    let mut pit_cmd: port::Port = port::Port::new(PIT_COMMAND_PORT);
+    let mut pit_data: port::Port = port::Port::new(PIT_CHANNEL_2_PORT);
+    let mut gate: port::Port = port::Port::new(PIT_GATE_PORT);
+    let mut remaining = microseconds;
+    while remaining > 0 {
+        // a 16 bit count runs out after about 54 ms
+        let chunk = remaining.min(50_000);
+        let count = (PIT_FREQUENCY * chunk / 1_000_000).max(1) as u16;
+        // gate on, speaker off
+        let gate_val = (read_one!(gate).unwrap() & !0x02) | 0x01;
+        write_one!(gate, gate_val).unwrap();
+        write_one!(pit_cmd, 0xB0).unwrap();
+        write_one!(pit_data, (count & 0xFF) as u8).unwrap();
+        write_one!(pit_data, ((count >> 8) & 0xFF) as u8).unwrap();
+        // output goes high on terminal count
+        while read_one!(gate).unwrap() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        remaining -= chunk;
+    }
+}
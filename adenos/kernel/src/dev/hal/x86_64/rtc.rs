@@ -0,0 +1,129 @@
+use crate::*;
+use dev::*;
+use x86_64::instructions::{interrupts, port::Port};
+use alloc::{format, string::String, vec, vec::Vec};
+use infinity::os::DateTime;
+use spin::Mutex;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// set along with the register number, so that no NMI arrives while one is selected
+const NMI_DISABLE: u8 = 0x80;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const BINARY_MODE: u8 = 0x04;
+const HOURS_24: u8 = 0x02;
+const HOUR_PM: u8 = 0x80;
+
+// the selected register is shared by everyone reading the CMOS
+static CMOS: Mutex<(Port<u8>, Port<u8>)> = Mutex::new((Port::new(CMOS_ADDRESS), Port::new(CMOS_DATA)));
+
+fn read_register(cmos: &mut (Port<u8>, Port<u8>), register: u8) -> u8 {
+    unsafe {
+        cmos.0.write(NMI_DISABLE | register);
+        cmos.1.read()
+    }
+}
+
+fn read_registers(cmos: &mut (Port<u8>, Port<u8>)) -> [u8; 6] {
+    while read_register(cmos, REGISTER_STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    [REGISTER_SECONDS, REGISTER_MINUTES, REGISTER_HOURS, REGISTER_DAY, REGISTER_MONTH, REGISTER_YEAR].map(|register| read_register(cmos, register))
+}
+
+// the clock keeps the time of day in whatever the firmware set it to, UTC as far as we are concerned
+pub fn read_time() -> DateTime {
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        // an update can still start halfway through, so read until two reads agree
+        let mut raw = read_registers(&mut cmos);
+        loop {
+            let again = read_registers(&mut cmos);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(&mut cmos, REGISTER_STATUS_B))
+    });
+    let binary = |value: u8| if status_b & BINARY_MODE != 0 {
+        value
+    } else {
+        (value >> 4) * 10 + (value & 0x0F)
+    };
+    let mut hour = binary(raw[2] & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight
+        hour = hour % 12 + if raw[2] & HOUR_PM != 0 { 12 } else { 0 };
+    }
+    DateTime {
+        // where the century register lives is up to the firmware, so assume this one
+        year: 2000 + binary(raw[5]) as u16,
+        month: binary(raw[4]),
+        day: binary(raw[3]),
+        hour,
+        minute: binary(raw[1]),
+        second: binary(raw[0]),
+    }
+}
+
+#[derive(Debug)]
+pub struct RealTimeClock {
+    report: Option<String>,
+    offset: usize,
+}
+
+impl RealTimeClock {
+    pub const fn new() -> RealTimeClock {
+        RealTimeClock {
+            report: None,
+            offset: 0,
+        }
+    }
+}
+
+impl Device for RealTimeClock {
+    fn device_path(&self) -> Vec<String> {
+        vec![String::from("System"), String::from("RealTimeClock")]
+    }
+
+    fn is_in_use(&self) -> bool {
+        false
+    }
+
+    fn unwrap(&mut self) -> DeviceClass {
+        DeviceClass::ReadWriteDevice(self)
+    }
+}
+
+impl Read for RealTimeClock {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let report = self.report.get_or_insert_with(|| {
+            let time = read_time();
+            format!("{}-{:02}-{:02} {:02}:{:02}:{:02}\n", time.year, time.month, time.day, time.hour, time.minute, time.second)
+        });
+        if self.offset >= report.len() {
+            self.report = None;
+            self.offset = 0;
+            return Err(Error::EndOfFile);
+        }
+        let count = buf.len().min(report.len() - self.offset);
+        buf[..count].copy_from_slice(&report.as_bytes()[self.offset..(self.offset + count)]);
+        self.offset += count;
+        Ok(count)
+    }
+}
+
+impl Write for RealTimeClock {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::Permissions)
+    }
+}
@@ -0,0 +1,374 @@
+use crate::*;
+use dev::hal::{cpu, pic, task, interrupts, mem::{self, page_mapper}, apic::lapic};
+use exec::scheduler;
+use alloc::{vec, vec::Vec};
+use core::{arch::global_asm, hint, ptr, sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use x86_64::{structures::{paging::PageTableFlags, idt::InterruptStackFrame}, instructions::tlb, VirtAddr};
+
+pub const MAX_PROCESSORS: usize = 64;
+// the startup code has to live below 1 MiB, the SIPI vector is its page number
+pub const TRAMPOLINE_ADDRESS: u64 = 0x70000;
+pub const RESCHEDULE_VECTOR: u8 = 0xF0;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF1;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const AP_STACK_SIZE: usize = 0x4000;
+// microseconds
+const INIT_DELAY: u64 = 10_000;
+const STARTUP_DELAY: u64 = 200;
+const STARTUP_TIMEOUT: u64 = 100_000;
+const NO_OWNER: usize = usize::MAX;
+const FLUSH_ALL: u64 = u64::MAX;
+
+// real mode entry of the application processors, copied to TRAMPOLINE_ADDRESS with the fields at the end filled in
+global_asm!("
+.section .text
+.global smp_trampoline_start
+.global smp_trampoline_end
+.global smp_trampoline_page_table
+.global smp_trampoline_stack
+.global smp_trampoline_entry
+.global smp_trampoline_cpu
+.code16
+smp_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    // PAE and global pages
+    mov eax, cr4
+    or eax, 0xA0
+    mov cr4, eax
+    mov eax, dword ptr [smp_trampoline_page_table - smp_trampoline_start]
+    mov cr3, eax
+    // long mode and no-execute
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 0x900
+    wrmsr
+    lgdt [smp_trampoline_gdtr - smp_trampoline_start]
+    // protected mode and paging at once
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+    // jmp dword 0x08:smp_trampoline_long_mode
+    .byte 0x66, 0xEA
+    .long {trampoline} + (smp_trampoline_long_mode - smp_trampoline_start)
+    .word 0x08
+.code64
+smp_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, qword ptr [rip + smp_trampoline_stack]
+    mov rdi, qword ptr [rip + smp_trampoline_cpu]
+    mov rax, qword ptr [rip + smp_trampoline_entry]
+    call rax
+    ud2
+.align 8
+smp_trampoline_page_table:
+    .quad 0
+smp_trampoline_stack:
+    .quad 0
+smp_trampoline_entry:
+    .quad 0
+smp_trampoline_cpu:
+    .quad 0
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+smp_trampoline_gdtr:
+    .word 23
+    .long {trampoline} + (smp_trampoline_gdt - smp_trampoline_start)
+smp_trampoline_end:
+", trampoline = const TRAMPOLINE_ADDRESS);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_page_table: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_entry: u8;
+    static smp_trampoline_cpu: u8;
+}
+
+pub struct Processor {
+    pub acpi_id: u8,
+    pub lapic_id: u8,
+    online: AtomicBool,
+    // set by whoever asks for a shootdown, cleared once this cpu has flushed
+    shootdown_pending: AtomicBool,
+}
+
+impl Processor {
+    fn new(acpi_id: u8, lapic_id: u8) -> Processor {
+        Processor {
+            acpi_id,
+            lapic_id,
+            online: AtomicBool::new(false),
+            shootdown_pending: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+// spin lock that the cpu holding it may take again, interrupts have to be off while it is held
+pub struct ProcessorLock {
+    owner: AtomicUsize,
+    depth: AtomicUsize,
+}
+
+impl ProcessorLock {
+    pub const fn new() -> ProcessorLock {
+        ProcessorLock {
+            owner: AtomicUsize::new(NO_OWNER),
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lock(&self) {
+        let cpu = current_cpu();
+        if self.owner.load(Ordering::Acquire) == cpu {
+            self.depth.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        while let Err(_) = self.owner.compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed) {
+            // whoever holds it might be waiting for us to flush
+            service_shootdown();
+            hint::spin_loop();
+        }
+        self.depth.store(1, Ordering::Relaxed);
+    }
+
+    pub fn unlock(&self) {
+        if self.depth.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.owner.store(NO_OWNER, Ordering::Release);
+        }
+    }
+}
+
+static mut PROCESSORS: Vec<Processor> = Vec::new();
+static SCHEDULER_STARTED: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+// the bootstrap processor always goes first, so it keeps index 0 once the others are counted
+pub fn register_processor(acpi_id: u8, lapic_id: u8) {
+    unsafe {
+        if PROCESSORS.len() >= MAX_PROCESSORS {
+            return;
+        }
+        let processor = Processor::new(acpi_id, lapic_id);
+        match lapic::local() {
+            Some(lapic) if lapic.id() == lapic_id => PROCESSORS.insert(0, processor),
+            _ => PROCESSORS.push(processor),
+        }
+    }
+}
+
+pub fn processors() -> &'static [Processor] {
+    unsafe { PROCESSORS.as_slice() }
+}
+
+pub fn processor_count() -> usize {
+    processors().len().max(1)
+}
+
+// index into processors(), the bootstrap processor is always 0
+pub fn current_cpu() -> usize {
+    let processors = processors();
+    if processors.len() <= 1 {
+        return 0;
+    }
+    match lapic::local() {
+        Some(lapic) => {
+            let id = lapic.id();
+            processors.iter().position(|cpu| cpu.lapic_id == id).unwrap_or(0)
+        },
+        None => 0,
+    }
+}
+
+fn other_online_processors() -> impl Iterator<Item = (usize, &'static Processor)> {
+    let current = current_cpu();
+    processors().iter().enumerate().filter(move |(cpu, processor)| *cpu != current && processor.is_online())
+}
+
+unsafe fn write_trampoline_field(field: &u8, value: u64) {
+    let offset = field as *const u8 as u64 - &smp_trampoline_start as *const u8 as u64;
+    ptr::write_volatile((TRAMPOLINE_ADDRESS + offset + mem::PHYSICAL_MEMORY_OFFSET) as *mut u64, value);
+}
+
+fn wait_online(cpu: usize, microseconds: u64) -> bool {
+    let mut waited = 0;
+    while !processors()[cpu].is_online() && waited < microseconds {
+        pic::busy_wait(10);
+        waited += 10;
+    }
+    processors()[cpu].is_online()
+}
+
+// has to run after the scheduler is initialized and before it is enabled
+pub fn start_application_processors() -> Result<(), Error> {
+    let lapic = lapic::local().ok_or(Error::InitFailure)?;
+    let processors = unsafe { &mut PROCESSORS };
+    if processors.is_empty() {
+        processors.push(Processor::new(0, lapic.id()));
+    }
+    processors[0].online.store(true, Ordering::Release);
+    cpu::register_vector_handler_addr(RESCHEDULE_VECTOR, task::reschedule_handler_save_context as u64);
+    cpu::register_vector_handler(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_handler);
+    cpu::register_vector_handler(SPURIOUS_VECTOR, spurious_handler);
+    if processors.len() == 1 {
+        return Ok(());
+    }
+    let kernel_page_table = unsafe { mem::KERNEL_PAGE_TABLE };
+    // loaded while still in real mode, so it only gets 32 bits
+    if kernel_page_table > u32::MAX as u64 {
+        return Err(Error::InitFailure);
+    }
+    for _ in 1..processors.len() {
+        scheduler::add_processor();
+    }
+    let l4_table = page_mapper::addr_to_page_table(kernel_page_table);
+    unsafe {
+        // paging is switched on before the far jump, so the trampoline has to be identity mapped
+        page_mapper::map_addr(l4_table, TRAMPOLINE_ADDRESS, TRAMPOLINE_ADDRESS, Some(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+        let start = &smp_trampoline_start as *const u8;
+        let size = &smp_trampoline_end as *const u8 as usize - start as usize;
+        ptr::copy_nonoverlapping(start, (TRAMPOLINE_ADDRESS + mem::PHYSICAL_MEMORY_OFFSET) as *mut u8, size);
+        write_trampoline_field(&smp_trampoline_page_table, kernel_page_table);
+        write_trampoline_field(&smp_trampoline_entry, application_processor_main as u64);
+    }
+    for cpu in 1..processors.len() {
+        let stack = vec![0u8; AP_STACK_SIZE].leak();
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+        unsafe {
+            write_trampoline_field(&smp_trampoline_stack, stack_top);
+            write_trampoline_field(&smp_trampoline_cpu, cpu as u64);
+        }
+        let lapic_id = processors[cpu].lapic_id;
+        lapic.send_init(lapic_id);
+        pic::busy_wait(INIT_DELAY);
+        lapic.send_startup(lapic_id, (TRAMPOLINE_ADDRESS >> 12) as u8);
+        // the second startup is only for cpus that missed the first one
+        if !wait_online(cpu, STARTUP_DELAY) {
+            lapic.send_startup(lapic_id, (TRAMPOLINE_ADDRESS >> 12) as u8);
+        }
+        if wait_online(cpu, STARTUP_TIMEOUT) {
+            serial_println!("CPU {} (LAPIC {}) online", cpu, lapic_id);
+        } else {
+            serial_println!("CPU {} (LAPIC {}) did not start", cpu, lapic_id);
+        }
+    }
+    page_mapper::unmap_addr(l4_table, TRAMPOLINE_ADDRESS);
+    tlb::flush(VirtAddr::new(TRAMPOLINE_ADDRESS));
+    Ok(())
+}
+
+extern "C" fn application_processor_main(cpu: usize) -> ! {
+    cpu::init_application_processor();
+    if let Some(lapic) = lapic::local() {
+        lapic.enable();
+    }
+    if let Err(err) = interrupts::init_application_processor() {
+        serial_println!("CPU {} has no timer: {:?}", cpu, err);
+    }
+    processors()[cpu].online.store(true, Ordering::Release);
+    while !SCHEDULER_STARTED.load(Ordering::Acquire) {
+        hint::spin_loop();
+    }
+    scheduler::context_switch(None, false);
+    cpu::grinding_halt()
+}
+
+// lets the application processors pick their first threads
+pub fn release_application_processors() {
+    SCHEDULER_STARTED.store(true, Ordering::Release);
+}
+
+pub fn send_reschedule(cpu: usize) {
+    if cpu == current_cpu() || !SCHEDULER_STARTED.load(Ordering::Acquire) {
+        return;
+    }
+    if let (Some(processor), Some(lapic)) = (processors().get(cpu), lapic::local()) {
+        if processor.is_online() {
+            lapic.send_fixed(processor.lapic_id, RESCHEDULE_VECTOR);
+        }
+    }
+}
+
+// with the PIC only the bootstrap processor gets timer interrupts, the local APIC timers tick on their own
+pub fn forward_tick() {
+    if !SCHEDULER_STARTED.load(Ordering::Acquire) || interrupts::controller() == interrupts::InterruptController::APIC {
+        return;
+    }
+    if let Some(lapic) = lapic::local() {
+        for (_, processor) in other_online_processors() {
+            lapic.send_fixed(processor.lapic_id, RESCHEDULE_VECTOR);
+        }
+    }
+}
+
+fn flush_local(address: u64) {
+    if address == FLUSH_ALL {
+        tlb::flush_all();
+    } else {
+        tlb::flush(VirtAddr::new(address));
+    }
+}
+
+// for cpus spinning with interrupts off on something a cpu waiting for their acknowledgement may hold
+pub fn service_shootdown() {
+    if let Some(processor) = processors().get(current_cpu()) {
+        if processor.shootdown_pending.load(Ordering::Acquire) {
+            flush_local(SHOOTDOWN_ADDRESS.load(Ordering::Acquire));
+            processor.shootdown_pending.store(false, Ordering::Release);
+        }
+    }
+}
+
+// flush a page, or everything, on every cpu that might have it cached
+pub fn shoot_down_tlb(address: Option<u64>) {
+    let address = address.unwrap_or(FLUSH_ALL);
+    flush_local(address);
+    if !SCHEDULER_STARTED.load(Ordering::Acquire) {
+        return;
+    }
+    let lapic = match lapic::local() {
+        Some(lapic) => lapic,
+        None => return,
+    };
+    while let Err(_) = SHOOTDOWN_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed) {
+        // the one holding it may be waiting for us, possibly with interrupts off
+        service_shootdown();
+        hint::spin_loop();
+    }
+    SHOOTDOWN_ADDRESS.store(address, Ordering::Release);
+    for (_, processor) in other_online_processors() {
+        processor.shootdown_pending.store(true, Ordering::Release);
+        lapic.send_fixed(processor.lapic_id, TLB_SHOOTDOWN_VECTOR);
+    }
+    while other_online_processors().any(|(_, processor)| processor.shootdown_pending.load(Ordering::Acquire)) {
+        hint::spin_loop();
+    }
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    service_shootdown();
+    if let Some(lapic) = lapic::local() {
+        lapic.end_of_interrupt();
+    }
+}
+
+// spurious interrupts must not be acknowledged
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
@@ -1,14 +1,29 @@
-use crate::{*, exec::{ExecutableInfo, SectionType}};
-use {dev::*, namespace};
+use crate::{*, exec::{ExecutableInfo, Section, SectionType}};
+use {dev::*, namespace::{self, HandleTable}, ipc::shared_memory::{self, SharedMemoryMapping}};
 use core::arch::asm;
-use dev::hal::{cpu, pic, mem::*, interrupts};
+use dev::hal::{cpu, smp, mem::{*, vma::{self, AddressSpace, VirtualMemoryArea, AreaKind}}, interrupts, apic::lapic};
+use core::sync::atomic::{AtomicBool, Ordering};
 use exec::scheduler;
-use x86_64::structures::paging::{PageTableFlags, PageTable};
-use core::slice;
+use x86_64::{structures::paging::PageTable, registers::model_specific::FsBase, VirtAddr};
+use core::{hint, slice};
+use alloc::{vec, vec::Vec, string::String};
+use spin::Mutex;
 
 use super::mem::page_mapper::addr_to_page_table;
 
 const STACK_SIZE: u64 = 0x4000;
+// left unmapped under every user stack so that an overflow faults instead of running into the next one
+const STACK_GUARD_SIZE: u64 = 0x1000;
+const USER_STACK_START: u64 = 0x6000_0000;
+const USER_STACK_END: u64 = shared_memory::SHARED_MEMORY_BASE;
+// room at the top of a new main thread's stack for arguments and environment
+pub const MAX_STARTUP_BLOCK_SIZE: usize = 0x1000;
+// where the thread pointer points, a pointer to itself and a word the user runtime keeps its per-thread data in
+const THREAD_CONTROL_BLOCK_SIZE: u64 = 0x10;
+
+const NOT_YIELDING: AtomicBool = AtomicBool::new(false);
+// per cpu, a yield on one must not be mistaken for a tick on another
+static YIELDING: [AtomicBool; smp::MAX_PROCESSORS] = [NOT_YIELDING; smp::MAX_PROCESSORS];
 
 #[repr(C, align(2))]
 #[derive(Debug, Clone)]
@@ -87,25 +102,105 @@ impl TaskContext {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Process {
     page_table: u64,
     pub threads: Vec<u32>,
+    pub shared_memory: Vec<SharedMemoryMapping>,
+    // taken through with_address_space, the page table under the areas changes along with them
+    pub address_space: Mutex<AddressSpace>,
+    // areas of reaped threads, the scheduler cannot wait for the address space to take them out itself
+    released_areas: Mutex<Vec<u64>>,
+    pub handles: HandleTable,
+    pub parent: Option<u32>,
+    pub exit_code: Option<i32>,
+    pub zombie: bool,
+    pub joiner: Option<u32>,
+    // the executable asked for it with PT_GNU_STACK
+    pub executable_stack: bool,
+    // the image every thread's thread-local storage starts out as
+    pub thread_local: Option<Section>,
 }
 
 impl Process {
-    pub fn new(page_table: u64) -> Process {
+    pub fn new(page_table: u64, process_id: u32) -> Process {
+        let mut handles = HandleTable::new(process_id);
+        // the standard handles of whoever creates the process
+        handles.inherit(namespace::handle_table());
         Process {
             page_table,
             threads: Vec::new(),
+            shared_memory: Vec::new(),
+            address_space: Mutex::new(AddressSpace::new()),
+            released_areas: Mutex::new(Vec::new()),
+            handles,
+            parent: scheduler::try_current_process(),
+            exit_code: None,
+            zombie: false,
+            joiner: None,
+            executable_stack: false,
+            thread_local: None,
+        }
+    }
+
+    pub fn page_table(&self) -> u64 {
+        self.page_table
+    }
+
+    // interrupts stay off while it is held, so that its holder cannot be preempted by a fault on the same cpu
+    // that then waits for it forever
+    pub fn with_address_space<R, F>(&self, f: F) -> R
+    where F: FnOnce(&mut AddressSpace) -> R {
+        let enabled = cpu::intflag();
+        cpu::disable_interrupts();
+        let mut address_space = loop {
+            if let Some(address_space) = self.address_space.try_lock() {
+                break address_space;
+            }
+            // the holder may be waiting for this cpu to flush its tlb
+            smp::service_shootdown();
+            hint::spin_loop();
+        };
+        for start in self.released_areas.lock().drain(..) {
+            address_space.remove(start);
+        }
+        let result = f(&mut address_space);
+        drop(address_space);
+        if enabled {
+            cpu::enable_interrupts();
         }
+        result
+    }
+
+    // the stack frames themselves are freed by the thread, the areas go on the next with_address_space
+    pub fn release_stack(&mut self, stack_base: u64) {
+        self.released_areas.lock().extend([stack_base, stack_base - STACK_GUARD_SIZE]);
+    }
+
+    // the frames themselves are freed by the thread
+    pub fn release_thread_local(&mut self, start: u64) {
+        self.released_areas.lock().push(start);
     }
 
     #[inline(always)]
-    pub fn die(&self) {
+    pub fn die(&mut self) {
+        self.handles.release_all();
         unsafe {
             enable_page_table(addr_to_page_table(KERNEL_PAGE_TABLE));
         }
+        // shared frames are not ours to free, take them out before tearing down the tables
+        for mapping in self.shared_memory.drain(..) {
+            mapping.unmap(addr_to_page_table(self.page_table));
+        }
+        // no thread is left that could be holding it
+        vma::release(self.address_space.get_mut(), addr_to_page_table(self.page_table));
+        page_mapper::unmap_userspace_page_tables(self.page_table);
+    }
+
+    // for a process that never got to run, its tables were never loaded on any cpu
+    pub fn discard(&mut self) {
+        self.handles.release_all();
+        vma::release(self.address_space.get_mut(), addr_to_page_table(self.page_table));
         page_mapper::unmap_userspace_page_tables(self.page_table);
     }
 }
@@ -117,9 +212,20 @@ pub struct Task {
     pub stack_base: u64,
     pub stack_size: u64,
     pub process_id: u32,
+    pub user_mode: bool,
     pub zombie: bool,
     pub suspended: bool,
     pub joiner: Option<u32>,
+    pub priority: scheduler::Priority,
+    pub time_slice: u32,
+    // the cpu whose run queue it goes back into
+    pub cpu: usize,
+    // what the thread is allocating kernel memory for right now
+    pub subsystem: heap::Subsystem,
+    // saved on every context switch, user threads point it at their thread control block
+    pub fs_base: u64,
+    // the thread-local storage area of a user thread, freed along with its stack
+    pub thread_local: Option<(u64, u64)>,
 }
 
 impl Task {
@@ -132,10 +238,17 @@ impl Task {
             page_table,
             stack_base,
             stack_size: stack_top - stack_base,
+            user_mode,
             zombie: false,
             suspended: false,
             process_id,
             joiner: None,
+            priority: scheduler::Priority::Normal,
+            time_slice: scheduler::Priority::Normal.time_slice(),
+            cpu: 0,
+            subsystem: heap::Subsystem::Kernel,
+            fs_base: 0,
+            thread_local: None,
         }
     }
 
@@ -145,13 +258,30 @@ impl Task {
             let val = self.page_table | 0x18;
             asm!("mov cr3, {0}", in(reg) val);
         }
+        FsBase::write(VirtAddr::new(self.fs_base));
+    }
+
+    #[inline(always)]
+    pub fn save_state(&mut self, state: TaskContext) {
+        self.state = state;
+        self.fs_base = FsBase::read().as_u64();
+    }
+
+    // only for the thread running on this cpu, which must not be switched out halfway
+    pub fn set_fs_base(&mut self, address: u64) {
+        cpu::atomic_no_interrupts(|| {
+            self.fs_base = address;
+            FsBase::write(VirtAddr::new(address));
+        });
     }
 
     #[inline(always)]
     pub fn die(&self) {
         let page_table = unsafe { ((self.page_table + PHYSICAL_MEMORY_OFFSET ) as *mut PageTable).as_mut().unwrap() };
-        // free up memory used as stack
-        for i in (self.stack_base..(self.stack_base + self.stack_size)).step_by(0x1000) {
+        // free up memory used as stack and thread-local storage
+        let stack = (self.stack_base..(self.stack_base + self.stack_size)).step_by(0x1000);
+        let thread_local = self.thread_local.map_or(0..0, |(start, end)| start..end).step_by(0x1000);
+        for i in stack.chain(thread_local) {
             if let Some(tran) = page_mapper::translate_addr_using_table(page_table, i as usize) {
                 page_mapper::unmap_addr(page_table, i);
                 unsafe {
@@ -161,79 +291,320 @@ impl Task {
         }
     }
 
-    pub unsafe fn exec(application: ExecutableInfo, process_id: u32) -> Result<(), Error> {
+    pub unsafe fn exec(application: ExecutableInfo, interpreter: Option<ExecutableInfo>, process_id: u32, args: &[String], env: &[String]) -> Result<(), Error> {
+        if application.interpreter.is_some() != interpreter.is_some() {
+            return Err(Error::InvalidExecutable);
+        }
         asm!("cli");
         let user_page_table = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
         let user_page_table_phys = (user_page_table as *const _ as u64) - PHYSICAL_MEMORY_OFFSET;
-        let flags = Some(PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE);
+        // create process
+        let mut process = Process::new(user_page_table_phys, process_id);
+        process.executable_stack = application.executable_stack;
+        process.thread_local = application.thread_local;
+        if let Err(err) = scheduler::add_process(process_id, process) {
+            page_mapper::unmap_userspace_page_tables(user_page_table_phys);
+            asm!("sti");
+            return Err(err);
+        }
+        if let Err(err) = Self::exec_process(user_page_table, process_id, &application, interpreter.as_ref(), args, env) {
+            // none of it ever ran, so whatever was mapped so far goes along with the tables
+            if let Some(mut process) = scheduler::remove_process(process_id) {
+                process.discard();
+            }
+            asm!("sti");
+            return Err(err);
+        }
+        Ok(())
+    }
 
-        if let Some(file) = namespace::get_file_handle(application.file_handle) {
-            for section in application.sections { // load all sections and prepare memory
-                if section.section_type != SectionType::Load {
-                    return Err(Error::InvalidExecutable); // so far we have no dynamic linking and interpreter support
-                }
+    unsafe fn exec_process(user_page_table: &mut PageTable, process_id: u32, application: &ExecutableInfo, interpreter: Option<&ExecutableInfo>, args: &[String], env: &[String]) -> Result<(), Error> {
+        scheduler::get_process(process_id)?.with_address_space(|address_space| {
+            Self::load_image(user_page_table, address_space, application)?;
+            // a fixed executable reaching into the interpreter's range fails here as an overlap
+            if let Some(interpreter) = interpreter {
+                Self::load_image(user_page_table, address_space, interpreter)?;
+            }
+            Ok(())
+        })?;
+        // create main thread, in the interpreter if there is one
+        let entry_point = interpreter.map_or(application.virt_entry_point, |interpreter| interpreter.virt_entry_point);
+        scheduler::add_thread(process_id, Self::exec_thread(entry_point as u64, process_id, args, env, &application.auxiliary_vector(interpreter))?)?;
+        Ok(())
+    }
 
-                let virt_base = section.virt_address as u64;
-                let mut bytes_left = section.size_in_file;
-                file.seek(section.file_offset)?;
-                for i in (0..(section.size_in_memory + 0xFFF) / 0x1000).step_by(0x1000) { // map all pages required and load data
-                    let frame = FRAME_ALLOCATOR.allocate_frame();
-                    page_mapper::map_addr(user_page_table, virt_base + i as u64, frame, flags);
-                    serial_println!("Mapping frame {:x} to page {:x}", frame, virt_base + i as u64);
-                    let frame_buf = (PHYSICAL_MEMORY_OFFSET + frame) as *mut u8;
-                    for j in 0..0x1000 { // clear frame with 0s
-                        *frame_buf.offset(j) = 0;
-                    }
-                    let slice_len = if bytes_left >= 0x1000 {
-                        0x1000
-                    } else {
-                        bytes_left
-                    };
-                    file.read(slice::from_raw_parts_mut(frame_buf, slice_len))?;
-                    if bytes_left >= 0x1000 {
-                        bytes_left -= 0x1000;
-                    } else {
-                        bytes_left = 0;
-                    }
+    // a failed image leaves behind neither its areas nor the pages it mapped
+    unsafe fn load_image(user_page_table: &mut PageTable, address_space: &mut AddressSpace, image: &ExecutableInfo) -> Result<(), Error> {
+        let previous = address_space.clone();
+        let mut mapped = Vec::new();
+        let result = Self::load_image_pages(user_page_table, address_space, image, &mut mapped);
+        if result.is_err() {
+            // the tables were never loaded, so there is nothing to flush
+            for page in mapped {
+                if let Some(frame) = page_mapper::translate_addr_using_table(user_page_table, page as usize) {
+                    page_mapper::unmap_addr(user_page_table, page);
+                    page_mapper::free_frame(frame);
                 }
             }
-            // create process
-            scheduler::add_process(Process::new(user_page_table_phys))?;
-            // create main thread
-            scheduler::add_thread(process_id, Self::exec_thread(application.virt_entry_point as u64, process_id)?);
-            Ok(())
-        } else {
-            Err(Error::EntryNotFound)
+            *address_space = previous;
         }
+        result
     }
 
-    pub unsafe fn exec_thread(entry_point: u64, process_id: u32) -> Result<Task, Error> {
-        asm!("cli");
-        let user_page_table = ((scheduler::get_process(process_id)?.page_table + PHYSICAL_MEMORY_OFFSET) as *mut PageTable).as_mut().unwrap();
-        let user_page_table_phys = (user_page_table as *const _ as u64) - PHYSICAL_MEMORY_OFFSET;
-        let flags = Some(PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE);
-        // prepare stack
-        let mut user_stack_virt_base = 0x60000000;
-        // find location for new stack
-        while let Some(_) = page_mapper::translate_addr(user_stack_virt_base as usize) {
-            user_stack_virt_base += STACK_SIZE;
+    unsafe fn load_image_pages(user_page_table: &mut PageTable, address_space: &mut AddressSpace, image: &ExecutableInfo, mapped: &mut Vec<u64>) -> Result<(), Error> {
+        let file = namespace::get_file_handle(image.file_handle).ok_or(Error::EntryNotFound)?;
+        // every segment gets its area first, so that a page two segments share ends up with the permissions of both
+        for section in image.sections.iter() {
+            match section.section_type {
+                SectionType::Load => {},
+                // read by the loader
+                SectionType::Dynamic | SectionType::Interpreter => continue,
+                _ => return Err(Error::InvalidExecutable),
+            }
+            let page_start = page_mapper::align(section.virt_address as u64);
+            let page_end = page_mapper::align((section.virt_address + section.size_in_memory) as u64 + 0xFFF);
+            let area_start = match address_space.find(page_start).map(|area| (area.writable, area.executable)) {
+                Some((writable, executable)) => {
+                    address_space.remove_range(page_start, page_start + 0x1000, |area| area.kind == AreaKind::Image)?;
+                    address_space.insert(VirtualMemoryArea::new(page_start, page_start + 0x1000, writable || section.writable, executable || section.executable, AreaKind::Image))?;
+                    page_start + 0x1000
+                },
+                None => page_start,
+            };
+            if area_start < page_end {
+                address_space.insert(VirtualMemoryArea::new(area_start, page_end, section.writable, section.executable, AreaKind::Image))?;
+            }
         }
-        for i in 0..(STACK_SIZE / 0x1000) {
-            let stack_frame = FRAME_ALLOCATOR.allocate_frame();
-            page_mapper::map_addr(user_page_table, user_stack_virt_base + (i * 0x1000), stack_frame, flags);
+        // only the pages holding file contents are loaded now, the rest are zero filled on first touch
+        for section in image.sections.iter().filter(|section| section.section_type == SectionType::Load) {
+            let start = section.virt_address as u64;
+            let file_end = start + section.size_in_file as u64;
+            file.seek(section.file_offset)?;
+            for page in (page_mapper::align(start)..page_mapper::align(file_end + 0xFFF)).step_by(0x1000) {
+                let frame = Self::image_frame(user_page_table, address_space, page, mapped)?;
+                let copy_start = start.max(page);
+                let copy_end = file_end.min(page + 0x1000);
+                let frame_buf = (PHYSICAL_MEMORY_OFFSET + frame + (copy_start - page)) as *mut u8;
+                file.read(slice::from_raw_parts_mut(frame_buf, (copy_end - copy_start) as usize))?;
+            }
+        }
+        // written through the frames, read-only pages included
+        for relocation in image.relocations.iter() {
+            for (i, byte) in (relocation.value as u64).to_le_bytes().iter().enumerate() {
+                let address = relocation.address as u64 + i as u64;
+                let frame = Self::image_frame(user_page_table, address_space, page_mapper::align(address), mapped)?;
+                *((PHYSICAL_MEMORY_OFFSET + frame + (address & 0xFFF)) as *mut u8) = *byte;
+            }
+        }
+        Ok(())
+    }
+
+    // the frame under a page of a user image that is being loaded, a zeroed one if nothing was put there yet
+    unsafe fn image_frame(page_table: &mut PageTable, address_space: &AddressSpace, page: u64, mapped: &mut Vec<u64>) -> Result<u64, Error> {
+        if let Some(frame) = page_mapper::translate_addr_using_table(page_table, page as usize) {
+            return Ok(frame);
         }
+        let area = address_space.find(page).filter(|area| area.kind == AreaKind::Image).ok_or(Error::InvalidExecutable)?;
+        let frame = page_mapper::new_frame_zeroed();
+        page_mapper::map_user_page(page_table, page, frame, area.page_flags());
+        mapped.push(page);
+        Ok(frame)
+    }
+
+    // the calling thread goes on in a new process from state, with the memory shared copy-on-write and all handles inherited
+    pub unsafe fn fork(state: TaskContext, process_id: u32) -> Result<(), Error> {
+        let thread = scheduler::current_task();
+        if !thread.user_mode {
+            return Err(Error::Permissions);
+        }
+        let mut parent = scheduler::get_process(thread.process_id)?;
+        let parent = &mut *parent;
+        let page_table = addr_to_page_table(parent.page_table);
+        let child_page_table = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+        let child_page_table_phys = (child_page_table as *const _ as u64) - PHYSICAL_MEMORY_OFFSET;
+        let address_space = parent.with_address_space(|address_space| {
+            address_space.share_copy_on_write(page_table, child_page_table);
+            address_space.clone()
+        });
+        let mut process = Process::new(child_page_table_phys, process_id);
+        process.address_space = Mutex::new(address_space);
+        process.shared_memory = parent.shared_memory.iter().map(|mapping| mapping.share_with(page_table, child_page_table)).collect();
+        process.handles.inherit_all(&mut parent.handles);
+        process.executable_stack = parent.executable_stack;
+        process.thread_local = parent.thread_local;
+        scheduler::add_process(process_id, process)?;
+        let mut task = Task::new(state.rip, state.rsp, thread.stack_base, child_page_table_phys, true, process_id);
+        task.state = state;
+        task.stack_size = thread.stack_size;
+        task.fs_base = thread.fs_base;
+        task.thread_local = thread.thread_local;
+        task.priority = thread.priority;
+        task.time_slice = thread.priority.time_slice();
+        scheduler::add_thread(process_id, task)?;
+        Ok(())
+    }
+
+    pub unsafe fn exec_thread(entry_point: u64, process_id: u32, args: &[String], env: &[String], auxiliary_vector: &[(u64, u64)]) -> Result<Task, Error> {
+        Self::new_user_thread(entry_point, process_id, |user_page_table, user_stack_virt_base| {
+            Self::write_startup_block(user_page_table, user_stack_virt_base + STACK_SIZE, args, env, auxiliary_vector)
+        })
+    }
+
+    pub unsafe fn user_thread(entry_point: u64, argument: u64, process_id: u32) -> Result<Task, Error> {
+        // entered like a called function, with the return address slot below an aligned stack
+        let mut task = Self::new_user_thread(entry_point, process_id, |_, user_stack_virt_base| Ok(user_stack_virt_base + STACK_SIZE - 8))?;
+        task.state.rdi = argument;
+        Ok(task)
+    }
+
+    // stack_top sets up the new stack and returns where the thread starts on it,
+    // the stack and thread-local storage are given back if any step fails
+    unsafe fn new_user_thread<F>(entry_point: u64, process_id: u32, stack_top: F) -> Result<Task, Error>
+    where F: FnOnce(&PageTable, u64) -> Result<u64, Error> {
+        asm!("cli");
+        let result = (|| {
+            let process = scheduler::get_process(process_id)?;
+            let user_page_table = addr_to_page_table(process.page_table);
+            let user_page_table_phys = process.page_table;
+            let user_stack_virt_base = Self::allocate_user_stack(user_page_table, process_id)?;
+            let started = stack_top(user_page_table, user_stack_virt_base)
+                .and_then(|stack_top| Ok((stack_top, Self::allocate_thread_local(user_page_table, process_id)?)));
+            let (stack_top, (thread_pointer, thread_local)) = match started {
+                Ok(started) => started,
+                Err(err) => {
+                    Self::release_user_area(user_page_table, &process, user_stack_virt_base - STACK_GUARD_SIZE, user_stack_virt_base + STACK_SIZE);
+                    return Err(err);
+                },
+            };
+            let mut task = Task::new(entry_point, stack_top, user_stack_virt_base, user_page_table_phys, true, process_id);
+            task.fs_base = thread_pointer;
+            task.thread_local = Some(thread_local);
+            Ok(task)
+        })();
         asm!("sti");
-        Ok(Task::new(entry_point, user_stack_virt_base + STACK_SIZE, user_stack_virt_base, user_page_table_phys, true, process_id))
+        result
+    }
+
+    // takes back an area allocate_user_stack or allocate_thread_local handed out, for a thread that never ran
+    unsafe fn release_user_area(user_page_table: &mut PageTable, process: &Process, start: u64, end: u64) {
+        process.with_address_space(|address_space| {
+            let mut frames = Vec::new();
+            for page in (start..end).step_by(0x1000) {
+                if let Some(frame) = page_mapper::translate_addr_using_table(user_page_table, page as usize) {
+                    page_mapper::unmap_addr(user_page_table, page);
+                    frames.push(frame);
+                }
+            }
+            // other threads of the process may have touched the area in the meantime
+            smp::shoot_down_tlb(None);
+            for frame in frames {
+                page_mapper::free_frame(frame);
+            }
+            let _ = address_space.remove_range(start, end, |area| matches!(area.kind, AreaKind::Stack | AreaKind::Guard | AreaKind::ThreadLocal));
+        });
+    }
+
+    // a copy of the process's PT_TLS image right below the thread control block, the x86_64 variant II layout,
+    // returns the thread pointer and the area; every user thread gets a control block, with or without PT_TLS
+    unsafe fn allocate_thread_local(user_page_table: &mut PageTable, process_id: u32) -> Result<(u64, (u64, u64)), Error> {
+        let process = scheduler::get_process(process_id)?;
+        let image = process.thread_local;
+        // the image's alignment was checked by the loader to be a power of two no larger than a page
+        let block_size = image.map_or(0, |image| (image.size_in_memory as u64 + image.alignment as u64 - 1) & !(image.alignment as u64 - 1));
+        let size = page_mapper::align(block_size + THREAD_CONTROL_BLOCK_SIZE + 0xFFF);
+        let start = process.with_address_space(|address_space| {
+            let start = address_space.find_free_below(size, vma::ANONYMOUS_START, vma::ANONYMOUS_END).ok_or(Error::OutOfSpace)?;
+            let area = VirtualMemoryArea::new(start, start + size, true, false, AreaKind::ThreadLocal);
+            let flags = area.page_flags();
+            address_space.insert(area)?;
+            for page in (start..(start + size)).step_by(0x1000) {
+                page_mapper::map_user_page(user_page_table, page, page_mapper::new_frame_zeroed(), flags);
+            }
+            Ok(start)
+        })?;
+        let thread_pointer = start + block_size;
+        let written = (|| {
+            let mut block = vec![0u8; (block_size + 8) as usize];
+            if let Some(image) = image {
+                user_memory::copy_from_user_table(user_page_table, image.virt_address, &mut block[..image.size_in_file])?;
+            }
+            block[(block_size as usize)..].copy_from_slice(&thread_pointer.to_le_bytes());
+            user_memory::copy_to_user_table(user_page_table, start as usize, block.as_slice())
+        })();
+        if let Err(err) = written {
+            Self::release_user_area(user_page_table, &process, start, start + size);
+            return Err(err);
+        }
+        Ok((thread_pointer, (start, start + size)))
+    }
+
+    unsafe fn allocate_user_stack(user_page_table: &mut PageTable, process_id: u32) -> Result<u64, Error> {
+        let process = scheduler::get_process(process_id)?;
+        let executable_stack = process.executable_stack;
+        process.with_address_space(|address_space| {
+            // find location for new stack
+            let guard_base = address_space.find_free(STACK_GUARD_SIZE + STACK_SIZE, USER_STACK_START, USER_STACK_END).ok_or(Error::OutOfSpace)?;
+            let user_stack_virt_base = guard_base + STACK_GUARD_SIZE;
+            let stack = VirtualMemoryArea::new(user_stack_virt_base, user_stack_virt_base + STACK_SIZE, true, executable_stack, AreaKind::Stack);
+            // the top page is written before the thread runs, everything below grows in on demand
+            let flags = stack.page_flags();
+            address_space.insert(VirtualMemoryArea::new(guard_base, user_stack_virt_base, false, false, AreaKind::Guard))?;
+            address_space.insert(stack)?;
+            page_mapper::map_user_page(user_page_table, user_stack_virt_base + STACK_SIZE - 0x1000, page_mapper::new_frame_zeroed(), flags);
+            Ok(user_stack_virt_base)
+        })
     }
 
-    pub unsafe fn kexec(application: unsafe fn(), process_id: u32) -> Result<(), Error> {
+    // SysV x86_64 process entry: argc, argv pointers, NULL, envp pointers, NULL, auxiliary vector,
+    // then the AT_RANDOM bytes and the strings
+    fn write_startup_block(page_table: &PageTable, stack_top: u64, args: &[String], env: &[String], auxiliary_vector: &[(u64, u64)]) -> Result<u64, Error> {
+        let strings_size: usize = args.iter().chain(env.iter()).map(|s| s.len() + 1).sum();
+        let vector_size = (1 + args.len() + 1 + env.len() + 1 + 2 * (auxiliary_vector.len() + 2)) * 8;
+        if strings_size + 16 + vector_size + 0x10 > MAX_STARTUP_BLOCK_SIZE {
+            return Err(Error::OutOfSpace);
+        }
+        let strings_base = stack_top - strings_size as u64;
+        let random_base = strings_base - 16;
+        // the stack pointer has to be 16-byte aligned at the entry point
+        let rsp = (random_base - vector_size as u64) & !0xF;
+        let mut block = vec![0u8; (stack_top - rsp) as usize];
+        let mut vector: Vec<u64> = Vec::with_capacity(vector_size / 8);
+        let mut string_offset = (strings_base - rsp) as usize;
+        vector.push(args.len() as u64);
+        for list in [args, env] {
+            for s in list {
+                vector.push(rsp + string_offset as u64);
+                block[string_offset..(string_offset + s.len())].copy_from_slice(s.as_bytes());
+                string_offset += s.len() + 1;
+            }
+            vector.push(0);
+        }
+        for (key, value) in auxiliary_vector {
+            vector.push(*key);
+            vector.push(*value);
+        }
+        vector.push(exec::AT_RANDOM);
+        vector.push(random_base);
+        vector.push(exec::AT_NULL);
+        vector.push(0);
+        let random_offset = (random_base - rsp) as usize;
+        block[random_offset..(random_offset + 8)].copy_from_slice(&cpu::random().to_le_bytes());
+        block[(random_offset + 8)..(random_offset + 16)].copy_from_slice(&cpu::random().to_le_bytes());
+        for (i, word) in vector.iter().enumerate() {
+            block[(i * 8)..(i * 8 + 8)].copy_from_slice(&word.to_le_bytes());
+        }
+        user_memory::copy_to_user_table(page_table, rsp as usize, block.as_slice())?;
+        Ok(rsp)
+    }
+
+    pub unsafe fn kexec(application: unsafe fn(), process_id: u32) -> Result<u32, Error> {
         asm!("cli");
         let child_page_table = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
         let child_page_table_phys = (child_page_table as *const _ as u64) - PHYSICAL_MEMORY_OFFSET;
-        scheduler::add_process(Process::new(child_page_table_phys));
-        scheduler::add_thread(process_id, Self::kexec_thread(application, process_id)?);
+        scheduler::add_process(process_id, Process::new(child_page_table_phys, process_id))?;
+        let tid = scheduler::add_thread(process_id, Self::kexec_thread(application, process_id)?)?;
         asm!("sti");
-        Ok(())
+        Ok(tid)
     }
 
     pub unsafe fn kexec_thread(application: unsafe fn(), process_id: u32) -> Result<Task, Error> {
@@ -266,15 +637,49 @@ pub unsafe extern "C" fn timer_handler_save_context() {
 
 #[no_mangle]
 pub unsafe extern "C" fn timer_handler_context_switch_part_2(context: *const TaskContext) {
-    cpu::DO_CONTEXT_SWITCH_NEXT_TIME = false;
-    pic::end_of_interrupt(interrupts::HardwareInterrupt::Timer);
+    let cpu = smp::current_cpu();
+    let on_timer = !YIELDING[cpu].swap(false, Ordering::Relaxed);
+    if on_timer {
+        interrupts::end_of_interrupt(interrupts::HardwareInterrupt::Timer);
+        // delays count down once per tick, not once per cpu
+        if cpu == 0 {
+            scheduler::tick();
+            smp::forward_tick();
+        }
+        if cpu::defer_preemption(cpu) {
+            restore_registers(&*context);
+        }
+    }
+    scheduler::context_switch(Some((*context).clone()), on_timer);
+}
+
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn reschedule_handler_save_context() {
+    asm!("cli; push r15; push r14; push r13; push r12; push r11; push r10; push r9;\
+    push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp;\
+    mov rdi, rsp; call reschedule_handler_context_switch_part_2;", options(noreturn));
+}
+
+// forwarded ticks and wake-ups from other cpus
+#[no_mangle]
+pub unsafe extern "C" fn reschedule_handler_context_switch_part_2(context: *const TaskContext) {
+    if let Some(lapic) = lapic::local() {
+        lapic.end_of_interrupt();
+    }
+    if cpu::defer_preemption(smp::current_cpu()) {
+        restore_registers(&*context);
+    }
     scheduler::context_switch(Some((*context).clone()), true);
 }
 
 pub fn trigger_context_switch() {
-    cpu::enable_interrupts();
+    cpu::disable_interrupts();
     unsafe {
-        asm!("int 0x20"); // trigger timer interrupt
+        // tell a yield apart from a real tick so that it does not eat the time slice or delays
+        YIELDING[smp::current_cpu()].store(true, Ordering::Relaxed);
+        // sti only takes effect after the next instruction, so no real tick can slip in between
+        asm!("sti; int 0x20"); // trigger timer interrupt
     }
 }
 
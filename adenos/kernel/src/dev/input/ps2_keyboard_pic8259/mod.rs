@@ -1,7 +1,7 @@
 use crate::*;
 use dev;
 use dev::*;
-use dev::hal::{cpu, pic, port, interrupts};
+use dev::hal::{port, interrupts};
 use dev::input::keyboard;
 use async_task::*;
 use x86_64::structures::idt;
@@ -35,7 +35,7 @@ impl PS2KeyboardPIC8259 {
             asm!("cli");
             Self::add_scancode(read_one!(KEYBOARD_PORT).unwrap());
         }
-        pic::end_of_interrupt(interrupts::HardwareInterrupt::Keyboard);
+        interrupts::end_of_interrupt(interrupts::HardwareInterrupt::Keyboard);
     }
 
     fn add_scancode(scancode: u8) {
@@ -72,8 +72,7 @@ impl StaticDevice for PS2KeyboardPIC8259 {
 
     fn init_device() -> Result<(), Error> {
         kernel_executor::spawn(Task::new(Self::_input_handler_task()));
-        cpu::register_interrupt_handler(interrupts::HardwareInterrupt::Keyboard, PS2KeyboardPIC8259::_input_handler);
-        Ok(())
+        interrupts::request_irq(interrupts::HardwareInterrupt::Keyboard, PS2KeyboardPIC8259::_input_handler)
     }
 }
 
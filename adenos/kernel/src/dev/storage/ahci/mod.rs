@@ -1,4 +1,4 @@
-use crate::{*, dev::hal::{mem, pci::{self, PCIHeaderType0}}};
+use crate::{*, dev::hal::{mem::{self, frame_allocator::Zone}, pci::{self, PCIHeaderType0}}};
 use {dev::*, namespace::{self, *}};
 use core::{mem::size_of, fmt::Display};
 use alloc::{vec, vec::Vec, string::{String, ToString}, boxed::Box};
@@ -175,14 +175,15 @@ impl Port {
         unsafe {
             self.stop_commands();
 
-            let command_list_base_new = mem::FRAME_ALLOCATOR.allocate_frame();
+            // the controller may only be able to address 32 bits
+            let command_list_base_new = mem::FRAME_ALLOCATOR.allocate_frames(1, 0x1000, Zone::DMA32).expect("AHCI_DMA_ALLOCATION_FAILURE");
             (*self.hba_port).command_list_base_address = command_list_base_new;
             let clear = (command_list_base_new + mem::PHYSICAL_MEMORY_OFFSET) as *mut u8;
             for i in 0..0x1000 {
                 *clear.offset(i) = 0;
             }
 
-            let fis_base_new = mem::FRAME_ALLOCATOR.allocate_frame();
+            let fis_base_new = mem::FRAME_ALLOCATOR.allocate_frames(1, 0x1000, Zone::DMA32).expect("AHCI_DMA_ALLOCATION_FAILURE");
             (*self.hba_port).fis_base_address = fis_base_new;
             let clear = (fis_base_new + mem::PHYSICAL_MEMORY_OFFSET) as *mut u8;
             for i in 0..256 {
@@ -191,14 +192,15 @@ impl Port {
 
             let command_header = ((*self.hba_port).command_list_base_address + mem::PHYSICAL_MEMORY_OFFSET) as *mut HBACommandHeader;
 
+            // 32 command tables of 256 bytes each, one after another
+            let command_tables = mem::FRAME_ALLOCATOR.allocate_frames(2, 0x1000, Zone::DMA32).expect("AHCI_DMA_ALLOCATION_FAILURE");
+            let clear = (command_tables + mem::PHYSICAL_MEMORY_OFFSET) as *mut u8;
+            for i in 0..0x2000 {
+                *clear.offset(i) = 0;
+            }
             for i in 0..32 {
-                let command_table_address = mem::FRAME_ALLOCATOR.allocate_frame();
-                let address = command_table_address + (i << 8);
+                let address = command_tables + (i << 8);
                 (*command_header.offset(i as isize)).set_command_table_descriptor_base_address(address);
-                let clear = (command_table_address + mem::PHYSICAL_MEMORY_OFFSET) as *mut u8;
-                for i in 0..256 {
-                    *clear.offset(i) = 0;
-                }
             }
 
             self.start_commands();
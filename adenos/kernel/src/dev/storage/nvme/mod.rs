@@ -1,4 +1,4 @@
-use crate::{*, dev::hal::{pci, mem}};
+use crate::{*, dev::hal::{pci, mem::{self, frame_allocator::Zone}}};
 use {dev::*, namespace::*};
 use alloc::{vec, vec::Vec, string::String};
 use modular_bitfield::{bitfield, specifiers::*};
@@ -177,14 +177,14 @@ impl Device for NVME {
 
         // set up admin queue
         unsafe {
-            let frame_admin_sub_queue = mem::FRAME_ALLOCATOR.allocate_frame();
+            let frame_admin_sub_queue = mem::FRAME_ALLOCATOR.allocate_frames(1, 0x1000, Zone::DMA32).ok_or(Error::InitFailure)?;
             let frame_admin_sub_queue_virt = frame_admin_sub_queue + mem::PHYSICAL_MEMORY_OFFSET;
             let clr = frame_admin_sub_queue_virt as *mut u8;
             for i in 0..0x1000 {
                 *clr.offset(i) = 0;
             }
 
-            let frame_admin_com_queue = mem::FRAME_ALLOCATOR.allocate_frame();
+            let frame_admin_com_queue = mem::FRAME_ALLOCATOR.allocate_frames(1, 0x1000, Zone::DMA32).ok_or(Error::InitFailure)?;
             let frame_admin_com_queue_virt = frame_admin_com_queue + mem::PHYSICAL_MEMORY_OFFSET;
             let clr = frame_admin_com_queue_virt as *mut u8;
             for i in 0..0x1000 {
@@ -243,7 +243,7 @@ impl Device for NVME {
 
         // attach all namespaces
         unsafe {
-            let prp = mem::FRAME_ALLOCATOR.allocate_frame();
+            let prp = mem::FRAME_ALLOCATOR.allocate_frames(1, 0x1000, Zone::DMA32).ok_or(Error::InitFailure)?;
             self.admin_queue.admin_identify(prp, 0xffffffff, 0, 0x01, 0);
         }
 
@@ -1,7 +1,30 @@
 use crate::*;
 use super::*;
-use {namespace::{self, *}, dev::*};
-use alloc::{vec, vec::Vec};
+use {namespace::{self, *}, dev::*, dev::hal::cpu};
+use file::File;
+use alloc::{vec, vec::Vec, string::String};
+use core::{mem, ptr, slice, str};
+use bitflags::bitflags;
+
+// executables are loaded below the anonymous memory
+pub const IMAGE_START: usize = 0x4000_0000;
+pub const IMAGE_END: usize = infinity::os::HEAP_BASE;
+// position independent executables go somewhere in the lower half, interpreters in the upper one
+const RANDOM_BASE_RANGE: usize = 0x0800_0000;
+const INTERPRETER_START: usize = IMAGE_START + RANDOM_BASE_RANGE;
+
+const SIGNATURE: u32 = 0x464C457F;
+const MAX_INTERPRETER_PATH: usize = 0x1000;
+// every thread gets a copy
+const MAX_THREAD_LOCAL_SIZE: usize = 0x10_0000;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
 
 #[repr(u16)]
 #[derive(Copy, Clone, Debug)]
@@ -86,42 +109,46 @@ struct ELF64HeaderExt {
     section_name_table_index: u16,
 }
 
+// kept as plain numbers in the program headers, types not listed here are ignored
 #[repr(u32)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum ELFSectionType {
     Null = 0,
     Load = 1,
     Dynamic = 2,
     Interpreter = 3,
     Note = 4,
+    ProgramHeaders = 6,
+    ThreadLocal = 7,
+    GnuStack = 0x6474E551,
 }
 
-#[repr(u32)]
-#[derive(Copy, Clone, Debug)]
-enum ELFSectionFlags {
-    Executable = 1,
-    Writable = 2,
-    Readable = 4,
+bitflags! {
+    struct ELFSectionFlags: u32 {
+        const EXECUTABLE = 1;
+        const WRITABLE = 2;
+        const READABLE = 4;
+    }
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 struct ProgramHeaderEntry32 {
-    section_type: ELFSectionType,
+    section_type: u32,
     file_offset: u32,
     virt_address: u32,
     _reserved: u32,
     size_in_file: u32,
     size_in_memory: u32,
-    flags: ELFSectionFlags,
+    flags: u32,
     required_alignment: u32,
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 struct ProgramHeaderEntry64 {
-    section_type: ELFSectionType,
-    flags: ELFSectionFlags,
+    section_type: u32,
+    flags: u32,
     file_offset: u64,
     virt_address: u64,
     _reserved: u64,
@@ -130,54 +157,251 @@ struct ProgramHeaderEntry64 {
     required_alignment: u64,
 }
 
+impl ProgramHeaderEntry64 {
+    fn section_type(&self) -> Option<ELFSectionType> {
+        Some(match self.section_type {
+            0 => ELFSectionType::Null,
+            1 => ELFSectionType::Load,
+            2 => ELFSectionType::Dynamic,
+            3 => ELFSectionType::Interpreter,
+            4 => ELFSectionType::Note,
+            6 => ELFSectionType::ProgramHeaders,
+            7 => ELFSectionType::ThreadLocal,
+            0x6474E551 => ELFSectionType::GnuStack,
+            _ => return None,
+        })
+    }
+
+    fn is(&self, section_type: ELFSectionType) -> bool {
+        self.section_type() == Some(section_type)
+    }
+
+    fn flags(&self) -> ELFSectionFlags {
+        ELFSectionFlags::from_bits_truncate(self.flags)
+    }
+
+    fn contains_in_file(&self, virt_address: u64) -> bool {
+        virt_address >= self.virt_address && virt_address < self.virt_address + self.size_in_file
+    }
+
+    fn section(&self, section_type: SectionType, base: usize) -> Section {
+        Section {
+            section_type,
+            file_offset: self.file_offset,
+            size_in_file: self.size_in_file as usize,
+            virt_address: self.virt_address as usize + base,
+            size_in_memory: self.size_in_memory as usize,
+            alignment: self.required_alignment as usize,
+            writable: self.flags().contains(ELFSectionFlags::WRITABLE),
+            executable: self.flags().contains(ELFSectionFlags::EXECUTABLE),
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+struct DynamicEntry64 {
+    tag: u64,
+    value: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+struct RelocationEntry64 {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+fn read_struct<T: Copy>(file: &mut File, offset: u64) -> Result<T, Error> {
+    let mut value = mem::MaybeUninit::<T>::zeroed();
+    file.seek(offset)?;
+    let buf = unsafe { slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>()) };
+    if file.read(buf)? != buf.len() {
+        return Err(Error::InvalidExecutable);
+    }
+    Ok(unsafe { ptr::read_unaligned(value.as_ptr()) })
+}
+
+// where the contents of a virtual address are in the file, before any relocation of the image
+fn file_offset(headers: &[ProgramHeaderEntry64], virt_address: u64) -> Option<u64> {
+    headers.iter()
+        .find(|phdr| phdr.is(ELFSectionType::Load) && phdr.contains_in_file(virt_address))
+        .map(|phdr| phdr.file_offset + (virt_address - phdr.virt_address))
+}
+
 pub struct ELFLoader {}
 
 impl ELFLoader {
     pub fn load_executable(handle: u32) -> Result<ExecutableInfo, Error> {
-        if let Some(file) = namespace::get_file_handle(handle) {
-            let mut buf = vec![0; 64];
-            file.seek(0)?;
-            file.read(&mut buf)?;
-            let head0 = unsafe { (buf.as_ptr() as *const ELFHeader).as_ref().unwrap() };
-            serial_println!("{:#x?}", head0);
-            
-            if head0.signature != 0x464C457F { // ELF signature
-                return Err(Error::InvalidExecutable);
-            }
+        Self::load(handle, false)
+    }
 
-            let mut entry_point = 0;
-            let mut sections = Vec::new();
-
-            if let ELFBitness::ELF64 = head0.bitness {
-                let head1 = unsafe { (head0.extension.as_ptr() as *const ELF64HeaderExt).as_ref().unwrap() };
-                serial_println!("{:#?}", head1);
-                entry_point = head1.entry_point as usize;
-                for i in 0..head1.program_header_entry_count {
-                    let mut buf = vec![0; head1.program_header_entry_size as usize];
-                    file.seek(head1.program_header_table_offset + (i * head1.program_header_entry_size) as u64)?;
-                    file.read(&mut buf)?;
-                    let phdr = unsafe { (buf.as_ptr() as *const ProgramHeaderEntry64).as_ref().unwrap() };
-                    if let ELFSectionType::Load = phdr.section_type {
-                        sections.push(Section {
-                            section_type: SectionType::Load,
-                            file_offset: phdr.file_offset,
-                            size_in_file: phdr.size_in_file as usize,
-                            virt_address: phdr.virt_address as usize,
-                            size_in_memory: phdr.size_in_memory as usize,
-                        });
-                    } // other sections later
-                }
-            } else {
-                return Err(Error::InvalidExecutable);
+    // the dynamic linker named by an executable's PT_INTERP, which has to be position independent
+    pub fn load_interpreter(handle: u32) -> Result<ExecutableInfo, Error> {
+        let info = Self::load(handle, true)?;
+        if info.interpreter.is_some() {
+            return Err(Error::InvalidExecutable);
+        }
+        Ok(info)
+    }
+
+    fn load(handle: u32, interpreter: bool) -> Result<ExecutableInfo, Error> {
+        let file = namespace::get_file_handle(handle).ok_or(Error::InvalidHandle)?;
+        let head0: ELFHeader = read_struct(file, 0)?;
+        if head0.signature != SIGNATURE {
+            return Err(Error::InvalidExecutable);
+        }
+        if !matches!(head0.bitness, ELFBitness::ELF64) || !matches!(head0.instruction_set, ELFInstructionSet::x86_64) {
+            return Err(Error::InvalidExecutable);
+        }
+        let position_independent = match head0.executable_type {
+            ELFExecutableType::Executable if !interpreter => false,
+            ELFExecutableType::Shared => true,
+            _ => return Err(Error::InvalidExecutable),
+        };
+        let head1 = unsafe { ptr::read_unaligned(head0.extension.as_ptr() as *const ELF64HeaderExt) };
+        if (head1.program_header_entry_size as usize) < mem::size_of::<ProgramHeaderEntry64>() {
+            return Err(Error::InvalidExecutable);
+        }
+
+        let mut headers = Vec::new();
+        for i in 0..head1.program_header_entry_count as u64 {
+            headers.push(read_struct::<ProgramHeaderEntry64>(file, head1.program_header_table_offset + i * head1.program_header_entry_size as u64)?);
+        }
+
+        let base = Self::choose_base(&headers, position_independent, if interpreter { INTERPRETER_START } else { IMAGE_START })?;
+        let mut info = ExecutableInfo {
+            file_handle: handle,
+            virt_entry_point: head1.entry_point as usize + base,
+            base,
+            sections: Vec::new(),
+            relocations: Vec::new(),
+            program_headers: None,
+            program_header_count: head1.program_header_entry_count as usize,
+            program_header_size: head1.program_header_entry_size as usize,
+            thread_local: None,
+            interpreter: None,
+            // without PT_GNU_STACK the stack is not executable either
+            executable_stack: false,
+        };
+        for phdr in headers.iter() {
+            match phdr.section_type() {
+                Some(ELFSectionType::Load) => info.sections.push(phdr.section(SectionType::Load, base)),
+                Some(ELFSectionType::Dynamic) => info.sections.push(phdr.section(SectionType::Dynamic, base)),
+                Some(ELFSectionType::Interpreter) => {
+                    info.sections.push(phdr.section(SectionType::Interpreter, base));
+                    info.interpreter = Some(Self::read_interpreter_path(file, phdr)?);
+                },
+                Some(ELFSectionType::ThreadLocal) => info.thread_local = Some(Self::thread_local_section(phdr, base)?),
+                Some(ELFSectionType::ProgramHeaders) => info.program_headers = Some(phdr.virt_address as usize + base),
+                Some(ELFSectionType::GnuStack) => info.executable_stack = phdr.flags().contains(ELFSectionFlags::EXECUTABLE),
+                _ => {},
             }
+        }
+        if info.program_headers.is_none() {
+            // most executables have them in the first loaded segment even without PT_PHDR
+            info.program_headers = headers.iter()
+                .find(|phdr| phdr.is(ELFSectionType::Load) && head1.program_header_table_offset >= phdr.file_offset && head1.program_header_table_offset < phdr.file_offset + phdr.size_in_file)
+                .map(|phdr| (phdr.virt_address + (head1.program_header_table_offset - phdr.file_offset)) as usize + base);
+        }
+        // with an interpreter the relocations, symbols included, are its job
+        if let (None, Some(dynamic)) = (info.interpreter.as_ref(), headers.iter().find(|phdr| phdr.is(ELFSectionType::Dynamic))) {
+            info.relocations = Self::read_relocations(file, &headers, dynamic, base)?;
+        }
+        Ok(info)
+    }
+
+    fn thread_local_section(phdr: &ProgramHeaderEntry64, base: usize) -> Result<Section, Error> {
+        let mut section = phdr.section(SectionType::ThreadLocal, base);
+        section.alignment = section.alignment.max(1);
+        // the thread pointer right above it has to be aligned within the page it is in
+        if !section.alignment.is_power_of_two() || section.alignment > 0x1000 {
+            return Err(Error::InvalidExecutable);
+        }
+        if section.size_in_file > section.size_in_memory || section.size_in_memory > MAX_THREAD_LOCAL_SIZE {
+            return Err(Error::InvalidExecutable);
+        }
+        Ok(section)
+    }
 
-            Ok(ExecutableInfo {
-                file_handle: handle,
-                virt_entry_point: entry_point,
-                sections,
-            })
+    fn read_interpreter_path(file: &mut File, phdr: &ProgramHeaderEntry64) -> Result<String, Error> {
+        if phdr.size_in_file == 0 || phdr.size_in_file > MAX_INTERPRETER_PATH as u64 {
+            return Err(Error::InvalidExecutable);
+        }
+        let mut buf = vec![0; phdr.size_in_file as usize];
+        file.seek(phdr.file_offset)?;
+        file.read(&mut buf)?;
+        let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+        str::from_utf8(&buf[..len]).map(String::from).map_err(|_| Error::InvalidExecutable)
+    }
+
+    // 0 for fixed executables, which have to fit the image range where they are,
+    // position independent ones go somewhere in the random base range above range_start
+    fn choose_base(headers: &[ProgramHeaderEntry64], position_independent: bool, range_start: usize) -> Result<usize, Error> {
+        let loads = || headers.iter().filter(|phdr| phdr.is(ELFSectionType::Load));
+        let alignment = loads().map(|phdr| phdr.required_alignment as usize).max().unwrap_or(0).max(0x1000);
+        if !alignment.is_power_of_two() {
+            return Err(Error::InvalidExecutable);
+        }
+        // the base is a multiple of the alignment, so every segment keeps its offset into its own alignment
+        let low = loads().map(|phdr| phdr.virt_address).min().ok_or(Error::InvalidExecutable)? as usize & !(alignment - 1);
+        // None if there are no segments or one of them wraps around
+        let high = loads()
+            .try_fold(None, |high, phdr| phdr.virt_address.checked_add(phdr.size_in_memory).map(|end| high.max(Some(end))))
+            .flatten()
+            .ok_or(Error::InvalidExecutable)? as usize;
+        if high < low {
+            return Err(Error::InvalidExecutable);
+        }
+        let base = if position_independent {
+            let span = (high - low + alignment - 1) & !(alignment - 1);
+            if span > RANDOM_BASE_RANGE {
+                return Err(Error::OutOfSpace);
+            }
+            let slots = (RANDOM_BASE_RANGE - span) / alignment + 1;
+            (range_start + (cpu::random() as usize % slots) * alignment).wrapping_sub(low)
         } else {
-            Err(Error::InvalidHandle)
+            0
+        };
+        if low.wrapping_add(base) < IMAGE_START || high.wrapping_add(base) > IMAGE_END {
+            return Err(Error::InvalidExecutable);
         }
+        Ok(base)
     }
-}
\ No newline at end of file
+
+    // only relative relocations, anything that needs symbols is for a dynamic linker
+    fn read_relocations(file: &mut File, headers: &[ProgramHeaderEntry64], dynamic: &ProgramHeaderEntry64, base: usize) -> Result<Vec<Relocation>, Error> {
+        let (mut table, mut table_size, mut entry_size) = (None, 0, mem::size_of::<RelocationEntry64>() as u64);
+        for i in 0..dynamic.size_in_file / mem::size_of::<DynamicEntry64>() as u64 {
+            let entry: DynamicEntry64 = read_struct(file, dynamic.file_offset + i * mem::size_of::<DynamicEntry64>() as u64)?;
+            match entry.tag {
+                DT_NULL => break,
+                DT_RELA => table = Some(entry.value),
+                DT_RELASZ => table_size = entry.value,
+                DT_RELAENT => entry_size = entry.value,
+                _ => {},
+            }
+        }
+        let table = match table {
+            Some(table) => file_offset(headers, table).ok_or(Error::InvalidExecutable)?,
+            None => return Ok(Vec::new()),
+        };
+        if entry_size < mem::size_of::<RelocationEntry64>() as u64 {
+            return Err(Error::InvalidExecutable);
+        }
+        let mut relocations = Vec::new();
+        for i in 0..table_size / entry_size {
+            let entry: RelocationEntry64 = read_struct(file, table + i * entry_size)?;
+            match entry.info as u32 {
+                R_X86_64_NONE => {},
+                R_X86_64_RELATIVE => relocations.push(Relocation {
+                    address: entry.offset as usize + base,
+                    value: (base as i64).wrapping_add(entry.addend) as usize,
+                }),
+                _ => return Err(Error::InvalidExecutable),
+            }
+        }
+        Ok(relocations)
+    }
+}
@@ -1,16 +1,31 @@
 use crate::*;
 use alloc::{string::String, vec::Vec};
 use self::elf::ELFLoader;
+use namespace;
+use dev::hal::mem::heap;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 pub mod elf;
 pub mod thread;
 pub mod scheduler;
 
+// auxiliary vector entries, passed to the main thread above its environment
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum SectionType {
     Load,
     Dynamic,
     Interpreter,
+    // the initialization image of every thread's thread-local storage
+    ThreadLocal,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -20,13 +35,62 @@ pub struct Section {
     pub size_in_file: usize,
     pub virt_address: usize,
     pub size_in_memory: usize,
+    pub alignment: usize,
+    pub writable: bool,
+    pub executable: bool,
 }
 
+// value is written as a 64-bit word at address once the image is loaded
+#[derive(Copy, Clone, Debug)]
+pub struct Relocation {
+    pub address: usize,
+    pub value: usize,
+}
+
+// all addresses already include base
 #[derive(Clone, Debug)]
 pub struct ExecutableInfo {
     pub file_handle: u32,
     pub virt_entry_point: usize,
+    // where a position independent executable was put, 0 for everything else
+    pub base: usize,
     pub sections: Vec<Section>,
+    pub relocations: Vec<Relocation>,
+    pub program_headers: Option<usize>,
+    pub program_header_count: usize,
+    pub program_header_size: usize,
+    pub thread_local: Option<Section>,
+    // path of the dynamic linker to load along with it
+    pub interpreter: Option<String>,
+    pub executable_stack: bool,
+}
+
+impl ExecutableInfo {
+    // AT_RANDOM and AT_NULL are added by whoever builds the stack
+    pub fn auxiliary_vector(&self, interpreter: Option<&ExecutableInfo>) -> Vec<(u64, u64)> {
+        let mut vector = Vec::new();
+        if let Some(program_headers) = self.program_headers {
+            vector.push((AT_PHDR, program_headers as u64));
+            vector.push((AT_PHENT, self.program_header_size as u64));
+            vector.push((AT_PHNUM, self.program_header_count as u64));
+        }
+        vector.push((AT_PAGESZ, 0x1000));
+        vector.push((AT_BASE, interpreter.map_or(0, |interpreter| interpreter.base as u64)));
+        // the interpreter runs first and jumps here when it is done
+        vector.push((AT_ENTRY, self.virt_entry_point as u64));
+        vector
+    }
+}
+
+// started by the kernel at boot, it may do what is otherwise left to the kernel's own threads
+static INIT_PROCESS: AtomicU32 = AtomicU32::new(u32::MAX);
+
+pub fn set_init_process(process_id: u32) {
+    INIT_PROCESS.store(process_id, Ordering::Relaxed);
+}
+
+pub fn is_init_process(process_id: u32) -> bool {
+    INIT_PROCESS.load(Ordering::Relaxed) == process_id
 }
 
 pub struct ExecutableLoader {}
@@ -35,4 +99,31 @@ impl ExecutableLoader {
     pub fn load_executable(handle: u32) -> Result<ExecutableInfo, Error> {
         ELFLoader::load_executable(handle)
     }
+
+    pub fn load_interpreter(handle: u32) -> Result<ExecutableInfo, Error> {
+        ELFLoader::load_interpreter(handle)
+    }
+}
+
+pub fn spawn(path: &str, args: &[String], env: &[String]) -> Result<u32, Error> {
+    let _subsystem = heap::enter(heap::Subsystem::Executable);
+    let file = file::File::open(String::from(path))?;
+    let id = file.id;
+    // the image is loaded into memory, the file is not needed once exec returns
+    let result = ExecutableLoader::load_executable(id).and_then(|exe| match exe.interpreter.clone() {
+        Some(interpreter) => with_interpreter(interpreter.as_str(), |interpreter| scheduler::exec(exe, Some(interpreter), args, env)),
+        None => scheduler::exec(exe, None, args, env),
+    });
+    namespace::release_handle(id)?;
+    result
+}
+
+// the interpreter's file stays open until f has loaded it
+fn with_interpreter<F>(path: &str, f: F) -> Result<u32, Error>
+where F: FnOnce(ExecutableInfo) -> Result<u32, Error> {
+    let file = file::File::open(String::from(path))?;
+    let id = file.id;
+    let result = ExecutableLoader::load_interpreter(id).and_then(f);
+    namespace::release_handle(id)?;
+    result
 }
\ No newline at end of file
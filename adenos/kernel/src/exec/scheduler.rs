@@ -1,21 +1,101 @@
 use crate::*;
 use exec::*;
 use collections::flat_map::*;
-use alloc::{vec, vec::Vec, collections::vec_deque::VecDeque, collections::BTreeMap};
-use dev::hal::{task, cpu};
+use alloc::{vec, vec::Vec, boxed::Box, string::String, sync::Arc, collections::vec_deque::VecDeque, collections::BTreeMap};
+use core::{cell::UnsafeCell, ops::{Deref, DerefMut}};
+use dev::hal::{task, cpu, smp::{self, ProcessorLock}};
 
 static TICKS_PER_MILLISECOND: u32 = 1;
 
+// exit code of a process that was terminated instead of exiting on its own
+pub const EXIT_CODE_TERMINATED: i32 = -1;
+// killed by the kernel for an access it was not allowed to make
+pub const EXIT_CODE_FAULT: i32 = -2;
+
+const PRIORITY_COUNT: usize = 3;
+
 static mut SCHEDULER: Option<Scheduler> = None;
+// taken again by the same cpu when the scheduler calls back into itself, e.g. through Task::kexec or a closing handle
+static SCHEDULER_LOCK: ProcessorLock = ProcessorLock::new();
 pub static DUMMY: &str = "hello";
 
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Realtime = 0,
+    Normal = 1,
+    Idle = 2,
+}
+
+impl Priority {
+    pub fn from_usize(val: usize) -> Option<Priority> {
+        match val {
+            0 => Some(Priority::Realtime),
+            1 => Some(Priority::Normal),
+            2 => Some(Priority::Idle),
+            _ => None,
+        }
+    }
+
+    // in timer ticks
+    pub fn time_slice(&self) -> u32 {
+        match self {
+            Priority::Realtime => 5,
+            Priority::Normal => 10,
+            Priority::Idle => 1,
+        }
+    }
+}
+
+// a process that stays where it is for as long as someone holds on to it, even after it has been reaped
+#[derive(Clone)]
+pub struct ProcessRef(Arc<UnsafeCell<task::Process>>);
+
+impl ProcessRef {
+    fn new(process: task::Process) -> ProcessRef {
+        ProcessRef(Arc::new(UnsafeCell::new(process)))
+    }
+}
+
+impl Deref for ProcessRef {
+    type Target = task::Process;
+
+    fn deref(&self) -> &task::Process {
+        unsafe { &*self.0.get() }
+    }
+}
+
+impl DerefMut for ProcessRef {
+    fn deref_mut(&mut self) -> &mut task::Process {
+        unsafe { &mut *self.0.get() }
+    }
+}
+
+// what each cpu is doing and what it has lined up
+struct Processor {
+    run_queues: [VecDeque<u32>; PRIORITY_COUNT],
+    running_thread: Option<u32>,
+    // not in any queue, runs only when nothing else wants the cpu
+    idle_thread: Option<u32>,
+}
+
+impl Processor {
+    fn new() -> Processor {
+        Processor {
+            run_queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            running_thread: None,
+            idle_thread: None,
+        }
+    }
+}
+
 pub struct Scheduler {
-    processes: BTreeMap<u32, task::Process>,
-    threads: FlatMap<task::Task>,
-    running_queue: Vec<u32>,
+    processes: BTreeMap<u32, ProcessRef>,
+    // boxed, so that a thread does not move when the map grows
+    threads: FlatMap<Box<task::Task>>,
+    processors: Vec<Processor>,
     suspended_queue: Vec<u32>,
     delta_queue: VecDeque<(u32, u32)>,
-    current_thread_queue_index: u32,
     next_process_id: u32,
 }
 
@@ -24,114 +104,224 @@ impl Scheduler {
         Scheduler {
             processes: BTreeMap::new(),
             threads: FlatMap::new(),
-            running_queue: Vec::new(),
+            processors: Vec::new(),
             suspended_queue: Vec::new(),
             delta_queue: VecDeque::new(),
-            current_thread_queue_index: 0,
             next_process_id: 0,
         }
     }
 
     fn current_process(&self) -> u32 {
-        self.threads[self.running_queue[self.current_thread_queue_index as usize]].process_id
+        self.threads[self.current_thread()].process_id
     }
 
     fn current_thread(&self) -> u32 {
-        self.running_queue[self.current_thread_queue_index as usize]
+        self.processors[smp::current_cpu()].running_thread.unwrap()
     }
 
-    #[inline(always)]
-    fn next(&mut self) {
-        self.current_thread_queue_index += 1;
-        if self.running_queue.len() <= self.current_thread_queue_index as usize {
-            self.current_thread_queue_index = 0;
+    fn try_current_thread(&self) -> Option<u32> {
+        self.processors.get(smp::current_cpu()).and_then(|cpu| cpu.running_thread)
+    }
+
+    fn add_processor(&mut self) -> usize {
+        self.processors.push(Processor::new());
+        self.processors.len() - 1
+    }
+
+    fn set_idle_thread(&mut self, cpu: usize, thread_id: u32) {
+        self.dequeue(thread_id);
+        let thread = &mut self.threads[thread_id];
+        thread.priority = Priority::Idle;
+        thread.cpu = cpu;
+        let _ = self.processors[cpu].idle_thread.insert(thread_id);
+    }
+
+    // returns the cpu whose queue the thread went into
+    fn enqueue(&mut self, thread_id: u32) -> usize {
+        let thread = &mut self.threads[thread_id];
+        thread.time_slice = thread.priority.time_slice();
+        let cpu = thread.cpu;
+        self.processors[cpu].run_queues[thread.priority as usize].push_back(thread_id);
+        cpu
+    }
+
+    fn dequeue(&mut self, thread_id: u32) -> bool {
+        let thread = &self.threads[thread_id];
+        let queue = &mut self.processors[thread.cpu].run_queues[thread.priority as usize];
+        if let Some(i) = queue.iter().position(|tid| *tid == thread_id) {
+            queue.remove(i);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ready_above(&self, cpu: usize, priority: Priority) -> bool {
+        self.processors[cpu].run_queues[..(priority as usize)].iter().any(|queue| !queue.is_empty())
+    }
+
+    // whether a thread that just became ready should kick the cpu it was queued on
+    fn should_preempt(&self, cpu: usize, priority: Priority) -> bool {
+        let processor = &self.processors[cpu];
+        match processor.running_thread {
+            Some(tid) if Some(tid) != processor.idle_thread => self.threads[tid].priority > priority,
+            _ => true,
+        }
+    }
+
+    fn make_ready(&mut self, thread_id: u32) {
+        let priority = self.threads[thread_id].priority;
+        let cpu = self.enqueue(thread_id);
+        if cpu != smp::current_cpu() && self.should_preempt(cpu, priority) {
+            smp::send_reschedule(cpu);
         }
     }
 
     #[inline(always)]
-    fn context_switch(&mut self, current_context: Option<task::TaskContext>, on_timer_interrupt: bool) {
-        if on_timer_interrupt {
-            // MUST happen on timer interrupt to keep delay queue accurate
-            let mut dequeue_delta = false;
-            let mut resume_thread = 0;
-            if let Some((tid, tick)) = self.delta_queue.get_mut(0) {
-                if *tick == 0 {
-                    resume_thread = *tid;
-                    dequeue_delta = true;
-                } else {
-                    *tick -= 1;
-                }
+    fn next(&mut self, cpu: usize) -> u32 {
+        for priority in 0..PRIORITY_COUNT {
+            if let Some(tid) = self.processors[cpu].run_queues[priority].pop_front() {
+                return tid;
+            }
+            // take work from busier cpus before going any lower
+            let stolen = self.processors.iter_mut().find_map(|processor| processor.run_queues[priority].pop_back());
+            if let Some(tid) = stolen {
+                self.threads[tid].cpu = cpu;
+                return tid;
             }
-            if dequeue_delta {
-                self.delta_queue.pop_front();
-                self.resume_thread(resume_thread);
+        }
+        self.processors[cpu].idle_thread.expect("NO_IDLE_THREAD")
+    }
+
+    fn reap_running_thread(&mut self, cpu: usize) {
+        let tid = self.processors[cpu].running_thread.unwrap();
+        let pid = self.threads[tid].process_id;
+        self.threads[tid].die();
+        let process = self.processes.get_mut(&pid).unwrap();
+        if self.threads[tid].user_mode {
+            process.release_stack(self.threads[tid].stack_base);
+            if let Some((start, _)) = self.threads[tid].thread_local {
+                process.release_thread_local(start);
+            }
+        }
+        let index = process.threads.iter().position(|&dt| dt == tid).unwrap();
+        process.threads.remove(index);
+        if self.processes[&pid].threads.len() == 0 {
+            // end process if no threads left in it, while the dying thread is still the current one
+            // so that resources closed along with its handles see who released them
+            self.end_process(pid);
+        }
+        self.threads.remove(tid);
+        self.processors[cpu].running_thread = None;
+    }
+
+    // only on the bootstrap processor's timer, the others get their ticks forwarded
+    fn tick(&mut self) {
+        // MUST happen on timer interrupt to keep delay queue accurate
+        let mut dequeue_delta = false;
+        let mut resume_thread = 0;
+        if let Some((tid, tick)) = self.delta_queue.get_mut(0) {
+            if *tick == 0 {
+                resume_thread = *tid;
+                dequeue_delta = true;
+            } else {
+                *tick -= 1;
             }
         }
-        if let Some(ctx) = current_context {
-            let tid = self.running_queue[self.current_thread_queue_index as usize];
+        if dequeue_delta {
+            self.delta_queue.pop_front();
+            let _ = self.resume_thread(resume_thread);
+        }
+    }
+
+    // returns the context to continue with, the caller restores it once the lock is dropped
+    #[inline(always)]
+    fn context_switch(&mut self, current_context: Option<task::TaskContext>, on_timer_interrupt: bool) -> task::TaskContext {
+        let cpu = smp::current_cpu();
+        if let (Some(ctx), Some(tid)) = (current_context, self.processors[cpu].running_thread) {
+            let idle = self.processors[cpu].idle_thread == Some(tid);
             let current_thread = &mut self.threads[tid];
             if current_thread.zombie {
-                let pid = self.threads[tid].process_id;
-                self.threads[tid].die();
-                let index = self.processes[&pid].threads.iter().position(|&dt| dt == tid).unwrap();
-                self.processes.get_mut(&pid).unwrap().threads.remove(index);
-                self.threads.remove(tid);
-                self.running_queue.remove(self.current_thread_queue_index as usize);
-                if self.processes[&pid].threads.len() == 0 {
-                    // remove process if no threads left in it
-                    self.processes[&pid].die();
-                    self.processes.remove(&pid);
-                }
-                if self.current_thread_queue_index as usize >= self.running_queue.len() {
-                    self.current_thread_queue_index = 0;
-                }
+                self.reap_running_thread(cpu);
             } else if current_thread.suspended {
-                self.threads[tid].state = ctx;
+                current_thread.save_state(ctx);
                 self.suspended_queue.push(tid);
-                self.running_queue.remove(self.current_thread_queue_index as usize);
-                if self.current_thread_queue_index as usize >= self.running_queue.len() {
-                    self.current_thread_queue_index = 0;
-                }
+                self.processors[cpu].running_thread = None;
+            } else if idle {
+                current_thread.save_state(ctx);
+                self.processors[cpu].running_thread = None;
             } else {
-                self.threads[tid].state = ctx;
-                self.next();
+                current_thread.save_state(ctx);
+                if on_timer_interrupt {
+                    current_thread.time_slice = current_thread.time_slice.saturating_sub(1);
+                }
+                let priority = current_thread.priority;
+                // keep running until the slice is used up, something more important wakes up, or the thread yields
+                if !on_timer_interrupt || current_thread.time_slice == 0 || self.ready_above(cpu, priority) {
+                    self.enqueue(tid);
+                    self.processors[cpu].running_thread = None;
+                }
             }
         }
-        let tid = self.running_queue[self.current_thread_queue_index as usize];
+        while let None = self.processors[cpu].running_thread {
+            let tid = self.next(cpu);
+            let _ = self.processors[cpu].running_thread.insert(tid);
+            if self.threads[tid].zombie {
+                // killed while waiting for its turn
+                self.reap_running_thread(cpu);
+            }
+        }
+        let tid = self.processors[cpu].running_thread.unwrap();
         self.threads[tid].restore_state();
-        unsafe { task::restore_registers(&self.threads[tid].state); }
+        self.threads[tid].state.clone()
+    }
+
+    fn end_process(&mut self, process_id: u32) {
+        let process = self.processes.get_mut(&process_id).unwrap();
+        process.die();
+        process.zombie = true;
+        let _ = process.exit_code.get_or_insert(0);
+        let joiner = process.joiner.take();
+        let orphan = process.parent.is_none();
+        // nobody is left to wait for the children
+        let children: Vec<u32> = self.processes.iter().filter(|(_, proc)| proc.parent == Some(process_id)).map(|(pid, _)| *pid).collect();
+        for child in children {
+            if self.processes[&child].zombie {
+                self.processes.remove(&child);
+            } else {
+                self.processes.get_mut(&child).unwrap().parent = None;
+            }
+        }
+        // the parent or a waiting kernel thread reaps the process, one nobody can wait for any more goes right away
+        match joiner {
+            Some(joiner) => {
+                let _ = self.resume_thread(joiner);
+            },
+            None if orphan => {
+                self.processes.remove(&process_id);
+            },
+            None => (),
+        }
     }
 
     fn add_thread(&mut self, process_id: u32, task: task::Task) -> Result<u32, Error> {
         if let None = self.processes.get(&process_id) {
             return Err(Error::EntryNotFound)
         }
-        let tid = self.threads.insert_where_you_can(task.clone());
+        let tid = self.threads.insert_where_you_can(Box::new(task.clone()));
         self.processes.get_mut(&process_id).unwrap().threads.push(tid);
-        self.running_queue.push(tid);
+        // start out on the creating cpu, idle ones steal it if this one is busy
+        self.threads[tid].cpu = smp::current_cpu();
+        self.make_ready(tid);
         Ok(tid)
     }
 
-    fn add_process(&mut self, task: task::Process) -> Result<u32, Error> {
-        let pid = self.get_new_process_id();
-        self.processes.insert(pid, task);
-        Ok(pid)
-    }
-
-    fn exec(&mut self, application: ExecutableInfo) -> Result<(), Error> {
-        let pid = self.get_new_process_id();
-        unsafe {
-            task::Task::exec(application, pid)?;
-            Ok(())
-        }
-    }
-
-    fn kexec(&mut self, application: unsafe fn()) -> Result<(), Error> {
-        let pid = self.get_new_process_id();
-        unsafe {
-            task::Task::kexec(application, pid)?;
+    fn add_process(&mut self, process_id: u32, task: task::Process) -> Result<u32, Error> {
+        if self.processes.contains_key(&process_id) {
+            return Err(Error::AlreadyOpen);
         }
-        Ok(())
+        self.processes.insert(process_id, ProcessRef::new(task));
+        Ok(process_id)
     }
 
     fn terminate_thread(&mut self, thread_id: u32) -> Result<(), Error> {
@@ -140,7 +330,6 @@ impl Scheduler {
             if let Some(joiner) = self.threads[thread_id].joiner {
                 self.resume_thread(joiner)?;
             }
-            task::trigger_context_switch();
             Ok(())
         } else {
             Err(Error::EntryNotFound)
@@ -150,7 +339,6 @@ impl Scheduler {
     fn suspend_thread(&mut self, thread_id: u32) -> Result<(), Error> {
         if let Some(_) = self.threads.get(thread_id) {
             self.threads[thread_id].suspended = true;
-            task::trigger_context_switch();
             Ok(())
         } else {
             Err(Error::EntryNotFound)
@@ -159,18 +347,34 @@ impl Scheduler {
 
     fn resume_thread(&mut self, thread_id: u32) -> Result<(), Error> {
         if let Some(quin) = self.suspended_queue.iter().position(|x| *x == thread_id) {
-            if let Some(tid) = self.suspended_queue.get(quin) {
-                self.suspended_queue.remove(quin);
-                self.running_queue.push(thread_id);
-                self.threads[thread_id].suspended = false;
-                return Ok(())
-            }
+            self.suspended_queue.remove(quin);
+            self.threads[thread_id].suspended = false;
+            self.make_ready(thread_id);
+            return Ok(())
+        }
+        match self.threads.get_mut(thread_id) {
+            Some(thread) if thread.suspended => {
+                // woken up before it got switched out, so it just keeps running
+                thread.suspended = false;
+                Ok(())
+            },
+            _ => Err(Error::EntryNotFound),
         }
-        Err(Error::EntryNotFound)
+    }
+
+    fn set_priority(&mut self, thread_id: u32, priority: Priority) -> Result<(), Error> {
+        if let None = self.threads.get(thread_id) {
+            return Err(Error::EntryNotFound);
+        }
+        let queued = self.dequeue(thread_id);
+        self.threads[thread_id].priority = priority;
+        if queued {
+            self.enqueue(thread_id);
+        }
+        Ok(())
     }
 
     fn delay_thread(&mut self, thread_id: u32, milliseconds: u32) -> Result<(), Error> {
-        cpu::disable_interrupts();
         let mut delta = milliseconds * TICKS_PER_MILLISECOND;
         let mut i = 0;
         while let Some((_, prev)) = self.delta_queue.get_mut(i) {
@@ -183,8 +387,20 @@ impl Scheduler {
             }
         }
         self.delta_queue.insert(i, (thread_id, delta));
-        self.suspend_thread(thread_id)?;
-        Ok(())
+        self.suspend_thread(thread_id)
+    }
+
+    fn cancel_delay(&mut self, thread_id: u32) -> bool {
+        if let Some(i) = self.delta_queue.iter().position(|(tid, _)| *tid == thread_id) {
+            let (_, delta) = self.delta_queue.remove(i).unwrap();
+            // give the remaining time to the next entry so that its deadline stays the same
+            if let Some((_, next)) = self.delta_queue.get_mut(i) {
+                *next += delta;
+            }
+            true
+        } else {
+            false
+        }
     }
 
     fn join_thread(&mut self, joiner: u32, joinee: u32) -> Result<(), Error> {
@@ -198,31 +414,99 @@ impl Scheduler {
         self.suspend_thread(joiner)?;
         Ok(())
     }
-    
+
+    fn join_process_thread(&mut self, joiner: u32, joinee: u32) -> Result<(), Error> {
+        let process_id = self.threads[joiner].process_id;
+        if let Some(thread) = self.threads.get(joinee) {
+            if thread.process_id != process_id || joiner == joinee {
+                return Err(Error::Permissions);
+            }
+            if thread.joiner.is_some() {
+                return Err(Error::AlreadyOpen);
+            }
+            if thread.zombie {
+                return Ok(());
+            }
+            self.join_thread(joiner, joinee)
+        } else {
+            // already gone
+            Ok(())
+        }
+    }
+
+    fn wait_process(&mut self, joiner: u32, process_id: u32) -> Result<(), Error> {
+        let waiter = &self.threads[joiner];
+        let (waiter_process, user_mode) = (waiter.process_id, waiter.user_mode);
+        let process = self.processes.get_mut(&process_id).ok_or(Error::EntryNotFound)?;
+        // kernel threads may wait for anything, processes only for their own children
+        if process_id == waiter_process || (user_mode && process.parent != Some(waiter_process)) {
+            return Err(Error::Permissions);
+        }
+        if process.zombie {
+            return Ok(());
+        }
+        if let Some(_) = process.joiner {
+            return Err(Error::AlreadyOpen);
+        }
+        let _ = process.joiner.insert(joiner);
+        self.suspend_thread(joiner)
+    }
+
+    fn reap_process(&mut self, process_id: u32) -> Result<i32, Error> {
+        match self.processes.get(&process_id) {
+            Some(process) if process.zombie => Ok(self.processes.remove(&process_id).unwrap().exit_code.unwrap_or(0)),
+            Some(_) => Err(Error::NoData),
+            None => Err(Error::EntryNotFound),
+        }
+    }
+
+    fn exit_process(&mut self, process_id: u32, exit_code: i32) -> Result<(), Error> {
+        match self.processes.get_mut(&process_id) {
+            Some(process) if !process.zombie => {
+                // the first code sticks when several threads exit at once
+                let _ = process.exit_code.get_or_insert(exit_code);
+            },
+            _ => return Err(Error::EntryNotFound),
+        }
+        self.terminate_process(process_id)
+    }
+
     fn terminate_process(&mut self, process_id: u32) -> Result<(), Error> {
-        if let None = self.processes.get(&process_id) {
-            return Err(Error::EntryNotFound);
+        match self.processes.get_mut(&process_id) {
+            Some(process) if !process.zombie => {
+                let _ = process.exit_code.get_or_insert(EXIT_CODE_TERMINATED);
+            },
+            _ => return Err(Error::EntryNotFound),
         }
         let thrdlist = self.processes.get(&process_id).unwrap().threads.clone();
         for thrd in thrdlist {
             self.threads[thrd].zombie = true;
+            // blocked threads have to run once more to be cleaned up
+            self.cancel_delay(thrd);
+            let _ = self.resume_thread(thrd);
+            // the ones running elsewhere get cleaned up on their cpu's next switch
+            if let Some(cpu) = self.processors.iter().position(|processor| processor.running_thread == Some(thrd)) {
+                smp::send_reschedule(cpu);
+            }
         }
-        task::trigger_context_switch();
         Ok(())
     }
 
+    // handing the id out right away keeps two cpus creating processes from getting the same one
     fn get_new_process_id(&mut self) -> u32 {
         while let Some(_) = self.processes.get(&self.next_process_id) {
             self.next_process_id += 1;
         }
-        self.next_process_id
+        let pid = self.next_process_id;
+        self.next_process_id += 1;
+        pid
     }
 
     fn get_main_thread(&self, process_id: u32) -> Result<&task::Task, Error> {
         if let Some(proc) = self.processes.get(&process_id) {
             if let Some(tid) = proc.threads.get(0) {
                 if let Some(task) = self.threads.get(*tid) {
-                    return Ok(task);
+                    return Ok(task.as_ref());
                 }
             }
         }
@@ -230,79 +514,235 @@ impl Scheduler {
     }
 }
 
+fn locked<R, F>(f: F) -> R
+where F: FnOnce(&mut Scheduler) -> R {
+    let enabled = cpu::intflag();
+    cpu::disable_interrupts();
+    SCHEDULER_LOCK.lock();
+    let result = f(unsafe { SCHEDULER.as_mut().unwrap() });
+    SCHEDULER_LOCK.unlock();
+    if enabled {
+        cpu::enable_interrupts();
+    }
+    result
+}
+
+// blocking operations only mark the thread, the switch happens after the lock is dropped
+fn yield_if_current(thread: u32) {
+    if locked(|sched| sched.try_current_thread()) == Some(thread) {
+        task::trigger_context_switch();
+    }
+}
+
 pub fn init() {
     unsafe {
-        SCHEDULER.insert(Scheduler::new());
+        let _ = SCHEDULER.insert(Scheduler::new());
+    }
+    add_processor();
+}
+
+// every cpu gets its own queues and idle thread before it starts scheduling
+pub fn add_processor() -> usize {
+    let cpu = locked(|sched| sched.add_processor());
+    let pid = locked(|sched| sched.get_new_process_id());
+    match unsafe { task::Task::kexec(idle, pid) } {
+        Ok(tid) => locked(|sched| sched.set_idle_thread(cpu, tid)),
+        Err(err) => panic!("IDLE_THREAD_FAILURE\n{:?}", err),
+    }
+    cpu
+}
+
+fn idle() {
+    loop {
+        cpu::halt();
+    }
+}
+
+pub fn kexec(application: unsafe fn()) -> Result<u32, Error> {
+    let pid = locked(|sched| sched.get_new_process_id());
+    unsafe { task::Task::kexec(application, pid) }
+}
+
+pub fn kexec_with_priority(application: unsafe fn(), priority: Priority) {
+    if let Ok(tid) = kexec(application) {
+        let _ = set_priority(tid, priority);
     }
 }
 
-pub fn kexec(application: unsafe fn()) {
-    unsafe { SCHEDULER.as_mut().unwrap().kexec(application); }
+pub fn exec(application: ExecutableInfo, interpreter: Option<ExecutableInfo>, args: &[String], env: &[String]) -> Result<u32, Error> {
+    let pid = locked(|sched| sched.get_new_process_id());
+    unsafe {
+        task::Task::exec(application, interpreter, pid, args, env)?;
+    }
+    Ok(pid)
 }
 
-pub fn exec(application: ExecutableInfo) -> Result<(), Error> {
-    unsafe { SCHEDULER.as_mut().unwrap().exec(application) }
+pub fn fork(state: task::TaskContext) -> Result<u32, Error> {
+    let pid = locked(|sched| sched.get_new_process_id());
+    unsafe {
+        task::Task::fork(state, pid)?;
+    }
+    Ok(pid)
 }
 
 #[inline(always)]
 pub fn context_switch(current_context: Option<task::TaskContext>, timer_interrupt: bool) {
-    unsafe { SCHEDULER.as_mut().unwrap().context_switch(current_context, timer_interrupt); }
+    // copied onto this cpu's interrupt stack, other cpus may move the thread table around once the lock is dropped
+    let next = locked(|sched| sched.context_switch(current_context, timer_interrupt));
+    unsafe { task::restore_registers(&next); }
 }
 
-#[inline(always)]
-pub fn next() {
-    unsafe { SCHEDULER.as_mut().unwrap().next(); }
+pub fn tick() {
+    time::tick();
+    locked(|sched| sched.tick());
 }
 
 #[inline(always)]
 pub fn current_thread() -> u32 {
-    unsafe {
-        SCHEDULER.as_mut().unwrap().current_thread() as u32
+    locked(|sched| sched.current_thread())
+}
+
+pub fn try_current_process() -> Option<u32> {
+    if let None = unsafe { SCHEDULER.as_ref() } {
+        return None;
     }
+    locked(|sched| sched.try_current_thread().map(|tid| sched.threads[tid].process_id))
 }
 
 #[inline(always)]
 pub fn current_process() -> u32 {
-    unsafe {
-        SCHEDULER.as_mut().unwrap().current_process()
-    }
+    locked(|sched| sched.current_process())
 }
 
 pub fn delay_thread(thread_id: u32, milliseconds: u32) {
-    unsafe {
-        SCHEDULER.as_mut().unwrap().delay_thread(thread_id, milliseconds);
+    if let Ok(()) = locked(|sched| sched.delay_thread(thread_id, milliseconds)) {
+        yield_if_current(thread_id);
+    }
+}
+
+pub fn suspend_thread(thread: u32) -> Result<(), Error> {
+    locked(|sched| sched.suspend_thread(thread))?;
+    yield_if_current(thread);
+    Ok(())
+}
+
+// the check runs under the scheduler lock, so a wake-up that makes it true cannot slip in before the thread is marked
+pub fn suspend_thread_unless<F>(thread: u32, ready: F) -> Result<(), Error>
+where F: FnOnce() -> bool {
+    let suspended = locked(|sched| match ready() {
+        true => Ok(false),
+        false => sched.suspend_thread(thread).map(|_| true),
+    })?;
+    if suspended {
+        yield_if_current(thread);
+    }
+    Ok(())
+}
+
+pub fn delay_thread_unless<F>(thread: u32, milliseconds: u32, ready: F) -> Result<(), Error>
+where F: FnOnce() -> bool {
+    let delayed = locked(|sched| match ready() {
+        true => Ok(false),
+        false => sched.delay_thread(thread, milliseconds).map(|_| true),
+    })?;
+    if delayed {
+        yield_if_current(thread);
     }
+    Ok(())
+}
+
+pub fn resume_thread(thread: u32) -> Result<(), Error> {
+    locked(|sched| sched.resume_thread(thread))
+}
+
+pub fn cancel_delay(thread: u32) -> bool {
+    locked(|sched| sched.cancel_delay(thread))
 }
 
 pub fn terminate_thread(thread: u32) {
-    unsafe { SCHEDULER.as_mut().unwrap().terminate_thread(thread); }
+    if let Ok(()) = locked(|sched| sched.terminate_thread(thread)) {
+        task::trigger_context_switch();
+    }
 }
 
 pub fn terminate_process(process: u32) {
-    unsafe { SCHEDULER.as_mut().unwrap().terminate_process(process); }
+    if let Ok(()) = locked(|sched| sched.terminate_process(process)) {
+        task::trigger_context_switch();
+    }
 }
 
-pub fn add_process(process: task::Process) -> Result<u32, Error> {
-    unsafe { SCHEDULER.as_mut().unwrap().add_process(process) }
+pub fn add_process(process_id: u32, process: task::Process) -> Result<u32, Error> {
+    locked(|sched| sched.add_process(process_id, process))
+}
+
+// only for a process none of whose threads were added yet
+pub fn remove_process(process_id: u32) -> Option<ProcessRef> {
+    locked(|sched| sched.processes.remove(&process_id))
 }
 
 pub fn add_thread(process_id: u32, task: task::Task) -> Result<u32, Error> {
-    unsafe {
-        SCHEDULER.as_mut().unwrap().add_thread(process_id, task)
-    }
+    locked(|sched| sched.add_thread(process_id, task))
 }
 
 pub fn join_thread(joiner: u32, joinee: u32) -> Result<(), Error> {
-    unsafe {
-        SCHEDULER.as_mut().unwrap().join_thread(joiner, joinee)
+    locked(|sched| sched.join_thread(joiner, joinee))?;
+    yield_if_current(joiner);
+    Ok(())
+}
+
+pub fn join_process_thread(joiner: u32, joinee: u32) -> Result<(), Error> {
+    locked(|sched| sched.join_process_thread(joiner, joinee))?;
+    yield_if_current(joiner);
+    Ok(())
+}
+
+pub fn wait_process(joiner: u32, process_id: u32) -> Result<i32, Error> {
+    locked(|sched| sched.wait_process(joiner, process_id))?;
+    yield_if_current(joiner);
+    locked(|sched| sched.reap_process(process_id))
+}
+
+pub fn exit_process(process: u32, exit_code: i32) {
+    if let Ok(()) = locked(|sched| sched.exit_process(process, exit_code)) {
+        task::trigger_context_switch();
     }
 }
 
-pub fn get_process(process_id: u32) -> Result<&'static task::Process, Error> {
-    unsafe {
-        if let Some(proc) = SCHEDULER.as_mut().unwrap().processes.get(&process_id) {
-            return Ok(proc);
-        }
-        Err(Error::EntryNotFound)
+pub fn get_thread_process(thread: u32) -> Result<u32, Error> {
+    locked(|sched| match sched.threads.get(thread) {
+        Some(thread) => Ok(thread.process_id),
+        None => Err(Error::EntryNotFound),
+    })
+}
+
+pub fn get_priority(thread: u32) -> Result<Priority, Error> {
+    locked(|sched| match sched.threads.get(thread) {
+        Some(thread) => Ok(thread.priority),
+        None => Err(Error::EntryNotFound),
+    })
+}
+
+pub fn set_priority(thread: u32, priority: Priority) -> Result<(), Error> {
+    locked(|sched| sched.set_priority(thread, priority))
+}
+
+// keeps the process around until the reference is dropped, whatever happens to it in the meantime
+pub fn get_process(process_id: u32) -> Result<ProcessRef, Error> {
+    locked(|sched| sched.processes.get(&process_id).cloned().ok_or(Error::EntryNotFound))
+}
+
+// a running thread is only reaped once its own cpu has switched away from it, so the current
+// one stays put in its box for as long as the caller can use the reference
+pub fn try_current_task_mut() -> Option<&'static mut task::Task> {
+    if let None = unsafe { SCHEDULER.as_ref() } {
+        return None;
     }
-}
\ No newline at end of file
+    locked(|sched| sched.try_current_thread().map(|tid| unsafe { &mut *(sched.threads[tid].as_mut() as *mut task::Task) }))
+}
+
+pub fn current_task() -> &'static task::Task {
+    locked(|sched| {
+        let task = sched.threads[sched.current_thread()].as_ref();
+        unsafe { &*(task as *const task::Task) }
+    })
+}
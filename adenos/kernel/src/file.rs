@@ -1,5 +1,5 @@
 use crate::{*, dev::{filesystem::FileSystem}, namespace::{ResourceType, Resource, Handle}};
-use dev::*;
+use dev::{*, hal::mem::heap};
 use alloc::{boxed::Box, string::{String, ToString}};
 use bitflags::bitflags;
 use alloc::{vec, vec::Vec};
@@ -31,39 +31,12 @@ impl File {
         }
     }
 
+    pub fn permissions(&self) -> FilePermissions {
+        self.permissions
+    }
+
     pub fn open(path: String) -> Result<&'static mut Handle, Error> {
-        let parts = namespace::split_resource_path(path.clone());
-        if let Some(r) = parts.get(0) {
-            if *r == "Files" {
-                if let Some(root) = parts.get(1) {
-                    if let Some(fs) = namespace::get_resource_non_generic(String::from("Files/") + root) {
-                        if let ResourceType::FileSystem(fs) = fs.unwrap() {
-                            let mut newpath = String::new();
-                            for part in &parts[2..] {
-                                newpath += (String::from("/") + part.as_str()).as_str();
-                            }
-                            let file = fs.open_file(newpath)?;
-                            if let Some(_) = namespace::get_resource_non_generic(file.resource_path_string()) {
-                                Err(Error::AlreadyOpen)
-                            } else {
-                                namespace::register_resource(file);
-                                namespace::acquire_handle(path, 0)
-                            }
-                        } else {
-                            Err(Error::EntryNotFound)
-                        }
-                    } else {
-                        Err(Error::EntryNotFound)
-                    }
-                } else {
-                    Err(Error::EntryNotFound)
-                }
-            } else {
-                Err(Error::EntryNotFound)
-            }
-        } else {
-            Err(Error::EntryNotFound)
-        }
+        volumes::open(path.as_str())
     }
 }
 
@@ -1,28 +1,51 @@
 use crate::*;
 use crate::exec::scheduler;
 use dev::*;
+use dev::hal::mem::heap;
 use namespace::*;
 use alloc::boxed::Box;
 use alloc::{vec, vec::Vec};
-use modular_bitfield::private::checks::True;
 use ringbuffer::{AllocRingBuffer, RingBufferWrite, RingBufferRead, RingBuffer, RingBufferExt};
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use spin::Mutex;
 use core::cell::RefCell;
 use alloc::string::{String, ToString};
 
+pub mod shared_memory;
+
 pub struct MessageQueue {
     owner: u32,
     endpoint: Endpoint,
     queue: Arc<Mutex<AllocRingBuffer<Message>>>,
+    waiting: Arc<Mutex<Vec<u32>>>,
 }
 
 impl MessageQueue {
     pub fn new(owner: u32, endpoint: Endpoint, capacity: usize) -> MessageQueue {
+        let _subsystem = heap::enter(heap::Subsystem::IPC);
         MessageQueue {
             owner,
             endpoint,
             queue: Arc::new(Mutex::new(AllocRingBuffer::with_capacity(capacity))),
+            waiting: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn check_owner(&self) -> Result<(), Error> {
+        if scheduler::current_process() != self.owner {
+            Err(Error::Permissions)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn remove_waiter(&self, thread_id: u32) -> bool {
+        let mut waiting = self.waiting.lock();
+        if let Some(i) = waiting.iter().position(|tid| *tid == thread_id) {
+            waiting.remove(i);
+            true
+        } else {
+            false
         }
     }
 }
@@ -34,32 +57,80 @@ impl MessageTransport for MessageQueue {
                 return Err(Error::Permissions)
             }
         }
-        self.queue.lock().push(message);
+        {
+            let mut queue = self.queue.lock();
+            if queue.is_full() {
+                return Err(Error::OutOfSpace);
+            }
+            queue.push(message);
+        }
+        // wake up one receiver, it will find the message on its next check
+        let waiter = self.waiting.lock().pop();
+        if let Some(tid) = waiter {
+            let _ = scheduler::resume_thread(tid);
+        }
         Ok(())
     }
 
-    fn receive(&self) -> Result<Message, Error> {
-        panic!("Fuck you");
-       /*if scheduler::current_thread() != self.owner {
-            return Err(Error::Permissions);
+    fn wait(&self, timeout: Option<u32>) -> Result<(), Error> {
+        self.check_owner()?;
+        let tid = scheduler::current_thread();
+        // waking up without a message must not start the timeout over
+        let deadline = timeout.map(|milliseconds| time::monotonic_microseconds() + milliseconds as u64 * 1000);
+        loop {
+            if self.queue.lock().len() > 0 {
+                return Ok(());
+            }
+            let timeout = deadline.map(|deadline| ((deadline.saturating_sub(time::monotonic_microseconds()) + 999) / 1000) as u32);
+            if let Some(0) = timeout {
+                return Err(Error::TimedOut);
+            }
+            // a sender on another cpu may pop us before we are asleep, so the queue is checked again
+            // under the scheduler lock, where its resume_thread cannot slip in between
+            self.waiting.lock().push(tid);
+            let has_message = || !self.queue.lock().is_empty();
+            let result = match timeout {
+                Some(milliseconds) => scheduler::delay_thread_unless(tid, milliseconds, has_message),
+                None => scheduler::suspend_thread_unless(tid, has_message),
+            };
+            if let Err(err) = result {
+                self.remove_waiter(tid);
+                return Err(err);
+            }
+            // a sender removes us from the waiting list, so still being on it means the delay ran out
+            let timed_out = self.remove_waiter(tid);
+            scheduler::cancel_delay(tid);
+            if timed_out && self.queue.lock().len() == 0 {
+                return Err(Error::TimedOut);
+            }
         }
+    }
+
+    fn try_receive(&self) -> Result<Message, Error> {
+        self.check_owner()?;
         if let Some(mesg) = self.queue.lock().dequeue() {
             Ok(mesg)
         } else {
             Err(Error::NoData)
-        }*/
+        }
     }
 
     fn peek_len(&self) -> Result<usize, Error> {
-        panic!("Fuck you");
-        /*if scheduler::current_thread() != self.owner {
-            return Err(Error::Permissions);
-        }
+        self.check_owner()?;
         if let Some(mesg) = self.queue.lock().peek() {
             Ok(mesg.bytes.len())
         } else {
             Err(Error::NoData)
-        }*/
+        }
+    }
+
+    fn peek_sender(&self) -> Result<u32, Error> {
+        self.check_owner()?;
+        if let Some(mesg) = self.queue.lock().peek() {
+            Ok(mesg.from)
+        } else {
+            Err(Error::NoData)
+        }
     }
 
     fn available(&self) -> usize {
@@ -78,8 +149,18 @@ impl MessageTransport for MessageQueue {
 
 pub trait MessageTransport {
     fn send(&self, message: Message) -> Result<(), Error>;
-    fn receive(&self) -> Result<Message, Error>;
+    fn wait(&self, timeout: Option<u32>) -> Result<(), Error>;
+    fn try_receive(&self) -> Result<Message, Error>;
+    fn receive(&self) -> Result<Message, Error> {
+        self.wait(None)?;
+        self.try_receive()
+    }
+    fn receive_timeout(&self, timeout: Option<u32>) -> Result<Message, Error> {
+        self.wait(timeout)?;
+        self.try_receive()
+    }
     fn peek_len(&self) -> Result<usize, Error>;
+    fn peek_sender(&self) -> Result<u32, Error>;
     fn available(&self) -> usize;
     fn owner(&self) -> u32;
     fn endpoint(&self) -> Endpoint;
@@ -102,6 +183,7 @@ impl Message {
 pub struct MessageChannel {
     name: String,
     channel: Box<dyn MessageTransport>,
+    references: Weak<()>,
 }
 
 impl MessageChannel {
@@ -109,6 +191,19 @@ impl MessageChannel {
         MessageChannel {
             name,
             channel,
+            references: Weak::new(),
+        }
+    }
+
+    pub fn receive_into(&self, buf: &mut [u8], timeout: Option<u32>) -> Result<usize, Error> {
+        self.channel.wait(timeout)?;
+        let pk = self.channel.peek_len()?;
+        if pk > buf.len() {
+            // leave the message queued so the receiver can retry with a larger buffer
+            Err(Error::BufferTooSmall)
+        } else {
+            buf[..pk].copy_from_slice(self.channel.try_receive()?.bytes.as_slice());
+            Ok(pk)
         }
     }
 }
@@ -131,30 +226,33 @@ impl MessageTransport for MessageChannel {
         self.channel.peek_len()
     }
 
+    fn peek_sender(&self) -> Result<u32, Error> {
+        self.channel.peek_sender()
+    }
+
     fn send(&self, message: Message) -> Result<(), Error> {
         self.channel.send(message)
     }
 
-    fn receive(&self) -> Result<Message, Error> {
-        self.channel.receive()
+    fn wait(&self, timeout: Option<u32>) -> Result<(), Error> {
+        self.channel.wait(timeout)
+    }
+
+    fn try_receive(&self) -> Result<Message, Error> {
+        self.channel.try_receive()
     }
 }
 
 impl Read for MessageChannel {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        let pk = self.channel.peek_len()?;
-        if pk > buf.len() {
-            Err(Error::BufferTooSmall)
-        } else {
-            buf[..pk].copy_from_slice(self.channel.receive()?.bytes.as_slice());
-            Ok(buf.len())
-        }
+        self.receive_into(buf, None)
     }
 }
 
 impl Write for MessageChannel {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        self.channel.send(Message::new(buf));
+        let _subsystem = heap::enter(heap::Subsystem::IPC);
+        self.channel.send(Message::new(buf))?;
         Ok(buf.len())
     }
 }
@@ -162,7 +260,8 @@ impl Write for MessageChannel {
 impl Resource for MessageChannel {
     fn is_open(&self) -> bool {
         if let Endpoint::Process(pid) = self.channel.endpoint() {
-            if syscall::_get_process_id() as u32 != pid {
+            let current = syscall::_get_process_id() as u32;
+            if current != pid && current != self.channel.owner() {
                 // reserved for a single process
                 return true;
             }
@@ -171,15 +270,16 @@ impl Resource for MessageChannel {
     }
 
     fn set_open_state(&mut self, open: bool) {
-        if syscall::_get_process_id() as u32 == self.channel.owner() {
-            if !open {
-                // destroy channel if owner drops handle
-                namespace::drop_resource_parts(self.resource_path());
-                return;
-            }
+        if !open {
+            // destroy channel once nobody holds a handle to it
+            namespace::drop_resource_parts(self.resource_path());
         }
     }
 
+    fn references(&mut self) -> Option<&mut Weak<()>> {
+        Some(&mut self.references)
+    }
+
     fn resource_path(&self) -> Vec<alloc::string::String> {
         vec![String::from("Processes"), self.channel.owner().to_string(), String::from("MessageChannels"), self.name.clone()]
     }
@@ -231,12 +331,12 @@ impl BidirectionalChannel {
 
 impl Read for BidirectionalChannel {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        Error::from_code_to_usize(syscall::_read(self.receiver as usize, buf.as_mut_ptr(), buf.len()) as i64)
+        Error::from_code_to_usize(syscall::_read(self.receiver as usize, buf) as i64)
     }
 }
 
 impl Write for BidirectionalChannel {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        Error::from_code_to_usize(syscall::_write(self.sender as usize, buf.as_ptr(), buf.len()) as i64)
+        Error::from_code_to_usize(syscall::_write(self.sender as usize, buf) as i64)
     }
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+use crate::*;
+use exec::scheduler;
+use namespace::*;
+use dev::hal::{cpu, smp, mem::{self, page_mapper}};
+use alloc::{vec, vec::Vec, sync::{Arc, Weak}, collections::BTreeMap};
+use alloc::string::{String, ToString};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+// shared regions are mapped in the top of the user space gigabyte, below it live the thread stacks
+pub const SHARED_MEMORY_BASE: u64 = 0x7000_0000;
+pub const SHARED_MEMORY_END: u64 = 0x8000_0000;
+pub const MAX_SHARED_MEMORY_SIZE: usize = 0x100_0000;
+
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SharedMemoryAccess {
+    ReadOnly = 0,
+    ReadWrite = 1,
+}
+
+impl SharedMemoryAccess {
+    pub fn from_usize(val: usize) -> Option<SharedMemoryAccess> {
+        match val {
+            0 => Some(SharedMemoryAccess::ReadOnly),
+            1 => Some(SharedMemoryAccess::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn allows(&self, requested: SharedMemoryAccess) -> bool {
+        *self == SharedMemoryAccess::ReadWrite || requested == SharedMemoryAccess::ReadOnly
+    }
+}
+
+// the physical frames backing a region, freed when the last reference is dropped
+#[derive(Debug)]
+pub struct SharedFrames {
+    frames: Vec<u64>,
+}
+
+impl SharedFrames {
+    fn allocate(pages: usize) -> Result<SharedFrames, Error> {
+        if unsafe { mem::FRAME_ALLOCATOR.get_free_pages() } <= pages {
+            return Err(Error::OutOfSpace);
+        }
+        Ok(SharedFrames {
+            frames: (0..pages).map(|_| page_mapper::new_frame_zeroed()).collect(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames.len() * 0x1000
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        for frame in self.frames.iter() {
+            page_mapper::free_frame(*frame);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SharedMemoryMapping {
+    pub base: u64,
+    frames: Arc<SharedFrames>,
+}
+
+impl SharedMemoryMapping {
+    pub fn size(&self) -> usize {
+        self.frames.size()
+    }
+
+    pub fn unmap(&self, page_table: &mut PageTable) {
+        for i in 0..self.frames.frames.len() as u64 {
+            page_mapper::unmap_addr(page_table, self.base + i * 0x1000);
+        }
+        // other threads of the process may be running elsewhere
+        smp::shoot_down_tlb(None);
+    }
+
+    // the same frames with the same access at the same place, for a forked child
+    pub fn share_with(&self, page_table: &PageTable, child_page_table: &mut PageTable) -> SharedMemoryMapping {
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for (i, frame) in self.frames.frames.iter().enumerate() {
+            let page = self.base + i as u64 * 0x1000;
+            if let Some(entry) = page_mapper::get_l1_entry_using_table(page_table, page) {
+                page_mapper::map_l1_table(child_page_table, page, Some(table_flags));
+                page_mapper::map_addr(child_page_table, page, *frame, Some(entry.flags()));
+            }
+        }
+        self.clone()
+    }
+}
+
+pub struct SharedMemory {
+    name: String,
+    owner: u32,
+    frames: Arc<SharedFrames>,
+    grants: BTreeMap<u32, SharedMemoryAccess>,
+    references: Weak<()>,
+}
+
+impl SharedMemory {
+    pub fn new(name: String, owner: u32, size: usize) -> Result<SharedMemory, Error> {
+        if size == 0 || size > MAX_SHARED_MEMORY_SIZE {
+            return Err(Error::InvalidData);
+        }
+        let mut grants = BTreeMap::new();
+        grants.insert(owner, SharedMemoryAccess::ReadWrite);
+        Ok(SharedMemory {
+            name,
+            owner,
+            frames: Arc::new(SharedFrames::allocate((size + 0xFFF) / 0x1000)?),
+            grants,
+            references: Weak::new(),
+        })
+    }
+
+    pub fn owner(&self) -> u32 {
+        self.owner
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames.size()
+    }
+
+    pub fn grant(&mut self, process_id: u32, access: SharedMemoryAccess) -> Result<(), Error> {
+        if scheduler::current_process() != self.owner || process_id == self.owner {
+            return Err(Error::Permissions);
+        }
+        self.grants.insert(process_id, access);
+        Ok(())
+    }
+
+    pub fn map(&self, access: SharedMemoryAccess) -> Result<u64, Error> {
+        let pid = scheduler::current_process();
+        match self.grants.get(&pid) {
+            Some(granted) if granted.allows(access) => (),
+            _ => return Err(Error::Permissions),
+        }
+        let mut process = scheduler::get_process(pid)?;
+        let page_table = page_mapper::addr_to_page_table(process.page_table());
+        let base = find_free_range(page_table, self.frames.frames.len() as u64).ok_or(Error::OutOfSpace)?;
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flags = match access {
+            SharedMemoryAccess::ReadOnly => PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+            SharedMemoryAccess::ReadWrite => table_flags,
+        };
+        cpu::atomic_no_interrupts(|| {
+            for (i, frame) in self.frames.frames.iter().enumerate() {
+                let page = base + i as u64 * 0x1000;
+                // create the intermediate tables up front so that read-only flags do not end up on them
+                page_mapper::map_l1_table(page_table, page, Some(table_flags));
+                page_mapper::map_addr(page_table, page, *frame, Some(flags));
+            }
+            process.shared_memory.push(SharedMemoryMapping {
+                base,
+                frames: self.frames.clone(),
+            });
+        });
+        Ok(base)
+    }
+}
+
+impl Resource for SharedMemory {
+    fn is_open(&self) -> bool {
+        // any number of processes may hold a handle, but only granted ones may acquire it
+        !self.grants.contains_key(&(syscall::_get_process_id() as u32))
+    }
+
+    fn set_open_state(&mut self, open: bool) {
+        if !open {
+            // the name goes away with the last handle, the frames live on in existing mappings
+            namespace::drop_resource_parts(self.resource_path());
+        }
+    }
+
+    fn references(&mut self) -> Option<&mut Weak<()>> {
+        Some(&mut self.references)
+    }
+
+    fn resource_path(&self) -> Vec<String> {
+        vec![String::from("Processes"), self.owner.to_string(), String::from("SharedMemory"), self.name.clone()]
+    }
+
+    fn unwrap(&mut self) -> ResourceType {
+        ResourceType::SharedMemory(self)
+    }
+}
+
+fn find_free_range(page_table: &PageTable, pages: u64) -> Option<u64> {
+    let mut base = SHARED_MEMORY_BASE;
+    let mut found = 0;
+    while found < pages {
+        let page = base + found * 0x1000;
+        if page >= SHARED_MEMORY_END {
+            return None;
+        }
+        if let Some(_) = page_mapper::translate_addr_using_table(page_table, page as usize) {
+            base = page + 0x1000;
+            found = 0;
+        } else {
+            found += 1;
+        }
+    }
+    Some(base)
+}
+
+pub fn unmap(address: u64) -> Result<(), Error> {
+    let mut process = scheduler::get_process(scheduler::current_process())?;
+    let index = process.shared_memory.iter().position(|mapping| mapping.base == address).ok_or(Error::InvalidAddress)?;
+    let page_table = page_mapper::addr_to_page_table(process.page_table());
+    cpu::atomic_no_interrupts(|| {
+        process.shared_memory.remove(index).unmap(page_table);
+    });
+    Ok(())
+}
@@ -14,6 +14,7 @@ use dev::hal::*;
 use async_task::*;
 use crate::exec::scheduler;
 use alloc::string::{String, ToString};
+use infinity::os::IOHandle;
 
 pub fn run_kernel() -> ! {
     
@@ -31,10 +32,14 @@ fn init_system() -> Result<(), Error> {
     infinity::connect_system_call_handler(syscall::system_call);
     early_print!("Linfinity Technologies AdenOS [Version {}]\n", sysinfo::ADEN_VERSION);
     dev::hal::init();
+    time::init();
     early_print!("[{} MB Memory Available]\n", unsafe { mem::FREE_MEMORY } / 1048576 + 1);
     println!("");
     scheduler::init();
+    // partitions mount what they hold as they initialize
+    volumes::init();
     let devices = namespace::namespace().get_subtree(String::from("Devices")).unwrap();
+    let subsystem = mem::heap::enter(mem::heap::Subsystem::Devices);
     for (_, dev) in devices.iter_mut_bf() {
         if let Some(dev) = dev {
             if let ResourceType::Device(dev) = dev.unwrap() {
@@ -45,21 +50,32 @@ fn init_system() -> Result<(), Error> {
             }
         }
     }
+    drop(subsystem);
     for (_, dev) in namespace::namespace().iter_mut_bf() {
         if let Some(dev) = dev {
             println!("{}", dev.resource_path_string());
         }
     }
-    let file = file::File::open(String::from("/Files/AdenOS/test.elf"))?;
-    let exe_inf = exec::elf::ELFLoader::load_executable(file.id)?;
-    scheduler::exec(exe_inf.clone())?;
-    serial_println!("{:#x?}", exe_inf);
+    // the local APIC is up now, the other cpus wait for the scheduler to be enabled
+    if let Err(err) = smp::start_application_processors() {
+        println!("Could not start application processors: {:?}", err);
+    }
+    println!("[{} Processors]", smp::processor_count());
+    for io in [IOHandle::Input, IOHandle::Output, IOHandle::Log] {
+        namespace::acquire_standard_handle(io, String::from("/Devices/Character/KernelLogger"))?;
+    }
+    let boot_volume = volumes::find_volume("AdenOS").ok_or(Error::EntryNotFound)?;
+    let init_path = boot_volume + "/test.elf";
+    let pid = exec::spawn(init_path.as_str(), &[init_path.clone()], &[])?;
+    exec::set_init_process(pid);
+    serial_println!("Started {} as process {}", init_path, pid);
 
     kernel_console::set_color(ConsoleColor::BrightBlue,  ConsoleColor::BrightBlack);
     kernel_executor::init();
     dev::input::PS2KeyboardPIC8259::set_input_handler(test_input_keyboard);
     dev::input::PS2KeyboardPIC8259::init_device().unwrap();
-    scheduler::kexec(kernel_executor::run);
+    // input handling runs on the executor, so it preempts batch work
+    scheduler::kexec_with_priority(kernel_executor::run, scheduler::Priority::Realtime);
     //scheduler::kexec(test_kernel_thread_with_ipc_recv);
     //scheduler::kexec(test_kernel_thread_with_ipc_send);
     //scheduler::kexec(test_kernel_thread_joiner);
@@ -93,7 +109,7 @@ fn test_kernel_thread_with_ipc_recv() {
         }
         println!();
     }
-    syscall::_exit();
+    syscall::_exit(0);
     loop {}
 }
 
@@ -102,7 +118,7 @@ fn test_kernel_thread_with_ipc_send() {
     for i in 0..128000 {
         mq.send(Message::new((String::from("Hello world") + i.to_string().as_str()).as_bytes()));
     }
-    syscall::_exit();
+    syscall::_exit(0);
     loop {}
 }
 
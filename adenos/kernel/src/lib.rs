@@ -17,9 +17,11 @@ use core::panic::PanicInfo;
 
 pub mod dev;
 pub mod ipc;
+pub mod boot;
 pub mod test;
 pub mod exec;
 pub mod file;
+pub mod time;
 pub mod panic;
 pub mod kernel;
 pub mod sysinfo;
@@ -45,12 +47,7 @@ entry_point!(test_kernel_main);
 #[cfg(test)]
 #[cfg(target_arch = "x86_64")]
 fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    unsafe { 
-        dev::hal::mem::PHYSICAL_MEMORY_OFFSET = boot_info.physical_memory_offset.into_option().unwrap();
-        dev::hal::mem::BOOT_MEMORY_MAP = Some(&boot_info.memory_regions);
-        let free_mem: usize = 0;
-        dev::hal::mem::FREE_MEMORY = free_mem;
-    }
+    boot::init(boot_info);
     dev::hal::init();
     #[cfg(test)]
     test_main();
@@ -67,5 +64,5 @@ fn panic(info: &PanicInfo) -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("\nMEMORY_ALLOCATION_ERROR\n{:#?}", layout)
+    dev::hal::mem::heap::out_of_memory(layout)
 }
\ No newline at end of file
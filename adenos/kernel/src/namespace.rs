@@ -1,11 +1,20 @@
 use crate::*;
-use alloc::{boxed::Box, string::String, vec, vec::Vec, collections::BTreeMap};
-use {dev::*, collections::tree::*, ipc::*};
+use alloc::{boxed::Box, string::String, vec, vec::Vec, collections::BTreeMap, sync::{Arc, Weak}};
+use {dev::*, collections::tree::*, ipc::*, exec::scheduler};
 use crate::{*, dev::{*, filesystem::FileSystem}};
+use infinity::os::IOHandle;
+use dev::hal::{cpu, smp::ProcessorLock, mem::heap};
+use core::fmt;
 
 static mut NAMESPACE: Tree<String, Box<dyn Resource>> = Tree::new(String::new(), None);
-static mut HANDLES: BTreeMap<u32, Handle> = BTreeMap::new();
-static mut HANDLE_ID: u32 = 0;
+// system calls on every cpu look things up in the tree while others add to it and take from it
+static NAMESPACE_LOCK: ProcessorLock = ProcessorLock::new();
+// used before the scheduler runs, and inherited by the first processes
+static mut KERNEL_HANDLES: HandleTable = HandleTable::new(KERNEL_HANDLE_OWNER);
+
+pub const KERNEL_HANDLE_OWNER: u32 = u32::MAX;
+// Input, Output and Log are always at the same ids, new handles are allocated after them
+pub const STANDARD_HANDLE_COUNT: u32 = 3;
 
 
 pub enum ResourceType<'a> {
@@ -14,6 +23,7 @@ pub enum ResourceType<'a> {
     FileSystem(&'a mut dyn FileSystem),
     File(&'a mut file::File),
     MessageChannel(&'a mut ipc::MessageChannel),
+    SharedMemory(&'a mut ipc::shared_memory::SharedMemory),
     Other
 }
 
@@ -26,6 +36,12 @@ pub trait Resource {
 
     }
 
+    // for a resource that any number of handles may be acquired to, shared by all of them so that
+    // it is closed along with the last one
+    fn references(&mut self) -> Option<&mut Weak<()>> {
+        None
+    }
+
     fn unwrap(&mut self) -> ResourceType;
     fn resource_path(&self) -> Vec<String>;
     fn resource_path_string(&self) -> String {
@@ -41,14 +57,24 @@ pub struct Handle {
     pub id: u32,
     pub owner: u32,
     pub resource: &'static mut Box<dyn Resource>,
+    references: Arc<()>,
 }
 
 impl Handle {
     pub fn new(id: u32, owner: u32, resource: &'static mut Box<dyn Resource>) -> Handle {
+        let references = match resource.references() {
+            Some(shared) => shared.upgrade().unwrap_or_else(|| {
+                let references = Arc::new(());
+                *shared = Arc::downgrade(&references);
+                references
+            }),
+            None => Arc::new(()),
+        };
         Handle {
             id,
             owner,
             resource,
+            references,
         }
     }
 
@@ -56,9 +82,156 @@ impl Handle {
         self.resource
     }
 
+    pub fn duplicate(&mut self, id: u32, owner: u32) -> Handle {
+        Handle {
+            id,
+            owner,
+            resource: unsafe { &mut *(self.resource as *mut Box<dyn Resource>) },
+            references: self.references.clone(),
+        }
+    }
+
     pub fn release(self) -> Result<(), Error> {
         namespace::release_handle(self.id)
     }
+
+    pub fn close(self) {
+        // duplicates share the open resource, only the last one closes it
+        if Arc::into_inner(self.references).is_some() {
+            self.resource.set_open_state(false);
+        }
+    }
+}
+
+pub struct HandleTable {
+    owner: u32,
+    // boxed, so that a handle does not move when the map changes around it
+    handles: BTreeMap<u32, Box<Handle>>,
+    // the threads of a process use its table from every cpu
+    lock: ProcessorLock,
+}
+
+fn next_id(handles: &BTreeMap<u32, Box<Handle>>) -> u32 {
+    let mut id = STANDARD_HANDLE_COUNT;
+    while handles.contains_key(&id) {
+        id += 1;
+    }
+    id
+}
+
+impl HandleTable {
+    pub const fn new(owner: u32) -> HandleTable {
+        HandleTable {
+            owner,
+            handles: BTreeMap::new(),
+            lock: ProcessorLock::new(),
+        }
+    }
+
+    // interrupts stay off while it is held, handles taken out of the table are closed after it is let go
+    fn locked<R, F>(&mut self, f: F) -> R
+    where F: FnOnce(&mut BTreeMap<u32, Box<Handle>>) -> R {
+        let enabled = cpu::intflag();
+        cpu::disable_interrupts();
+        self.lock.lock();
+        let result = f(&mut self.handles);
+        self.lock.unlock();
+        if enabled {
+            cpu::enable_interrupts();
+        }
+        result
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Handle> {
+        let hndl = self.locked(|handles| handles.get_mut(&id).map(|hndl| &mut **hndl as *mut Handle));
+        hndl.map(|hndl| unsafe { &mut *hndl })
+    }
+
+    pub fn insert(&mut self, resource: &'static mut Box<dyn Resource>, id: Option<u32>) -> &mut Handle {
+        let owner = self.owner;
+        // whatever had the id is closed before the new handle is made, it may be the same resource
+        if let Some(old) = id.and_then(|id| self.locked(|handles| handles.remove(&id))) {
+            old.close();
+        }
+        let (hndl, old) = self.locked(|handles| {
+            let id = id.unwrap_or_else(|| next_id(handles));
+            let mut hndl = Box::new(Handle::new(id, owner, resource));
+            let ptr = &mut *hndl as *mut Handle;
+            (ptr, handles.insert(id, hndl))
+        });
+        if let Some(old) = old {
+            old.close();
+        }
+        unsafe { &mut *hndl }
+    }
+
+    pub fn duplicate(&mut self, id: u32, target: Option<u32>) -> Result<u32, Error> {
+        if target == Some(id) {
+            return Ok(id);
+        }
+        let owner = self.owner;
+        let (new_id, old) = self.locked(|handles| {
+            let new_id = target.unwrap_or_else(|| next_id(handles));
+            let dup = handles.get_mut(&id).ok_or(Error::InvalidHandle)?.duplicate(new_id, owner);
+            Ok((new_id, handles.insert(new_id, Box::new(dup))))
+        })?;
+        if let Some(old) = old {
+            old.close();
+        }
+        Ok(new_id)
+    }
+
+    // whether any handle in the table is to the resource at path
+    pub fn holds(&mut self, path: &[String]) -> bool {
+        self.locked(|handles| handles.values().any(|hndl| hndl.resource.resource_path() == path))
+    }
+
+    pub fn release(&mut self, id: u32) -> Result<(), Error> {
+        self.locked(|handles| handles.remove(&id)).ok_or(Error::InvalidHandle)?.close();
+        Ok(())
+    }
+
+    pub fn release_all(&mut self) {
+        for (_, hndl) in self.locked(|handles| core::mem::take(handles)) {
+            hndl.close();
+        }
+    }
+
+    pub fn inherit(&mut self, parent: &mut HandleTable) {
+        let owner = self.owner;
+        let inherited: Vec<Box<Handle>> = parent.locked(|handles| {
+            [IOHandle::Input, IOHandle::Output, IOHandle::Log].into_iter()
+                .map(|io| io as u32)
+                .filter_map(|id| handles.get_mut(&id).map(|hndl| Box::new(hndl.duplicate(id, owner))))
+                .collect()
+        });
+        self.locked(|handles| {
+            for hndl in inherited {
+                handles.insert(hndl.id, hndl);
+            }
+        });
+    }
+
+    // every handle, for a forked child
+    pub fn inherit_all(&mut self, parent: &mut HandleTable) {
+        let owner = self.owner;
+        let inherited: Vec<Box<Handle>> = parent.locked(|handles| {
+            handles.iter_mut().map(|(id, hndl)| Box::new(hndl.duplicate(*id, owner))).collect()
+        });
+        self.locked(|handles| {
+            for hndl in inherited {
+                if !handles.contains_key(&hndl.id) {
+                    handles.insert(hndl.id, hndl);
+                }
+            }
+        });
+    }
+}
+
+impl fmt::Debug for HandleTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.handles.iter().map(|(id, hndl)| (id, hndl.resource.resource_path_string()))).finish()
+    }
 }
 
 pub fn cast_resource<D>(resource: &'static mut Box<dyn Resource>) -> &'static mut D {
@@ -67,6 +240,21 @@ pub fn cast_resource<D>(resource: &'static mut Box<dyn Resource>) -> &'static mu
     }
 }
 
+// interrupts stay off while it is held, a process ending on the timer drops its resources from the tree
+fn locked<R, F>(f: F) -> R
+where F: FnOnce(&'static mut Tree<String, Box<dyn Resource>>) -> R {
+    let enabled = cpu::intflag();
+    cpu::disable_interrupts();
+    NAMESPACE_LOCK.lock();
+    let result = f(unsafe { &mut NAMESPACE });
+    NAMESPACE_LOCK.unlock();
+    if enabled {
+        cpu::enable_interrupts();
+    }
+    result
+}
+
+// without the lock, for walking the devices at boot before the other cpus are started
 pub fn namespace() -> &'static mut Tree<String, Box<dyn Resource>> {
     unsafe {
         &mut NAMESPACE
@@ -74,16 +262,23 @@ pub fn namespace() -> &'static mut Tree<String, Box<dyn Resource>> {
 }
 
 pub fn subtree_parts(path: Vec<String>) -> Option<&'static mut Tree<String, Box<dyn Resource>>> {
-    namespace().get_node_by_path(path)
+    locked(|namespace| namespace.get_node_by_path(path))
 }
 
 pub fn subtree(path: String) -> Option<&'static mut Tree<String, Box<dyn Resource>>> {
-    namespace().get_node_by_path(split_resource_path(path))
+    subtree_parts(split_resource_path(path))
+}
+
+// whether anything is registered anywhere below path, not counting path itself
+pub fn has_resources_below(path: Vec<String>) -> bool {
+    locked(|namespace| namespace.get_node_by_path(path).map_or(false, |subtree| subtree.iter_mut_bf().any(|(_, resource)| resource.is_some())))
 }
 
 pub fn init_namespace() {
-    namespace().insert_subtree(String::from("Devices"), None);
-    namespace().insert_subtree(String::from("Files"), None);
+    locked(|namespace| {
+        namespace.insert_subtree(String::from("Devices"), None);
+        namespace.insert_subtree(String::from("Files"), None);
+    });
 }
 
 pub fn split_resource_path(path: String) -> Vec<String> {
@@ -107,22 +302,26 @@ pub fn get_resource_non_generic(path: String) -> Option<&'static mut Box<dyn Res
 }
 
 pub fn get_resource_non_generic_parts(path: Vec<String>) -> Option<&'static mut Box<dyn Resource>> {
-    if let Some(node) = namespace().get_node_by_path(path) {
-        node.value()
-    } else {
-        None
-    }
+    locked(|namespace| namespace.get_node_by_path(path).and_then(|node| node.value()))
 }
 
 pub fn register_resource<T: Resource + 'static>(resource: T) -> &'static mut T {
     let path = resource.resource_path();
-    namespace().insert_node_by_path(path.clone(), Some(Box::new(resource)));
-    get_resource_parts(path).unwrap()
+    register_resource_path(path, resource)
 }
 
 pub fn register_resource_path<T: Resource + 'static>(path: Vec<String>, resource: T) -> &'static mut T {
-    namespace().insert_node_by_path(path.clone(), Some(Box::new(resource)));
-    get_resource_parts(path).unwrap()
+    let _subsystem = heap::enter(heap::Subsystem::Namespace);
+    cast_resource(register_boxed_resource_path(path, Box::new(resource)))
+}
+
+// looked up again under the same lock, so that the reference is to what was just inserted
+pub fn register_boxed_resource_path(path: Vec<String>, resource: Box<dyn Resource>) -> &'static mut Box<dyn Resource> {
+    let _subsystem = heap::enter(heap::Subsystem::Namespace);
+    locked(|namespace| {
+        namespace.insert_node_by_path(path.clone(), Some(resource));
+        namespace.get_node_by_path(path).and_then(|node| node.value()).unwrap()
+    })
 }
 
 pub fn get_block_device(path: String) -> Option<&'static mut dyn dev::BlockReadWrite> {
@@ -140,50 +339,47 @@ pub fn get_block_device_parts(path: Vec<String>) -> Option<&'static mut dyn dev:
     None
 }
 
-pub fn get_new_handle_id() -> u32 {
+pub fn kernel_handle_table() -> &'static mut HandleTable {
     unsafe {
-        while HANDLES.contains_key(&HANDLE_ID) {
-            HANDLE_ID += 1;
-        }
-        let ohid = HANDLE_ID;
-        HANDLE_ID += 1;
-        ohid
+        &mut KERNEL_HANDLES
     }
 }
 
-pub fn acquire_handle(path: String, owner: u32) -> Result<&'static mut Handle, Error> {
-    serial_println!("Code reached here (path: {})", path);
-    if let Some(res) = get_resource_non_generic(path) {
-        serial_println!("Found res");
+pub fn handle_table() -> &'static mut HandleTable {
+    match scheduler::try_current_process().and_then(|pid| scheduler::get_process(pid).ok()) {
+        // the calling thread keeps its own process from being reaped
+        Some(mut process) => unsafe { &mut *(&mut process.handles as *mut HandleTable) },
+        None => kernel_handle_table(),
+    }
+}
+
+fn acquire_handle_at(path: String, id: Option<u32>) -> Result<&'static mut Handle, Error> {
+    // checked and marked in one go, so that two cpus cannot both open it
+    let res = locked(|namespace| {
+        let res = namespace.get_node_by_path(split_resource_path(path)).and_then(|node| node.value()).ok_or(Error::EntryNotFound)?;
         if res.is_open() {
-            serial_println!("Perms err");
-            Err(Error::Permissions)
-        } else {
-            serial_println!("Creating hancle");
-            res.set_open_state(true);
-            let id = get_new_handle_id();
-            let hndl = Handle::new(id, owner, res);
-            unsafe {
-                HANDLES.insert(hndl.id, hndl);
-                serial_println!("Created handle {}, next one is {}", id, HANDLE_ID);
-                Ok(HANDLES.get_mut(&id).unwrap())
-            }
+            return Err(Error::Permissions);
         }
-    } else {
-        Err(Error::EntryNotFound)
-    }
+        res.set_open_state(true);
+        Ok(res)
+    })?;
+    Ok(handle_table().insert(res, id))
+}
+
+pub fn acquire_handle(path: String) -> Result<&'static mut Handle, Error> {
+    acquire_handle_at(path, None)
+}
+
+pub fn acquire_standard_handle(io: IOHandle, path: String) -> Result<&'static mut Handle, Error> {
+    acquire_handle_at(path, Some(io as u32))
+}
+
+pub fn duplicate_handle(id: u32, target: Option<u32>) -> Result<u32, Error> {
+    handle_table().duplicate(id, target)
 }
 
 pub fn release_handle(id: u32) -> Result<(), Error> {
-    if let Some(res) = unsafe { HANDLES.remove(&id) } {
-        res.resource.set_open_state(false);
-        unsafe {
-            namespace::HANDLE_ID = res.id;
-        }
-        Ok(())
-    } else {
-        Err(Error::InvalidHandle)
-    }
+    handle_table().release(id)
 }
 
 pub fn drop_resource(path: String) -> Result<(), Error> {
@@ -191,64 +387,66 @@ pub fn drop_resource(path: String) -> Result<(), Error> {
 }
 
 pub fn drop_resource_parts(path: Vec<String>) -> Result<(), Error> {
-    unsafe {
-        NAMESPACE.remove_node_by_path(path)
-    }
+    locked(|namespace| namespace.remove_node_by_path(path))
 }
 
 pub fn get_rw_handle(handle: u32) -> Option<&'static mut dyn ReadWrite> {
-    unsafe {
-        if let Some(hndl) = HANDLES.get_mut(&handle) {
-            match hndl.unwrap().unwrap() {
-                ResourceType::File(file) => Some(file),
-                ResourceType::MessageChannel(que) => Some(que),
-                ResourceType::ReadWriteDevice(dev) => Some(dev),
-                _ => None,
-            }
-        } else {
-            None
+    if let Some(hndl) = handle_table().get_mut(handle) {
+        match hndl.unwrap().unwrap() {
+            ResourceType::File(file) => Some(file),
+            ResourceType::MessageChannel(que) => Some(que),
+            ResourceType::ReadWriteDevice(dev) => Some(dev),
+            _ => None,
         }
+    } else {
+        None
     }
 }
 
 pub fn get_seek_handle(handle: u32) -> Option<&'static mut dyn Seek> {
-    unsafe {
-        if let Some(hndl) = HANDLES.get_mut(&handle) {
-            if let ResourceType::File(file) = hndl.unwrap().unwrap() {
-                Some(file)
-            } else {
-                None
-            }
+    if let Some(hndl) = handle_table().get_mut(handle) {
+        if let ResourceType::File(file) = hndl.unwrap().unwrap() {
+            Some(file)
         } else {
             None
         }
+    } else {
+        None
     }
 }
 
 pub fn get_file_handle(handle: u32) -> Option<&'static mut file::File> {
-    unsafe {
-        if let Some(hndl) = HANDLES.get_mut(&handle) {
-            if let ResourceType::File(file) = hndl.unwrap().unwrap() {
-                Some(file)
-            } else {
-                None
-            }
+    if let Some(hndl) = handle_table().get_mut(handle) {
+        if let ResourceType::File(file) = hndl.unwrap().unwrap() {
+            Some(file)
         } else {
             None
         }
+    } else {
+        None
     }
 }
 
 pub fn get_message_channel_handle(handle: u32) -> Option<&'static mut ipc::MessageChannel> {
-    unsafe {
-            if let Some(hndl) = HANDLES.get_mut(&handle) {
-            if let ResourceType::MessageChannel(que) = hndl.unwrap().unwrap() {
-                Some(que)
-            } else {
-                None
-            }
+    if let Some(hndl) = handle_table().get_mut(handle) {
+        if let ResourceType::MessageChannel(que) = hndl.unwrap().unwrap() {
+            Some(que)
         } else {
             None
         }
+    } else {
+        None
+    }
+}
+
+pub fn get_shared_memory_handle(handle: u32) -> Option<&'static mut ipc::shared_memory::SharedMemory> {
+    if let Some(hndl) = handle_table().get_mut(handle) {
+        if let ResourceType::SharedMemory(shm) = hndl.unwrap().unwrap() {
+            Some(shm)
+        } else {
+            None
+        }
+    } else {
+        None
     }
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
-use crate::{*, exec::scheduler, ipc::MessageQueue};
-use core::{str, slice};
-use alloc::{vec, vec::Vec, string::ToString, boxed::Box};
+use crate::{*, exec::scheduler, ipc::{MessageQueue, shared_memory::{self, SharedMemory, SharedMemoryAccess}}};
+use core::str;
+use alloc::{vec, vec::Vec, string::{String, ToString}, boxed::Box};
 use {namespace, ipc::*};
-use cstr_core::CStr;
+use dev::hal::{task, mem::{heap, user_memory, vma}};
+use infinity::os::{MemoryProtection, MappingFlags, DirectoryEntry, FileSystemCheck, MAX_FILE_NAME};
 
 pub const SYSTEM_CALL_READ: usize = 0;
 pub const SYSTEM_CALL_WRITE: usize = 1;
@@ -15,44 +16,245 @@ pub const SYSTEM_CALL_ACQUIRE_HANDLE: usize = 7;
 pub const SYSTEM_CALL_RELEASE_HANDLE: usize = 8;
 pub const SYSTEM_CALL_MESSAGE_QUEUE_COUNT: usize = 9;
 pub const SYSTEM_CALL_AVAILABLE_MESSAGE_SIZE: usize = 10;
+pub const SYSTEM_CALL_SEND_MESSAGE: usize = 11;
+pub const SYSTEM_CALL_RECEIVE_MESSAGE: usize = 12;
+pub const SYSTEM_CALL_CREATE_SHARED_MEMORY: usize = 13;
+pub const SYSTEM_CALL_GRANT_SHARED_MEMORY: usize = 14;
+pub const SYSTEM_CALL_MAP_SHARED_MEMORY: usize = 15;
+pub const SYSTEM_CALL_UNMAP_SHARED_MEMORY: usize = 16;
+pub const SYSTEM_CALL_DUPLICATE_HANDLE: usize = 17;
+pub const SYSTEM_CALL_SPAWN: usize = 18;
+pub const SYSTEM_CALL_WAIT: usize = 19;
+pub const SYSTEM_CALL_CREATE_THREAD: usize = 20;
+pub const SYSTEM_CALL_JOIN_THREAD: usize = 21;
+pub const SYSTEM_CALL_EXIT_THREAD: usize = 22;
+pub const SYSTEM_CALL_SLEEP: usize = 23;
+pub const SYSTEM_CALL_GET_PRIORITY: usize = 24;
+pub const SYSTEM_CALL_SET_PRIORITY: usize = 25;
+pub const SYSTEM_CALL_MAP_MEMORY: usize = 26;
+pub const SYSTEM_CALL_UNMAP_MEMORY: usize = 27;
+pub const SYSTEM_CALL_FORK: usize = 28;
+pub const SYSTEM_CALL_MAP_FILE: usize = 29;
+pub const SYSTEM_CALL_SYNC_MEMORY: usize = 30;
+pub const SYSTEM_CALL_SET_FS_BASE: usize = 31;
+pub const SYSTEM_CALL_MOUNT: usize = 32;
+pub const SYSTEM_CALL_UNMOUNT: usize = 33;
+pub const SYSTEM_CALL_READ_DIRECTORY: usize = 34;
+pub const SYSTEM_CALL_STAT: usize = 35;
+pub const SYSTEM_CALL_REMOVE: usize = 36;
+pub const SYSTEM_CALL_RENAME: usize = 37;
+pub const SYSTEM_CALL_CREATE_DIRECTORY: usize = 38;
+pub const SYSTEM_CALL_REMOVE_DIRECTORY: usize = 39;
+pub const SYSTEM_CALL_CHECK_FILE_SYSTEM: usize = 40;
+pub const SYSTEM_CALL_GET_TIME: usize = 41;
+pub const SYSTEM_CALL_GET_MONOTONIC_TIME: usize = 42;
+pub const SYSTEM_CALL_WAIT_MESSAGE: usize = 43;
+
+pub const TIMEOUT_INFINITE: u32 = u32::MAX;
+pub const MAX_SPAWN_ARGUMENTS: usize = 256;
+
+// entered from user space, user_stack is where the trap handler saved the registers it restores on the way out
+#[no_mangle]
+pub extern "C" fn user_system_call(syscall: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, user_stack: usize) -> isize {
+    match syscall {
+        SYSTEM_CALL_FORK => {
+            let _subsystem = heap::enter(heap::Subsystem::SystemCall);
+            _fork(user_stack)
+        },
+        _ => system_call(syscall, arg0, arg1, arg2, arg3),
+    }
+}
 
 #[no_mangle]
 #[inline(always)]
 #[allow(unused_variables)]
 pub extern "C" fn system_call(syscall: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+    let _subsystem = heap::enter(heap::Subsystem::SystemCall);
     match syscall {
-        SYSTEM_CALL_READ => _read(arg0, arg1 as *mut u8, arg2) as isize,
-        SYSTEM_CALL_WRITE => _write(arg0, arg1 as *const u8, arg2),
+        SYSTEM_CALL_READ => user_read(arg0, arg1, arg2),
+        SYSTEM_CALL_WRITE => user_write(arg0, arg1, arg2),
         SYSTEM_CALL_SEEK => _seek(arg0, arg1 as i64, arg2 == 1),
         SYSTEM_CALL_RESERVED0 => 0,
-        SYSTEM_CALL_EXIT => _exit(),
+        SYSTEM_CALL_EXIT => _exit(arg0 as i32),
         SYSTEM_CALL_GET_PROCESS_ID => _get_process_id(),
-        SYSTEM_CALL_CREATE_MESSAGE_QUEUE => _create_message_queue(c_str(arg0), arg1 as u32),
-        SYSTEM_CALL_ACQUIRE_HANDLE => _acquire_handle(c_str(arg0)),
+        SYSTEM_CALL_CREATE_MESSAGE_QUEUE => match c_str(arg0) {
+            Ok(name) => _create_message_queue(name.as_str(), arg1 as u32),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_ACQUIRE_HANDLE => match c_str(arg0) {
+            Ok(path) => _acquire_handle(path.as_str()),
+            Err(err) => err.code() as isize,
+        },
         SYSTEM_CALL_RELEASE_HANDLE => _release_handle(arg0 as u32),
         SYSTEM_CALL_MESSAGE_QUEUE_COUNT => _available_messages(arg0 as u32),
         SYSTEM_CALL_AVAILABLE_MESSAGE_SIZE => _available_message_size(arg0 as u32),
+        SYSTEM_CALL_SEND_MESSAGE => match user_memory::read_user_vec(arg1, arg2.min(user_memory::MAX_USER_TRANSFER)) {
+            Ok(buf) => _send_message(arg0 as u32, buf.as_slice()),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_RECEIVE_MESSAGE => user_read_with(arg1, arg2, |buf| _receive_message(arg0 as u32, buf, timeout(arg3))),
+        SYSTEM_CALL_WAIT_MESSAGE => _wait_message(arg0 as u32, timeout(arg1)),
+        SYSTEM_CALL_CREATE_SHARED_MEMORY => match c_str(arg0) {
+            Ok(name) => _create_shared_memory(name.as_str(), arg1),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_GRANT_SHARED_MEMORY => match SharedMemoryAccess::from_usize(arg2) {
+            Some(access) => _grant_shared_memory(arg0 as u32, arg1 as u32, access),
+            None => Error::InvalidData.code() as isize,
+        },
+        SYSTEM_CALL_MAP_SHARED_MEMORY => match SharedMemoryAccess::from_usize(arg1) {
+            Some(access) => _map_shared_memory(arg0 as u32, access),
+            None => Error::InvalidData.code() as isize,
+        },
+        SYSTEM_CALL_UNMAP_SHARED_MEMORY => _unmap_shared_memory(arg0 as u64),
+        SYSTEM_CALL_DUPLICATE_HANDLE => _duplicate_handle(arg0 as u32, match arg1 as u32 {
+            u32::MAX => None,
+            target => Some(target),
+        }),
+        SYSTEM_CALL_SPAWN => match (c_str(arg0), c_str_list(arg1), c_str_list(arg2)) {
+            (Ok(path), Ok(args), Ok(env)) => _spawn(path.as_str(), args.as_slice(), env.as_slice()),
+            (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => err.code() as isize,
+        },
+        SYSTEM_CALL_WAIT => _wait(arg0 as u32, arg1),
+        SYSTEM_CALL_CREATE_THREAD => _create_thread(arg0, arg1),
+        SYSTEM_CALL_JOIN_THREAD => _join_thread(arg0 as u32),
+        SYSTEM_CALL_EXIT_THREAD => _exit_thread(),
+        SYSTEM_CALL_SLEEP => _sleep(arg0 as u32),
+        SYSTEM_CALL_GET_PRIORITY => _get_priority(arg0 as u32),
+        SYSTEM_CALL_SET_PRIORITY => _set_priority(arg0 as u32, arg1),
+        SYSTEM_CALL_MAP_MEMORY => match MemoryProtection::from_bits(arg2) {
+            Some(protection) => _map_memory(match arg0 {
+                0 => None,
+                address => Some(address as u64),
+            }, arg1 as u64, protection),
+            None => Error::InvalidData.code() as isize,
+        },
+        SYSTEM_CALL_UNMAP_MEMORY => _unmap_memory(arg0 as u64, arg1 as u64),
+        // the protection in the low byte, the mapping flags above it
+        SYSTEM_CALL_MAP_FILE => match (MemoryProtection::from_bits(arg3 & 0xFF), MappingFlags::from_bits(arg3 >> 8)) {
+            (Some(protection), Some(flags)) => _map_file(arg0 as u32, arg1 as u64, arg2 as u64, protection, flags),
+            _ => Error::InvalidData.code() as isize,
+        },
+        SYSTEM_CALL_SYNC_MEMORY => _sync_memory(arg0 as u64, arg1 as u64),
+        SYSTEM_CALL_SET_FS_BASE => _set_fs_base(arg0 as u64),
+        // a NULL type tries every registered file system
+        SYSTEM_CALL_MOUNT => match (c_str(arg0), c_str(arg1), arg2) {
+            (Ok(device), Ok(point), 0) => _mount(device.as_str(), point.as_str(), None),
+            (Ok(device), Ok(point), fs_type) => match c_str(fs_type) {
+                Ok(fs_type) => _mount(device.as_str(), point.as_str(), Some(fs_type.as_str())),
+                Err(err) => err.code() as isize,
+            },
+            (Err(err), _, _) | (_, Err(err), _) => err.code() as isize,
+        },
+        SYSTEM_CALL_UNMOUNT => match c_str(arg0) {
+            Ok(point) => _unmount(point.as_str()),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_READ_DIRECTORY => match c_str(arg0) {
+            Ok(path) => _read_directory(path.as_str(), arg1, arg2, arg3),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_STAT => match c_str(arg0) {
+            Ok(path) => _stat(path.as_str(), arg1),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_REMOVE => match c_str(arg0) {
+            Ok(path) => _remove(path.as_str()),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_RENAME => match (c_str(arg0), c_str(arg1)) {
+            (Ok(from), Ok(to)) => _rename(from.as_str(), to.as_str()),
+            (Err(err), _) | (_, Err(err)) => err.code() as isize,
+        },
+        SYSTEM_CALL_CREATE_DIRECTORY => match c_str(arg0) {
+            Ok(path) => _create_directory(path.as_str()),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_REMOVE_DIRECTORY => match c_str(arg0) {
+            Ok(path) => _remove_directory(path.as_str()),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_CHECK_FILE_SYSTEM => match c_str(arg0) {
+            Ok(point) => _check_file_system(point.as_str(), arg1 != 0, arg2),
+            Err(err) => err.code() as isize,
+        },
+        SYSTEM_CALL_GET_TIME => _get_time(),
+        SYSTEM_CALL_GET_MONOTONIC_TIME => _get_monotonic_time(),
+        // only threads that came in through user_system_call have a user state to duplicate
+        SYSTEM_CALL_FORK => Error::Permissions.code() as isize,
         _ => 1,
     }
 }
 
-fn c_str(addr: usize) -> &'static str {
-    unsafe {
-        CStr::from_ptr(addr as *const _).to_str().unwrap()
+fn c_str(addr: usize) -> Result<String, Error> {
+    user_memory::copy_c_str_from_user(addr, user_memory::MAX_USER_STRING)
+}
+
+// NULL-terminated array of string pointers, a NULL array is an empty list
+fn c_str_list(addr: usize) -> Result<Vec<String>, Error> {
+    let mut list = Vec::new();
+    if addr == 0 {
+        return Ok(list);
+    }
+    loop {
+        let ptr = user_memory::read_user_value::<usize>(addr + list.len() * 8)?;
+        if ptr == 0 {
+            return Ok(list);
+        }
+        if list.len() == MAX_SPAWN_ARGUMENTS {
+            return Err(Error::OutOfSpace);
+        }
+        list.push(c_str(ptr)?);
     }
 }
 
-pub fn _write(_handle: usize, _buffer: *const u8, _count: usize) -> isize {
+fn user_write(handle: usize, buffer: usize, count: usize) -> isize {
+    let count = count.min(user_memory::MAX_USER_TRANSFER);
+    match user_memory::read_user_vec(buffer, count) {
+        Ok(buf) => _write(handle, buf.as_slice()),
+        Err(err) => err.code() as isize,
+    }
+}
+
+fn timeout(arg: usize) -> Option<u32> {
+    match arg as u32 {
+        TIMEOUT_INFINITE => None,
+        ms => Some(ms),
+    }
+}
+
+fn user_read(handle: usize, buffer: usize, count: usize) -> isize {
+    user_read_with(buffer, count, |buf| _read(handle, buf))
+}
+
+fn user_read_with<F>(buffer: usize, count: usize, f: F) -> isize
+where F: FnOnce(&mut [u8]) -> isize {
+    let count = count.min(user_memory::MAX_USER_TRANSFER);
+    // validate up front so that data is not consumed when the buffer is bad
+    if let Err(err) = user_memory::check_user_buffer(buffer, count, true) {
+        return err.code() as isize;
+    }
+    let mut buf = vec![0; count];
+    let read = f(buf.as_mut_slice());
+    if read > 0 {
+        if let Err(err) = user_memory::copy_to_user(buffer, &buf[..read as usize]) {
+            return err.code() as isize;
+        }
+    }
+    read
+}
+
+pub fn _write(_handle: usize, _buffer: &[u8]) -> isize {
     if let Some(hndl) = namespace::get_rw_handle(_handle as u32) {
-        result_code_val!(hndl.write(unsafe { slice::from_raw_parts(_buffer, _count) })) as isize
+        result_code_val!(hndl.write(_buffer)) as isize
     } else {
         Error::InvalidHandle.code() as isize
     }
 }
 
-pub fn _read(_handle: usize, _buffer: *mut u8, _count: usize) -> isize {
+pub fn _read(_handle: usize, _buffer: &mut [u8]) -> isize {
     if let Some(hndl) = namespace::get_rw_handle(_handle as u32) {
-        result_code_val!(hndl.read(unsafe { slice::from_raw_parts_mut(_buffer, _count) })) as isize
+        result_code_val!(hndl.read(_buffer)) as isize
     } else {
         Error::InvalidHandle.code() as isize
     }
@@ -70,23 +272,33 @@ pub fn _seek(_handle: usize, _offset: i64, relative: bool) -> isize {
     }
 }
 
-pub fn _exit() -> isize {
-    scheduler::terminate_thread(scheduler::current_thread());
+pub fn _exit(code: i32) -> isize {
+    scheduler::exit_process(scheduler::current_process(), code);
     0
 }
 
 pub fn _get_process_id() -> isize {
-    scheduler::current_thread() as isize
+    scheduler::current_process() as isize
 }
 
 pub fn _create_message_queue(name: &str, endpoint: u32) -> isize {
     let endpoint = endpoint.into();
-    namespace::register_resource(MessageChannel::new(name.to_string(), Box::new(MessageQueue::new(scheduler::current_thread() as u32, endpoint, 128))));
-    _acquire_handle(namespace::concat_resource_path(vec!["Processes".to_string(), scheduler::current_thread().to_string(), "MessageQueues".to_string(), name.to_string()]).as_str())
+    let path = vec!["Processes".to_string(), scheduler::current_process().to_string(), "MessageChannels".to_string(), name.to_string()];
+    if let Some(_) = namespace::get_resource_non_generic_parts(path.clone()) {
+        return Error::AlreadyOpen.code() as isize;
+    }
+    namespace::register_resource(MessageChannel::new(name.to_string(), Box::new(MessageQueue::new(scheduler::current_process(), endpoint, 128))));
+    _acquire_handle(namespace::concat_resource_path(path).as_str())
 }
 
 pub fn _acquire_handle(resource_path: &str) -> isize {
-    match namespace::acquire_handle(resource_path.to_string(), scheduler::current_thread() as u32) {
+    // files are opened through whatever is mounted where they live
+    let handle = if volumes::is_file_path(resource_path) {
+        file::File::open(resource_path.to_string())
+    } else {
+        namespace::acquire_handle(resource_path.to_string())
+    };
+    match handle {
         Ok(hndl) => hndl.id as isize,
         Err(err) => err.code() as isize,
     }
@@ -99,6 +311,13 @@ pub fn _release_handle(id: u32) -> isize {
     }
 }
 
+pub fn _duplicate_handle(id: u32, target: Option<u32>) -> isize {
+    match namespace::duplicate_handle(id, target) {
+        Ok(id) => id as isize,
+        Err(err) => err.code() as isize,
+    }
+}
+
 pub fn _available_messages(handle: u32) -> isize {
     match namespace::get_message_channel_handle(handle) {
         Some(que) => que.available() as isize,
@@ -114,4 +333,298 @@ pub fn _available_message_size(handle: u32) -> isize {
         },
         None => Error::InvalidHandle.code() as isize,
     }
-}
\ No newline at end of file
+}
+
+pub fn _send_message(handle: u32, message: &[u8]) -> isize {
+    match namespace::get_message_channel_handle(handle) {
+        Some(que) => result_code!(que.send(Message::new(message))) as isize,
+        None => Error::InvalidHandle.code() as isize,
+    }
+}
+
+pub fn _receive_message(handle: u32, buffer: &mut [u8], timeout: Option<u32>) -> isize {
+    match namespace::get_message_channel_handle(handle) {
+        Some(que) => result_code_val!(que.receive_into(buffer, timeout)) as isize,
+        None => Error::InvalidHandle.code() as isize,
+    }
+}
+
+// the sender of the next message, which is left in the queue
+pub fn _wait_message(handle: u32, timeout: Option<u32>) -> isize {
+    match namespace::get_message_channel_handle(handle) {
+        Some(que) => match que.wait(timeout).and_then(|_| que.peek_sender()) {
+            Ok(from) => from as isize,
+            Err(err) => err.code() as isize,
+        },
+        None => Error::InvalidHandle.code() as isize,
+    }
+}
+
+pub fn _create_shared_memory(name: &str, size: usize) -> isize {
+    let path = vec!["Processes".to_string(), scheduler::current_process().to_string(), "SharedMemory".to_string(), name.to_string()];
+    if let Some(_) = namespace::get_resource_non_generic_parts(path.clone()) {
+        return Error::AlreadyOpen.code() as isize;
+    }
+    match SharedMemory::new(name.to_string(), scheduler::current_process(), size) {
+        Ok(shm) => {
+            namespace::register_resource(shm);
+            _acquire_handle(namespace::concat_resource_path(path).as_str())
+        },
+        Err(err) => err.code() as isize,
+    }
+}
+
+pub fn _grant_shared_memory(handle: u32, process_id: u32, access: SharedMemoryAccess) -> isize {
+    match namespace::get_shared_memory_handle(handle) {
+        Some(shm) => result_code!(shm.grant(process_id, access)) as isize,
+        None => Error::InvalidHandle.code() as isize,
+    }
+}
+
+pub fn _map_shared_memory(handle: u32, access: SharedMemoryAccess) -> isize {
+    match namespace::get_shared_memory_handle(handle) {
+        Some(shm) => match shm.map(access) {
+            Ok(addr) => addr as isize,
+            Err(err) => err.code() as isize,
+        },
+        None => Error::InvalidHandle.code() as isize,
+    }
+}
+
+pub fn _unmap_shared_memory(address: u64) -> isize {
+    result_code!(shared_memory::unmap(address)) as isize
+}
+
+pub fn _spawn(path: &str, args: &[String], env: &[String]) -> isize {
+    match exec::spawn(path, args, env) {
+        Ok(pid) => pid as isize,
+        Err(err) => err.code() as isize,
+    }
+}
+
+pub fn _wait(process_id: u32, status: usize) -> isize {
+    // exit codes can be negative, so they are not mixed with error codes in the return value
+    if status != 0 {
+        if let Err(err) = user_memory::check_user_buffer(status, 4, true) {
+            return err.code() as isize;
+        }
+    }
+    match scheduler::wait_process(scheduler::current_thread(), process_id) {
+        Ok(code) if status != 0 => result_code!(user_memory::write_user_value(status, code)) as isize,
+        Ok(_) => 0,
+        Err(err) => err.code() as isize,
+    }
+}
+
+pub fn _create_thread(entry_point: usize, argument: usize) -> isize {
+    // kernel threads have exec::thread for this
+    if !scheduler::current_task().user_mode {
+        return Error::Permissions.code() as isize;
+    }
+    if let Err(err) = user_memory::check_user_range(entry_point, 1) {
+        return err.code() as isize;
+    }
+    let pid = scheduler::current_process();
+    match unsafe { task::Task::user_thread(entry_point as u64, argument as u64, pid) }.and_then(|thread| scheduler::add_thread(pid, thread)) {
+        Ok(tid) => tid as isize,
+        Err(err) => err.code() as isize,
+    }
+}
+
+pub fn _join_thread(thread_id: u32) -> isize {
+    result_code!(scheduler::join_process_thread(scheduler::current_thread(), thread_id)) as isize
+}
+
+pub fn _exit_thread() -> isize {
+    scheduler::terminate_thread(scheduler::current_thread());
+    0
+}
+
+pub fn _sleep(milliseconds: u32) -> isize {
+    scheduler::delay_thread(scheduler::current_thread(), milliseconds);
+    0
+}
+
+// u32::MAX stands for the calling thread, user threads may only look at threads of their own process
+fn priority_target(thread_id: u32) -> Result<u32, Error> {
+    let current = scheduler::current_thread();
+    if thread_id == u32::MAX || thread_id == current {
+        return Ok(current);
+    }
+    if scheduler::current_task().user_mode && scheduler::get_thread_process(thread_id)? != scheduler::current_process() {
+        return Err(Error::Permissions);
+    }
+    Ok(thread_id)
+}
+
+pub fn _get_priority(thread_id: u32) -> isize {
+    result_code_val!(priority_target(thread_id).and_then(|tid| scheduler::get_priority(tid))) as isize
+}
+
+pub fn _set_priority(thread_id: u32, priority: usize) -> isize {
+    let priority = match scheduler::Priority::from_usize(priority) {
+        Some(priority) => priority,
+        None => return Error::InvalidData.code() as isize,
+    };
+    // realtime is reserved for the kernel's own threads
+    if priority == scheduler::Priority::Realtime && scheduler::current_task().user_mode {
+        return Error::Permissions.code() as isize;
+    }
+    result_code!(priority_target(thread_id).and_then(|tid| scheduler::set_priority(tid, priority))) as isize
+}
+
+pub fn _map_memory(address: Option<u64>, size: u64, protection: MemoryProtection) -> isize {
+    result_code_val!(vma::map_anonymous(address, size, protection.contains(MemoryProtection::WRITE), protection.contains(MemoryProtection::EXECUTE))) as isize
+}
+
+pub fn _unmap_memory(address: u64, size: u64) -> isize {
+    result_code!(vma::unmap(address, size)) as isize
+}
+
+pub fn _map_file(handle: u32, offset: u64, size: u64, protection: MemoryProtection, flags: MappingFlags) -> isize {
+    result_code_val!(vma::map_file(handle, offset, size, protection.contains(MemoryProtection::WRITE), protection.contains(MemoryProtection::EXECUTE), flags.contains(MappingFlags::SHARED))) as isize
+}
+
+pub fn _sync_memory(address: u64, size: u64) -> isize {
+    result_code!(vma::sync(address, size)) as isize
+}
+
+// for runtimes that lay out thread-local storage themselves, like a dynamic linker
+pub fn _set_fs_base(address: u64) -> isize {
+    // never dereferenced by the kernel, it only has to stay out of kernel space
+    if address as usize >= user_memory::USER_SPACE_END {
+        return Error::InvalidAddress.code() as isize;
+    }
+    match scheduler::try_current_task_mut() {
+        Some(task) if task.user_mode => {
+            task.set_fs_base(address);
+            0
+        },
+        _ => Error::Permissions.code() as isize,
+    }
+}
+
+// rbx, rbp, r12 to r15, rflags and rip, in the order the trap handler pops them
+pub fn _fork(user_stack: usize) -> isize {
+    if !scheduler::current_task().user_mode {
+        return Error::Permissions.code() as isize;
+    }
+    let saved: [u64; 8] = match user_memory::read_user_value(user_stack) {
+        Ok(saved) => saved,
+        Err(err) => return err.code() as isize,
+    };
+    let mut state = task::TaskContext::new(saved[7], user_stack as u64 + 8 * 8);
+    state.rbx = saved[0];
+    state.rbp = saved[1];
+    state.r12 = saved[2];
+    state.r13 = saved[3];
+    state.r14 = saved[4];
+    state.r15 = saved[5];
+    state.rflags = saved[6];
+    // the child returns 0, the parent gets its process id
+    state.rax = 0;
+    result_code_val!(scheduler::fork(state)) as isize
+}
+
+// the kernel's own threads, the init process and whoever holds a handle to the device itself
+fn may_manage_volume(device: &[String]) -> bool {
+    !scheduler::current_task().user_mode
+        || exec::is_init_process(scheduler::current_process())
+        || namespace::handle_table().holds(device)
+}
+
+pub fn _mount(device: &str, point: &str, file_system_type: Option<&str>) -> isize {
+    if !may_manage_volume(&volumes::normalize_path(device)) {
+        return Error::Permissions.code() as isize;
+    }
+    result_code!(volumes::mount(device, point, file_system_type)) as isize
+}
+
+pub fn _unmount(point: &str) -> isize {
+    // a point with nothing mounted on it is left for unmount to report
+    if let Some(device) = volumes::mount_device(point) {
+        if !may_manage_volume(&device) {
+            return Error::Permissions.code() as isize;
+        }
+    }
+    result_code!(volumes::unmount(point)) as isize
+}
+
+// entries from index start on, as many as fit in count
+pub fn _read_directory(path: &str, start: usize, entries: usize, count: usize) -> isize {
+    let size = match count.checked_mul(core::mem::size_of::<DirectoryEntry>()) {
+        Some(size) => size,
+        None => return Error::InvalidData.code() as isize,
+    };
+    if let Err(err) = user_memory::check_user_buffer(entries, size, true) {
+        return err.code() as isize;
+    }
+    let listing = match volumes::read_dir(path) {
+        Ok(listing) => listing,
+        Err(err) => return err.code() as isize,
+    };
+    let mut written = 0;
+    for info in listing.into_iter().skip(start).take(count) {
+        let name = info.name.as_bytes();
+        let name_length = name.len().min(MAX_FILE_NAME);
+        let mut ent = DirectoryEntry {
+            status: info.status,
+            name_length: name_length as u32,
+            name: [0; MAX_FILE_NAME],
+        };
+        ent.name[..name_length].copy_from_slice(&name[..name_length]);
+        if let Err(err) = user_memory::write_user_value(entries + written * core::mem::size_of::<DirectoryEntry>(), ent) {
+            return err.code() as isize;
+        }
+        written += 1;
+    }
+    written as isize
+}
+
+pub fn _stat(path: &str, status: usize) -> isize {
+    match volumes::stat(path) {
+        Ok(file_status) => result_code!(user_memory::write_user_value(status, file_status)) as isize,
+        Err(err) => err.code() as isize,
+    }
+}
+
+pub fn _remove(path: &str) -> isize {
+    result_code!(volumes::remove(path)) as isize
+}
+
+pub fn _rename(from: &str, to: &str) -> isize {
+    result_code!(volumes::rename(from, to)) as isize
+}
+
+pub fn _create_directory(path: &str) -> isize {
+    result_code!(volumes::create_directory(path)) as isize
+}
+
+pub fn _remove_directory(path: &str) -> isize {
+    result_code!(volumes::remove_directory(path)) as isize
+}
+
+pub fn _check_file_system(point: &str, repair: bool, report: usize) -> isize {
+    // checked before a repair starts, so that its outcome is not lost
+    if let Err(err) = user_memory::check_user_buffer(report, core::mem::size_of::<FileSystemCheck>(), true) {
+        return err.code() as isize;
+    }
+    // anyone may look, only those who could unmount the volume may change it
+    if let (true, Some(device)) = (repair, volumes::mount_device(point)) {
+        if !may_manage_volume(&device) {
+            return Error::Permissions.code() as isize;
+        }
+    }
+    match volumes::check(point, repair) {
+        Ok(result) => result_code!(user_memory::write_user_value(report, result)) as isize,
+        Err(err) => err.code() as isize,
+    }
+}
+
+pub fn _get_time() -> isize {
+    time::unix_time() as isize
+}
+
+pub fn _get_monotonic_time() -> isize {
+    time::monotonic_microseconds() as isize
+}
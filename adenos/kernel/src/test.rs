@@ -1,6 +1,8 @@
 use crate::*;
 use dev::power;
 use dev::PowerControl;
+use dev::hal::cpu;
+use exec::scheduler;
 
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
@@ -23,3 +25,20 @@ where T: Fn() + ?Sized {
         serial_println!("[ok]");
     }
 }
+
+static mut TEST_MAIN: Option<fn()> = None;
+
+// for tests that need a current thread, test_main runs as the first kernel thread
+pub fn run_on_scheduler(test_main: fn()) -> ! {
+    unsafe { TEST_MAIN = Some(test_main); }
+    scheduler::init();
+    let _ = scheduler::kexec(run_test_main);
+    cpu::enable_scheduler();
+    loop {
+        cpu::halt();
+    }
+}
+
+fn run_test_main() {
+    unsafe { TEST_MAIN.unwrap()() }
+}
@@ -0,0 +1,42 @@
+use crate::*;
+use dev::hal::{interrupts, rtc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use infinity::os::DateTime;
+
+// timer interrupts since boot, counted on the first cpu only
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// wall-clock time at the first tick, in microseconds since 1970-01-01
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    set_time(rtc::read_time());
+}
+
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn monotonic_microseconds() -> u64 {
+    ticks() * interrupts::TIMER_PERIOD
+}
+
+// the real-time clock is only read once, after that the wall clock runs off the timer
+pub fn set_time(time: DateTime) {
+    BOOT_TIME.store((time.unix_time() * 1_000_000).saturating_sub(monotonic_microseconds()), Ordering::Relaxed);
+}
+
+pub fn unix_time_microseconds() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + monotonic_microseconds()
+}
+
+pub fn unix_time() -> u64 {
+    unix_time_microseconds() / 1_000_000
+}
+
+pub fn now() -> DateTime {
+    DateTime::from_unix_time(unix_time())
+}
@@ -1,9 +1,313 @@
 use crate::*;
-use namespace;
+use namespace::{self, Handle, Resource, ResourceType};
+use dev::{filesystem, hal::mem::heap};
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use infinity::os::{DateTime, FileAttributes, FileStatus, FileSystemCheck};
+use spin::Mutex;
 
-pub fn probe_partitions() -> Result<(), Error> {
-    for (_name, _dev) in namespace::namespace().iter_mut_bf() {
+// everything mounted lives under here
+pub const FILES_ROOT: &str = "Files";
 
+// makes a file system out of the device at device_path, None if the device does not hold one of its kind
+pub type ProbeFn = fn(device_path: String) -> Result<Option<Box<dyn Resource>>, Error>;
+
+#[derive(Copy, Clone)]
+pub struct FileSystemType {
+    pub name: &'static str,
+    pub probe: ProbeFn,
+}
+
+#[derive(Clone, Debug)]
+pub struct Mount {
+    // canonical, starting with FILES_ROOT
+    pub point: Vec<String>,
+    pub device: Vec<String>,
+    pub file_system_type: &'static str,
+}
+
+// probed in the order they were registered
+static FILE_SYSTEM_TYPES: Mutex<Vec<FileSystemType>> = Mutex::new(Vec::new());
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+pub fn init() {
+    register_file_system_type("FAT", filesystem::fat::probe);
+}
+
+pub fn register_file_system_type(name: &'static str, probe: ProbeFn) {
+    let mut types = FILE_SYSTEM_TYPES.lock();
+    if !types.iter().any(|fs_type| fs_type.name == name) {
+        types.push(FileSystemType {
+            name,
+            probe,
+        });
+    }
+}
+
+// resolves . and .. and drops empty components, .. at the root stays at the root
+pub fn normalize_path(path: &str) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop();
+            },
+            part => parts.push(String::from(part)),
+        }
+    }
+    parts
+}
+
+pub fn canonical_path(path: &str) -> String {
+    namespace::concat_resource_path(normalize_path(path))
+}
+
+pub fn is_file_path(path: &str) -> bool {
+    normalize_path(path).first().map_or(false, |root| root == FILES_ROOT)
+}
+
+pub fn mounts() -> Vec<Mount> {
+    MOUNTS.lock().clone()
+}
+
+// the device mounted at point
+pub fn mount_device(point: &str) -> Option<Vec<String>> {
+    let point = normalize_path(point);
+    MOUNTS.lock().iter().find(|mount| mount.point == point).map(|mount| mount.device.clone())
+}
+
+// an open file or a mount at path or anywhere below it
+fn in_use(path: &[String]) -> bool {
+    namespace::get_resource_non_generic_parts(path.to_vec()).is_some() || namespace::has_resources_below(path.to_vec())
+}
+
+fn file_system(point: &[String]) -> Result<&'static mut dyn filesystem::FileSystem, Error> {
+    match namespace::get_resource_non_generic_parts(point.to_vec()).ok_or(Error::EntryNotFound)?.unwrap() {
+        ResourceType::FileSystem(fs) => Ok(fs),
+        _ => Err(Error::InvalidDevice),
     }
+}
+
+// mounts the file system on device at point, trying every registered type unless one is given
+pub fn mount(device: &str, point: &str, file_system_type: Option<&str>) -> Result<(), Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let device = normalize_path(device);
+    let point = normalize_path(point);
+    if point.len() < 2 || point[0] != FILES_ROOT {
+        return Err(Error::InvalidData);
+    }
+    namespace::get_block_device_parts(device.clone()).ok_or(Error::InvalidDevice)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.device == device) {
+        return Err(Error::AlreadyOpen);
+    }
+    if in_use(&point) {
+        return Err(Error::AlreadyOpen);
+    }
+    // closed files leave empty directory nodes behind
+    if namespace::subtree_parts(point.clone()).is_some() {
+        namespace::drop_resource_parts(point.clone())?;
+    }
+    let types: Vec<FileSystemType> = FILE_SYSTEM_TYPES.lock().iter()
+        .filter(|fs_type| file_system_type.map_or(true, |name| fs_type.name == name))
+        .copied()
+        .collect();
+    if types.is_empty() {
+        return Err(Error::DriverNotFound);
+    }
+    for fs_type in types {
+        if let Some(mut fs) = (fs_type.probe)(namespace::concat_resource_path(device.clone()))? {
+            match fs.unwrap() {
+                ResourceType::FileSystem(fs) => fs.set_mount_point(point.clone()),
+                _ => return Err(Error::InvalidDevice),
+            }
+            namespace::register_boxed_resource_path(point.clone(), fs);
+            mounts.push(Mount {
+                point,
+                device,
+                file_system_type: fs_type.name,
+            });
+            return Ok(());
+        }
+    }
+    Err(Error::InvalidData)
+}
+
+// refused while files on it are open or something else is mounted inside it
+pub fn unmount(point: &str) -> Result<(), Error> {
+    let point = normalize_path(point);
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().position(|mount| mount.point == point).ok_or(Error::EntryNotFound)?;
+    if namespace::has_resources_below(point.clone()) {
+        return Err(Error::AlreadyOpen);
+    }
+    file_system(&point)?.unmount()?;
+    namespace::drop_resource_parts(point)?;
+    mounts.remove(index);
     Ok(())
-}
\ No newline at end of file
+}
+
+// AHCI-Drive0-Partition0 for /Devices/Storage/AHCI/Drive0/Partition0, the same on every boot whatever the volume is called
+pub fn stable_name(device: &[String]) -> String {
+    device.iter().skip(2).cloned().collect::<Vec<String>>().join("-")
+}
+
+// mounts whatever is on device at its stable name, if anything recognizes it
+pub fn auto_mount(device: Vec<String>) -> Result<Option<String>, Error> {
+    let point = namespace::concat_resource_path(vec![String::from(FILES_ROOT), stable_name(&device)]);
+    match mount(namespace::concat_resource_path(device).as_str(), point.as_str(), None) {
+        Ok(()) => Ok(Some(point)),
+        Err(Error::InvalidData) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// where the first volume with this label is mounted
+pub fn find_volume(label: &str) -> Option<String> {
+    mounts().into_iter()
+        .find(|mount| file_system(&mount.point).map_or(false, |fs| fs.volume_label() == label))
+        .map(|mount| namespace::concat_resource_path(mount.point))
+}
+
+fn innermost_mount(path: &[String]) -> Result<Vec<String>, Error> {
+    MOUNTS.lock().iter()
+        .filter(|mount| path.starts_with(&mount.point))
+        .max_by_key(|mount| mount.point.len())
+        .map(|mount| mount.point.clone())
+        .ok_or(Error::EntryNotFound)
+}
+
+// the innermost mount containing path and the rest of the path inside it
+fn resolve(path: &[String]) -> Result<(&'static mut dyn filesystem::FileSystem, Vec<String>), Error> {
+    let point = innermost_mount(path)?;
+    Ok((file_system(&point)?, path[point.len()..].to_vec()))
+}
+
+// like resolve, for changes to an entry, which has to be inside a volume and not in use
+fn resolve_entry(path: &[String]) -> Result<(&'static mut dyn filesystem::FileSystem, String), Error> {
+    let (fs, rest) = resolve(path)?;
+    if rest.is_empty() {
+        return Err(Error::Permissions);
+    }
+    if in_use(path) {
+        return Err(Error::AlreadyOpen);
+    }
+    Ok((fs, namespace::concat_resource_path(rest)))
+}
+
+// the directories mount points make above the volumes, like /Files itself
+fn mount_directories(path: &[String]) -> Option<Vec<String>> {
+    if path.first().map_or(true, |root| root != FILES_ROOT) {
+        return None;
+    }
+    let mut names: Vec<String> = Vec::new();
+    for mount in MOUNTS.lock().iter() {
+        if mount.point.len() > path.len() && mount.point.starts_with(path) && !names.contains(&mount.point[path.len()]) {
+            names.push(mount.point[path.len()].clone());
+        }
+    }
+    if names.is_empty() && path.len() > 1 {
+        None
+    } else {
+        Some(names)
+    }
+}
+
+fn directory_status() -> FileStatus {
+    FileStatus {
+        size: 0,
+        attributes: FileAttributes::DIRECTORY,
+        created: DateTime::default(),
+        modified: DateTime::default(),
+        accessed: DateTime::default(),
+    }
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<filesystem::FileInfo>, Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let path = normalize_path(path);
+    let mounted = mount_directories(&path);
+    let mut entries = match resolve(&path) {
+        Ok((fs, rest)) => fs.read_dir(namespace::concat_resource_path(rest))?,
+        Err(Error::EntryNotFound) if mounted.is_some() => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    // volumes mounted inside this directory show up in it, over whatever the directory holds
+    for name in mounted.unwrap_or_default() {
+        entries.retain(|ent| ent.name != name);
+        entries.push(filesystem::FileInfo {
+            name,
+            status: directory_status(),
+        });
+    }
+    Ok(entries)
+}
+
+pub fn stat(path: &str) -> Result<FileStatus, Error> {
+    let path = normalize_path(path);
+    match resolve(&path) {
+        Ok((fs, rest)) => fs.stat(namespace::concat_resource_path(rest)),
+        Err(Error::EntryNotFound) if mount_directories(&path).is_some() => Ok(directory_status()),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn remove(path: &str) -> Result<(), Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let (fs, rest) = resolve_entry(&normalize_path(path))?;
+    fs.remove(rest)
+}
+
+// only within one volume
+pub fn rename(from: &str, to: &str) -> Result<(), Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let from = normalize_path(from);
+    let to = normalize_path(to);
+    if innermost_mount(&from)? != innermost_mount(&to)? {
+        return Err(Error::InvalidDevice);
+    }
+    let (_, to_rest) = resolve_entry(&to)?;
+    let (fs, from_rest) = resolve_entry(&from)?;
+    fs.rename(from_rest, to_rest)
+}
+
+pub fn create_directory(path: &str) -> Result<(), Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let (fs, rest) = resolve_entry(&normalize_path(path))?;
+    fs.create_directory(rest)
+}
+
+pub fn remove_directory(path: &str) -> Result<(), Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let (fs, rest) = resolve_entry(&normalize_path(path))?;
+    fs.remove_directory(rest)
+}
+
+pub fn open(path: &str) -> Result<&'static mut Handle, Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let path = normalize_path(path);
+    let (fs, rest) = resolve(&path)?;
+    if rest.is_empty() {
+        return Err(Error::EntryNotFound);
+    }
+    let file = fs.open_file(namespace::concat_resource_path(rest))?;
+    if let Some(_) = namespace::get_resource_non_generic(file.resource_path_string()) {
+        Err(Error::AlreadyOpen)
+    } else {
+        namespace::register_resource(file);
+        namespace::acquire_handle(namespace::concat_resource_path(path))
+    }
+}
+
+// only whole volumes, and only while nothing on them is open when repairing
+pub fn check(point: &str, repair: bool) -> Result<FileSystemCheck, Error> {
+    let _subsystem = heap::enter(heap::Subsystem::FileSystem);
+    let point = normalize_path(point);
+    if !MOUNTS.lock().iter().any(|mount| mount.point == point) {
+        return Err(Error::EntryNotFound);
+    }
+    if repair && namespace::has_resources_below(point.clone()) {
+        return Err(Error::AlreadyOpen);
+    }
+    file_system(&point)?.check(repair)
+}
@@ -0,0 +1,432 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+extern crate alloc;
+
+use adenos::*;
+use dev::{*, filesystem::{FileSystem, fat::FATFileSystem}};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+use core::fmt::Debug;
+use spin::Mutex;
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::init(boot_info);
+    dev::hal::init();
+    test_main();
+    loop {
+        dev::hal::cpu::halt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+const SECTOR_SIZE: usize = 512;
+
+// sectors that were never written read as zeroes
+type Sectors = Mutex<BTreeMap<u64, Vec<u8>>>;
+
+fn read_bytes(sectors: &Sectors, offset: usize, buf: &mut [u8]) {
+    let sectors = sectors.lock();
+    for (i, b) in buf.iter_mut().enumerate() {
+        let at = offset + i;
+        *b = sectors.get(&((at / SECTOR_SIZE) as u64)).map_or(0, |sector| sector[at % SECTOR_SIZE]);
+    }
+}
+
+fn write_bytes(sectors: &Sectors, offset: usize, buf: &[u8]) {
+    let mut sectors = sectors.lock();
+    for (i, b) in buf.iter().enumerate() {
+        let at = offset + i;
+        sectors.entry((at / SECTOR_SIZE) as u64).or_insert_with(|| vec![0; SECTOR_SIZE])[at % SECTOR_SIZE] = *b;
+    }
+}
+
+fn read_u32(sectors: &Sectors, offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    read_bytes(sectors, offset, &mut buf);
+    u32::from_le_bytes(buf)
+}
+
+// a volume in memory, the test looks at its sectors behind the file system's back
+struct RamDisk {
+    name: &'static str,
+    sectors: &'static Sectors,
+    sector_count: usize,
+    position: u64,
+}
+
+impl Debug for RamDisk {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RamDisk").field("name", &self.name).field("position", &self.position).finish()
+    }
+}
+
+impl Device for RamDisk {
+    fn device_path(&self) -> Vec<String> {
+        vec![String::from(self.name)]
+    }
+
+    fn unwrap(&mut self) -> DeviceClass {
+        DeviceClass::BlockDevice(self)
+    }
+}
+
+impl Seek for RamDisk {
+    fn seek(&mut self, position: u64) -> Result<(), Error> {
+        self.position = position;
+        Ok(())
+    }
+
+    fn offset(&self) -> u64 {
+        self.position
+    }
+
+    fn size(&self) -> u64 {
+        (self.sector_count * SECTOR_SIZE) as u64
+    }
+}
+
+impl Read for RamDisk {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let start = self.position as usize;
+        let end = (start + buf.len()).min(self.sector_count * SECTOR_SIZE);
+        read_bytes(self.sectors, start, &mut buf[..end - start]);
+        self.position = end as u64;
+        Ok(end - start)
+    }
+}
+
+impl Write for RamDisk {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let start = self.position as usize;
+        let end = (start + buf.len()).min(self.sector_count * SECTOR_SIZE);
+        write_bytes(self.sectors, start, &buf[..end - start]);
+        self.position = end as u64;
+        Ok(end - start)
+    }
+}
+
+impl BlockRead for RamDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_block(&mut self, block: u64, buffer: *mut u8) -> Result<(), Error> {
+        self.read_blocks(block, 1, buffer)
+    }
+
+    fn read_blocks(&mut self, start_block: u64, count: u64, buffer: *mut u8) -> Result<(), Error> {
+        if (start_block + count) as usize > self.sector_count {
+            return Err(Error::ReadFailure);
+        }
+        let buf = unsafe { core::slice::from_raw_parts_mut(buffer, count as usize * SECTOR_SIZE) };
+        read_bytes(self.sectors, start_block as usize * SECTOR_SIZE, buf);
+        Ok(())
+    }
+}
+
+impl BlockWrite for RamDisk {
+    fn write_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.write_blocks(block, &mut buffer[..SECTOR_SIZE])
+    }
+
+    fn write_blocks(&mut self, start_block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        if start_block as usize * SECTOR_SIZE + buffer.len() > self.sector_count * SECTOR_SIZE {
+            return Err(Error::WriteFailure);
+        }
+        write_bytes(self.sectors, start_block as usize * SECTOR_SIZE, buffer);
+        Ok(())
+    }
+}
+
+// the boot sector fields every FAT type has
+fn boot_sector(reserved_sectors: usize, fat_copies: usize, root_entries: usize, sector_count: usize, fat_sectors: usize) -> Vec<u8> {
+    let mut boot = vec![0u8; SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"ADENOS  ");
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(reserved_sectors as u16).to_le_bytes());
+    boot[16] = fat_copies as u8;
+    boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    boot[19..21].copy_from_slice(&(sector_count as u16).to_le_bytes());
+    boot[21] = 0xF8;
+    boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+    boot
+}
+
+// registered under /Devices, where the file system finds it
+fn mount(name: &'static str, sectors: &'static Sectors, sector_count: usize) -> FATFileSystem {
+    namespace::register_resource(RamDisk {
+        name,
+        sectors,
+        sector_count,
+        position: 0,
+    });
+    remount(name)
+}
+
+fn remount(name: &'static str) -> FATFileSystem {
+    FATFileSystem::new(String::from("/Devices/") + name).unwrap().unwrap()
+}
+
+mod fat12 {
+    use super::*;
+
+    pub const FAT_SECTORS: usize = 3;
+    pub const FAT_COPIES: usize = 2;
+    const ROOT_ENTRIES: usize = 64;
+    const CLUSTERS: usize = 700;
+    const FIRST_FAT_SECTOR: usize = 1;
+    const DATA_SECTOR: usize = FIRST_FAT_SECTOR + FAT_SECTORS * FAT_COPIES + ROOT_ENTRIES * 32 / SECTOR_SIZE;
+    pub const SECTORS: usize = DATA_SECTOR + CLUSTERS;
+    // every other cluster up to the last one looked at is taken by this, chosen so that no two nibbles are alike
+    pub const USED: u16 = 0x9A5;
+    // odd, its entry starts in the last byte of the first FAT sector
+    pub const ODD_CLUSTER: u32 = 341;
+    // even, its entry starts in the last byte of the second FAT sector
+    pub const EVEN_CLUSTER: u32 = 682;
+
+    fn fat12_entry(fat: &[u8], cluster: u32) -> u16 {
+        let off = cluster as usize + cluster as usize / 2;
+        let packed = u16::from_le_bytes([fat[off], fat[off + 1]]);
+        match cluster & 1 {
+            0 => packed & 0xFFF,
+            _ => packed >> 4,
+        }
+    }
+
+    fn set_fat12_entry(fat: &mut [u8], cluster: u32, value: u16) {
+        let off = cluster as usize + cluster as usize / 2;
+        let packed = u16::from_le_bytes([fat[off], fat[off + 1]]);
+        let packed = match cluster & 1 {
+            0 => (packed & 0xF000) | value,
+            _ => (packed & 0x000F) | (value << 4),
+        };
+        fat[off..off + 2].copy_from_slice(&packed.to_le_bytes());
+    }
+
+    // every cluster up to EVEN_CLUSTER + 1 taken except ODD_CLUSTER and EVEN_CLUSTER
+    pub fn volume() -> &'static Sectors {
+        let sectors: &'static Sectors = Box::leak(Box::new(Mutex::new(BTreeMap::new())));
+        let mut boot = boot_sector(FIRST_FAT_SECTOR, FAT_COPIES, ROOT_ENTRIES, SECTORS, FAT_SECTORS);
+        boot[38] = 0x29;
+        boot[43..54].copy_from_slice(b"NO NAME    ");
+        boot[54..62].copy_from_slice(b"FAT12   ");
+        write_bytes(sectors, 0, &boot);
+        let mut fat = vec![0u8; FAT_SECTORS * SECTOR_SIZE];
+        set_fat12_entry(&mut fat, 0, 0xFF8);
+        set_fat12_entry(&mut fat, 1, 0xFFF);
+        for cluster in 2..=EVEN_CLUSTER + 1 {
+            if cluster != ODD_CLUSTER && cluster != EVEN_CLUSTER {
+                set_fat12_entry(&mut fat, cluster, USED);
+            }
+        }
+        for copy in 0..FAT_COPIES {
+            write_bytes(sectors, (FIRST_FAT_SECTOR + copy * FAT_SECTORS) * SECTOR_SIZE, &fat);
+        }
+        sectors
+    }
+
+    // the entries of cluster and its neighbours, in every FAT
+    pub fn entries_around(sectors: &Sectors, cluster: u32) -> Vec<[u16; 3]> {
+        (0..FAT_COPIES).map(|copy| {
+            let mut fat = vec![0u8; FAT_SECTORS * SECTOR_SIZE];
+            read_bytes(sectors, (FIRST_FAT_SECTOR + copy * FAT_SECTORS) * SECTOR_SIZE, &mut fat);
+            [fat12_entry(&fat, cluster - 1), fat12_entry(&fat, cluster), fat12_entry(&fat, cluster + 1)]
+        }).collect()
+    }
+}
+
+mod fat32 {
+    use super::*;
+
+    // just enough clusters not to be FAT12, the FAT32 signature makes it FAT32
+    pub const CLUSTERS: u32 = 4100;
+    pub const FAT_SECTORS: usize = ((CLUSTERS as usize + 2) * 4 + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    pub const FAT_COPIES: usize = 2;
+    const FS_INFO_SECTOR: usize = 1;
+    const FIRST_FAT_SECTOR: usize = 2;
+    pub const DATA_SECTOR: usize = FIRST_FAT_SECTOR + FAT_SECTORS * FAT_COPIES;
+    pub const SECTORS: usize = DATA_SECTOR + CLUSTERS as usize;
+    pub const ROOT_CLUSTER: u32 = 2;
+    pub const END_OF_CHAIN: u32 = 0x0FFFFFFF;
+    pub const CLEAN_BIT: u32 = 0x08000000;
+    pub const UNKNOWN: u32 = 0xFFFFFFFF;
+
+    // an empty root directory in ROOT_CLUSTER and FSInfo saying where to allocate next
+    pub fn volume(next_free: u32) -> &'static Sectors {
+        let sectors: &'static Sectors = Box::leak(Box::new(Mutex::new(BTreeMap::new())));
+        let mut boot = boot_sector(FIRST_FAT_SECTOR, FAT_COPIES, 0, SECTORS, 0);
+        boot[36..40].copy_from_slice(&(FAT_SECTORS as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+        boot[48..50].copy_from_slice(&(FS_INFO_SECTOR as u16).to_le_bytes());
+        boot[66] = 0x29;
+        boot[71..82].copy_from_slice(b"NO NAME    ");
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        write_bytes(sectors, 0, &boot);
+        let mut fs_info = vec![0u8; SECTOR_SIZE];
+        fs_info[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        fs_info[488..492].copy_from_slice(&(CLUSTERS - 1).to_le_bytes());
+        fs_info[492..496].copy_from_slice(&next_free.to_le_bytes());
+        fs_info[508..512].copy_from_slice(&0xAA550000u32.to_le_bytes());
+        write_bytes(sectors, FS_INFO_SECTOR * SECTOR_SIZE, &fs_info);
+        for (cluster, value) in [(0, 0x0FFFFFF8), (1, END_OF_CHAIN), (ROOT_CLUSTER, END_OF_CHAIN)] {
+            set_entry(sectors, cluster, value);
+        }
+        sectors
+    }
+
+    pub fn entry(sectors: &Sectors, copy: usize, cluster: u32) -> u32 {
+        read_u32(sectors, (FIRST_FAT_SECTOR + copy * FAT_SECTORS) * SECTOR_SIZE + cluster as usize * 4)
+    }
+
+    pub fn set_entry(sectors: &Sectors, cluster: u32, value: u32) {
+        for copy in 0..FAT_COPIES {
+            write_bytes(sectors, (FIRST_FAT_SECTOR + copy * FAT_SECTORS) * SECTOR_SIZE + cluster as usize * 4, &value.to_le_bytes());
+        }
+    }
+
+    // free_count and next_free
+    pub fn fs_info(sectors: &Sectors) -> (u32, u32) {
+        let offset = FS_INFO_SECTOR * SECTOR_SIZE;
+        (read_u32(sectors, offset + 488), read_u32(sectors, offset + 492))
+    }
+
+    pub fn cluster_offset(cluster: u32) -> usize {
+        (DATA_SECTOR + cluster as usize - 2) * SECTOR_SIZE
+    }
+}
+
+#[test_case]
+fn test_fat12_entries_straddling_sectors() {
+    use fat12::*;
+    let sectors = volume();
+    let fs = mount("FAT12Straddle", sectors, SECTORS);
+    assert_eq!(ODD_CLUSTER as usize + ODD_CLUSTER as usize / 2, SECTOR_SIZE - 1);
+    assert_eq!(EVEN_CLUSTER as usize + EVEN_CLUSTER as usize / 2, SECTOR_SIZE * 2 - 1);
+
+    // the two free clusters are the only ones a directory can get
+    fs.create_directory(String::from("ODD")).unwrap();
+    fs.create_directory(String::from("EVEN")).unwrap();
+    for entries in entries_around(sectors, ODD_CLUSTER) {
+        assert_eq!(entries, [USED, 0xFFF, USED]);
+    }
+    for entries in entries_around(sectors, EVEN_CLUSTER) {
+        assert_eq!(entries, [USED, 0xFFF, USED]);
+    }
+
+    fs.remove_directory(String::from("ODD")).unwrap();
+    fs.remove_directory(String::from("EVEN")).unwrap();
+    for entries in entries_around(sectors, ODD_CLUSTER) {
+        assert_eq!(entries, [USED, 0, USED]);
+    }
+    for entries in entries_around(sectors, EVEN_CLUSTER) {
+        assert_eq!(entries, [USED, 0, USED]);
+    }
+}
+
+#[test_case]
+fn test_fat32_allocation_starts_at_fs_info_hint() {
+    use fat32::*;
+    const HINT: u32 = 1000;
+    let sectors = volume(HINT);
+    let fs = mount("FAT32Hint", sectors, SECTORS);
+    fs.create_directory(String::from("HINTED")).unwrap();
+    for copy in 0..FAT_COPIES {
+        assert_eq!(entry(sectors, copy, HINT), END_OF_CHAIN);
+        assert_eq!(entry(sectors, copy, ROOT_CLUSTER + 1), 0);
+    }
+    assert_eq!(fs_info(sectors), (CLUSTERS - 2, HINT + 1));
+
+    // freeing gives the space back but leaves the hint where it is
+    fs.remove_directory(String::from("HINTED")).unwrap();
+    assert_eq!(entry(sectors, 0, HINT), 0);
+    assert_eq!(fs_info(sectors), (CLUSTERS - 1, HINT + 1));
+}
+
+#[test_case]
+fn test_fat32_unknown_hint_allocates_from_the_start() {
+    use fat32::*;
+    let sectors = volume(UNKNOWN);
+    let fs = mount("FAT32NoHint", sectors, SECTORS);
+    fs.create_directory(String::from("FIRST")).unwrap();
+    assert_eq!(entry(sectors, 0, ROOT_CLUSTER + 1), END_OF_CHAIN);
+    assert_eq!(fs_info(sectors), (CLUSTERS - 2, ROOT_CLUSTER + 2));
+}
+
+#[test_case]
+fn test_clean_bit_round_trip() {
+    use fat32::*;
+    let sectors = volume(UNKNOWN);
+    let mut fs = mount("FAT32Clean", sectors, SECTORS);
+    assert!(fs.mounted_clean());
+
+    // cleared while mounted, in every FAT
+    fs.set_clean(false).unwrap();
+    for copy in 0..FAT_COPIES {
+        assert_eq!(entry(sectors, copy, 1), END_OF_CHAIN & !CLEAN_BIT);
+    }
+    assert!(!remount("FAT32Clean").mounted_clean());
+
+    fs.unmount().unwrap();
+    for copy in 0..FAT_COPIES {
+        assert_eq!(entry(sectors, copy, 1), END_OF_CHAIN);
+    }
+    assert!(remount("FAT32Clean").mounted_clean());
+}
+
+// a short name entry in the FAT32 root directory
+fn add_file(sectors: &Sectors, slot: usize, name: &[u8; 11], first_cluster: u32, size: u32) {
+    let mut ent = [0u8; 32];
+    ent[..11].copy_from_slice(name);
+    ent[11] = 0x20;
+    ent[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    ent[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    ent[28..32].copy_from_slice(&size.to_le_bytes());
+    write_bytes(sectors, fat32::cluster_offset(fat32::ROOT_CLUSTER) + slot * 32, &ent);
+}
+
+#[test_case]
+fn test_check_volume_finds_and_repairs_cross_linked_files() {
+    use fat32::*;
+    let sectors = volume(UNKNOWN);
+    // both files are two clusters long, but B runs into the second cluster of A
+    add_file(sectors, 0, b"A       TXT", 3, 2 * SECTOR_SIZE as u32);
+    add_file(sectors, 1, b"B       TXT", 5, 2 * SECTOR_SIZE as u32);
+    for (cluster, value) in [(3, 4), (4, END_OF_CHAIN), (5, 4)] {
+        set_entry(sectors, cluster, value);
+    }
+    let fs = mount("FAT32CrossLinked", sectors, SECTORS);
+
+    let report = fs.check_volume(false).unwrap();
+    assert_eq!(report.cross_linked_files, 1);
+    assert_eq!(report.size_mismatches, 1);
+    assert_eq!(report.errors(), 2);
+    assert!(!report.repaired);
+    // only looked
+    assert_eq!(entry(sectors, 0, 5), 4);
+
+    let report = fs.check_volume(true).unwrap();
+    assert_eq!(report.errors(), 2);
+    assert!(report.repaired);
+    // A keeps the shared cluster, B ends before it and is as long as what is left of it
+    for copy in 0..FAT_COPIES {
+        assert_eq!(entry(sectors, copy, 4), END_OF_CHAIN);
+        assert_eq!(entry(sectors, copy, 5), END_OF_CHAIN);
+    }
+    assert_eq!(read_u32(sectors, cluster_offset(ROOT_CLUSTER) + 32 + 28), SECTOR_SIZE as u32);
+    assert_eq!(fs.check_volume(false).unwrap().errors(), 0);
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+use adenos::*;
+use dev::hal::mem::{self, page_mapper, user_memory::USER_SPACE_START, vma::*};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::init(boot_info);
+    dev::hal::init();
+    test_main();
+    loop {
+        dev::hal::cpu::halt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+const PAGE: u64 = USER_SPACE_START as u64;
+
+fn frame_and_flags(page_table: &PageTable) -> (u64, PageTableFlags) {
+    let entry = page_mapper::get_l1_entry_using_table(page_table, PAGE).unwrap();
+    (entry.addr().as_u64(), entry.flags())
+}
+
+// the page as the kernel sees it, through the physical memory mapping
+fn contents(page_table: &PageTable) -> &'static mut u64 {
+    let (frame, _) = frame_and_flags(page_table);
+    unsafe { &mut *((frame + mem::PHYSICAL_MEMORY_OFFSET) as *mut u64) }
+}
+
+#[test_case]
+fn test_write_after_fork_copies_the_page() {
+    let mut address_space = AddressSpace::new();
+    address_space.insert(VirtualMemoryArea::new(PAGE, PAGE + 0x1000, true, false, AreaKind::Anonymous)).unwrap();
+    let parent = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+    let child = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+    address_space.populate(parent, PAGE, true).unwrap();
+    *contents(parent) = 0x1234;
+
+    address_space.share_copy_on_write(parent, child);
+    let (frame, flags) = frame_and_flags(parent);
+    assert_eq!(frame_and_flags(child), (frame, flags));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(COPY_ON_WRITE));
+    assert!(unsafe { mem::FRAME_ALLOCATOR.is_frame_shared(frame) });
+
+    // the child writes first and gets a copy, the parent keeps the frame
+    address_space.populate(child, PAGE, true).unwrap();
+    let (child_frame, child_flags) = frame_and_flags(child);
+    assert_ne!(child_frame, frame);
+    assert!(child_flags.contains(PageTableFlags::WRITABLE));
+    assert!(!child_flags.contains(COPY_ON_WRITE));
+    assert_eq!(*contents(child), 0x1234);
+    *contents(child) = 0x5678;
+    assert_eq!(*contents(parent), 0x1234);
+    assert!(!unsafe { mem::FRAME_ALLOCATOR.is_frame_shared(frame) });
+
+    // nobody else has the frame now, so the parent just gets write access back
+    address_space.populate(parent, PAGE, true).unwrap();
+    let (parent_frame, parent_flags) = frame_and_flags(parent);
+    assert_eq!(parent_frame, frame);
+    assert!(parent_flags.contains(PageTableFlags::WRITABLE));
+    assert!(!parent_flags.contains(COPY_ON_WRITE));
+    assert_eq!(*contents(parent), 0x1234);
+}
+
+#[test_case]
+fn test_read_after_fork_does_not_copy() {
+    let mut address_space = AddressSpace::new();
+    address_space.insert(VirtualMemoryArea::new(PAGE, PAGE + 0x1000, true, false, AreaKind::Anonymous)).unwrap();
+    let parent = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+    let child = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+    address_space.populate(parent, PAGE, false).unwrap();
+    address_space.share_copy_on_write(parent, child);
+    // the page is there already, reading it cannot fault
+    assert!(matches!(address_space.populate(child, PAGE, false), Err(Error::Permissions)));
+    assert_eq!(frame_and_flags(child).0, frame_and_flags(parent).0);
+}
+
+#[test_case]
+fn test_read_only_area_stays_read_only() {
+    let mut address_space = AddressSpace::new();
+    address_space.insert(VirtualMemoryArea::new(PAGE, PAGE + 0x1000, false, false, AreaKind::Image)).unwrap();
+    let parent = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+    let child = page_mapper::copy_over_kernel_tables_but_not_userspace_ones();
+    address_space.populate(parent, PAGE, false).unwrap();
+    address_space.share_copy_on_write(parent, child);
+    assert!(!frame_and_flags(child).1.contains(COPY_ON_WRITE));
+    assert!(matches!(address_space.populate(child, PAGE, true), Err(Error::InvalidAddress)));
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+extern crate alloc;
+
+use adenos::*;
+use dev::hal::mem::{self, frame_allocator::*};
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::init(boot_info);
+    dev::hal::init();
+    test_main();
+    loop {
+        dev::hal::cpu::halt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+fn allocator() -> &'static mut BuddyFrameAllocator {
+    unsafe { &mut mem::FRAME_ALLOCATOR }
+}
+
+#[test_case]
+fn test_allocate_and_free_restore_free_pages() {
+    let free = allocator().get_free_pages();
+    let used = allocator().get_used_pages();
+    let frame = allocator().allocate_frames(5, FRAME_SIZE, Zone::Normal).unwrap();
+    assert_eq!(frame % FRAME_SIZE, 0);
+    assert_eq!(allocator().get_free_pages(), free - 5);
+    assert_eq!(allocator().get_used_pages(), used + 5);
+    allocator().free_frames(frame, 5);
+    assert_eq!(allocator().get_free_pages(), free);
+    assert_eq!(allocator().get_used_pages(), used);
+}
+
+#[test_case]
+fn test_buddies_coalesce_to_max_order() {
+    let free = allocator().get_free_pages();
+    let block_size = FRAME_SIZE << MAX_ORDER;
+    let frame = allocator().allocate_frames(1 << MAX_ORDER, block_size, Zone::Normal).unwrap();
+    assert_eq!(frame % block_size, 0);
+    // odd frames first, so nothing can merge until the even ones come back
+    for i in (1..1u64 << MAX_ORDER).step_by(2) {
+        allocator().free_frame(frame + i * FRAME_SIZE);
+    }
+    for i in (0..1u64 << MAX_ORDER).step_by(2) {
+        allocator().free_frame(frame + i * FRAME_SIZE);
+    }
+    assert_eq!(allocator().get_free_pages(), free);
+    // the block is only handed out whole again if every buddy merged back into it
+    let mut blocks = Vec::new();
+    while let Some(block) = allocator().allocate_frames(1 << MAX_ORDER, block_size, Zone::Normal) {
+        blocks.push(block);
+    }
+    let found = blocks.contains(&frame);
+    for block in blocks {
+        allocator().free_frames(block, 1 << MAX_ORDER);
+    }
+    assert!(found);
+    assert_eq!(allocator().get_free_pages(), free);
+}
+
+#[test_case]
+fn test_dma32_allocation_is_below_limit_and_aligned() {
+    let free = allocator().get_free_pages();
+    for &(count, align) in &[(1, FRAME_SIZE), (3, 0x10000), (16, 0x40000), (1 << HUGE_PAGE_ORDER, HUGE_PAGE_SIZE)] {
+        let frame = allocator().allocate_frames(count, align, Zone::DMA32).unwrap();
+        assert_eq!(frame % align, 0);
+        assert!(frame + count as u64 * FRAME_SIZE <= DMA32_LIMIT);
+        allocator().free_frames(frame, count);
+    }
+    assert_eq!(allocator().get_free_pages(), free);
+}
+
+#[test_case]
+fn test_shared_frame_is_freed_by_last_reference() {
+    let free = allocator().get_free_pages();
+    let frame = allocator().allocate_frame();
+    allocator().share_frame(frame);
+    allocator().share_frame(frame);
+    assert!(allocator().is_frame_shared(frame));
+    allocator().free_frame(frame);
+    assert!(allocator().is_frame_shared(frame));
+    allocator().free_frame(frame);
+    assert!(!allocator().is_frame_shared(frame));
+    assert_eq!(allocator().get_free_pages(), free - 1);
+    allocator().free_frame(frame);
+    assert_eq!(allocator().get_free_pages(), free);
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+extern crate alloc;
+
+use adenos::*;
+use namespace::*;
+use infinity::os::IOHandle;
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::init(boot_info);
+    dev::hal::init();
+    test_main();
+    loop {
+        dev::hal::cpu::halt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+struct TestResource {
+    name: &'static str,
+    open: bool,
+}
+
+impl Resource for TestResource {
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn set_open_state(&mut self, open: bool) {
+        self.open = open;
+    }
+
+    fn unwrap(&mut self) -> ResourceType {
+        ResourceType::Other
+    }
+
+    fn resource_path(&self) -> Vec<String> {
+        vec![String::from("Tests"), String::from(self.name)]
+    }
+}
+
+// opened the way acquiring a handle opens it
+fn open_resource(name: &'static str) -> &'static mut Box<dyn Resource> {
+    let path = vec![String::from("Tests"), String::from(name)];
+    register_resource_path(path.clone(), TestResource { name, open: false });
+    let resource = get_resource_non_generic_parts(path).unwrap();
+    resource.set_open_state(true);
+    resource
+}
+
+fn is_open(name: &'static str) -> bool {
+    get_resource_non_generic_parts(vec![String::from("Tests"), String::from(name)]).unwrap().is_open()
+}
+
+#[test_case]
+fn test_duplicate_keeps_resource_open_until_last_close() {
+    let mut table = HandleTable::new(1);
+    let id = table.insert(open_resource("Duplicated"), None).id;
+    assert_eq!(id, STANDARD_HANDLE_COUNT);
+    let dup = table.duplicate(id, None).unwrap();
+    assert_ne!(dup, id);
+    table.release(id).unwrap();
+    assert!(is_open("Duplicated"));
+    assert!(table.get_mut(id).is_none());
+    table.release(dup).unwrap();
+    assert!(!is_open("Duplicated"));
+    assert!(matches!(table.release(dup), Err(Error::InvalidHandle)));
+}
+
+#[test_case]
+fn test_duplicate_over_a_handle_closes_it() {
+    let mut table = HandleTable::new(1);
+    let first = table.insert(open_resource("Replaced"), None).id;
+    let second = table.insert(open_resource("Replacing"), None).id;
+    assert_eq!(table.duplicate(second, Some(first)).unwrap(), first);
+    assert!(!is_open("Replaced"));
+    table.release(second).unwrap();
+    assert!(is_open("Replacing"));
+    table.release_all();
+    assert!(!is_open("Replacing"));
+}
+
+#[test_case]
+fn test_inherit_shares_standard_handles_only() {
+    let mut parent = HandleTable::new(1);
+    parent.insert(open_resource("Input"), Some(IOHandle::Input as u32));
+    parent.insert(open_resource("Output"), Some(IOHandle::Output as u32));
+    let private = parent.insert(open_resource("Private"), None).id;
+    let mut child = HandleTable::new(2);
+    child.inherit(&mut parent);
+    assert_eq!(child.get_mut(IOHandle::Input as u32).unwrap().owner, 2);
+    assert!(child.get_mut(IOHandle::Output as u32).is_some());
+    assert!(child.get_mut(IOHandle::Log as u32).is_none());
+    assert!(child.get_mut(private).is_none());
+
+    // the parent closing its side leaves the child's open
+    parent.release_all();
+    assert!(!is_open("Private"));
+    assert!(is_open("Input"));
+    assert!(is_open("Output"));
+    child.release_all();
+    assert!(!is_open("Input"));
+    assert!(!is_open("Output"));
+}
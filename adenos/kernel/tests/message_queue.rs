@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+extern crate alloc;
+
+use adenos::*;
+use ipc::*;
+use exec::scheduler;
+use alloc::{boxed::Box, string::String};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::init(boot_info);
+    dev::hal::init();
+    // waiting needs a thread to put to sleep
+    test::run_on_scheduler(test_main)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+fn queue(capacity: usize) -> MessageQueue {
+    MessageQueue::new(scheduler::current_process(), Endpoint::Any, capacity)
+}
+
+#[test_case]
+fn test_wait_without_time_times_out_at_once() {
+    assert!(matches!(queue(4).wait(Some(0)), Err(Error::TimedOut)));
+}
+
+#[test_case]
+fn test_wait_times_out_when_nothing_arrives() {
+    let mq = queue(4);
+    assert!(matches!(mq.wait(Some(20)), Err(Error::TimedOut)));
+    assert!(matches!(mq.try_receive(), Err(Error::NoData)));
+}
+
+#[test_case]
+fn test_wait_returns_when_a_message_is_queued() {
+    let mq = queue(4);
+    mq.send(Message::new(b"hello")).unwrap();
+    assert!(mq.wait(Some(20)).is_ok());
+    assert_eq!(mq.try_receive().unwrap().bytes.as_slice(), b"hello");
+}
+
+#[test_case]
+fn test_send_to_full_queue_is_out_of_space() {
+    let mq = queue(2);
+    mq.send(Message::new(b"a")).unwrap();
+    mq.send(Message::new(b"b")).unwrap();
+    assert!(matches!(mq.send(Message::new(b"c")), Err(Error::OutOfSpace)));
+    // nothing was dropped to make room
+    assert_eq!(mq.try_receive().unwrap().bytes.as_slice(), b"a");
+    assert_eq!(mq.try_receive().unwrap().bytes.as_slice(), b"b");
+    assert!(matches!(mq.try_receive(), Err(Error::NoData)));
+}
+
+#[test_case]
+fn test_oversize_message_stays_queued() {
+    let channel = MessageChannel::new(String::from("oversize"), Box::new(queue(4)));
+    channel.send(Message::new(b"too long for it")).unwrap();
+    let mut small = [0; 4];
+    assert!(matches!(channel.receive_into(&mut small, Some(0)), Err(Error::BufferTooSmall)));
+    let mut buf = [0; 32];
+    let len = channel.receive_into(&mut buf, Some(0)).unwrap();
+    assert_eq!(&buf[..len], b"too long for it");
+}
+
+#[test_case]
+fn test_only_the_owner_may_wait() {
+    let mq = MessageQueue::new(scheduler::current_process() + 1, Endpoint::Any, 4);
+    assert!(matches!(mq.wait(Some(0)), Err(Error::Permissions)));
+}
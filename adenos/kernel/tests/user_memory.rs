@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+use adenos::*;
+use dev::hal::mem::user_memory::*;
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+#[test_case]
+fn test_user_range_inside_user_space() {
+    assert!(check_user_range(USER_SPACE_START, 0x1000).is_ok());
+    assert!(check_user_range(USER_SPACE_END - 0x1000, 0x1000).is_ok());
+    assert!(check_user_range(USER_SPACE_START, USER_SPACE_END - USER_SPACE_START).is_ok());
+}
+
+#[test_case]
+fn test_user_range_outside_user_space() {
+    assert!(matches!(check_user_range(0, 0x1000), Err(Error::InvalidAddress)));
+    assert!(matches!(check_user_range(USER_SPACE_START - 1, 0x10), Err(Error::InvalidAddress)));
+    // the kernel heap and the physical memory mapping
+    assert!(matches!(check_user_range(0x_4444_4444_0000, 0x1000), Err(Error::InvalidAddress)));
+    assert!(matches!(check_user_range(0x_4000_0000_0000, 0x1000), Err(Error::InvalidAddress)));
+}
+
+#[test_case]
+fn test_user_range_crossing_the_end() {
+    assert!(matches!(check_user_range(USER_SPACE_END - 0x10, 0x11), Err(Error::InvalidAddress)));
+    assert!(matches!(check_user_range(USER_SPACE_END, 1), Err(Error::InvalidAddress)));
+}
+
+#[test_case]
+fn test_user_range_overflow() {
+    assert!(matches!(check_user_range(USER_SPACE_START, usize::MAX), Err(Error::InvalidAddress)));
+    assert!(matches!(check_user_range(usize::MAX, 2), Err(Error::InvalidAddress)));
+}
+
+#[test_case]
+fn test_empty_user_range_is_accepted_anywhere() {
+    assert!(check_user_range(0, 0).is_ok());
+    assert!(check_user_range(USER_SPACE_END, 0).is_ok());
+    assert!(check_user_range(usize::MAX, 0).is_ok());
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(adenos::test::test_runner)]
+
+extern crate alloc;
+
+use adenos::*;
+use alloc::{string::String, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(test_kernel_main);
+
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    boot::init(boot_info);
+    dev::hal::init();
+    test_main();
+    loop {
+        dev::hal::cpu::halt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    adenos::panic::test_panic(info)
+}
+
+fn parts(path: &[&str]) -> Vec<String> {
+    path.iter().map(|&part| String::from(part)).collect()
+}
+
+#[test_case]
+fn test_normalize_path_drops_empty_and_current() {
+    assert_eq!(volumes::normalize_path("/Files//a/./b/"), parts(&["Files", "a", "b"]));
+    assert_eq!(volumes::normalize_path("Files/a"), parts(&["Files", "a"]));
+    assert_eq!(volumes::normalize_path(""), parts(&[]));
+}
+
+#[test_case]
+fn test_normalize_path_resolves_parent() {
+    assert_eq!(volumes::normalize_path("/Files/a/../b"), parts(&["Files", "b"]));
+    assert_eq!(volumes::normalize_path("/Files/a/b/../.."), parts(&["Files"]));
+}
+
+#[test_case]
+fn test_normalize_path_stays_at_root() {
+    assert_eq!(volumes::normalize_path("/.."), parts(&[]));
+    assert_eq!(volumes::normalize_path("/../../Files/a"), parts(&["Files", "a"]));
+    assert_eq!(volumes::normalize_path("/Files/../../.."), parts(&[]));
+    assert_eq!(volumes::canonical_path("/../Files/./a/"), "/Files/a");
+}
+
+#[test_case]
+fn test_is_file_path() {
+    assert!(volumes::is_file_path("/Files/a"));
+    assert!(volumes::is_file_path("/Devices/../Files"));
+    assert!(!volumes::is_file_path("/Files/../Devices"));
+    assert!(!volumes::is_file_path("/.."));
+}
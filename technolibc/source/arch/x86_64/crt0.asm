@@ -11,7 +11,7 @@ _start:
 	mov rbp, 0 # set up stack frame
 	mov rdi, [rsp]      # argc
 	lea rsi, [rsp + 8]  # argv
-	lea rdx, [rsp + 16] # envp
+	lea rdx, [rsi + rdi * 8 + 8] # envp, past the NULL that ends argv
 
 	push rdx # save main arguments
 	push rsi
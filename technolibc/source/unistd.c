@@ -0,0 +1,6 @@
+#include <arch.h>
+#include <unistd.h>
+
+int fork(void) {
+    return (int)_syscall(SYSCALL_FORK, 0, 0, 0, 0);
+}
\ No newline at end of file